@@ -0,0 +1,110 @@
+//! Conversions between the translation interfaces of the crate, so code
+//! written against one of them can be given any of the others:
+//!
+//! - every [`GuestMemoryAccessor`] is a [`GuestTranslator`] and an
+//!   [`EPTTranslator`] translating the first byte;
+//! - a `dyn GuestTranslator` is a [`GuestMemoryAccessor`];
+//! - an [`EPTTranslator`], e.g., an address space, becomes a
+//!   [`GuestMemoryAccessor`] with [`EptAccessor`].
+
+use core::marker::PhantomData;
+
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::PagingHandler;
+
+use crate::{
+    EPTTranslator, GuestMemoryAccessor, GuestPhysAddr, GuestTranslator, HostPhysAddr, PAGE_SIZE,
+};
+
+impl<T: GuestMemoryAccessor> EPTTranslator for T {
+    fn guest_phys_to_host_phys(&self, gpa: GuestPhysAddr) -> Option<HostPhysAddr> {
+        self.translate_and_get_limit(gpa)
+            .map(|(host_addr, _)| host_addr)
+    }
+}
+
+impl GuestMemoryAccessor for dyn GuestTranslator + '_ {
+    fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        self.translate_guest(guest_addr)
+    }
+
+    fn mark_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+        self.mark_guest_dirty(guest_addr, len)
+    }
+}
+
+/// A [`GuestMemoryAccessor`] over an [`EPTTranslator`], accessing the host
+/// physical addresses through the linear mapping of `H`.
+///
+/// Only the translated page is known to be contiguous, so accesses are
+/// split at page boundaries. Host writes are not reported to the dirty log
+/// of the translator, see [`AddrSpace::mark_dirty`](crate::AddrSpace::mark_dirty).
+pub struct EptAccessor<'a, T: EPTTranslator + ?Sized, H: PagingHandler> {
+    translator: &'a T,
+    _phantom: PhantomData<H>,
+}
+
+impl<'a, T: EPTTranslator + ?Sized, H: PagingHandler> EptAccessor<'a, T, H> {
+    /// Creates an accessor to the guest memory translated by `translator`.
+    pub const fn new(translator: &'a T) -> Self {
+        Self {
+            translator,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: EPTTranslator + ?Sized, H: PagingHandler> GuestMemoryAccessor for EptAccessor<'_, T, H> {
+    fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        let hpa = self.translator.guest_phys_to_host_phys(guest_addr)?;
+        let host_addr = PhysAddr::from(H::phys_to_virt(hpa).as_usize());
+        Some((host_addr, PAGE_SIZE - guest_addr.align_offset(PAGE_SIZE)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{BASE_PADDR, MEMORY_LEN, MockHal, mock_hal_test};
+    use crate::{MappingFlags, StaticAddrSpace};
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_accessor_bridge() {
+        let base = GuestPhysAddr::from(0);
+        let ram = PhysAddr::from(BASE_PADDR + MEMORY_LEN - 0x2000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = StaticAddrSpace::<MockHal, 1>::new_empty(base, 0x2000).unwrap();
+        aspace.map_linear(base, ram, 0x2000, rw).unwrap();
+
+        // An address space as an accessor, across the page boundary.
+        let accessor = EptAccessor::<_, MockHal>::new(&aspace);
+        accessor
+            .write_buffer(base + 0xffe, &0x1234_5678u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(
+            accessor
+                .translate_and_get_limit(base + 0xffe)
+                .map(|(_, limit)| limit),
+            Some(2)
+        );
+        let mut buf = [0; 4];
+        accessor.read_buffer(base + 0xffe, &mut buf).unwrap();
+        assert_eq!(u32::from_le_bytes(buf), 0x1234_5678);
+
+        // And back to a translator.
+        assert_eq!(
+            accessor.guest_phys_to_host_phys(base + 0x1000),
+            Some(PhysAddr::from(
+                MockHal::phys_to_virt(ram + 0x1000).as_usize()
+            ))
+        );
+        let translator: &dyn GuestTranslator = &accessor;
+        assert_eq!(translator.read_obj::<u16>(base + 0x1000), Ok(0x1234));
+        assert_eq!(
+            translator.read_obj::<u16>(base + 0x2000),
+            Err(axerrno::AxError::InvalidInput)
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use memory_addr::{AddrRange, PhysAddr, VirtAddr, def_usize_addr, def_usize_addr_formatter};
+use axerrno::AxResult;
+use memory_addr::{
+    AddrRange, MemoryAddr, PageIter, PhysAddr, VirtAddr, def_usize_addr, def_usize_addr_formatter,
+};
+use page_table_multiarch::PageSize;
 
 /// Host virtual address.
 pub type HostVirtAddr = VirtAddr;
@@ -17,11 +21,164 @@ def_usize_addr_formatter! {
     GuestPhysAddr = "GPA:{}";
 }
 
+/// The size of the smallest guest page (the translation granule) in bytes.
+///
+/// Page boundaries and alignment are computed with this rather than with a
+/// hard-coded 4K, see also [`AddrSpace::page_size`](crate::AddrSpace::page_size).
+pub const PAGE_SIZE: usize = memory_addr::PAGE_SIZE_4K;
+
+/// [`PAGE_SIZE`] as a page table [`PageSize`].
+#[cfg_attr(not(feature = "alloc"), allow(dead_code))]
+pub(crate) const BASE_PAGE_SIZE: PageSize = PageSize::Size4K;
+
+/// An iterator over the base pages of a guest range, physical by default.
+pub type GuestPageIter<A = GuestPhysAddr> = PageIter<PAGE_SIZE, A>;
+
 /// Guest virtual address range.
 pub type GuestVirtAddrRange = AddrRange<GuestVirtAddr>;
 /// Guest physical address range.
 pub type GuestPhysAddrRange = AddrRange<GuestPhysAddr>;
 
+/// Page iteration over guest address ranges, for [`GuestPhysAddrRange`] and
+/// [`GuestVirtAddrRange`] alike.
+pub trait GuestAddrRangeExt<A: MemoryAddr> {
+    /// Returns an iterator over the base pages overlapping the range, i.e.,
+    /// from its start aligned down to its end aligned up to [`PAGE_SIZE`].
+    ///
+    /// A range ending in the last page of the address space stops before
+    /// that page, whose end cannot be represented.
+    fn pages(&self) -> GuestPageIter<A>;
+}
+
+impl<A: MemoryAddr> GuestAddrRangeExt<A> for AddrRange<A> {
+    fn pages(&self) -> GuestPageIter<A> {
+        let start = self.start.align_down(PAGE_SIZE);
+        let end = match self.end.into().checked_next_multiple_of(PAGE_SIZE) {
+            Some(end) => A::from(end),
+            None => self.end.align_down(PAGE_SIZE),
+        };
+        GuestPageIter::new(start, end.max(start)).unwrap()
+    }
+}
+
+/// The cache line size in bytes assumed for DMA and cache maintenance.
+///
+/// This is the largest line size of common cores of the architecture, e.g.,
+/// 128 bytes on AArch64 (as Linux's `ARCH_DMA_MINALIGN`), so that buffers
+/// aligned to it never share a line with unrelated data.
+pub const CACHE_LINE_SIZE: usize = if cfg!(target_arch = "aarch64") {
+    128
+} else {
+    64
+};
+
+impl GuestPhysAddr {
+    /// Aligns the address upwards to [`CACHE_LINE_SIZE`].
+    pub fn align_up_to_cacheline(self) -> Self {
+        self.align_up(CACHE_LINE_SIZE)
+    }
+
+    /// Aligns the address downwards to [`CACHE_LINE_SIZE`].
+    pub fn align_down_to_cacheline(self) -> Self {
+        self.align_down(CACHE_LINE_SIZE)
+    }
+
+    /// Whether the address is aligned to [`CACHE_LINE_SIZE`].
+    pub fn is_cacheline_aligned(self) -> bool {
+        self.is_aligned(CACHE_LINE_SIZE)
+    }
+}
+
+/// The alignment required by a device for DMA buffers, e.g., when deciding
+/// whether a guest buffer must be bounced or which range to pin.
+///
+/// Always a power of two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DmaAlign(usize);
+
+impl DmaAlign {
+    /// Cache line alignment, the minimum for non-coherent DMA.
+    pub const CACHE_LINE: Self = Self(CACHE_LINE_SIZE);
+    /// Base page alignment.
+    pub const PAGE: Self = Self(PAGE_SIZE);
+
+    /// Creates an alignment of `align` bytes, or returns `None` if `align` is
+    /// not a power of two.
+    pub const fn new(align: usize) -> Option<Self> {
+        if align.is_power_of_two() {
+            Some(Self(align))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the alignment in bytes.
+    pub const fn get(self) -> usize {
+        self.0
+    }
+
+    /// Whether `addr` is aligned.
+    pub fn is_aligned<A: MemoryAddr>(self, addr: A) -> bool {
+        addr.is_aligned(self.0)
+    }
+
+    /// Whether both ends of `range` are aligned, i.e., whether a device can
+    /// access it directly without touching the bytes around it.
+    pub fn is_range_aligned<A: MemoryAddr>(self, range: AddrRange<A>) -> bool {
+        self.is_aligned(range.start) && self.is_aligned(range.end)
+    }
+
+    /// Returns the smallest aligned range containing `range`, e.g., the range
+    /// to flush from the caches or to pin. Returns `None` if its end
+    /// overflows.
+    pub fn expand<A: MemoryAddr>(self, range: AddrRange<A>) -> Option<AddrRange<A>> {
+        let end = range.end.into().checked_next_multiple_of(self.0)?;
+        Some(AddrRange::new(range.start.align_down(self.0), A::from(end)))
+    }
+}
+
+/// Returns the range `[start, start + size)`, or fails with `InvalidInput` if
+/// its end does not fit in a `usize`.
+///
+/// Unlike [`GuestPhysAddrRange::from_start_size`], this never panics, so it
+/// is used on sizes coming from callers.
+pub(crate) fn checked_range(start: GuestPhysAddr, size: usize) -> AxResult<GuestPhysAddrRange> {
+    GuestPhysAddrRange::try_from_start_size(start, size)
+        .ok_or_else(|| axerrno::ax_err_type!(InvalidInput, "range end overflows"))
+}
+
+/// Fails with `InvalidInput` if `range` extends beyond
+/// [`npt::max_supported_gpa`](crate::npt::max_supported_gpa).
+pub(crate) fn check_max_gpa(range: GuestPhysAddrRange) -> AxResult {
+    let max = crate::npt::max_supported_gpa();
+    if range.end.as_usize().saturating_sub(1) > max.as_usize() {
+        warn!("{range:?} extends beyond the highest translatable GPA {max:?}");
+        return axerrno::ax_err!(InvalidInput, "range beyond the supported GPA width");
+    }
+    Ok(())
+}
+
+/// Allows stage-1 (VS-stage) page tables indexed by guest virtual addresses.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+impl page_table_multiarch::riscv::SvVirtAddr for GuestVirtAddr {
+    /// Flushes the VS-stage TLB of the current guest, for `vaddr` only if
+    /// given.
+    fn flush_tlb(vaddr: Option<Self>) {
+        unsafe {
+            match vaddr {
+                Some(vaddr) => core::arch::asm!(
+                    "hfence.vvma {}",
+                    in(reg) vaddr.as_usize(),
+                    options(nostack, nomem, preserves_flags)
+                ),
+                None => {
+                    core::arch::asm!("hfence.vvma", options(nostack, nomem, preserves_flags))
+                }
+            }
+        }
+    }
+}
+
 #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
 impl page_table_multiarch::riscv::SvVirtAddr for GuestPhysAddr {
     /// Flushes the TLB for the entire address space. The `_vaddr` parameter is ignored.
@@ -34,3 +191,58 @@ impl page_table_multiarch::riscv::SvVirtAddr for GuestPhysAddr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Generic code written against `MemoryAddr` accepts both guest address
+    /// kinds.
+    fn page_count<A: MemoryAddr>(range: AddrRange<A>) -> usize {
+        range.pages().count()
+    }
+
+    #[test]
+    fn test_guest_range_pages() {
+        let gva = GuestVirtAddrRange::from_start_size(GuestVirtAddr::from(0x1ff0), 0x20);
+        let pages: [GuestVirtAddr; 2] = [0x1000.into(), 0x2000.into()];
+        assert!(gva.pages().eq(pages));
+        assert_eq!(gva.start.align_offset_4k(), 0xff0);
+        let gpa = GuestPhysAddrRange::from_start_size(GuestPhysAddr::from(0x3000), 0x2000);
+        assert_eq!(page_count(gpa), 2);
+        let empty = GuestVirtAddrRange::from_start_size(GuestVirtAddr::from(0x3000), 0);
+        assert_eq!(page_count(empty), 0);
+        let top = GuestVirtAddrRange::new((usize::MAX - 0x1fff).into(), usize::MAX.into());
+        assert_eq!(page_count(top), 1);
+    }
+
+    #[test]
+    fn test_dma_alignment() {
+        let gpa = GuestPhysAddr::from(0x1001);
+        assert_eq!(
+            gpa.align_up_to_cacheline(),
+            (0x1000 + CACHE_LINE_SIZE).into()
+        );
+        assert_eq!(gpa.align_down_to_cacheline(), 0x1000.into());
+        assert!(!gpa.is_cacheline_aligned());
+        assert!(GuestPhysAddr::from(0x1000).is_cacheline_aligned());
+
+        assert_eq!(DmaAlign::new(48), None);
+        let align = DmaAlign::new(0x200).unwrap();
+        assert!(align.is_aligned(GuestPhysAddr::from(0x1200)));
+        let buf = GuestPhysAddrRange::from_start_size(0x1210.into(), 0x100);
+        assert!(!align.is_range_aligned(buf));
+        let expanded = align.expand(buf).unwrap();
+        assert_eq!(
+            expanded,
+            GuestPhysAddrRange::new(0x1200.into(), 0x1400.into())
+        );
+        assert!(align.is_range_aligned(expanded));
+        assert!(
+            DmaAlign::PAGE
+                .is_range_aligned(GuestPhysAddrRange::from_start_size(0x3000.into(), 0x1000))
+        );
+        let top = GuestPhysAddrRange::new((usize::MAX - 0xff).into(), (usize::MAX - 1).into());
+        assert_eq!(DmaAlign::PAGE.expand(top), None);
+    }
+}
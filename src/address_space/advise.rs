@@ -0,0 +1,270 @@
+//! Usage hints for guest physical ranges.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, is_aligned};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend};
+use crate::{
+    BASE_PAGE_SIZE, FaultContext, GuestPageIter, GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, npt,
+};
+
+/// Number of pages populated after a fault in a [`Advice::Sequential`] range,
+/// in addition to the faulting one.
+const FAULT_AROUND_PAGES: usize = 8;
+
+/// Advice about the use of a guest physical range, given to
+/// [`AddrSpace::advise`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// The range will be accessed soon: populate it now.
+    WillNeed,
+    /// The contents of the range are not needed anymore: release its frames.
+    DontNeed,
+    /// The range will be accessed sequentially: fault in the following pages
+    /// together with the faulting one.
+    Sequential,
+    /// The range will be accessed randomly: fault in single pages only.
+    Random,
+    /// The range should be mapped with huge pages where possible.
+    HugePage,
+    /// The range must not be mapped with huge pages.
+    NoHugePage,
+}
+
+/// The expected access pattern of a range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessPattern {
+    /// No particular pattern.
+    #[default]
+    Normal,
+    /// Set by [`Advice::Sequential`].
+    Sequential,
+    /// Set by [`Advice::Random`].
+    Random,
+}
+
+/// Whether a range may be mapped with huge pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HugePagePolicy {
+    /// As allowed by the granularity of the areas.
+    #[default]
+    Default,
+    /// Set by [`Advice::HugePage`].
+    Always,
+    /// Set by [`Advice::NoHugePage`].
+    Never,
+}
+
+/// The persistent hints of a range, set by [`AddrSpace::advise`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RangeHints {
+    /// The expected access pattern.
+    pub access: AccessPattern,
+    /// The huge page policy.
+    pub huge_pages: HugePagePolicy,
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Gives advice about the use of `[start, start + size)`.
+    ///
+    /// [`Advice::WillNeed`] and [`Advice::DontNeed`] act immediately on the
+    /// lazily allocated areas of the range (other areas are left alone):
+    /// the former faults in every page, the latter releases the frames, so
+    /// that the pages read as zeros when faulted in again. `DontNeed` is
+    /// rejected while the address space is sealed.
+    ///
+    /// The other advices are remembered for the range (not for the areas, so
+    /// they outlive remapping) and can be queried with
+    /// [`AddrSpace::hints_at`]. [`Advice::Sequential`] makes page faults
+    /// populate a few following pages as well.
+    pub fn advise(&mut self, start: GuestPhysAddr, size: usize, advice: Advice) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned(PAGE_SIZE) || !is_aligned(size, PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        match advice {
+            Advice::WillNeed => self.populate_lazy(range),
+            Advice::DontNeed => self.release_lazy(range),
+            Advice::Sequential | Advice::Random => {
+                let access = if advice == Advice::Sequential {
+                    AccessPattern::Sequential
+                } else {
+                    AccessPattern::Random
+                };
+                self.layout.hints.update(range, |h| h.access = access);
+                Ok(())
+            }
+            Advice::HugePage | Advice::NoHugePage => {
+                let policy = if advice == Advice::HugePage {
+                    HugePagePolicy::Always
+                } else {
+                    HugePagePolicy::Never
+                };
+                self.layout.hints.update(range, |h| h.huge_pages = policy);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the hints in effect at `gpa`.
+    pub fn hints_at(&self, gpa: GuestPhysAddr) -> RangeHints {
+        self.layout.hints.get(gpa)
+    }
+
+    /// Returns the lazily allocated parts of `range`, with their flags.
+    fn lazy_parts(
+        &self,
+        range: GuestPhysAddrRange,
+    ) -> Vec<(GuestPhysAddr, GuestPhysAddr, MappingFlags)> {
+        self.layout
+            .areas()
+            .iter()
+            .filter(|a| a.start() < range.end && a.end() > range.start)
+            .filter(|a| {
+                matches!(
+                    a.backend(),
+                    Backend::Alloc {
+                        populate: false,
+                        ..
+                    }
+                )
+            })
+            .map(|a| {
+                (
+                    a.start().max(range.start),
+                    a.end().min(range.end),
+                    a.flags(),
+                )
+            })
+            .collect()
+    }
+
+    fn populate_lazy(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        for (start, end, flags) in self.lazy_parts(range) {
+            let Some(area) = self.layout.find_area(start) else {
+                continue;
+            };
+            let backend = area.backend();
+            let block = backend.granularity().min() as usize;
+            for addr in GuestPageIter::new(start, end).unwrap() {
+                if self.state.pt.query(addr).is_err() {
+                    if !backend.handle_page_fault(
+                        addr,
+                        flags,
+                        &mut self.state.pt,
+                        &FaultContext::NONE,
+                    ) {
+                        return ax_err!(NoMemory, "failed to populate range");
+                    }
+                    self.mark_dirty(addr.align_down(block), block);
+                }
+            }
+            self.rmap_update(start, end - start);
+        }
+        Ok(())
+    }
+
+    /// Releases the frames of the lazily allocated parts of `range`, see
+    /// [`Advice::DontNeed`].
+    pub(crate) fn release_lazy(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        self.check_unsealed()?;
+        self.check_split_points(range.start, range.size())?;
+        for (start, end, _) in self.lazy_parts(range) {
+            for addr in GuestPageIter::new(start, end).unwrap() {
+                if let Ok((frame, BASE_PAGE_SIZE, tlb)) = self.state.pt.unmap(addr) {
+                    tlb.ignore();
+                    H::dealloc_frame(frame);
+                }
+                // Restore the placeholder entry of the lazy mapping.
+                let _ = npt::map_lazy_placeholder(&mut self.state.pt, addr);
+            }
+            self.rmap_update(start, end - start);
+        }
+        npt::flush_tlb(None);
+        Ok(())
+    }
+
+    /// Populates the pages following `vaddr` in a sequentially accessed
+    /// range, after a fault at `vaddr` was handled.
+    pub(crate) fn fault_around(&mut self, vaddr: GuestPhysAddr) {
+        if self.hints_at(vaddr).access != AccessPattern::Sequential {
+            return;
+        }
+        let Some(area) = self.layout.find_area(vaddr) else {
+            return;
+        };
+        let next = vaddr.align_down(PAGE_SIZE) + PAGE_SIZE;
+        let end = (next + FAULT_AROUND_PAGES * PAGE_SIZE).min(area.end());
+        for addr in GuestPageIter::new(next, end).unwrap() {
+            if !self.layout.contains(addr)
+                || self.hints_at(addr).access != AccessPattern::Sequential
+                || self.is_ballooned(addr)
+            {
+                break;
+            }
+            if self.state.pt.query(addr).is_err() {
+                if !area.backend().handle_page_fault(
+                    addr,
+                    area.flags(),
+                    &mut self.state.pt,
+                    &FaultContext::NONE,
+                ) {
+                    break;
+                }
+                let block = area.backend().granularity().min() as usize;
+                self.mark_dirty(addr.align_down(block), block);
+            }
+        }
+        self.rmap_update(next, end - next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEALLOC_COUNT, MockHal, mock_hal_test};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_advise() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x8000, rw, false).unwrap();
+
+        aspace.advise(base, 0x2000, Advice::WillNeed).unwrap();
+        assert!(aspace.translate(base + 0x1000).is_some());
+        assert!(aspace.translate(base + 0x2000).is_none());
+
+        aspace.translated_byte_buffer(base, 1).unwrap()[0][0] = 0x42;
+        aspace.advise(base, 0x2000, Advice::DontNeed).unwrap();
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), 2);
+        assert!(aspace.translate(base).is_none());
+        assert!(aspace.handle_page_fault(base, MappingFlags::READ));
+        assert_eq!(aspace.translated_byte_buffer(base, 1).unwrap()[0][0], 0);
+
+        // Sequential ranges are faulted around, up to the end of the range.
+        aspace
+            .advise(base + 0x4000, 0x3000, Advice::Sequential)
+            .unwrap();
+        assert_eq!(
+            aspace.hints_at(base + 0x5000).access,
+            AccessPattern::Sequential
+        );
+        assert!(aspace.handle_page_fault(base + 0x4000, MappingFlags::READ));
+        assert!(aspace.translate(base + 0x6000).is_some());
+        assert!(aspace.translate(base + 0x7000).is_none());
+
+        aspace.advise(base, 0x1000, Advice::NoHugePage).unwrap();
+        assert_eq!(aspace.hints_at(base).huge_pages, HugePagePolicy::Never);
+        assert_eq!(aspace.hints_at(base + 0x1000), RangeHints::default());
+    }
+}
@@ -0,0 +1,132 @@
+//! Attributes of areas that change how the address space treats them.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, TeardownReport};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, npt};
+
+bitflags::bitflags! {
+    /// Attributes of an area, set with [`AddrSpace::set_area_attributes`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct AreaAttributes: u32 {
+        /// The area survives [`AddrSpace::clear`], e.g., a per-VM shared
+        /// info page that must outlive a guest-requested memory wipe or
+        /// soft reboot. It is still removed by [`AddrSpace::unmap`] and when
+        /// the address space is closed or dropped.
+        const PERSISTENT = 1 << 0;
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Sets the attributes of the area containing `gpa`, replacing the
+    /// previous ones.
+    ///
+    /// The attributes belong to the area: they apply to all of it, and are
+    /// dropped with the parts of it that are unmapped. Fails with
+    /// [`AxError::NotFound`](axerrno::AxError::NotFound) if no area contains
+    /// `gpa`.
+    pub fn set_area_attributes(&mut self, gpa: GuestPhysAddr, attrs: AreaAttributes) -> AxResult {
+        let Some(area) = self.layout.find_area(gpa) else {
+            return ax_err!(NotFound, "no area at the address");
+        };
+        let range = area.va_range();
+        self.layout.attributes.set(range, attrs);
+        Ok(())
+    }
+
+    /// Returns the attributes of the area containing `gpa`, empty if there
+    /// is none.
+    pub fn area_attributes(&self, gpa: GuestPhysAddr) -> AreaAttributes {
+        self.layout.attributes.get(gpa)
+    }
+
+    /// Removes every area but the [`AreaAttributes::PERSISTENT`] ones, see
+    /// [`AddrSpace::clear`].
+    pub(crate) fn clear_non_persistent(&mut self) -> TeardownReport {
+        let mut report = TeardownReport::default();
+        let doomed: Vec<GuestPhysAddrRange> = self
+            .layout
+            .areas()
+            .iter()
+            .filter(|area| {
+                !self
+                    .area_attributes(area.start())
+                    .contains(AreaAttributes::PERSISTENT)
+            })
+            .map(|area| area.va_range())
+            .collect();
+        for range in doomed {
+            match self.unmap(range.start, range.size()) {
+                Ok(_) => report.areas_unmapped += 1,
+                Err(err) => {
+                    warn!("clear: failed to unmap {range:?}: {err:?}");
+                    report.failed_areas.push((range, err));
+                }
+            }
+        }
+        self.remove_stray_mappings(&mut report);
+        npt::flush_tlb(None);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_persistent_areas() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x4000, rw, true).unwrap();
+        // A shared info page and a balloon stats page.
+        let shared_info = base + 0x8000;
+        aspace
+            .map_linear(shared_info, PhysAddr::from(0x40_0000), 0x1000, rw)
+            .unwrap();
+        let stats = base + 0xa000;
+        aspace.map_alloc(stats, 0x2000, rw, true).unwrap();
+        assert_eq!(
+            aspace.set_area_attributes(base + 0x9000, AreaAttributes::PERSISTENT),
+            Err(AxError::NotFound)
+        );
+        aspace
+            .set_area_attributes(shared_info, AreaAttributes::PERSISTENT)
+            .unwrap();
+        aspace
+            .set_area_attributes(stats + 0x1000, AreaAttributes::PERSISTENT)
+            .unwrap();
+        assert!(
+            aspace
+                .area_attributes(stats)
+                .contains(AreaAttributes::PERSISTENT)
+        );
+        let stats_frame = aspace.translate(stats).unwrap();
+
+        aspace.clear().unwrap();
+        assert!(aspace.translate(base).is_none());
+        assert_eq!(
+            aspace.translate(shared_info),
+            Some(PhysAddr::from(0x40_0000))
+        );
+        assert_eq!(aspace.translate(stats), Some(stats_frame));
+        assert_eq!(aspace.layout.areas().len(), 2);
+
+        // Unmapping drops the attribute with the area.
+        aspace.unmap(stats, 0x2000).unwrap();
+        assert_eq!(aspace.area_attributes(stats), AreaAttributes::empty());
+        aspace.map_alloc(stats, 0x1000, rw, false).unwrap();
+        aspace.clear().unwrap();
+        assert_eq!(aspace.layout.areas().len(), 1);
+    }
+}
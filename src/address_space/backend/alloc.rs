@@ -1,14 +1,73 @@
-use memory_addr::{PageIter4K, PhysAddr};
-use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
+use alloc::vec::Vec;
 
-use super::Backend;
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use log::Level;
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::{MappingFlags, PagingError, PagingHandler, PagingResult};
+
+use super::{Backend, MapGranularity, base_pages};
+#[cfg(test)]
+use crate::test_utils::{FaultInjector, PtOp};
+use crate::{
+    BASE_PAGE_SIZE, FaultContext, GuestPhysAddr,
+    npt::{self, NestedPageTable as PageTable},
+};
+
+/// Returns whether `addr` is mapped to a frame. Lazy placeholders are not.
+fn is_present<H: PagingHandler>(pt: &PageTable<H>, addr: GuestPhysAddr) -> PagingResult<bool> {
+    #[cfg(test)]
+    FaultInjector::check(PtOp::Query, addr)?;
+    match pt.query(addr) {
+        Ok(_) => Ok(true),
+        Err(PagingError::NotMapped) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Maps the base page at `addr` to a newly allocated frame, which is freed
+/// on failure. Replaces the lazy placeholder at `addr` if `remap`.
+fn map_new_frame<H: PagingHandler>(
+    pt: &mut PageTable<H>,
+    addr: GuestPhysAddr,
+    flags: MappingFlags,
+    remap: bool,
+) -> PagingResult {
+    let frame = H::alloc_frame().ok_or(PagingError::NoMemory)?;
+    #[cfg(feature = "verify-frames")]
+    if !crate::address_space::frame_check::verify_frame::<H>(addr, frame) {
+        H::dealloc_frame(frame);
+        return Err(PagingError::NoMemory);
+    }
+    let res = map_frame(pt, addr, frame, flags, remap);
+    if res.is_err() {
+        H::dealloc_frame(frame);
+    }
+    res
+}
+
+fn map_frame<H: PagingHandler>(
+    pt: &mut PageTable<H>,
+    addr: GuestPhysAddr,
+    frame: PhysAddr,
+    flags: MappingFlags,
+    remap: bool,
+) -> PagingResult {
+    #[cfg(test)]
+    FaultInjector::check(PtOp::Map, addr)?;
+    if remap {
+        pt.remap(addr, frame, flags).map(|(_, tlb)| tlb.ignore())
+    } else {
+        pt.map(addr, frame, BASE_PAGE_SIZE, flags)
+            .map(|tlb| tlb.ignore())
+    }
+}
 
 impl<H: PagingHandler> Backend<H> {
     /// Creates a new allocation mapping backend.
     pub const fn new_alloc(populate: bool) -> Self {
         Self::Alloc {
             populate,
+            granularity: MapGranularity::DEFAULT,
+            dealloc_huge: None,
             _phantom: core::marker::PhantomData,
         }
     }
@@ -21,35 +80,55 @@ impl<H: PagingHandler> Backend<H> {
         pt: &mut PageTable<H>,
         populate: bool,
     ) -> bool {
-        debug!(
-            "map_alloc: [{:#x}, {:#x}) {:?} (populate={})",
-            start,
-            start + size,
-            flags,
-            populate
-        );
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "map_alloc: [{:#x}, {:#x}) {:?} (populate={})",
+                start,
+                start + size,
+                flags,
+                populate
+            );
+        }
+        // Pages that are already present (e.g. in an adopted page table) are
+        // taken over as they are.
         if populate {
             // allocate all possible physical frames for populated mapping.
-            for addr in PageIter4K::new(start, start + size).unwrap() {
-                if H::alloc_frame()
-                    .and_then(|frame| pt.map(addr, frame, PageSize::Size4K, flags).ok())
-                    .is_none()
-                {
+            // On failure, the pages mapped so far are rolled back.
+            let Some(pages) = base_pages("map_alloc", start, start + size) else {
+                return false;
+            };
+            let mut mapped = Vec::new();
+            for addr in pages {
+                let res = match is_present(pt, addr) {
+                    Ok(true) => continue,
+                    Ok(false) => map_new_frame(pt, addr, flags, false),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    warn!("map_alloc: failed to populate {addr:?}: {e:?}");
+                    for addr in mapped {
+                        if let Ok((frame, _, tlb)) = pt.unmap(addr) {
+                            tlb.ignore();
+                            H::dealloc_frame(frame);
+                        }
+                    }
                     return false;
                 }
+                mapped.push(addr);
             }
             true
         } else {
-            // Map to a empty entry for on-demand mapping.
-            pt.map_region(
-                start,
-                |_va| PhysAddr::from(0),
-                size,
-                MappingFlags::empty(),
-                false,
-                false,
-            )
-            .is_ok()
+            // Map to a placeholder entry for on-demand mapping.
+            let Some(pages) = base_pages("map_alloc", start, start + size) else {
+                return false;
+            };
+            for addr in pages {
+                match npt::map_lazy_placeholder(pt, addr) {
+                    Ok(()) | Err(PagingError::AlreadyMapped) => {}
+                    Err(_) => return false,
+                }
+            }
+            true
         }
     }
 
@@ -60,15 +139,19 @@ impl<H: PagingHandler> Backend<H> {
         pt: &mut PageTable<H>,
         _populate: bool,
     ) -> bool {
-        debug!("unmap_alloc: [{:#x}, {:#x})", start, start + size);
-        for addr in PageIter4K::new(start, start + size).unwrap() {
+        if log_enabled!(Level::Debug) {
+            debug!("unmap_alloc: [{:#x}, {:#x})", start, start + size);
+        }
+        let Some(pages) = base_pages("unmap_alloc", start, start + size) else {
+            return false;
+        };
+        for addr in pages {
             if let Ok((frame, page_size, _)) = pt.unmap(addr) {
                 // Deallocate the physical frame if there is a mapping in the
                 // page table.
-                if page_size.is_huge() {
+                if !self.dealloc_page(frame, page_size) {
                     return false;
                 }
-                H::dealloc_frame(frame);
             } else {
                 // It's fine if the page is not mapped.
             }
@@ -82,16 +165,151 @@ impl<H: PagingHandler> Backend<H> {
         orig_flags: MappingFlags,
         pt: &mut PageTable<H>,
         populate: bool,
+        ctx: &FaultContext,
     ) -> bool {
         if populate {
             false // Populated mappings should not trigger page faults.
         } else {
-            // Allocate a physical frame lazily and map it to the fault address.
-            // `vaddr` does not need to be aligned. It will be automatically
-            // aligned during `pt.remap` regardless of the page size.
-            H::alloc_frame()
-                .and_then(|frame| pt.remap(vaddr, frame, orig_flags).ok())
-                .is_some()
+            // Allocate physical frames lazily for the whole block of the
+            // minimum granularity containing the fault address. Pages of the
+            // block that are already present are kept.
+            let block_size = self.granularity().min() as usize;
+            let block = vaddr.align_down(block_size);
+            let Some(pages) = base_pages("handle_page_fault_alloc", block, block + block_size)
+            else {
+                return false;
+            };
+            for addr in pages {
+                let res = match is_present(pt, addr) {
+                    Ok(true) => continue,
+                    Ok(false) => map_new_frame(pt, addr, orig_flags, true),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    warn!("{ctx}: failed to fault in {addr:?}: {e:?}");
+                    return false;
+                }
+            }
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{
+        ALLOC_COUNT, DEALLOC_COUNT, FaultInjector, MockHal, PtOp, mock_hal_test,
+    };
+    use crate::{AddrSpace, Backend, GuestPhysAddr, MappingFlags};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+    use memory_addr::PhysAddr;
+    use memory_set::MappingBackend;
+    use page_table_multiarch::PagingError;
+
+    fn live_frames() -> usize {
+        ALLOC_COUNT.load(Ordering::SeqCst) - DEALLOC_COUNT.load(Ordering::SeqCst)
+    }
+
+    /// Creates an address space with the page tables for `[base, base + 2M)`
+    /// already allocated, so that the live frames only count data pages.
+    fn setup() -> (AddrSpace<MockHal>, GuestPhysAddr) {
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x20_0000).unwrap();
+        aspace
+            .map_alloc(base + 0x1f_f000, 0x1000, MappingFlags::READ, false)
+            .unwrap();
+        (aspace, base)
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_populate_map_failure() {
+        let (mut aspace, base) = setup();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let frames = live_frames();
+        FaultInjector::fail(PtOp::Map, base + 0x2000, PagingError::NoMemory);
+        assert!(aspace.map_alloc(base, 0x4000, rw, true).is_err());
+        // The frames of the pages populated before the failure are freed.
+        assert_eq!(live_frames(), frames);
+        assert!(aspace.translate(base).is_none());
+
+        FaultInjector::clear();
+        aspace.map_alloc(base, 0x4000, rw, true).unwrap();
+        assert_eq!(live_frames(), frames + 4);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_fault_remap_failure() {
+        let (mut aspace, base) = setup();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x1000, rw, false).unwrap();
+        let frames = live_frames();
+        FaultInjector::fail(PtOp::Map, base, PagingError::NoMemory);
+        assert!(!aspace.handle_page_fault(base, MappingFlags::WRITE));
+        assert_eq!(live_frames(), frames);
+        assert!(aspace.translate(base).is_none());
+
+        FaultInjector::clear();
+        assert!(aspace.handle_page_fault(base, MappingFlags::WRITE));
+        assert_eq!(live_frames(), frames + 1);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_fault_query_error() {
+        let (mut aspace, base) = setup();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x1000, rw, false).unwrap();
+        let frames = live_frames();
+        // Only a missing mapping is faulted in: the entry may be changing
+        // under a racing update, so nothing is allocated.
+        FaultInjector::fail(PtOp::Query, base, PagingError::MappedToHugePage);
+        assert!(!aspace.handle_page_fault(base, MappingFlags::READ));
+        assert_eq!(live_frames(), frames);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_lazy_placeholder() {
+        let (mut aspace, base) = setup();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x2000, rw, false).unwrap();
+        aspace
+            .map_linear(base + 0x2000, PhysAddr::from(0), 0x1000, rw)
+            .unwrap();
+        assert!(aspace.is_lazy_placeholder(base + 0x1234));
+        assert!(aspace.translate(base).is_none());
+        // Neither a page mapped to address 0 nor an unmapped one.
+        assert!(!aspace.is_lazy_placeholder(base + 0x2000));
+        assert!(!aspace.is_lazy_placeholder(base + 0x3000));
+        assert!(!aspace.is_lazy_placeholder(base + 0x40_0000));
+
+        assert!(aspace.handle_page_fault(base, MappingFlags::WRITE));
+        assert!(!aspace.is_lazy_placeholder(base));
+        assert!(aspace.translate(base).is_some());
+        aspace.unmap(base + 0x1000, 0x1000).unwrap();
+        assert!(!aspace.is_lazy_placeholder(base + 0x1000));
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_unaligned_range() {
+        let (mut aspace, base) = setup();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let frames = live_frames();
+        // A caller breaking the alignment contract fails the operation
+        // instead of panicking the host.
+        for backend in [
+            Backend::<MockHal>::new_alloc(true),
+            Backend::new_alloc(false),
+            Backend::new_cow(0),
+        ] {
+            assert!(!backend.map(base + 0x800, 0x1000, rw, &mut aspace.state.pt));
+            assert!(!backend.unmap(base, 0x800, &mut aspace.state.pt));
         }
+        assert_eq!(live_frames(), frames);
+        assert!(aspace.translate(base).is_none());
     }
 }
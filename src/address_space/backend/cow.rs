@@ -0,0 +1,154 @@
+use alloc::vec::Vec;
+
+use log::Level;
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::{MappingFlags, PagingError, PagingHandler, PagingResult};
+
+use super::{Backend, base_pages};
+use crate::{
+    BASE_PAGE_SIZE, FaultContext, GuestPhysAddr, PAGE_SIZE, npt::NestedPageTable as PageTable,
+};
+
+/// Maps the base page at `addr` to a new frame holding a copy of `template`,
+/// which is freed on failure. Replaces the present entry at `addr` if
+/// `remap`.
+fn map_copy<H: PagingHandler>(
+    pt: &mut PageTable<H>,
+    addr: GuestPhysAddr,
+    template: PhysAddr,
+    flags: MappingFlags,
+    remap: bool,
+) -> PagingResult {
+    let frame = H::alloc_frame().ok_or(PagingError::NoMemory)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            H::phys_to_virt(template).as_ptr(),
+            H::phys_to_virt(frame).as_mut_ptr(),
+            PAGE_SIZE,
+        );
+    }
+    let res = if remap {
+        pt.remap(addr, frame, flags).map(|(_, tlb)| tlb.ignore())
+    } else {
+        pt.map(addr, frame, BASE_PAGE_SIZE, flags)
+            .map(|tlb| tlb.ignore())
+    };
+    if res.is_err() {
+        H::dealloc_frame(frame);
+    }
+    res
+}
+
+impl<H: PagingHandler> Backend<H> {
+    /// Creates a new copy-on-write mapping backend.
+    pub const fn new_cow(pa_va_offset: usize) -> Self {
+        Self::CoW { pa_va_offset }
+    }
+
+    /// Returns the template page of the page containing `vaddr`.
+    pub(crate) fn template_page(vaddr: GuestPhysAddr, pa_va_offset: usize) -> PhysAddr {
+        PhysAddr::from(
+            vaddr
+                .align_down(PAGE_SIZE)
+                .as_usize()
+                .wrapping_sub(pa_va_offset),
+        )
+    }
+
+    pub(crate) fn map_cow(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable<H>,
+        pa_va_offset: usize,
+    ) -> bool {
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "map_cow: [{:#x}, {:#x}) -> {:#x} {:?}",
+                start,
+                start + size,
+                Self::template_page(start, pa_va_offset),
+                flags
+            );
+        }
+        // Pages that are already present (e.g. in an adopted page table) are
+        // taken over as they are, whether shared or copied.
+        let Some(pages) = base_pages("map_cow", start, start + size) else {
+            return false;
+        };
+        let mut mapped = Vec::new();
+        for addr in pages {
+            if pt.query(addr).is_ok() {
+                continue;
+            }
+            let template = Self::template_page(addr, pa_va_offset);
+            match pt.map(addr, template, BASE_PAGE_SIZE, flags - MappingFlags::WRITE) {
+                Ok(tlb) => tlb.ignore(),
+                Err(e) => {
+                    warn!("map_cow: failed to map {addr:?}: {e:?}");
+                    for addr in mapped {
+                        if let Ok((_, _, tlb)) = pt.unmap(addr) {
+                            tlb.ignore();
+                        }
+                    }
+                    return false;
+                }
+            }
+            mapped.push(addr);
+        }
+        true
+    }
+
+    pub(crate) fn unmap_cow(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        pt: &mut PageTable<H>,
+        pa_va_offset: usize,
+    ) -> bool {
+        if log_enabled!(Level::Debug) {
+            debug!("unmap_cow: [{:#x}, {:#x})", start, start + size);
+        }
+        let Some(pages) = base_pages("unmap_cow", start, start + size) else {
+            return false;
+        };
+        for addr in pages {
+            if let Ok((frame, page_size, _)) = pt.unmap(addr) {
+                if page_size.is_huge() {
+                    return false;
+                }
+                // Only the copies are owned, the template is left alone.
+                if frame != Self::template_page(addr, pa_va_offset) {
+                    H::dealloc_frame(frame);
+                }
+            }
+        }
+        true
+    }
+
+    /// Copies the template page of `vaddr` to a private frame. A page
+    /// already copied is left as it is.
+    pub(crate) fn handle_page_fault_cow(
+        &self,
+        vaddr: GuestPhysAddr,
+        orig_flags: MappingFlags,
+        pt: &mut PageTable<H>,
+        pa_va_offset: usize,
+        ctx: &FaultContext,
+    ) -> bool {
+        let addr = vaddr.align_down(PAGE_SIZE);
+        let template = Self::template_page(addr, pa_va_offset);
+        let res = match pt.query(addr) {
+            Ok((paddr, _, _)) if paddr != template => return true,
+            Ok(_) => map_copy(pt, addr, template, orig_flags, true),
+            Err(PagingError::NotMapped) => map_copy(pt, addr, template, orig_flags, false),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("{ctx}: failed to copy {addr:?}: {e:?}");
+            return false;
+        }
+        true
+    }
+}
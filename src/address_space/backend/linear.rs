@@ -1,13 +1,18 @@
-use memory_addr::PhysAddr;
-use page_table_multiarch::{MappingFlags, PagingHandler};
+use log::Level;
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::{MappingFlags, PageSize, PagingError, PagingHandler};
 
-use super::Backend;
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use super::{Backend, MapGranularity};
+use crate::npt::{GenericPTE, NestedPageTable as PageTable, tables};
+use crate::{GuestPhysAddr, PAGE_SIZE};
 
 impl<H: PagingHandler> Backend<H> {
     /// Creates a new linear mapping backend.
     pub const fn new_linear(pa_va_offset: usize) -> Self {
-        Self::Linear { pa_va_offset }
+        Self::Linear {
+            pa_va_offset,
+            granularity: MapGranularity::DEFAULT,
+        }
     }
 
     pub(crate) fn map_linear(
@@ -18,24 +23,111 @@ impl<H: PagingHandler> Backend<H> {
         pt: &mut PageTable<H>,
         pa_va_offset: usize,
     ) -> bool {
-        let pa_start = PhysAddr::from(start.as_usize() - pa_va_offset);
-        debug!(
-            "map_linear: [{:#x}, {:#x}) -> [{:#x}, {:#x}) {:?}",
-            start,
-            start + size,
-            pa_start,
-            pa_start + size,
-            flags
-        );
-        pt.map_region(
-            start,
-            |va| PhysAddr::from(va.as_usize() - pa_va_offset),
-            size,
-            flags,
-            false,
-            false,
-        )
-        .is_ok()
+        let pa_start = PhysAddr::from(start.as_usize().wrapping_sub(pa_va_offset));
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "map_linear: [{:#x}, {:#x}) -> [{:#x}, {:#x}) {:?}",
+                start,
+                start + size,
+                pa_start,
+                pa_start + size,
+                flags
+            );
+        }
+        if self.is_host_only(flags) {
+            // Never installed in the page table.
+            return true;
+        }
+        if pt.query(start).is_ok() {
+            // The region is already mapped, e.g. in an adopted page table.
+            // Take it over only if it matches the requested mapping.
+            return Self::adopt_linear(start, size, pt, pa_va_offset);
+        }
+        match self.map_linear_pages(start, size, flags, pt, pa_va_offset) {
+            Ok(()) => true,
+            Err((failed_at, _)) => {
+                Self::rollback_linear(start, failed_at, pt);
+                false
+            }
+        }
+    }
+
+    /// Maps `[start, start + size)` page by page with the largest page sizes
+    /// allowed, without rolling back on failure.
+    ///
+    /// On failure, returns the address that could not be mapped (everything
+    /// before it is mapped) and the cause.
+    pub(crate) fn map_linear_pages(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable<H>,
+        pa_va_offset: usize,
+    ) -> Result<(), (GuestPhysAddr, PagingError)> {
+        let granularity = self.granularity();
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            let paddr = PhysAddr::from(addr.as_usize().wrapping_sub(pa_va_offset));
+            // The largest allowed page size that fits the remaining region.
+            let page_size = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K]
+                .into_iter()
+                .filter(|&ps| granularity.allows(ps))
+                .find(|&ps| {
+                    addr.is_aligned(ps) && paddr.is_aligned(ps) && end - addr >= ps as usize
+                });
+            let Some(page_size) = page_size else {
+                warn!("map_linear: {addr:?} not aligned to {granularity:?}");
+                return Err((addr, PagingError::NotAligned));
+            };
+            match pt.map(addr, paddr, page_size, flags) {
+                Ok(tlb) => tlb.ignore(),
+                Err(err) => return Err((addr, err)),
+            }
+            addr += page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Removes the pages mapped by a failed [`Backend::map_linear_pages`]
+    /// before `failed_at`.
+    pub(crate) fn rollback_linear(
+        start: GuestPhysAddr,
+        failed_at: GuestPhysAddr,
+        pt: &mut PageTable<H>,
+    ) {
+        if failed_at > start {
+            let _ = pt.unmap_region(start, failed_at - start, true);
+        }
+    }
+
+    fn adopt_linear(
+        start: GuestPhysAddr,
+        size: usize,
+        pt: &PageTable<H>,
+        pa_va_offset: usize,
+    ) -> bool {
+        let end = start.as_usize() + size;
+        // The first address not known to be mapped as expected.
+        let mut next = start.as_usize();
+        let mut matched = true;
+        tables::for_each_leaf_in::<H>(pt.root_paddr(), next, end, &mut |leaf, len, entry| {
+            if matched
+                && (leaf > next || entry.paddr().as_usize() != leaf.wrapping_sub(pa_va_offset))
+            {
+                warn!(
+                    "adopt_linear: mismatched existing mapping at {:#x}",
+                    leaf.max(next)
+                );
+                matched = false;
+            }
+            next = leaf + len;
+        });
+        if matched && next < end {
+            warn!("adopt_linear: mismatched existing mapping at {next:#x}");
+        }
+        matched && next >= end
     }
 
     pub(crate) fn unmap_linear(
@@ -45,7 +137,23 @@ impl<H: PagingHandler> Backend<H> {
         pt: &mut PageTable<H>,
         _pa_va_offset: usize,
     ) -> bool {
-        debug!("unmap_linear: [{:#x}, {:#x})", start, start + size);
-        pt.unmap_region(start, size, true).is_ok()
+        if log_enabled!(Level::Debug) {
+            debug!("unmap_linear: [{:#x}, {:#x})", start, start + size);
+        }
+        // Unlike `unmap_region`, skip the pages that are not mapped anymore,
+        // i.e., poisoned ones.
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            addr = match pt.unmap(addr) {
+                Ok((_, page_size, tlb)) => {
+                    tlb.flush();
+                    addr.align_down(page_size) + page_size as usize
+                }
+                Err(PagingError::NotMapped) => addr + PAGE_SIZE,
+                Err(_) => return false,
+            };
+        }
+        true
     }
 }
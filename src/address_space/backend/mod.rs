@@ -1,21 +1,38 @@
 //! Memory mapping backends.
 
+use memory_addr::{MemoryAddr, PhysAddr};
 use memory_set::MappingBackend;
-use page_table_multiarch::{MappingFlags, PagingHandler};
+use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
 
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use super::MapGranularity;
+use crate::{
+    FaultContext, GuestPageIter, GuestPhysAddr, PAGE_SIZE, npt::NestedPageTable as PageTable,
+};
 
 mod alloc;
+mod cow;
 mod linear;
 
 /// A unified enum type for different memory mapping backends.
 ///
-/// Currently, two backends are implemented:
+/// Currently, three backends are implemented:
 ///
 /// - **Linear**: used for linear mappings. The target physical frames are
 ///   contiguous and their addresses should be known when creating the mapping.
 /// - **Allocation**: used in general, or for lazy mappings. The target physical
 ///   frames are obtained from the global allocator.
+/// - **Copy-on-write**: used for memory shared with a template, e.g., by
+///   clones of a template VM. The template is mapped read-only, and pages are
+///   copied to frames from the global allocator when first written.
+///
+/// When a backend maps a region whose pages are already present in the page
+/// table (as when re-attaching to a page table built by a previous instance,
+/// see [`AddrSpace::import_state`](crate::AddrSpace::import_state)), the
+/// existing entries are taken over instead of being replaced. A linear region
+/// is only taken over if it maps exactly to the expected physical addresses.
+///
+/// Every backend carries the [`MapGranularity`] of its area, which bounds the
+/// page sizes used to map it and the size of the blocks it is faulted in by.
 pub enum Backend<H: PagingHandler> {
     /// Linear mapping backend.
     ///
@@ -23,31 +40,85 @@ pub enum Backend<H: PagingHandler> {
     /// constant, which is specified by `pa_va_offset`. For example, the virtual
     /// address `vaddr` is mapped to the physical address `vaddr - pa_va_offset`.
     Linear {
-        /// `vaddr - paddr`.
+        /// `vaddr - paddr` (wrapping).
         pa_va_offset: usize,
+        /// The mapping granularity of the area.
+        granularity: MapGranularity,
     },
     /// Allocation mapping backend.
     ///
     /// If `populate` is `true`, all physical frames are allocated when the
     /// mapping is created, and no page faults are triggered during the memory
     /// access. Otherwise, the physical frames are allocated on demand (by
-    /// handling page faults).
+    /// handling page faults). Populated areas may also be backed by huge
+    /// frames, see [`AddrSpace::map_alloc_with_policy`].
+    ///
+    /// [`AddrSpace::map_alloc_with_policy`]: crate::AddrSpace::map_alloc_with_policy
     Alloc {
         /// Whether to populate the physical frames when creating the mapping.
         populate: bool,
+        /// The mapping granularity of the area.
+        granularity: MapGranularity,
+        /// Frees the huge frames allocated for the area by
+        /// [`AddrSpace::map_alloc_with_policy`], i.e.,
+        /// [`AxMmHal::dealloc_frames`]. Huge pages of areas without it were
+        /// taken over from an existing page table, and are never freed.
+        ///
+        /// [`AddrSpace::map_alloc_with_policy`]: crate::AddrSpace::map_alloc_with_policy
+        /// [`AxMmHal::dealloc_frames`]: crate::AxMmHal::dealloc_frames
+        dealloc_huge: Option<fn(PhysAddr, usize)>,
         /// A phantom data for the paging handler.
         _phantom: core::marker::PhantomData<H>,
     },
+    /// Copy-on-write mapping backend.
+    ///
+    /// The pages are mapped read-only to the host memory of a template, at a
+    /// constant offset as for `Linear`. The first write to a page copies it
+    /// to a frame allocated from the global allocator, owned by the backend
+    /// and mapped with the flags of the area. The template is never written,
+    /// so it can be shared by many address spaces, and is owned by the
+    /// caller. Pages are always mapped with 4K pages.
+    CoW {
+        /// `vaddr - paddr` (wrapping) of the template.
+        pa_va_offset: usize,
+    },
+}
+
+/// Returns the base pages of `[start, end)` for the operation `op` of a
+/// backend.
+///
+/// Backends are only given page-aligned ranges, but breaking this must not
+/// panic the host, so it is logged and the operation fails instead.
+fn base_pages(op: &str, start: GuestPhysAddr, end: GuestPhysAddr) -> Option<GuestPageIter> {
+    let pages = GuestPageIter::new(start, end);
+    if pages.is_none() {
+        warn!("{op}: [{start:?}, {end:?}) not page-aligned");
+    }
+    pages
 }
 
 impl<H: PagingHandler> Clone for Backend<H> {
     fn clone(&self) -> Self {
         match *self {
-            Self::Linear { pa_va_offset } => Self::Linear { pa_va_offset },
-            Self::Alloc { populate, .. } => Self::Alloc {
+            Self::Linear {
+                pa_va_offset,
+                granularity,
+            } => Self::Linear {
+                pa_va_offset,
+                granularity,
+            },
+            Self::Alloc {
                 populate,
+                granularity,
+                dealloc_huge,
+                ..
+            } => Self::Alloc {
+                populate,
+                granularity,
+                dealloc_huge,
                 _phantom: core::marker::PhantomData,
             },
+            Self::CoW { pa_va_offset } => Self::CoW { pa_va_offset },
         }
     }
 }
@@ -65,15 +136,19 @@ impl<H: PagingHandler> MappingBackend for Backend<H> {
         pt: &mut PageTable<H>,
     ) -> bool {
         match *self {
-            Self::Linear { pa_va_offset } => self.map_linear(start, size, flags, pt, pa_va_offset),
+            Self::Linear { pa_va_offset, .. } => {
+                self.map_linear(start, size, flags, pt, pa_va_offset)
+            }
             Self::Alloc { populate, .. } => self.map_alloc(start, size, flags, pt, populate),
+            Self::CoW { pa_va_offset } => self.map_cow(start, size, flags, pt, pa_va_offset),
         }
     }
 
     fn unmap(&self, start: GuestPhysAddr, size: usize, pt: &mut PageTable<H>) -> bool {
         match *self {
-            Self::Linear { pa_va_offset } => self.unmap_linear(start, size, pt, pa_va_offset),
+            Self::Linear { pa_va_offset, .. } => self.unmap_linear(start, size, pt, pa_va_offset),
             Self::Alloc { populate, .. } => self.unmap_alloc(start, size, pt, populate),
+            Self::CoW { pa_va_offset } => self.unmap_cow(start, size, pt, pa_va_offset),
         }
     }
 
@@ -84,26 +159,158 @@ impl<H: PagingHandler> MappingBackend for Backend<H> {
         new_flags: MappingFlags,
         page_table: &mut PageTable<H>,
     ) -> bool {
-        page_table
-            .protect_region(start, size, new_flags, true)
-            // If the TLB is refreshed immediately every time, there might be performance issues.
-            // The TLB refresh is managed uniformly at a higher level.
-            .map(|tlb| tlb.ignore())
-            .is_ok()
+        // Pages that are not present (e.g. not yet faulted in) are skipped,
+        // they get the new flags of the area when they are mapped.
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            let flags = match (self, page_table.query(addr)) {
+                (Self::CoW { .. }, Ok((paddr, _, _))) => self.page_flags(addr, paddr, new_flags),
+                _ => new_flags,
+            };
+            let next = match page_table.protect(addr, flags) {
+                Ok((page_size, tlb)) => {
+                    // If the TLB is refreshed immediately every time, there might be performance issues.
+                    // The TLB refresh is managed uniformly at a higher level.
+                    tlb.ignore();
+                    addr.align_down(page_size) + page_size as usize
+                }
+                Err(_) => addr.align_down(PAGE_SIZE) + PAGE_SIZE,
+            };
+            addr = next;
+        }
+        true
     }
 }
 
 impl<H: PagingHandler> Backend<H> {
+    /// Returns the mapping granularity of the backend.
+    pub const fn granularity(&self) -> MapGranularity {
+        match *self {
+            Self::Linear { granularity, .. } | Self::Alloc { granularity, .. } => granularity,
+            Self::CoW { .. } => MapGranularity::DEFAULT,
+        }
+    }
+
+    /// Whether `paddr`, which `vaddr` is mapped to, is a frame owned by the
+    /// backend, i.e., to be freed when unmapped.
+    pub(crate) fn owns_frame(&self, vaddr: GuestPhysAddr, paddr: PhysAddr) -> bool {
+        match *self {
+            Self::Linear { .. } => false,
+            Self::Alloc { .. } => true,
+            Self::CoW { pa_va_offset } => {
+                paddr.align_down(PAGE_SIZE) != Self::template_page(vaddr, pa_va_offset)
+            }
+        }
+    }
+
+    /// Returns the flags of the page of `vaddr`, mapped to `paddr`, in an
+    /// area with `flags`: the template pages of copy-on-write areas are never
+    /// writable.
+    pub(crate) fn page_flags(
+        &self,
+        vaddr: GuestPhysAddr,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+    ) -> MappingFlags {
+        match *self {
+            Self::CoW { .. } if !self.owns_frame(vaddr, paddr) => flags - MappingFlags::WRITE,
+            _ => flags,
+        }
+    }
+
+    /// Whether an area with this backend and `flags` is host-only, i.e., a
+    /// linear area without any access permission, see
+    /// [`AddrSpace::map_host_only`](crate::AddrSpace::map_host_only).
+    pub(crate) const fn is_host_only(&self, flags: MappingFlags) -> bool {
+        matches!(self, Self::Linear { .. })
+            && !flags.intersects(
+                MappingFlags::READ
+                    .union(MappingFlags::WRITE)
+                    .union(MappingFlags::EXECUTE),
+            )
+    }
+
+    /// Returns the backend with its mapping granularity replaced.
+    ///
+    /// Copy-on-write backends are returned as they are, as they always map
+    /// 4K pages.
+    pub const fn with_granularity(mut self, new_granularity: MapGranularity) -> Self {
+        match &mut self {
+            Self::Linear { granularity, .. } | Self::Alloc { granularity, .. } => {
+                *granularity = new_granularity
+            }
+            Self::CoW { .. } => {}
+        }
+        self
+    }
+
+    /// Frees the owned frames of a page of `page_size` mapped to `frame`.
+    ///
+    /// Huge pages are only freed, as a whole, by allocation areas that
+    /// allocated them, see `dealloc_huge`. Otherwise they were taken over
+    /// from an existing page table, and `false` is returned.
+    pub(crate) fn dealloc_page(&self, frame: PhysAddr, page_size: PageSize) -> bool {
+        if !page_size.is_huge() {
+            H::dealloc_frame(frame);
+            return true;
+        }
+        match *self {
+            Self::Alloc {
+                dealloc_huge: Some(dealloc),
+                ..
+            } => {
+                dealloc(frame, page_size as usize / PAGE_SIZE);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes every page present in `[start, start + size)` from the page
+    /// table, skipping holes, and frees the frames owned by the backend.
+    ///
+    /// Unlike [`MappingBackend::unmap`], never stops halfway. Returns the
+    /// number of owned base frames that could not be freed, i.e., those of
+    /// huge pages the backend did not allocate.
+    pub(crate) fn release(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        pt: &mut PageTable<H>,
+    ) -> usize {
+        let end = start + size;
+        let mut leaked = 0;
+        let mut addr = start;
+        while addr < end {
+            addr = match pt.unmap(addr) {
+                Ok((frame, page_size, tlb)) => {
+                    tlb.ignore();
+                    if self.owns_frame(addr, frame) && !self.dealloc_page(frame, page_size) {
+                        leaked += page_size as usize / PAGE_SIZE;
+                    }
+                    addr.align_down(page_size) + page_size as usize
+                }
+                Err(_) => addr + PAGE_SIZE,
+            };
+        }
+        leaked
+    }
+
     pub(crate) fn handle_page_fault(
         &self,
         vaddr: GuestPhysAddr,
         orig_flags: MappingFlags,
         page_table: &mut PageTable<H>,
+        ctx: &FaultContext,
     ) -> bool {
         match *self {
             Self::Linear { .. } => false, // Linear mappings should not trigger page faults.
             Self::Alloc { populate, .. } => {
-                self.handle_page_fault_alloc(vaddr, orig_flags, page_table, populate)
+                self.handle_page_fault_alloc(vaddr, orig_flags, page_table, populate, ctx)
+            }
+            Self::CoW { pa_va_offset } => {
+                self.handle_page_fault_cow(vaddr, orig_flags, page_table, pa_va_offset, ctx)
             }
         }
     }
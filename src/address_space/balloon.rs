@@ -0,0 +1,191 @@
+//! Guest-driven ballooning: pages handed back to the host by the guest, e.g.,
+//! through a balloon hypercall or the inflate queue of a virtio-balloon
+//! device, and taken back later.
+
+use alloc::collections::BTreeSet;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, AreaAttributes, Backend, FaultDisposition, ReplayRecord};
+use crate::{BASE_PAGE_SIZE, GuestPhysAddr, GuestPhysAddrRange, MemType, PAGE_SIZE, npt};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Releases the guest pages at `pages` to the host, returning the number
+    /// of pages newly released.
+    ///
+    /// The frames of the pages are freed, and accessing them is a guest
+    /// error, reported as [`PageFaultResult::Guard`], until they are taken
+    /// back with [`AddrSpace::guest_reclaim_pages`]. Duplicates and pages
+    /// already released are ignored.
+    ///
+    /// The list comes from the guest, so it is validated before any page is
+    /// released: fails with `InvalidInput` if an address is not page-aligned
+    /// or outside the address space, and with `PermissionDenied` if a page is
+    /// not guest RAM the host can take back, i.e., not in a lazily allocated,
+    /// readable and writable area of normal memory with 4K granularity, or
+    /// in an [`AreaAttributes::PERSISTENT`] area, or with another
+    /// [`FaultDisposition`] than `Resolve`. Also fails with `BadState` if the
+    /// address space is sealed.
+    ///
+    /// [`PageFaultResult::Guard`]: super::PageFaultResult::Guard
+    pub fn guest_release_pages(&mut self, pages: &[GuestPhysAddr]) -> AxResult<usize> {
+        let sorted = self.check_balloon_pages(pages)?;
+        let mut released = 0;
+        for gpa in sorted {
+            if !self.state.ballooned.insert(gpa) {
+                continue;
+            }
+            // This also drops the placeholder of a page never faulted in.
+            if let Ok((frame, BASE_PAGE_SIZE, tlb)) = self.state.pt.unmap(gpa) {
+                tlb.flush();
+                H::dealloc_frame(frame);
+            }
+            self.rmap_update(gpa, PAGE_SIZE);
+            released += 1;
+        }
+        self.record(ReplayRecord::Release { pages });
+        debug!("guest_release_pages: released {released} pages");
+        Ok(released)
+    }
+
+    /// Takes back guest pages released with
+    /// [`AddrSpace::guest_release_pages`], returning the number of pages
+    /// reclaimed.
+    ///
+    /// The pages are added back lazily: they read as zeros, and a frame is
+    /// allocated at the first access. Duplicates and pages not released are
+    /// ignored. The list is validated as for
+    /// [`AddrSpace::guest_release_pages`].
+    pub fn guest_reclaim_pages(&mut self, pages: &[GuestPhysAddr]) -> AxResult<usize> {
+        let sorted = self.check_balloon_pages(pages)?;
+        let mut reclaimed = 0;
+        for gpa in sorted {
+            if self.state.ballooned.remove(&gpa) {
+                let _ = npt::map_lazy_placeholder(&mut self.state.pt, gpa);
+                reclaimed += 1;
+            }
+        }
+        self.record(ReplayRecord::Reclaim { pages });
+        debug!("guest_reclaim_pages: reclaimed {reclaimed} pages");
+        Ok(reclaimed)
+    }
+
+    /// Returns the number of pages released by the guest and not reclaimed
+    /// yet.
+    pub fn ballooned_pages(&self) -> usize {
+        self.state.ballooned.len()
+    }
+
+    /// Returns whether the page at `gpa` was released by the guest, see
+    /// [`AddrSpace::guest_release_pages`].
+    pub fn is_ballooned(&self, gpa: GuestPhysAddr) -> bool {
+        !self.state.ballooned.is_empty()
+            && self.state.ballooned.contains(&gpa.align_down(PAGE_SIZE))
+    }
+
+    /// Forgets the released pages in `[start, start + size)`, once their
+    /// areas are unmapped or reset.
+    pub(crate) fn clear_ballooned(&mut self, start: GuestPhysAddr, size: usize) {
+        if !self.state.ballooned.is_empty() {
+            let range = GuestPhysAddrRange::from_start_size(start, size);
+            self.state.ballooned.retain(|&gpa| !range.contains(gpa));
+        }
+    }
+
+    /// Validates a guest-provided page list, returning the pages sorted and
+    /// deduplicated.
+    fn check_balloon_pages(&self, pages: &[GuestPhysAddr]) -> AxResult<BTreeSet<GuestPhysAddr>> {
+        self.check_unsealed()?;
+        for &gpa in pages {
+            if !gpa.is_aligned(PAGE_SIZE) {
+                return ax_err!(InvalidInput, "page address not aligned");
+            }
+            if !self.layout.contains(gpa) {
+                return ax_err!(InvalidInput, "page address out of range");
+            }
+            let Some(area) = self.layout.find_area(gpa) else {
+                warn!("balloon: guest page {gpa:?} is not mapped");
+                return ax_err!(PermissionDenied, "page not mapped");
+            };
+            let rw = MappingFlags::READ | MappingFlags::WRITE;
+            let allowed = matches!(
+                area.backend(),
+                Backend::Alloc {
+                    populate: false,
+                    ..
+                }
+            ) && area.backend().granularity().min() == BASE_PAGE_SIZE
+                && area.flags().contains(rw)
+                && MemType::from_flags(area.flags()) == MemType::Normal
+                && !self
+                    .area_attributes(gpa)
+                    .contains(AreaAttributes::PERSISTENT)
+                && self.fault_disposition(gpa) == FaultDisposition::Resolve;
+            if !allowed {
+                warn!("balloon: guest page {gpa:?} cannot be released");
+                return ax_err!(PermissionDenied, "page cannot be released");
+            }
+        }
+        Ok(pages.iter().copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEALLOC_COUNT, MockHal, mock_hal_test};
+    use crate::{FaultContext, PageFaultResult};
+    use axerrno::AxError;
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_guest_balloon() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x4000, rw, false).unwrap();
+        aspace.map_alloc(base + 0x8000, 0x1000, rw, true).unwrap();
+        assert!(aspace.handle_page_fault(base + 0x1000, MappingFlags::WRITE));
+        let fault = |aspace: &mut AddrSpace<MockHal>, gpa| {
+            aspace.handle_page_fault_result(gpa, MappingFlags::READ, &FaultContext::NONE)
+        };
+
+        // Bad lists are rejected as a whole.
+        let deallocs = DEALLOC_COUNT.load(Ordering::SeqCst);
+        for (pages, err) in [
+            ([base + 0x1000, base + 0x800], AxError::InvalidInput),
+            ([base + 0x1000, base + 0x10000], AxError::InvalidInput),
+            ([base + 0x1000, base + 0x5000], AxError::PermissionDenied),
+            ([base + 0x1000, base + 0x8000], AxError::PermissionDenied),
+        ] {
+            assert_eq!(aspace.guest_release_pages(&pages), Err(err));
+        }
+        assert_eq!(aspace.ballooned_pages(), 0);
+
+        // A populated page, a lazy one, and duplicates.
+        let pages = [base + 0x1000, base + 0x2000, base + 0x1000];
+        assert_eq!(aspace.guest_release_pages(&pages), Ok(2));
+        assert_eq!(aspace.guest_release_pages(&pages), Ok(0));
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), deallocs + 1);
+        assert!(aspace.is_ballooned(base + 0x1800));
+        assert_eq!(fault(&mut aspace, base + 0x1000), PageFaultResult::Guard);
+        assert_eq!(fault(&mut aspace, base + 0x2000), PageFaultResult::Guard);
+        assert_eq!(fault(&mut aspace, base + 0x3000), PageFaultResult::Handled);
+
+        assert_eq!(aspace.guest_reclaim_pages(&[base + 0x1000, base]), Ok(1));
+        assert!(aspace.is_lazy_placeholder(base + 0x1000));
+        assert_eq!(fault(&mut aspace, base + 0x1000), PageFaultResult::Handled);
+        assert_eq!(
+            aspace.translated_byte_buffer(base + 0x1000, 4).unwrap()[0],
+            [0; 4]
+        );
+
+        // Unmapping forgets the released pages.
+        aspace.unmap(base, 0x4000).unwrap();
+        assert_eq!(aspace.ballooned_pages(), 0);
+    }
+}
@@ -0,0 +1,194 @@
+//! Mapping many linear regions at once, e.g., the memory layout of a guest
+//! at boot.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use memory_set::MemoryArea;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+#[cfg(feature = "frame-ownership")]
+use super::ownership;
+use super::{AddrSpace, Backend, MapGranularity, ReplayRecord};
+use crate::{
+    GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, mapping_err_to_ax_err, npt, paging_err_to_ax_err,
+};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Adds the linear mappings `(start_vaddr, start_paddr, size, flags)` of
+    /// `regions` like [`AddrSpace::map_linear`], in one pass.
+    ///
+    /// The regions are sorted, and adjacent ones with contiguous host
+    /// memory and the same flags are merged into a single area. The nested
+    /// page table is then filled descending once into every table, rather
+    /// than walking it from the root for every page.
+    ///
+    /// Every region is checked before anything is mapped: fails with
+    /// `InvalidInput` if one is empty, not page-aligned, out of range or
+    /// overlaps another, or as [`AddrSpace::map_linear`] otherwise. The
+    /// address space is left as it was on failure.
+    pub fn map_batch(
+        &mut self,
+        regions: &[(GuestPhysAddr, PhysAddr, usize, MappingFlags)],
+    ) -> AxResult {
+        self.check_context("map_batch");
+        self.check_unsealed()?;
+        let mut sorted = regions.to_vec();
+        sorted.sort_by_key(|&(start, ..)| start);
+        for &(start, paddr, size, _) in &sorted {
+            if size == 0 {
+                return ax_err!(InvalidInput, "empty mapping");
+            }
+            if !self.contains_range(start, size) {
+                return ax_err!(InvalidInput, "address out of range");
+            }
+            if !start.is_aligned(PAGE_SIZE)
+                || !paddr.is_aligned(PAGE_SIZE)
+                || !size.is_multiple_of(PAGE_SIZE)
+            {
+                return ax_err!(InvalidInput, "address not aligned");
+            }
+            self.check_reserved_overlap(start, size)?;
+            self.check_dma_windows_enabled(start, size)?;
+            self.check_mmio_overlap(start, size)?;
+            if self
+                .layout
+                .areas()
+                .overlaps(GuestPhysAddrRange::from_start_size(start, size))
+            {
+                return ax_err!(AlreadyExists, "mapping overlaps an existing area");
+            }
+        }
+        if sorted.windows(2).any(|w| w[0].0 + w[0].2 > w[1].0) {
+            return ax_err!(InvalidInput, "mappings overlap");
+        }
+
+        let mut merged: Vec<(GuestPhysAddr, PhysAddr, usize, MappingFlags)> = Vec::new();
+        for (start, paddr, size, flags) in sorted {
+            match merged.last_mut() {
+                Some(last)
+                    if last.0 + last.2 == start && last.1 + last.2 == paddr && last.3 == flags =>
+                {
+                    last.2 += size
+                }
+                _ => merged.push((start, paddr, size, flags)),
+            }
+        }
+
+        #[cfg(feature = "frame-ownership")]
+        let claims = {
+            let mut claims = Vec::with_capacity(merged.len());
+            for &(start, paddr, size, _) in &merged {
+                let claim = memory_addr::PhysAddrRange::from_start_size(paddr, size);
+                let owner = ownership::FrameOwner {
+                    space: self.state.space_id,
+                    gpa: start,
+                };
+                if let Err(err) = ownership::claim(claim, owner) {
+                    for &claim in &claims {
+                        ownership::release(self.state.space_id, claim);
+                    }
+                    return Err(err);
+                }
+                claims.push(claim);
+            }
+            claims
+        };
+        let offset_of =
+            |start: GuestPhysAddr, paddr: PhysAddr| start.as_usize().wrapping_sub(paddr.as_usize());
+        // Host-only areas are never installed in the page table.
+        let pages: Vec<_> = merged
+            .iter()
+            .filter(|&&(start, paddr, _, flags)| {
+                !Backend::<H>::new_linear(offset_of(start, paddr)).is_host_only(flags)
+            })
+            .map(|&(start, paddr, size, flags)| (start.as_usize(), paddr.as_usize(), size, flags))
+            .collect();
+        // Map the pages first. The areas then take them over, as they do for
+        // an adopted page table.
+        if let Err(err) = npt::tables::map_batch::<H>(self.state.pt.root_paddr(), &pages) {
+            for &(start, _, size, _) in &pages {
+                let mut addr = GuestPhysAddr::from(start);
+                while addr < GuestPhysAddr::from(start + size) {
+                    if let Ok((_, _, tlb)) = self.state.pt.unmap(addr) {
+                        tlb.ignore();
+                    }
+                    addr += PAGE_SIZE;
+                }
+            }
+            #[cfg(feature = "frame-ownership")]
+            for claim in claims {
+                ownership::release(self.state.space_id, claim);
+            }
+            return Err(paging_err_to_ax_err(err));
+        }
+        for &(start, paddr, size, flags) in &merged {
+            let backend = Backend::new_linear(offset_of(start, paddr));
+            let area = MemoryArea::new(start, size, flags, backend);
+            self.layout
+                .areas_mut()
+                .map(area, &mut self.state.pt, false)
+                .map_err(mapping_err_to_ax_err)?;
+            self.mark_dirty(start, size);
+            self.record(ReplayRecord::MapLinear {
+                start,
+                paddr,
+                size,
+                flags,
+                granularity: MapGranularity::DEFAULT,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_map_batch() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let ram = PhysAddr::from(0x80_0000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x20_0000).unwrap();
+        aspace.map_alloc(base, 0x1000, rw, false).unwrap();
+        let frames = aspace.page_table_frames();
+
+        // Out of order, contiguous regions are merged, across a 2M boundary.
+        let regions = [
+            (base + 0x3000, ram + 0x2000, 0x1000, rw),
+            (base + 0x1000, ram, 0x2000, rw),
+            (base + 0x4000, ram + 0x10000, 0x1000, MappingFlags::READ),
+            (base + 0x1ef000, ram + 0x1000_0000, 0x2000, rw),
+        ];
+        aspace.map_batch(&regions).unwrap();
+        assert_eq!(aspace.layout.areas().len(), 4);
+        for (start, paddr, size, _) in regions {
+            assert_eq!(aspace.translate(start), Some(paddr));
+            assert_eq!(aspace.translate(start + size - 1), Some(paddr + size - 1));
+        }
+        assert_eq!(aspace.translate(base + 0x5000), None);
+        // One more last-level table for the second 2M region.
+        assert_eq!(aspace.page_table_frames(), frames + 1);
+
+        // Invalid batches leave the address space alone.
+        let overlapping = [
+            (base + 0x8000, ram, 0x2000, rw),
+            (base + 0x9000, ram, 0x1000, rw),
+        ];
+        assert_eq!(aspace.map_batch(&overlapping), Err(AxError::InvalidInput));
+        let over_area = [
+            (base + 0x8000, ram, 0x1000, rw),
+            (base + 0x4000, ram, 0x1000, rw),
+        ];
+        assert_eq!(aspace.map_batch(&over_area), Err(AxError::AlreadyExists));
+        assert_eq!(aspace.translate(base + 0x8000), None);
+        assert_eq!(aspace.layout.areas().len(), 4);
+    }
+}
@@ -0,0 +1,218 @@
+//! Changing the backing storage of mapped areas.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, Backend, ReplayRecord};
+use crate::{GuestPageIter, GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr, PAGE_SIZE};
+
+/// The backing storage to convert an area to, given to
+/// [`AddrSpace::convert_area`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Host memory owned by the caller, contiguous from `start_paddr`.
+    Linear {
+        /// The host physical address the start of the range is mapped to.
+        start_paddr: HostPhysAddr,
+    },
+    /// Frames allocated from the paging handler and owned by the address
+    /// space.
+    Alloc,
+    /// Copy-on-write pages of the template contiguous from
+    /// `template_paddr`, see [`AddrSpace::map_cow`].
+    CoW {
+        /// The host physical address of the template of the start of the
+        /// range.
+        template_paddr: HostPhysAddr,
+    },
+}
+
+impl BackendKind {
+    /// Returns the kind of `backend`, the backend of an area starting at
+    /// `start`.
+    pub(crate) fn of<H: PagingHandler>(backend: &Backend<H>, start: GuestPhysAddr) -> Self {
+        let start = start.as_usize();
+        match *backend {
+            Backend::Linear { pa_va_offset, .. } => Self::Linear {
+                start_paddr: PhysAddr::from(start.wrapping_sub(pa_va_offset)),
+            },
+            Backend::Alloc { .. } => Self::Alloc,
+            Backend::CoW { pa_va_offset } => Self::CoW {
+                template_paddr: PhysAddr::from(start.wrapping_sub(pa_va_offset)),
+            },
+        }
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Moves the contents of `range` to another kind of backing storage,
+    /// e.g., from a host carve-out to reclaimable frames.
+    ///
+    /// `range` must lie within a single area, and is split from it if
+    /// needed. Its flags and granularity are kept.
+    ///
+    /// - To [`BackendKind::Alloc`], every page is copied into a freshly
+    ///   allocated frame. The previous host memory is left untouched and can
+    ///   be reused by the caller afterwards. Ranges already backed by
+    ///   allocated frames are left alone.
+    /// - To [`BackendKind::Linear`], the contents are copied to the host
+    ///   memory at `start_paddr` (pages not faulted in yet are zeroed) and the
+    ///   frames owned by the address space are released. The target must not
+    ///   overlap the current backing memory.
+    ///
+    /// Copy-on-write areas can only be converted to [`BackendKind::Linear`],
+    /// and no area to [`BackendKind::CoW`], otherwise fails with
+    /// `Unsupported`.
+    ///
+    /// The guest must not access the range during the conversion.
+    pub fn convert_area(&mut self, range: GuestPhysAddrRange, to: BackendKind) -> AxResult {
+        // Recorded as a whole, as the copied contents are not recorded.
+        self.unrecorded(|aspace| aspace.convert_backend(range, to))?;
+        self.record(ReplayRecord::Convert { range, to });
+        Ok(())
+    }
+
+    fn convert_backend(&mut self, range: GuestPhysAddrRange, to: BackendKind) -> AxResult {
+        self.check_unsealed()?;
+        if !range.start.is_aligned(PAGE_SIZE) || !range.end.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        let Some(area) = self.layout.find_area(range.start) else {
+            return ax_err!(NotFound, "range not mapped");
+        };
+        if range.is_empty() || area.end() < range.end {
+            return ax_err!(InvalidInput, "range must lie within a single area");
+        }
+        self.check_dma_windows_enabled(range.start, range.size())?;
+        let flags = area.flags();
+        let backend = area.backend().clone();
+        let granularity = backend.granularity();
+        self.check_split_points(range.start, range.size())?;
+
+        match (to, backend) {
+            (BackendKind::CoW { .. }, _) => {
+                ax_err!(Unsupported, "cannot convert to a copy-on-write area")
+            }
+            (BackendKind::Alloc, Backend::Alloc { .. }) => Ok(()),
+            (BackendKind::Alloc, Backend::CoW { .. }) => {
+                ax_err!(Unsupported, "cannot convert a copy-on-write area")
+            }
+            (BackendKind::Alloc, Backend::Linear { pa_va_offset, .. }) => {
+                let old_paddr = |gpa: usize| PhysAddr::from_usize(gpa.wrapping_sub(pa_va_offset));
+                self.unmap(range.start, range.size())?;
+                if let Err(err) = self.map_alloc_with_granularity(
+                    range.start,
+                    range.size(),
+                    flags,
+                    true,
+                    granularity,
+                ) {
+                    // Restore the original mapping, nothing was copied yet.
+                    self.map_linear_with_granularity(
+                        range.start,
+                        old_paddr(range.start.as_usize()),
+                        range.size(),
+                        flags,
+                        granularity,
+                    )?;
+                    return Err(err);
+                }
+                for gpa in GuestPageIter::new(range.start, range.end).unwrap() {
+                    let Some(new_paddr) = self.translate(gpa) else {
+                        return ax_err!(BadState, "page not populated");
+                    };
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            H::phys_to_virt(old_paddr(gpa.as_usize())).as_ptr(),
+                            H::phys_to_virt(new_paddr).as_mut_ptr(),
+                            PAGE_SIZE,
+                        );
+                    }
+                }
+                Ok(())
+            }
+            (BackendKind::Linear { start_paddr }, _) => {
+                if !granularity.is_aligned(start_paddr.as_usize()) {
+                    return ax_err!(InvalidInput, "address not aligned");
+                }
+                for gpa in GuestPageIter::new(range.start, range.end).unwrap() {
+                    let dst = H::phys_to_virt(start_paddr + (gpa - range.start)).as_mut_ptr();
+                    unsafe {
+                        match self.translate(gpa) {
+                            Some(src) => core::ptr::copy_nonoverlapping(
+                                H::phys_to_virt(src).as_ptr(),
+                                dst,
+                                PAGE_SIZE,
+                            ),
+                            None => core::ptr::write_bytes(dst, 0, PAGE_SIZE),
+                        }
+                    }
+                }
+                self.unmap(range.start, range.size())?;
+                self.map_linear_with_granularity(
+                    range.start,
+                    start_paddr,
+                    range.size(),
+                    flags,
+                    granularity,
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEALLOC_COUNT, MockHal, mock_hal_test};
+    use crate::{GuestPhysAddr, MappingFlags, PhysFrame};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_convert_area() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        // A two-page carve-out owned by the test.
+        let carve_out = [
+            PhysFrame::<MockHal>::alloc().unwrap(),
+            PhysFrame::alloc().unwrap(),
+        ];
+        let carve_paddr = carve_out[0].start_paddr();
+        assert_eq!(carve_out[1].start_paddr(), carve_paddr + PAGE_SIZE);
+        aspace.map_linear(base, carve_paddr, 0x2000, rw).unwrap();
+        aspace.translated_byte_buffer(base + 0x1000, 1).unwrap()[0][0] = 0x33;
+
+        let second = GuestPhysAddrRange::from_start_size(base + 0x1000, 0x1000);
+        aspace.convert_area(second, BackendKind::Alloc).unwrap();
+        assert_ne!(
+            aspace.translate(base + 0x1000),
+            Some(carve_paddr + PAGE_SIZE)
+        );
+        assert_eq!(aspace.translate(base), Some(carve_paddr));
+        assert_eq!(
+            aspace.translated_byte_buffer(base + 0x1000, 1).unwrap()[0][0],
+            0x33
+        );
+        assert!(matches!(
+            aspace.layout.find_area(base + 0x1000).unwrap().backend(),
+            Backend::Alloc { .. }
+        ));
+
+        // And back to the carve-out, releasing the allocated frame.
+        unsafe { *carve_out[1].as_mut_ptr() = 0 };
+        let start_paddr = carve_paddr + PAGE_SIZE;
+        aspace
+            .convert_area(second, BackendKind::Linear { start_paddr })
+            .unwrap();
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), 1);
+        assert_eq!(aspace.translate(base + 0x1000), Some(start_paddr));
+        assert_eq!(unsafe { *carve_out[1].as_mut_ptr() }, 0x33);
+
+        let across = GuestPhysAddrRange::from_start_size(base + 0x1000, 0x2000);
+        assert!(aspace.convert_area(across, BackendKind::Alloc).is_err());
+    }
+}
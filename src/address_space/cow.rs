@@ -0,0 +1,234 @@
+//! Copy-on-write mappings of a template, e.g., the memory of a template VM
+//! shared by its clones.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use memory_set::MemoryArea;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend, ReplayRecord};
+use crate::{
+    GuestPageIter, GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, mapping_err_to_ax_err, npt,
+};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Adds a copy-on-write mapping of the template at `start_paddr`.
+    ///
+    /// The pages are mapped read-only to the template, and copied to frames
+    /// allocated from `H` and owned by the address space when the guest
+    /// first writes them (see [`Backend::CoW`]). The template must stay
+    /// unchanged while mapped, and can be mapped by many address spaces at
+    /// once, e.g., to start clones of a template VM without copying its
+    /// memory.
+    ///
+    /// Host writes through translated addresses (e.g., a
+    /// [`GuestMemoryAccessor`](crate::GuestMemoryAccessor)) would reach the
+    /// template, so the pages must be copied first, as
+    /// [`loader::load_bytes`](crate::loader::load_bytes) does. See
+    /// [`AddrSpace::is_cow_shared`].
+    ///
+    /// The addresses and the size must be page-aligned.
+    pub fn map_cow(
+        &mut self,
+        start_vaddr: GuestPhysAddr,
+        start_paddr: PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> AxResult {
+        self.check_context("map_cow");
+        self.check_unsealed()?;
+        if size == 0 {
+            return ax_err!(InvalidInput, "empty mapping");
+        }
+        if !self.contains_range(start_vaddr, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start_vaddr.is_aligned(PAGE_SIZE)
+            || !start_paddr.is_aligned(PAGE_SIZE)
+            || !size.is_multiple_of(PAGE_SIZE)
+        {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        self.check_reserved_overlap(start_vaddr, size)?;
+        self.check_dma_windows_enabled(start_vaddr, size)?;
+        self.check_mmio_overlap(start_vaddr, size)?;
+
+        let offset = start_vaddr.as_usize().wrapping_sub(start_paddr.as_usize());
+        let area = MemoryArea::new(start_vaddr, size, flags, Backend::new_cow(offset));
+        self.layout
+            .areas_mut()
+            .map(area, &mut self.state.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        self.mark_dirty(start_vaddr, size);
+        self.record(ReplayRecord::MapCoW {
+            start: start_vaddr,
+            paddr: start_paddr,
+            size,
+            flags,
+        });
+        Ok(())
+    }
+
+    /// Returns whether `gpa` is in a page of a copy-on-write area that is
+    /// still mapped to the template.
+    pub fn is_cow_shared(&self, gpa: GuestPhysAddr) -> bool {
+        let Some(area) = self.layout.find_area(gpa) else {
+            return false;
+        };
+        matches!(area.backend(), Backend::CoW { .. })
+            && self
+                .translate_fast(gpa)
+                .is_some_and(|paddr| !area.backend().owns_frame(gpa, paddr))
+    }
+
+    /// Whether a page of `range` is still mapped to the template of a
+    /// copy-on-write area, and thus must not be written by the host.
+    pub(crate) fn has_cow_shared(&self, range: GuestPhysAddrRange) -> bool {
+        self.layout
+            .areas_overlapping(range)
+            .filter(|area| matches!(area.backend(), Backend::CoW { .. }))
+            .any(|area| {
+                let start = area.start().max(range.start).align_down(PAGE_SIZE);
+                let end = area.end().min(range.end);
+                GuestPageIter::new(start, end)
+                    .unwrap()
+                    .any(|page| self.is_cow_shared(page))
+            })
+    }
+
+    /// Discards the copies of the pages of copy-on-write areas in `range`,
+    /// mapping them to their template again, and returns the number of
+    /// pages reverted.
+    ///
+    /// This resets a clone to the state of its template, e.g., on a guest
+    /// reboot. The parts of `range` in other areas are left alone.
+    ///
+    /// Fails with `InvalidInput` if `range` is not page-aligned or not inside
+    /// the address space, or with `BadState` if the address space is sealed.
+    pub fn revert_cow(&mut self, range: GuestPhysAddrRange) -> AxResult<usize> {
+        self.check_context("revert_cow");
+        self.check_unsealed()?;
+        if !self.layout.contains_range(range) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !range.start.is_aligned(PAGE_SIZE) || !range.end.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        let parts: Vec<_> = self
+            .layout
+            .areas_overlapping(range)
+            .filter_map(|area| match *area.backend() {
+                Backend::CoW { pa_va_offset } => Some((
+                    area.start().max(range.start),
+                    area.end().min(range.end),
+                    pa_va_offset,
+                    area.flags(),
+                )),
+                _ => None,
+            })
+            .collect();
+        let mut reverted = 0;
+        for (start, end, pa_va_offset, flags) in parts {
+            for addr in GuestPageIter::new(start, end).unwrap() {
+                let template = Backend::<H>::template_page(addr, pa_va_offset);
+                let Ok((frame, _, _)) = self.state.pt.query(addr) else {
+                    continue;
+                };
+                if frame == template {
+                    continue;
+                }
+                match self
+                    .state
+                    .pt
+                    .remap(addr, template, flags - MappingFlags::WRITE)
+                {
+                    Ok((_, tlb)) => tlb.ignore(),
+                    Err(e) => {
+                        warn!("revert_cow: failed to remap {addr:?}: {e:?}");
+                        continue;
+                    }
+                }
+                H::dealloc_frame(frame);
+                self.mark_dirty(addr, PAGE_SIZE);
+                reverted += 1;
+            }
+            self.rmap_update(start, end - start);
+        }
+        if reverted > 0 {
+            npt::flush_tlb(None);
+        }
+        self.record(ReplayRecord::RevertCoW { range });
+        debug!("revert_cow: reverted {reverted} pages");
+        Ok(reverted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEALLOC_COUNT, MockHal, mock_hal_test};
+    use crate::{FaultContext, PageFaultResult};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_cow() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        // The template VM, and its memory as the template of two clones.
+        let mut template = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        template.map_alloc(base, 0x2000, rw, true).unwrap();
+        template.translated_byte_buffer(base, 1).unwrap()[0][0] = 0x5a;
+        template.translated_byte_buffer(base + 0x1000, 1).unwrap()[0][0] = 0xa5;
+        let frames = [
+            template.translate(base).unwrap(),
+            template.translate(base + 0x1000).unwrap(),
+        ];
+        let mut clones = [0, 1].map(|_| {
+            let mut clone = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+            clone.map_cow(base, frames[0], 0x1000, rw).unwrap();
+            clone.map_cow(base + 0x1000, frames[1], 0x1000, rw).unwrap();
+            clone
+        });
+        let read =
+            |aspace: &AddrSpace<MockHal>, gpa| aspace.translated_byte_buffer(gpa, 1).unwrap()[0][0];
+
+        // Reads share the template, writes copy it.
+        let [first, second] = &mut clones;
+        assert_eq!(first.translate(base), Some(frames[0]));
+        assert!(first.is_cow_shared(base) && !first.allows_access(base, MappingFlags::WRITE));
+        assert_eq!(
+            first.handle_page_fault_result(base, MappingFlags::WRITE, &FaultContext::NONE),
+            PageFaultResult::Handled
+        );
+        assert!(!first.is_cow_shared(base));
+        assert_ne!(first.translate(base), Some(frames[0]));
+        assert!(first.allows_access(base, MappingFlags::WRITE));
+        assert_eq!(read(first, base), 0x5a);
+        first.translated_byte_buffer(base, 1).unwrap()[0][0] = 1;
+        assert_eq!(read(&template, base), 0x5a);
+        assert_eq!(read(second, base), 0x5a);
+        assert!(second.is_cow_shared(base) && first.is_cow_shared(base + 0x1000));
+
+        // Dirty logging leaves the template read-only.
+        first.enable_dirty_logging().unwrap();
+        first.take_dirty_pages();
+        first.disable_dirty_logging();
+        assert!(first.allows_access(base, MappingFlags::WRITE));
+        assert!(!first.allows_access(base + 0x1000, MappingFlags::WRITE));
+
+        // Reverting frees the copy, unmapping frees only the copies.
+        let deallocs = DEALLOC_COUNT.load(Ordering::SeqCst);
+        let whole = GuestPhysAddrRange::from_start_size(base, 0x2000);
+        assert_eq!(first.revert_cow(whole), Ok(1));
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), deallocs + 1);
+        assert_eq!(read(first, base), 0x5a);
+        assert!(second.handle_page_fault(base + 0x1000, MappingFlags::WRITE));
+        second.unmap(base, 0x2000).unwrap();
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), deallocs + 2);
+        assert_eq!(read(&template, base + 0x1000), 0xa5);
+    }
+}
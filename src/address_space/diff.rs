@@ -0,0 +1,204 @@
+//! Comparison of address spaces, e.g., of the source and the destination of
+//! a migration.
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, AreaSnapshot, MeasurementHasher};
+use crate::GuestPhysAddr;
+
+/// An owned page that differs, see [`AddrSpace::diff_layout_with_pages`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageDiff {
+    /// The page is owned by `self` only, i.e., faulted in or copied there.
+    OnlyInSelf(GuestPhysAddr),
+    /// The page is owned by the other address space only.
+    OnlyInOther(GuestPhysAddr),
+    /// The page is owned by both, with different contents.
+    Contents(GuestPhysAddr),
+}
+
+/// The differences between two address spaces, returned by
+/// [`AddrSpace::diff_layout`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LayoutDiff {
+    /// The areas of `self` with no area of the same range in the other
+    /// address space.
+    pub only_in_self: Vec<AreaSnapshot>,
+    /// The areas of the other address space with no area of the same range
+    /// in `self`.
+    pub only_in_other: Vec<AreaSnapshot>,
+    /// The areas of the same range with different flags, backends,
+    /// granularities or attributes, as `(self, other)` pairs.
+    pub changed: Vec<(AreaSnapshot, AreaSnapshot)>,
+    /// Whether the windows, MMIO regions and their handlers, reserved
+    /// ranges or seal modes differ.
+    pub ranges_differ: bool,
+    /// The owned pages that differ, in ascending address order. Only filled
+    /// by [`AddrSpace::diff_layout_with_pages`].
+    pub pages: Vec<PageDiff>,
+}
+
+impl LayoutDiff {
+    /// Whether the address spaces are the same.
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty()
+            && self.only_in_other.is_empty()
+            && self.changed.is_empty()
+            && !self.ranges_differ
+            && self.pages.is_empty()
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Compares the layout of the address space with `other`, as described
+    /// by [`AddrSpace::snapshot`].
+    pub fn diff_layout(&self, other: &Self) -> LayoutDiff {
+        let (ours, theirs) = (self.snapshot(), other.snapshot());
+        let mut diff = LayoutDiff {
+            ranges_differ: ours.windows != theirs.windows
+                || ours.mmio_regions != theirs.mmio_regions
+                || ours.mmio_handlers != theirs.mmio_handlers
+                || ours.reserved != theirs.reserved
+                || ours.sealed != theirs.sealed,
+            ..Default::default()
+        };
+        let mut ours = ours.areas.into_iter().peekable();
+        let mut theirs = theirs.areas.into_iter().peekable();
+        loop {
+            let order = match (ours.peek(), theirs.peek()) {
+                (Some(a), Some(b)) => {
+                    (a.range.start, a.range.end).cmp(&(b.range.start, b.range.end))
+                }
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+            match order {
+                Ordering::Less => diff.only_in_self.extend(ours.next()),
+                Ordering::Greater => diff.only_in_other.extend(theirs.next()),
+                Ordering::Equal => {
+                    let (a, b) = (ours.next().unwrap(), theirs.next().unwrap());
+                    if a != b {
+                        diff.changed.push((a, b));
+                    }
+                }
+            }
+        }
+        diff
+    }
+
+    /// Like [`AddrSpace::diff_layout`], also comparing the digests of the
+    /// pages owned by both address spaces, as returned by
+    /// [`AddrSpace::snapshot_pages`].
+    ///
+    /// The guests must not run while the pages are read.
+    pub fn diff_layout_with_pages<M: MeasurementHasher>(
+        &self,
+        other: &Self,
+        hasher: &mut M,
+    ) -> LayoutDiff
+    where
+        M::Digest: PartialEq,
+    {
+        let mut diff = self.diff_layout(other);
+        let mut ours = self.snapshot_pages().peekable();
+        let mut theirs = other.snapshot_pages().peekable();
+        loop {
+            let order = match (ours.peek(), theirs.peek()) {
+                (Some((a, _)), Some((b, _))) => a.cmp(b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+            match order {
+                Ordering::Less => diff
+                    .pages
+                    .push(PageDiff::OnlyInSelf(ours.next().unwrap().0)),
+                Ordering::Greater => diff
+                    .pages
+                    .push(PageDiff::OnlyInOther(theirs.next().unwrap().0)),
+                Ordering::Equal => {
+                    let ((gpa, a), (_, b)) = (ours.next().unwrap(), theirs.next().unwrap());
+                    hasher.update(a);
+                    let digest = hasher.finalize_reset();
+                    hasher.update(b);
+                    if hasher.finalize_reset() != digest {
+                        diff.pages.push(PageDiff::Contents(gpa));
+                    }
+                }
+            }
+        }
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{BackendKind, GuestPhysAddrRange, MappingFlags};
+    use alloc::vec;
+    use axin::axin;
+
+    /// FNV-1a, good enough to detect content changes in tests.
+    struct Fnv(u64);
+
+    impl MeasurementHasher for Fnv {
+        type Digest = u64;
+
+        fn update(&mut self, data: &[u8]) {
+            for &b in data {
+                self.0 = (self.0 ^ b as u64).wrapping_mul(0x100_0000_01b3);
+            }
+        }
+
+        fn finalize_reset(&mut self) -> u64 {
+            core::mem::replace(&mut self.0, 0xcbf2_9ce4_8422_2325)
+        }
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_diff_layout() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+        let mut source = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        source.map_alloc(base, 0x2000, rw, false).unwrap();
+        source.map_alloc(base + 0x4000, 0x1000, rw, false).unwrap();
+        let mut dest = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        dest.map_alloc(base, 0x2000, rw, false).unwrap();
+        dest.map_alloc(base + 0x4000, 0x1000, rw, false).unwrap();
+        assert!(source.diff_layout_with_pages(&dest, &mut hasher).is_empty());
+
+        assert!(source.handle_page_fault(base, MappingFlags::WRITE));
+        assert!(source.handle_page_fault(base + 0x1000, MappingFlags::WRITE));
+        assert!(dest.handle_page_fault(base, MappingFlags::WRITE));
+        source.translated_byte_buffer(base, 1).unwrap()[0][0] = 1;
+        dest.protect(base + 0x4000, 0x1000, MappingFlags::READ)
+            .unwrap();
+        dest.map_alloc(base + 0x8000, 0x1000, rw, false).unwrap();
+        dest.reserve_mmio(GuestPhysAddrRange::from_start_size(base + 0xa000, 0x1000))
+            .unwrap();
+
+        let diff = source.diff_layout_with_pages(&dest, &mut hasher);
+        assert!(diff.only_in_self.is_empty());
+        assert_eq!(diff.only_in_other.len(), 1);
+        assert_eq!(diff.only_in_other[0].backend, BackendKind::Alloc);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].1.flags, MappingFlags::READ);
+        assert!(diff.ranges_differ);
+        assert_eq!(
+            diff.pages,
+            vec![
+                PageDiff::Contents(base),
+                PageDiff::OnlyInSelf(base + 0x1000)
+            ]
+        );
+        // The layout alone does not read the pages.
+        assert!(source.diff_layout(&dest).pages.is_empty());
+    }
+}
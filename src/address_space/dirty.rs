@@ -0,0 +1,270 @@
+//! Dirty page logging, e.g., for live migration.
+//!
+//! While logging is enabled, the writable pages of normal memory areas are
+//! write-protected in the nested page table. The first guest write to such a
+//! page faults, marks it dirty and restores write access. Writes done by the
+//! host (device emulation, DMA) do not fault, so they must be reported with
+//! [`AddrSpace::mark_dirty`], which [`GuestMemoryAccessor`] does for its
+//! writes.
+//!
+//! [`GuestMemoryAccessor`]: crate::GuestMemoryAccessor
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::AddrSpace;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, MemType, PAGE_SIZE, npt};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Starts dirty page logging.
+    ///
+    /// The bitmap takes one bit per page of the whole address space,
+    /// including the windows added with [`AddrSpace::extend_va_range`].
+    pub fn enable_dirty_logging(&mut self) -> AxResult {
+        if self.state.dirty_bitmap.is_some() {
+            return ax_err!(AlreadyExists, "dirty logging already enabled");
+        }
+        let pages = self.layout.num_pages();
+        self.state.dirty_bitmap =
+            Some((0..pages.div_ceil(64)).map(|_| AtomicU64::new(0)).collect());
+        self.set_write_protection(true);
+        Ok(())
+    }
+
+    /// Stops dirty page logging, restoring write access to every page.
+    ///
+    /// Pages dirtied since the last [`AddrSpace::take_dirty_pages`] are
+    /// forgotten.
+    pub fn disable_dirty_logging(&mut self) {
+        if self.state.dirty_bitmap.take().is_some() {
+            self.set_write_protection(false);
+        }
+    }
+
+    /// Whether dirty page logging is enabled.
+    pub fn is_dirty_logging(&self) -> bool {
+        self.state.dirty_bitmap.is_some()
+    }
+
+    /// Marks the pages overlapping `[gpa, gpa + len)` dirty.
+    ///
+    /// Does nothing if dirty logging is disabled. Addresses outside the
+    /// address space are ignored.
+    pub fn mark_dirty(&self, gpa: GuestPhysAddr, len: usize) {
+        let Some(bitmap) = &self.state.dirty_bitmap else {
+            return;
+        };
+        for window in self.layout.windows() {
+            let start = gpa.max(window.start);
+            let end = (gpa + len).min(window.end);
+            if start >= end {
+                continue;
+            }
+            let first = self.layout.page_index(start).unwrap();
+            let last = self.layout.page_index(end - 1).unwrap();
+            for page in first..=last {
+                let bit = 1 << (page % 64);
+                let old = bitmap[page / 64].fetch_or(bit, Ordering::Relaxed);
+                if old & bit == 0
+                    && let Some(heatmap) = &self.state.heatmap
+                {
+                    heatmap.count_dirty(start.align_down(PAGE_SIZE) + (page - first) * PAGE_SIZE);
+                }
+            }
+        }
+    }
+
+    /// Returns the pages dirtied since logging was enabled or since the last
+    /// call, in ascending order, and write-protects them again.
+    ///
+    /// Returns an empty list if dirty logging is disabled.
+    pub fn take_dirty_pages(&mut self) -> Vec<GuestPhysAddr> {
+        let Some(bitmap) = &self.state.dirty_bitmap else {
+            return Vec::new();
+        };
+        let mut pages = Vec::new();
+        for (i, word) in bitmap.iter().enumerate() {
+            let mut bits = word.swap(0, Ordering::Relaxed);
+            while bits != 0 {
+                let page = i * 64 + bits.trailing_zeros() as usize;
+                pages.push(self.layout.page_at(page).unwrap());
+                bits &= bits - 1;
+            }
+        }
+        // Windows added below earlier ones come later in the bitmap.
+        pages.sort_unstable();
+        for &gpa in &pages {
+            if let Some(flags) = self.logged_flags(gpa)
+                && let Ok((_, tlb)) = self.state.pt.protect(gpa, flags - MappingFlags::WRITE)
+            {
+                tlb.ignore();
+            }
+        }
+        if !pages.is_empty() {
+            npt::flush_tlb(None);
+        }
+        pages
+    }
+
+    /// Handles a write fault on a page write-protected for dirty logging.
+    ///
+    /// Returns `true` if the fault was caused by dirty logging, and accounts
+    /// it to `vcpu`.
+    pub(crate) fn handle_dirty_fault(&mut self, vcpu: Option<usize>, vaddr: GuestPhysAddr) -> bool {
+        if self.state.dirty_bitmap.is_none() {
+            return false;
+        }
+        let Some(flags) = self.logged_flags(vaddr) else {
+            return false;
+        };
+        let Ok((paddr, pte_flags, page_size)) = self.state.pt.query(vaddr) else {
+            return false;
+        };
+        // Template pages of copy-on-write areas are copied on write instead.
+        let flags = match self.layout.find_area(vaddr) {
+            Some(area) => area.backend().page_flags(vaddr, paddr, flags),
+            None => flags,
+        };
+        if pte_flags.contains(MappingFlags::WRITE) || !flags.contains(MappingFlags::WRITE) {
+            return false;
+        }
+        let page = vaddr.align_down(page_size);
+        match self.state.pt.protect(page, flags) {
+            Ok((_, tlb)) => tlb.ignore(),
+            Err(_) => return false,
+        }
+        npt::flush_tlb(Some(page));
+        self.mark_dirty(page, page_size as usize);
+        self.account_dirty_fault(vcpu, page_size as usize);
+        true
+    }
+
+    /// Returns the flags of the area containing `gpa` if its pages are
+    /// write-protected while logging.
+    fn logged_flags(&self, gpa: GuestPhysAddr) -> Option<MappingFlags> {
+        let flags = self.layout.find_area(gpa)?.flags();
+        (flags.contains(MappingFlags::WRITE) && MemType::from_flags(flags) == MemType::Normal)
+            .then_some(flags)
+    }
+
+    /// Removes write access again from the present pages of `range` logged
+    /// for dirtiness, after their flags changed while logging.
+    pub(crate) fn write_protect_logged(&mut self, range: GuestPhysAddrRange) {
+        if self.state.dirty_bitmap.is_none() {
+            return;
+        }
+        let mut gpa = range.start;
+        while gpa < range.end {
+            gpa = match self.state.pt.query(gpa) {
+                Ok((_, _, page_size)) => {
+                    if let Some(flags) = self.logged_flags(gpa)
+                        && let Ok((_, tlb)) =
+                            self.state.pt.protect(gpa, flags - MappingFlags::WRITE)
+                    {
+                        tlb.ignore();
+                    }
+                    gpa.align_down(page_size) + page_size as usize
+                }
+                Err(_) => gpa + PAGE_SIZE,
+            };
+        }
+    }
+
+    /// Removes (or restores) write access on every present page logged for
+    /// dirtiness.
+    fn set_write_protection(&mut self, protect: bool) {
+        let areas: Vec<_> = self
+            .layout
+            .areas()
+            .iter()
+            .filter_map(|a| {
+                let flags = self.logged_flags(a.start())?;
+                Some((a.start(), a.end(), flags, a.backend().clone()))
+            })
+            .collect();
+        for (start, end, flags, backend) in areas {
+            let mut gpa = start;
+            while gpa < end {
+                gpa = match self.state.pt.query(gpa) {
+                    Ok((paddr, _, page_size)) => {
+                        let flags = if protect {
+                            flags - MappingFlags::WRITE
+                        } else {
+                            backend.page_flags(gpa, paddr, flags)
+                        };
+                        if let Ok((_, tlb)) = self.state.pt.protect(gpa, flags) {
+                            tlb.ignore();
+                        }
+                        gpa.align_down(page_size) + page_size as usize
+                    }
+                    Err(_) => gpa + PAGE_SIZE,
+                };
+            }
+        }
+        npt::flush_tlb(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GuestMemoryAccessor;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axin::axin;
+    use memory_addr::PhysAddr;
+
+    /// Accesses guest memory through the host mapping, like device emulation.
+    struct DeviceAccessor<'a>(&'a AddrSpace<MockHal>);
+
+    impl GuestMemoryAccessor for DeviceAccessor<'_> {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            let paddr = self.0.translate(guest_addr)?;
+            let vaddr = MockHal::phys_to_virt(paddr).as_usize();
+            Some((vaddr.into(), PAGE_SIZE - guest_addr.align_offset(PAGE_SIZE)))
+        }
+
+        fn mark_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+            self.0.mark_dirty(guest_addr, len);
+        }
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_dirty_logging() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x4000, rw, true).unwrap();
+        aspace.enable_dirty_logging().unwrap();
+        assert!(aspace.enable_dirty_logging().is_err());
+        let flags_at = |aspace: &AddrSpace<MockHal>, gpa| aspace.state.pt.query(gpa).unwrap().1;
+        assert_eq!(flags_at(&aspace, base), MappingFlags::READ);
+
+        // A guest write traps once and restores write access.
+        assert!(aspace.handle_page_fault(base, MappingFlags::WRITE));
+        assert_eq!(flags_at(&aspace, base), rw);
+
+        // Device writes do not trap, but are reported by the accessor.
+        let accessor = DeviceAccessor(&aspace);
+        accessor.write_buffer(base + 0x1ffe, &[0xaa; 4]).unwrap();
+        let _: u32 = accessor.read_obj(base + 0x3000).unwrap();
+        assert_eq!(
+            aspace.take_dirty_pages(),
+            [base, base + 0x1000, base + 0x2000]
+        );
+        assert_eq!(flags_at(&aspace, base), MappingFlags::READ);
+        assert!(aspace.take_dirty_pages().is_empty());
+
+        DeviceAccessor(&aspace)
+            .write_obj(base + 0x3000, 1u8)
+            .unwrap();
+        assert_eq!(aspace.take_dirty_pages(), [base + 0x3000]);
+
+        aspace.disable_dirty_logging();
+        assert_eq!(flags_at(&aspace, base + 0x3000), rw);
+    }
+}
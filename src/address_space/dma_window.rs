@@ -0,0 +1,273 @@
+//! DMA windows programmed by the guest through an emulated IOMMU.
+//!
+//! When the address space is the stage-2 (or IOMMU) translation of a device,
+//! a guest vIOMMU driver turns whole ranges of it on and off, e.g., when the
+//! device is attached to or detached from a domain. A disabled window has no
+//! page mapped, so every DMA to it faults, but its areas are kept: enabling
+//! it again maps them back from their metadata, without the VMM replaying
+//! the mappings.
+//!
+//! Only linear areas, whose mappings follow from the area alone, can be in a
+//! disabled window.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, paging_err_to_ax_err};
+
+/// A DMA window of an address space, created with
+/// [`AddrSpace::register_dma_window`].
+///
+/// The window stays registered until it is given back to
+/// [`AddrSpace::unregister_dma_window`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct DmaWindow {
+    range: GuestPhysAddrRange,
+}
+
+impl DmaWindow {
+    /// Returns the guest physical range of the window.
+    pub const fn range(&self) -> GuestPhysAddrRange {
+        self.range
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Registers the DMA window `range`, enabled.
+    ///
+    /// `range` must be non-empty, page-aligned and inside the address space.
+    /// It does not need to be mapped. Fails with `InvalidInput` otherwise,
+    /// or with `AlreadyExists` if it overlaps another window.
+    pub fn register_dma_window(&mut self, range: GuestPhysAddrRange) -> AxResult<DmaWindow> {
+        self.check_unsealed()?;
+        if range.is_empty()
+            || !range.start.is_aligned(PAGE_SIZE)
+            || !range.end.is_aligned(PAGE_SIZE)
+            || !self.layout.contains_range(range)
+        {
+            return ax_err!(InvalidInput, "bad DMA window");
+        }
+        let overlaps = self
+            .layout
+            .dma_windows
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, &(r, _))| r.overlaps(range));
+        if overlaps {
+            return ax_err!(AlreadyExists, "DMA window overlaps another window");
+        }
+        self.layout.dma_windows.insert(range.start, (range, true));
+        Ok(DmaWindow { range })
+    }
+
+    /// Unregisters `window`, enabling it first if needed.
+    pub fn unregister_dma_window(&mut self, window: DmaWindow) -> AxResult {
+        self.enable_dma_window(&window)?;
+        self.layout.dma_windows.remove(&window.range.start);
+        Ok(())
+    }
+
+    /// Removes the mappings of `window`, keeping its areas.
+    ///
+    /// Until the window is enabled again, accesses to it fault with
+    /// [`PageFaultResult::DmaDisabled`], and new mappings overlapping it
+    /// fail with `BadState`. Areas can still be protected or unmapped.
+    ///
+    /// Fails with `InvalidInput` if an area in the window is not linear, or
+    /// cannot be split at the boundaries of the window, and with `NotFound`
+    /// if `window` is not registered in this address space.
+    ///
+    /// [`PageFaultResult::DmaDisabled`]: super::PageFaultResult::DmaDisabled
+    pub fn disable_dma_window(&mut self, window: &DmaWindow) -> AxResult {
+        self.check_unsealed()?;
+        if !self.dma_window_state(window)? {
+            return Ok(());
+        }
+        let range = window.range;
+        if self
+            .layout
+            .areas_overlapping(range)
+            .any(|area| !matches!(area.backend(), Backend::Linear { .. }))
+        {
+            return ax_err!(InvalidInput, "DMA window holds non-linear areas");
+        }
+        self.demote_split_points(range.start, range.size())?;
+        self.check_split_points(range.start, range.size())?;
+        self.unmap_window_pages(range.start, range.end);
+        self.flush_tlb(Some(range));
+        self.layout.dma_windows.insert(range.start, (range, false));
+        Ok(())
+    }
+
+    /// Maps the areas of `window` back, as they were when it was disabled
+    /// or as they were changed since.
+    ///
+    /// Fails with `NotFound` if `window` is not registered in this address
+    /// space, with `BadState` if the address space is sealed, or with
+    /// `NoMemory` if a table cannot be allocated, leaving the window
+    /// disabled.
+    pub fn enable_dma_window(&mut self, window: &DmaWindow) -> AxResult {
+        self.check_unsealed()?;
+        if self.dma_window_state(window)? {
+            return Ok(());
+        }
+        let range = window.range;
+        let mut result = Ok(());
+        for area in self.layout.areas_overlapping(range) {
+            let Backend::Linear { pa_va_offset, .. } = *area.backend() else {
+                result = ax_err!(BadState, "non-linear area in a disabled DMA window");
+                break;
+            };
+            let (start, end) = (area.start().max(range.start), area.end().min(range.end));
+            if area.backend().is_host_only(area.flags()) {
+                continue;
+            }
+            if let Err((_, err)) = area.backend().map_linear_pages(
+                start,
+                end - start,
+                area.flags(),
+                &mut self.state.pt,
+                pa_va_offset,
+            ) {
+                result = Err(paging_err_to_ax_err(err));
+                break;
+            }
+        }
+        if result.is_err() {
+            // Nothing was mapped in the window before.
+            self.unmap_window_pages(range.start, range.end);
+            return result;
+        }
+        self.layout.dma_windows.insert(range.start, (range, true));
+        Ok(())
+    }
+
+    /// Returns whether `window` is enabled, or fails with `NotFound` if it
+    /// is not registered in this address space.
+    pub fn is_dma_window_enabled(&self, window: &DmaWindow) -> AxResult<bool> {
+        self.dma_window_state(window)
+    }
+
+    /// Removes the pages mapped in `[start, end)`, skipping the holes.
+    fn unmap_window_pages(&mut self, start: GuestPhysAddr, end: GuestPhysAddr) {
+        let mut addr = start;
+        while addr < end {
+            addr = match self.state.pt.unmap(addr) {
+                Ok((_, page_size, tlb)) => {
+                    tlb.ignore();
+                    addr + page_size as usize
+                }
+                Err(_) => addr + PAGE_SIZE,
+            };
+        }
+    }
+
+    fn dma_window_state(&self, window: &DmaWindow) -> AxResult<bool> {
+        match self.layout.dma_windows.get(&window.range.start) {
+            Some(&(range, enabled)) if range == window.range => Ok(enabled),
+            _ => ax_err!(NotFound, "DMA window not registered"),
+        }
+    }
+
+    /// Returns whether `gpa` is in a disabled DMA window.
+    pub(crate) fn in_disabled_dma_window(&self, gpa: GuestPhysAddr) -> bool {
+        self.layout
+            .dma_windows
+            .range(..=gpa)
+            .next_back()
+            .is_some_and(|(_, &(range, enabled))| !enabled && range.contains(gpa))
+    }
+
+    /// Fails with `BadState` if `[start, start + size)` overlaps a disabled
+    /// DMA window.
+    pub(crate) fn check_dma_windows_enabled(&self, start: GuestPhysAddr, size: usize) -> AxResult {
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        let disabled = self
+            .layout
+            .dma_windows
+            .range(..range.end)
+            .rev()
+            .map(|(_, &window)| window)
+            .take_while(|&(window, _)| window.end > range.start)
+            .any(|(window, enabled)| !enabled && window.overlaps(range));
+        if disabled {
+            return ax_err!(BadState, "range overlaps a disabled DMA window");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{FaultContext, MappingFlags, PageFaultResult, SealMode};
+    use axerrno::AxError;
+    use axin::axin;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_dma_window() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let ram = PhysAddr::from(0x80_0000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_linear(base, ram, 0x4000, rw).unwrap();
+        aspace.map_alloc(base + 0x8000, 0x1000, rw, false).unwrap();
+        let range = GuestPhysAddrRange::from_start_size(base + 0x1000, 0x2000);
+        let window = aspace.register_dma_window(range).unwrap();
+        assert_eq!(
+            aspace.register_dma_window(GuestPhysAddrRange::from_start_size(base, 0x2000)),
+            Err(AxError::AlreadyExists)
+        );
+
+        // Detach: the window is unmapped, the rest of the area stays.
+        aspace.disable_dma_window(&window).unwrap();
+        assert_eq!(aspace.is_dma_window_enabled(&window), Ok(false));
+        assert_eq!(aspace.translate(base + 0x1000), None);
+        assert_eq!(aspace.translate(base + 0x2fff), None);
+        assert_eq!(aspace.translate(base), Some(ram));
+        assert_eq!(aspace.translate(base + 0x3000), Some(ram + 0x3000));
+        assert_eq!(aspace.layout.areas().len(), 2);
+        assert_eq!(
+            aspace.handle_page_fault_result(
+                base + 0x1000,
+                MappingFlags::WRITE,
+                &FaultContext::NONE
+            ),
+            PageFaultResult::DmaDisabled
+        );
+        assert_eq!(
+            aspace.map_linear(base + 0x2000, ram, 0x1000, rw),
+            Err(AxError::BadState)
+        );
+        aspace
+            .protect(base + 0x1000, 0x1000, MappingFlags::READ)
+            .unwrap();
+
+        // Attach: the mappings come back, with the flags changed meanwhile.
+        aspace.seal(SealMode::Temporary);
+        assert_eq!(aspace.enable_dma_window(&window), Err(AxError::BadState));
+        assert_eq!(aspace.translate(base + 0x1000), None);
+        aspace.unseal().unwrap();
+        aspace.enable_dma_window(&window).unwrap();
+        assert_eq!(aspace.translate(base + 0x1000), Some(ram + 0x1000));
+        assert!(!aspace.allows_access(base + 0x1000, MappingFlags::WRITE));
+        assert!(aspace.allows_access(base + 0x2000, MappingFlags::WRITE));
+
+        // Windows over allocated memory cannot be disabled.
+        let ram_window = aspace
+            .register_dma_window(GuestPhysAddrRange::from_start_size(base + 0x8000, 0x1000))
+            .unwrap();
+        assert_eq!(
+            aspace.disable_dma_window(&ram_window),
+            Err(AxError::InvalidInput)
+        );
+        aspace.disable_dma_window(&window).unwrap();
+        aspace.unregister_dma_window(window).unwrap();
+        assert_eq!(aspace.translate(base + 0x1000), Some(ram + 0x1000));
+    }
+}
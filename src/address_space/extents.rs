@@ -0,0 +1,122 @@
+//! Host memory released by unmapping, for callers keeping tables of their
+//! own in sync, e.g., IOMMU, vhost or reverse maps.
+
+use alloc::vec::Vec;
+
+use page_table_multiarch::{GenericPTE, PagingHandler};
+
+use super::AddrSpace;
+use crate::{GuestPhysAddr, HostPhysAddr, npt};
+
+/// A contiguous range of host memory that was mapped into the guest,
+/// returned by [`AddrSpace::unmap`] and [`AddrSpace::unmap_keep_frames`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostExtent {
+    /// The guest physical address the extent was mapped at.
+    pub gpa: GuestPhysAddr,
+    /// The start of the extent in host physical memory.
+    pub hpa: HostPhysAddr,
+    /// The size of the extent in bytes.
+    pub size: usize,
+    /// Whether the frames were owned by the address space, i.e., allocated
+    /// for the guest rather than linear memory of the caller or copy-on-write
+    /// templates.
+    pub owned: bool,
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns the host extents mapped in `[start, start + size)`, in
+    /// ascending guest address order, with the contiguous ones merged.
+    ///
+    /// The range must not split a huge page, see
+    /// [`AddrSpace::check_split_points`].
+    pub(crate) fn host_extents(&self, start: GuestPhysAddr, size: usize) -> Vec<HostExtent> {
+        let mut extents: Vec<HostExtent> = Vec::new();
+        let (start, end) = (start.as_usize(), start.as_usize() + size);
+        npt::tables::for_each_leaf_in::<H>(
+            self.state.pt.root_paddr(),
+            start,
+            end,
+            &mut |leaf, len, entry| {
+                let gpa = GuestPhysAddr::from(leaf);
+                let hpa = entry.paddr();
+                let owned = self
+                    .layout
+                    .find_area(gpa)
+                    .is_some_and(|area| area.backend().owns_frame(gpa, hpa));
+                match extents.last_mut() {
+                    Some(last)
+                        if last.gpa + last.size == gpa
+                            && last.hpa + last.size == hpa
+                            && last.owned == owned =>
+                    {
+                        last.size += len
+                    }
+                    _ => extents.push(HostExtent {
+                        gpa,
+                        hpa,
+                        size: len,
+                        owned,
+                    }),
+                }
+            },
+        );
+        extents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axin::axin;
+    use memory_addr::PhysAddr;
+    use page_table_multiarch::PagingHandler;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_unmap_extents() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let ram = PhysAddr::from(0x80_0000);
+        let template = MockHal::alloc_frame().unwrap();
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        // Two linear areas with contiguous host memory, merged in one extent.
+        aspace.map_linear(base, ram, 0x2000, rw).unwrap();
+        aspace
+            .map_linear(base + 0x2000, ram + 0x2000, 0x1000, MappingFlags::READ)
+            .unwrap();
+        aspace.map_alloc(base + 0x3000, 0x2000, rw, false).unwrap();
+        aspace.map_cow(base + 0x5000, template, 0x1000, rw).unwrap();
+        assert!(aspace.handle_page_fault(base + 0x4000, MappingFlags::WRITE));
+        let frame = aspace.translate(base + 0x4000).unwrap();
+
+        let extents = aspace.unmap(base + 0x1000, 0x5000).unwrap();
+        assert_eq!(
+            extents,
+            [
+                HostExtent {
+                    gpa: base + 0x1000,
+                    hpa: ram + 0x1000,
+                    size: 0x2000,
+                    owned: false,
+                },
+                HostExtent {
+                    gpa: base + 0x4000,
+                    hpa: frame,
+                    size: 0x1000,
+                    owned: true,
+                },
+                HostExtent {
+                    gpa: base + 0x5000,
+                    hpa: template,
+                    size: 0x1000,
+                    owned: false,
+                },
+            ]
+        );
+        assert_eq!(aspace.translate(base), Some(ram));
+        assert!(aspace.unmap(base + 0x8000, 0x1000).unwrap().is_empty());
+    }
+}
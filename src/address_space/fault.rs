@@ -0,0 +1,208 @@
+//! How page faults are handled in each guest physical range.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::device::AccessWidth;
+use crate::{FaultContext, GuestPhysAddr, GuestPhysAddrRange, NestedPageFaultInfo, PAGE_SIZE};
+
+/// How [`AddrSpace::handle_page_fault_result`] treats the faults in a range,
+/// set with [`AddrSpace::set_fault_disposition`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FaultDisposition {
+    /// Faults are resolved by the backend of the area, e.g., by allocating
+    /// a frame.
+    #[default]
+    Resolve,
+    /// Faults are never resolved but reported as [`PageFaultResult::Mmio`],
+    /// for device emulation. Ranges reserved with
+    /// [`AddrSpace::reserve_mmio`] always have this disposition.
+    Mmio,
+    /// A guard range: any access is a guest error, reported as
+    /// [`PageFaultResult::Guard`].
+    Guard,
+    /// Read-only memory, e.g., a firmware ROM: read faults are resolved, and
+    /// writes are reported as [`PageFaultResult::RomWrite`] for the VMM to
+    /// discard. The area should be mapped without `WRITE`, so that writes
+    /// keep faulting once its pages are present.
+    Rom,
+}
+
+/// An access reported by [`PageFaultResult::Mmio`], for device emulation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioAccess {
+    /// The guest physical address accessed.
+    pub gpa: GuestPhysAddr,
+    /// Whether the access is a write.
+    pub is_write: bool,
+    /// The width of the access, if known from the exit information, see
+    /// [`AddrSpace::handle_nested_page_fault`].
+    pub width: Option<AccessWidth>,
+    /// Where to dispatch the access, if it is in a range registered with
+    /// [`AddrSpace::register_mmio`].
+    pub route: Option<MmioRoute>,
+}
+
+/// The device emulation an [`MmioAccess`] is dispatched to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioRoute {
+    /// The handler the range was registered with.
+    pub handler_id: usize,
+    /// The offset of the access in the range.
+    pub offset: usize,
+}
+
+/// The outcome of [`AddrSpace::handle_page_fault_result`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultResult {
+    /// The fault was resolved, the access can be retried.
+    Handled,
+    /// The fault could not be resolved, e.g., because of a permission
+    /// violation or an address not covered by any area.
+    Unhandled,
+    /// The access is to be emulated, see [`FaultDisposition::Mmio`].
+    Mmio(MmioAccess),
+    /// The access hit a guard range, see [`FaultDisposition::Guard`].
+    Guard,
+    /// The access is a write to read-only memory, see
+    /// [`FaultDisposition::Rom`].
+    RomWrite,
+    /// The page was lost to a host memory error (see
+    /// [`AddrSpace::poison_frame`]), the VMM is expected to report it to the
+    /// guest, e.g., by injecting a machine check.
+    HwPoisoned,
+    /// The access is in a disabled DMA window (see
+    /// [`AddrSpace::disable_dma_window`]), the vIOMMU emulation is expected
+    /// to report a translation fault to the guest.
+    DmaDisabled,
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Sets how page faults in `range` are handled.
+    ///
+    /// `range` must be non-empty, page-aligned and inside the address space.
+    /// It does not need to be mapped: the disposition applies to whatever
+    /// area is mapped there later, and is kept when areas are unmapped.
+    pub fn set_fault_disposition(
+        &mut self,
+        range: GuestPhysAddrRange,
+        disposition: FaultDisposition,
+    ) -> AxResult {
+        self.check_unsealed()?;
+        if range.is_empty() || !self.layout.contains_range(range) {
+            return ax_err!(InvalidInput, "range out of range");
+        }
+        if !range.start.is_aligned(PAGE_SIZE) || !range.end.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "range not aligned");
+        }
+        self.layout.dispositions.set(range, disposition);
+        Ok(())
+    }
+
+    /// Handles the nested page fault described by `info`, like
+    /// [`AddrSpace::handle_page_fault_result`], additionally reporting the
+    /// access width of MMIO accesses when `info` has it.
+    pub fn handle_nested_page_fault(
+        &mut self,
+        info: &NestedPageFaultInfo,
+        ctx: &FaultContext,
+    ) -> PageFaultResult {
+        let mut result =
+            self.handle_page_fault_result(info.fault_guest_paddr, info.access_flags, ctx);
+        if let PageFaultResult::Mmio(access) = &mut result {
+            access.width = info.access_width;
+        }
+        result
+    }
+
+    /// Returns how page faults at `gpa` are handled.
+    pub fn fault_disposition(&self, gpa: GuestPhysAddr) -> FaultDisposition {
+        if self.is_mmio(gpa) {
+            return FaultDisposition::Mmio;
+        }
+        self.layout.dispositions.get(gpa)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_fault_dispositions() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x2000, rw, false).unwrap();
+        aspace
+            .map_alloc(base + 0x2000, 0x2000, MappingFlags::READ, false)
+            .unwrap();
+        let range = |off, size| GuestPhysAddrRange::from_start_size(base + off, size);
+        aspace
+            .set_fault_disposition(range(0x1000, 0x1000), FaultDisposition::Guard)
+            .unwrap();
+        aspace
+            .set_fault_disposition(range(0x2000, 0x2000), FaultDisposition::Rom)
+            .unwrap();
+        aspace.reserve_mmio(range(0x8000, 0x1000)).unwrap();
+        let mut fault =
+            |off, access| aspace.handle_page_fault_result(base + off, access, &FaultContext::NONE);
+
+        assert_eq!(fault(0, MappingFlags::WRITE), PageFaultResult::Handled);
+        assert_eq!(fault(0x1000, MappingFlags::READ), PageFaultResult::Guard);
+        assert_eq!(
+            fault(0x2000, MappingFlags::WRITE),
+            PageFaultResult::RomWrite
+        );
+        assert_eq!(fault(0x2000, MappingFlags::READ), PageFaultResult::Handled);
+        assert_eq!(
+            fault(0x8000, MappingFlags::READ),
+            PageFaultResult::Mmio(MmioAccess {
+                gpa: base + 0x8000,
+                is_write: false,
+                width: None,
+                route: None,
+            })
+        );
+        assert_eq!(
+            fault(0x9000, MappingFlags::READ),
+            PageFaultResult::Unhandled
+        );
+        assert!(aspace.translate(base + 0x1000).is_none());
+
+        aspace
+            .set_fault_disposition(range(0, 0x4000), FaultDisposition::Resolve)
+            .unwrap();
+        assert_eq!(
+            aspace.fault_disposition(base + 0x2000),
+            FaultDisposition::Resolve
+        );
+        assert_eq!(
+            aspace.fault_disposition(base + 0x8000),
+            FaultDisposition::Mmio
+        );
+        assert!(aspace.handle_page_fault(base + 0x1000, MappingFlags::READ));
+
+        // The exit information gives the width of MMIO accesses.
+        let info = NestedPageFaultInfo {
+            access_flags: MappingFlags::WRITE,
+            fault_guest_paddr: base + 0x8004,
+            access_width: Some(AccessWidth::Dword),
+        };
+        assert_eq!(
+            aspace.handle_nested_page_fault(&info, &FaultContext::NONE),
+            PageFaultResult::Mmio(MmioAccess {
+                gpa: base + 0x8004,
+                is_write: true,
+                width: Some(AccessWidth::Dword),
+                route: None,
+            })
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Verification of the frames given to allocation areas (`verify-frames`
+//! feature).
+//!
+//! Frames are mapped into the guest as the paging handler returns them, so a
+//! handler returning dirty frames leaks the data of their previous user,
+//! e.g., another VM, into the guest. With this feature, the contents of
+//! every frame allocated for a page of an allocation area are checked by the
+//! [`FrameVerifier`] installed with [`set_frame_verifier`] before it is
+//! mapped. A rejected frame is logged and given back, and the page fault
+//! (or the population of the area) fails.
+
+use axerrno::{AxResult, ax_err};
+use lazyinit::LazyInit;
+use page_table_multiarch::PagingHandler;
+
+use crate::{GuestPhysAddr, HostPhysAddr, PAGE_SIZE};
+
+/// Checks the contents of the frames newly allocated for guest pages.
+pub trait FrameVerifier: Sync {
+    /// Whether `contents`, those of a frame just allocated for the page at
+    /// `gpa`, may be exposed to the guest.
+    fn verify(&self, gpa: GuestPhysAddr, contents: &[u8]) -> bool;
+}
+
+/// Accepts zero-filled frames only.
+pub struct ZeroFilled;
+
+impl FrameVerifier for ZeroFilled {
+    fn verify(&self, _gpa: GuestPhysAddr, contents: &[u8]) -> bool {
+        contents.iter().all(|&b| b == 0)
+    }
+}
+
+/// Accepts frames filled with the given byte only, for handlers poisoning
+/// the frames they free.
+pub struct PoisonFilled(pub u8);
+
+impl FrameVerifier for PoisonFilled {
+    fn verify(&self, _gpa: GuestPhysAddr, contents: &[u8]) -> bool {
+        contents.iter().all(|&b| b == self.0)
+    }
+}
+
+static VERIFIER: LazyInit<&'static dyn FrameVerifier> = LazyInit::new();
+
+/// Installs the verifier of the frames allocated for guest pages.
+///
+/// Fails with `AlreadyExists` if a verifier is already installed. Until
+/// then, nothing is checked.
+pub fn set_frame_verifier(verifier: &'static dyn FrameVerifier) -> AxResult {
+    match VERIFIER.call_once(|| verifier) {
+        Some(_) => Ok(()),
+        None => ax_err!(AlreadyExists, "frame verifier already installed"),
+    }
+}
+
+/// Whether `frame`, just allocated for the page at `gpa`, passes the
+/// installed verifier.
+pub(crate) fn verify_frame<H: PagingHandler>(gpa: GuestPhysAddr, frame: HostPhysAddr) -> bool {
+    let Some(verifier) = VERIFIER.get() else {
+        return true;
+    };
+    // SAFETY: the frame was just allocated, nothing else refers to it.
+    let contents =
+        unsafe { core::slice::from_raw_parts(H::phys_to_virt(frame).as_ptr(), PAGE_SIZE) };
+    let ok = verifier.verify(gpa, contents);
+    if !ok {
+        error!("frame {frame:?} allocated for {gpa:?} failed verification");
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, NEXT_PADDR, mock_hal_test};
+    use crate::{AddrSpace, MappingFlags};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_frame_verifier() {
+        static ZERO: ZeroFilled = ZeroFilled;
+        // Other tests may have installed it first.
+        let _ = set_frame_verifier(&ZERO);
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x2000, rw, false).unwrap();
+        assert!(aspace.handle_page_fault(base, MappingFlags::WRITE));
+
+        // The handler hands out a frame with stale data.
+        let next = HostPhysAddr::from(NEXT_PADDR.load(Ordering::SeqCst));
+        unsafe { *MockHal::mock_phys_to_virt(next + 0x10).as_mut_ptr() = 0xa5 };
+        assert!(!aspace.handle_page_fault(base + 0x1000, MappingFlags::WRITE));
+        assert_eq!(aspace.translate(base + 0x1000), None);
+
+        let poisoned = [0x6b; PAGE_SIZE];
+        assert!(PoisonFilled(0x6b).verify(base, &poisoned));
+        assert!(!ZeroFilled.verify(base, &poisoned));
+    }
+}
@@ -0,0 +1,304 @@
+//! Allocation of guest physical ranges inside a window, e.g., for placing the
+//! BARs of PCI devices in the MMIO holes below and above 4G.
+
+use alloc::collections::BTreeMap;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, is_aligned};
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE};
+
+/// An allocator of aligned sub-ranges of a guest physical window.
+///
+/// On its own, it only keeps its sub-ranges apart. Use
+/// [`AddrSpace::alloc_mmio`] to also keep them clear of what the address
+/// space already has there, and to reserve them for MMIO.
+#[derive(Debug, Clone)]
+pub struct GpaAllocator {
+    window: GuestPhysAddrRange,
+    /// The free ranges, as `start -> end`, never adjacent.
+    free: BTreeMap<GuestPhysAddr, GuestPhysAddr>,
+    /// The allocated ranges, as `start -> end`.
+    allocated: BTreeMap<GuestPhysAddr, GuestPhysAddr>,
+}
+
+impl GpaAllocator {
+    /// Creates an allocator of the ranges of `window`, all free.
+    ///
+    /// Fails with `InvalidInput` if `window` is empty or not page-aligned.
+    pub fn new(window: GuestPhysAddrRange) -> AxResult<Self> {
+        if window.is_empty() {
+            return ax_err!(InvalidInput, "empty window");
+        }
+        if !window.start.is_aligned(PAGE_SIZE) || !window.end.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "window not aligned");
+        }
+        Ok(Self {
+            window,
+            free: BTreeMap::from([(window.start, window.end)]),
+            allocated: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the window the ranges are allocated from.
+    pub const fn window(&self) -> GuestPhysAddrRange {
+        self.window
+    }
+
+    /// Returns the number of free bytes in the window.
+    pub fn free_bytes(&self) -> usize {
+        self.free.iter().map(|(&start, &end)| end - start).sum()
+    }
+
+    /// Allocates the lowest free range of `size` bytes aligned to `align`.
+    ///
+    /// `size` must be a non-zero multiple of the page size, and `align` a
+    /// power of two, at least the page size (PCI BARs are aligned to their
+    /// size), otherwise fails with `InvalidInput`. Fails with `NoMemory` if
+    /// no free range fits.
+    pub fn allocate(&mut self, size: usize, align: usize) -> AxResult<GuestPhysAddrRange> {
+        self.allocate_where(size, align, |_| None)
+    }
+
+    /// Allocates exactly `range`, e.g., a BAR fixed by the firmware.
+    ///
+    /// Fails with `InvalidInput` if `range` is empty, not page-aligned or
+    /// not inside the window, and with `AlreadyExists` if part of it is
+    /// already allocated.
+    pub fn allocate_at(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        self.check_range(range)?;
+        match self.free.range(..=range.start).next_back() {
+            Some((&start, &end)) if range.end <= end => {
+                self.take(start, end, range);
+                Ok(())
+            }
+            _ => ax_err!(AlreadyExists, "range already allocated"),
+        }
+    }
+
+    /// Frees a range returned by [`GpaAllocator::allocate`] or allocated
+    /// with [`GpaAllocator::allocate_at`].
+    ///
+    /// Fails with `NotFound` if `range` is not such a range.
+    pub fn free(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        if self.allocated.get(&range.start) != Some(&range.end) {
+            return ax_err!(NotFound, "range not allocated");
+        }
+        self.allocated.remove(&range.start);
+        let mut start = range.start;
+        let mut end = range.end;
+        if let Some((&prev_start, &prev_end)) = self.free.range(..start).next_back()
+            && prev_end == start
+        {
+            self.free.remove(&prev_start);
+            start = prev_start;
+        }
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
+        self.free.insert(start, end);
+        Ok(())
+    }
+
+    /// Like [`GpaAllocator::allocate`], but skips the candidate ranges for
+    /// which `conflict` returns an address, resuming the search there.
+    ///
+    /// `conflict` must return an address past the start of the candidate.
+    fn allocate_where(
+        &mut self,
+        size: usize,
+        align: usize,
+        conflict: impl Fn(GuestPhysAddrRange) -> Option<GuestPhysAddr>,
+    ) -> AxResult<GuestPhysAddrRange> {
+        if size == 0 || !is_aligned(size, PAGE_SIZE) {
+            return ax_err!(InvalidInput, "size not aligned");
+        }
+        if !align.is_power_of_two() || align < PAGE_SIZE {
+            return ax_err!(InvalidInput, "bad alignment");
+        }
+        let mut found = None;
+        'search: for (&start, &end) in &self.free {
+            let mut candidate = start.align_up(align);
+            while candidate >= start
+                && let Some(candidate_end) = candidate.as_usize().checked_add(size)
+                && candidate_end <= end.as_usize()
+            {
+                let range = GuestPhysAddrRange::from_start_size(candidate, size);
+                match conflict(range) {
+                    None => {
+                        found = Some((start, end, range));
+                        break 'search;
+                    }
+                    Some(next) => candidate = next.align_up(align),
+                }
+            }
+        }
+        let Some((start, end, range)) = found else {
+            return ax_err!(NoMemory, "no free range in the window");
+        };
+        self.take(start, end, range);
+        Ok(range)
+    }
+
+    /// Allocates `range` out of the free range `[start, end)`.
+    fn take(&mut self, start: GuestPhysAddr, end: GuestPhysAddr, range: GuestPhysAddrRange) {
+        self.free.remove(&start);
+        if start < range.start {
+            self.free.insert(start, range.start);
+        }
+        if range.end < end {
+            self.free.insert(range.end, end);
+        }
+        self.allocated.insert(range.start, range.end);
+    }
+
+    fn check_range(&self, range: GuestPhysAddrRange) -> AxResult {
+        if range.is_empty() || !self.window.contains_range(range) {
+            return ax_err!(InvalidInput, "range out of the window");
+        }
+        if !range.start.is_aligned(PAGE_SIZE) || !range.end.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "range not aligned");
+        }
+        Ok(())
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Allocates a range of `size` bytes aligned to `align` from `allocator`
+    /// and reserves it for MMIO with [`AddrSpace::reserve_mmio`].
+    ///
+    /// The lowest free range of the allocator not overlapping any area,
+    /// reserved MMIO range or range reserved with
+    /// [`AddrSpace::add_reserved_range`] is chosen, so the result never
+    /// conflicts with the address space. Fails as
+    /// [`GpaAllocator::allocate`], with `InvalidInput` if the window of the
+    /// allocator is not inside the address space, or with `BadState` if the
+    /// address space is sealed.
+    pub fn alloc_mmio(
+        &mut self,
+        allocator: &mut GpaAllocator,
+        size: usize,
+        align: usize,
+    ) -> AxResult<GuestPhysAddrRange> {
+        self.check_unsealed()?;
+        if !self.layout.contains_range(allocator.window()) {
+            return ax_err!(InvalidInput, "window out of the address space");
+        }
+        let range = allocator.allocate_where(size, align, |range| self.mmio_conflict(range))?;
+        if let Err(err) = self.reserve_mmio(range) {
+            allocator.free(range).unwrap();
+            return Err(err);
+        }
+        Ok(range)
+    }
+
+    /// Releases a range allocated with [`AddrSpace::alloc_mmio`], both from
+    /// the MMIO ranges and from `allocator`.
+    ///
+    /// Fails with `NotFound` if `range` is not reserved for MMIO or not
+    /// allocated from `allocator`, in which case nothing is released.
+    pub fn free_mmio(
+        &mut self,
+        allocator: &mut GpaAllocator,
+        range: GuestPhysAddrRange,
+    ) -> AxResult {
+        if allocator.allocated.get(&range.start) != Some(&range.end) {
+            return ax_err!(NotFound, "range not allocated");
+        }
+        self.release_mmio(range)?;
+        allocator.free(range)
+    }
+
+    /// Returns the end of the first thing `range` overlaps among the areas,
+    /// the MMIO ranges and the reserved ranges, if any.
+    fn mmio_conflict(&self, range: GuestPhysAddrRange) -> Option<GuestPhysAddr> {
+        if let Some(area) = self.layout.areas_overlapping(range).next() {
+            return Some(area.end());
+        }
+        if let Some((_, mmio)) = self.layout.mmio_regions.range(..range.end).next_back()
+            && mmio.overlaps(range)
+        {
+            return Some(mmio.end);
+        }
+        self.layout
+            .reserved
+            .overlapping(range)
+            .first()
+            .map(|(reserved, _)| reserved.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_gpa_allocator() {
+        let base = GuestPhysAddr::from(0x10000);
+        let range = |off, size| GuestPhysAddrRange::from_start_size(base + off, size);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x20000).unwrap();
+        // The MMIO hole already holds some RAM, an emulated device and a
+        // permanently reserved page.
+        let window = range(0x10000, 0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base + 0x10000, 0x1000, rw, false).unwrap();
+        aspace.reserve_mmio(range(0x14000, 0x1000)).unwrap();
+        aspace.add_reserved_range(range(0x18000, 0x1000)).unwrap();
+        let mut bars = GpaAllocator::new(window).unwrap();
+
+        assert_eq!(
+            aspace.alloc_mmio(&mut bars, 0x2000, 0x2000),
+            Ok(range(0x12000, 0x2000))
+        );
+        assert_eq!(
+            aspace.alloc_mmio(&mut bars, 0x1000, 0x1000),
+            Ok(range(0x11000, 0x1000))
+        );
+        assert_eq!(
+            aspace.alloc_mmio(&mut bars, 0x4000, 0x4000),
+            Ok(range(0x1c000, 0x4000))
+        );
+        assert!(aspace.is_mmio(base + 0x1c000));
+        assert_eq!(
+            aspace.alloc_mmio(&mut bars, 0x4000, 0x4000),
+            Err(AxError::NoMemory)
+        );
+        assert_eq!(
+            aspace.alloc_mmio(&mut bars, 0x1000, 0x800),
+            Err(AxError::InvalidInput)
+        );
+
+        // Freed ranges are merged back and reused.
+        assert_eq!(
+            aspace.free_mmio(&mut bars, range(0x12000, 0x1000)),
+            Err(AxError::NotFound)
+        );
+        aspace.free_mmio(&mut bars, range(0x12000, 0x2000)).unwrap();
+        aspace.free_mmio(&mut bars, range(0x11000, 0x1000)).unwrap();
+        assert!(!aspace.is_mmio(base + 0x12000));
+        assert_eq!(bars.free_bytes(), 0xc000);
+        assert_eq!(
+            aspace.alloc_mmio(&mut bars, 0x2000, 0x1000),
+            Ok(range(0x11000, 0x2000))
+        );
+
+        // Fixed ranges, on the allocator alone.
+        let mut alloc = GpaAllocator::new(window).unwrap();
+        alloc.allocate_at(range(0x13000, 0x1000)).unwrap();
+        assert_eq!(
+            alloc.allocate_at(range(0x12000, 0x2000)),
+            Err(AxError::AlreadyExists)
+        );
+        assert_eq!(alloc.allocate(0x4000, 0x4000), Ok(range(0x14000, 0x4000)));
+        assert_eq!(alloc.allocate(0x1000, 0x1000), Ok(range(0x10000, 0x1000)));
+        alloc.free(range(0x13000, 0x1000)).unwrap();
+        assert_eq!(alloc.free_bytes(), 0xb000);
+    }
+}
@@ -0,0 +1,868 @@
+//! Per-area bounds on the mapping granularity.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
+
+use super::{AddrSpace, Backend, HugePagePolicy, ReplayRecord};
+use crate::addr::checked_range;
+use crate::{
+    AxMmHal, GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, mapping_err_to_ax_err, npt,
+    paging_err_to_ax_err,
+};
+
+/// Bounds on the page sizes used to map an area.
+///
+/// `min` is the unit in which the area is mapped, faulted in and split: the
+/// area itself, and any range that unmaps or re-protects part of it, must be
+/// aligned to it. A lazily allocated area faults in a whole `min`-sized block
+/// at a time (backed by 4K frames). `max` is the largest page size the area
+/// may be mapped with.
+///
+/// For example, guest RAM that is dirty-logged at 2M granularity uses a
+/// `min` of 2M, while an area subject to sub-page operations uses exactly 4K.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapGranularity {
+    min: PageSize,
+    max: PageSize,
+}
+
+impl MapGranularity {
+    /// 4K pages only, the granularity of areas mapped without an explicit one.
+    pub const DEFAULT: Self = Self::exact(PageSize::Size4K);
+
+    /// Creates a new granularity allowing page sizes from `min` to `max`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is larger than `max`.
+    pub const fn new(min: PageSize, max: PageSize) -> Self {
+        assert!(min as usize <= max as usize, "min granularity exceeds max");
+        Self { min, max }
+    }
+
+    /// Creates a new granularity allowing exactly one page size.
+    pub const fn exact(size: PageSize) -> Self {
+        Self {
+            min: size,
+            max: size,
+        }
+    }
+
+    /// Returns the minimum granularity.
+    pub const fn min(&self) -> PageSize {
+        self.min
+    }
+
+    /// Returns the maximum granularity.
+    pub const fn max(&self) -> PageSize {
+        self.max
+    }
+
+    /// Whether pages of `size` may be used.
+    pub const fn allows(&self, size: PageSize) -> bool {
+        self.min as usize <= size as usize && size as usize <= self.max as usize
+    }
+
+    /// Whether `addr_or_size` is aligned to the minimum granularity.
+    pub const fn is_aligned(&self, addr_or_size: usize) -> bool {
+        self.min.is_aligned(addr_or_size)
+    }
+
+    /// Returns why `[gpa, gpa + size)` cannot be mapped to `hpa` with the
+    /// largest page size it could be mapped with at its own alignment, or
+    /// `None` if it can.
+    pub(crate) fn alignment_mismatch(
+        &self,
+        gpa: GuestPhysAddr,
+        hpa: PhysAddr,
+        size: usize,
+    ) -> Option<HugeAlignmentMismatch> {
+        let sizes = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K];
+        let end = gpa.as_usize().checked_add(size)?;
+        // The largest page size mapping at least one page of the range.
+        let wanted = sizes.into_iter().find(|&ps| {
+            self.allows(ps)
+                && gpa
+                    .as_usize()
+                    .checked_next_multiple_of(ps as usize)
+                    .is_some_and(|page| page + ps as usize <= end)
+        })?;
+        let offset = gpa.as_usize().wrapping_sub(hpa.as_usize());
+        if wanted.is_aligned(offset) {
+            return None;
+        }
+        let align = |addr: usize| (1 << addr.trailing_zeros().min(63)).min(wanted as usize);
+        Some(HugeAlignmentMismatch {
+            gpa_align: align(gpa.as_usize()),
+            hpa_align: align(hpa.as_usize()),
+        })
+    }
+
+    /// Encodes the granularity into 16 bits, used by the exported state.
+    /// Zero is [`MapGranularity::DEFAULT`].
+    pub(crate) const fn to_bits(self) -> u64 {
+        (size_index(self.min) | size_index(self.max) << 8) as u64
+    }
+
+    /// Decodes a granularity encoded by [`MapGranularity::to_bits`].
+    pub(crate) const fn from_bits(bits: u64) -> Option<Self> {
+        match (index_size(bits & 0xff), index_size(bits >> 8)) {
+            (Some(min), Some(max)) if min as usize <= max as usize => Some(Self { min, max }),
+            _ => None,
+        }
+    }
+}
+
+/// The alignments of a linear mapping whose guest and host addresses are
+/// aligned differently within a huge page, so that it cannot use huge pages
+/// (all of them, or the largest ones).
+///
+/// Returned in [`MapLinearError::alignment`] when the minimum granularity
+/// cannot be used. Otherwise the mapping falls back to smaller pages, which
+/// [`AddrSpace::huge_alignment_fallbacks`] counts.
+///
+/// [`MapLinearError::alignment`]: super::MapLinearError::alignment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HugeAlignmentMismatch {
+    /// The alignment of the guest physical address, up to the page size the
+    /// mapping could use.
+    pub gpa_align: usize,
+    /// The alignment of the host physical address, up to the same size.
+    pub hpa_align: usize,
+}
+
+/// Which page sizes to map an area with, given to
+/// [`AddrSpace::map_alloc_with_policy`] and
+/// [`AddrSpace::map_linear_with_policy`].
+///
+/// Huge pages are used where the area covers a whole aligned huge page (and,
+/// for linear areas, where the host addresses are aligned alike), and
+/// smaller pages elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageSizePolicy {
+    /// Huge pages up to 1G, unless the range was advised with
+    /// [`Advice::NoHugePage`](super::Advice::NoHugePage).
+    #[default]
+    Auto,
+    /// 4K pages only.
+    Force4K,
+    /// Pages up to 2M.
+    Prefer2M,
+    /// Pages up to 1G.
+    Prefer1G,
+}
+
+impl PageSizePolicy {
+    /// Returns the granularity of the areas mapped with the policy, with a
+    /// minimum of 4K.
+    pub const fn granularity(self) -> MapGranularity {
+        match self {
+            Self::Force4K => MapGranularity::DEFAULT,
+            Self::Prefer2M => MapGranularity::new(PageSize::Size4K, PageSize::Size2M),
+            Self::Auto | Self::Prefer1G => MapGranularity::new(PageSize::Size4K, PageSize::Size1G),
+        }
+    }
+}
+
+impl Default for MapGranularity {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+const fn size_index(size: PageSize) -> usize {
+    match size {
+        PageSize::Size4K => 0,
+        PageSize::Size2M => 1,
+        PageSize::Size1G => 2,
+    }
+}
+
+const fn index_size(index: u64) -> Option<PageSize> {
+    match index {
+        0 => Some(PageSize::Size4K),
+        1 => Some(PageSize::Size2M),
+        2 => Some(PageSize::Size1G),
+        _ => None,
+    }
+}
+
+impl<H: PagingHandler + AxMmHal> AddrSpace<H> {
+    /// Adds a new allocation mapping like [`AddrSpace::map_alloc`], with the
+    /// page sizes chosen by `policy`.
+    ///
+    /// Populated areas are backed by huge frames from
+    /// [`AxMmHal::alloc_frames`] where the policy allows, falling back to 4K
+    /// frames when none is available, and freed with
+    /// [`AxMmHal::dealloc_frames`]. Pages already present in the range are
+    /// taken over instead, as by [`AddrSpace::map_alloc`], and the area then
+    /// uses 4K frames only. Lazily allocated areas are faulted in with 4K
+    /// frames.
+    pub fn map_alloc_with_policy(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        populate: bool,
+        policy: PageSizePolicy,
+    ) -> AxResult {
+        let granularity = self.policy_granularity(start, size, policy);
+        if !populate || granularity.max() == PageSize::Size4K || self.has_present_pages(start, size)
+        {
+            return self.map_alloc_with_granularity(start, size, flags, populate, granularity);
+        }
+        self.check_map_alloc(start, size, granularity)?;
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        if self.layout.areas().overlaps(range) {
+            return ax_err!(AlreadyExists, "mapping overlaps an existing area");
+        }
+
+        // Map the huge pages first, the area then takes them over and fills
+        // the rest with 4K frames.
+        let mut huge = Vec::new();
+        let mut addr = start;
+        while addr < range.end {
+            let fits = |ps: PageSize| {
+                granularity.allows(ps)
+                    && addr.is_aligned(ps as usize)
+                    && range.end.as_usize() - addr.as_usize() >= ps as usize
+            };
+            let Some(ps) = [PageSize::Size1G, PageSize::Size2M]
+                .into_iter()
+                .find(|&ps| fits(ps))
+            else {
+                addr = (addr.align_down(PageSize::Size2M as usize) + PageSize::Size2M as usize)
+                    .min(range.end);
+                continue;
+            };
+            let num_frames = ps as usize / PAGE_SIZE;
+            if let Some(frame) = H::alloc_frames(num_frames, ps as usize) {
+                match self.state.pt.map(addr, frame, ps, flags) {
+                    Ok(tlb) => {
+                        tlb.ignore();
+                        huge.push((addr, frame, num_frames));
+                    }
+                    Err(_) => H::dealloc_frames(frame, num_frames),
+                }
+            }
+            addr += ps as usize;
+        }
+        let backend = Backend::Alloc {
+            populate: true,
+            granularity,
+            dealloc_huge: Some(<H as AxMmHal>::dealloc_frames),
+            _phantom: core::marker::PhantomData,
+        };
+        let res = self.map_alloc_backend(start, size, flags, backend);
+        if res.is_err() {
+            for (addr, frame, num_frames) in huge {
+                if let Ok((_, _, tlb)) = self.state.pt.unmap(addr) {
+                    tlb.ignore();
+                    H::dealloc_frames(frame, num_frames);
+                }
+            }
+        }
+        res
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Adds a new linear mapping like [`AddrSpace::map_linear`], with the
+    /// page sizes chosen by `policy`.
+    pub fn map_linear_with_policy(
+        &mut self,
+        start_vaddr: GuestPhysAddr,
+        start_paddr: PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        policy: PageSizePolicy,
+    ) -> AxResult {
+        let granularity = self.policy_granularity(start_vaddr, size, policy);
+        self.map_linear_with_granularity(start_vaddr, start_paddr, size, flags, granularity)
+    }
+
+    /// Whether any page of `[start, start + size)` is present in the nested
+    /// page table.
+    fn has_present_pages(&self, start: GuestPhysAddr, size: usize) -> bool {
+        let Ok(range) = checked_range(start, size) else {
+            return false;
+        };
+        let mut present = false;
+        npt::tables::for_each_leaf_in::<H>(
+            self.state.pt.root_paddr(),
+            range.start.as_usize(),
+            range.end.as_usize(),
+            &mut |_, _, _| present = true,
+        );
+        present
+    }
+
+    /// Resolves `policy` for `[start, start + size)`.
+    fn policy_granularity(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        policy: PageSizePolicy,
+    ) -> MapGranularity {
+        let never = |range| {
+            self.layout
+                .hints
+                .overlapping(range)
+                .iter()
+                .any(|(_, h)| h.huge_pages == HugePagePolicy::Never)
+        };
+        match checked_range(start, size) {
+            Ok(range) if policy == PageSizePolicy::Auto && never(range) => MapGranularity::DEFAULT,
+            _ => policy.granularity(),
+        }
+    }
+
+    /// Returns the number of linear mappings that used smaller pages than
+    /// their granularity and guest alignment allowed, because their host
+    /// addresses were aligned differently.
+    pub fn huge_alignment_fallbacks(&self) -> u64 {
+        self.state.events.huge_fallbacks()
+    }
+
+    /// Returns the mapping granularity of the area containing `gpa`, or
+    /// `None` if `gpa` is not mapped by any area.
+    pub fn granularity_at(&self, gpa: GuestPhysAddr) -> Option<MapGranularity> {
+        self.layout
+            .find_area(gpa)
+            .map(|area| area.backend().granularity())
+    }
+
+    /// Remaps the linear areas within `range` with huge pages where the
+    /// alignment allows (`allow_huge`), or with their minimum page size only,
+    /// keeping every guest page mapped to the same host address.
+    ///
+    /// This updates the maximum [`MapGranularity`] of the areas, e.g., to
+    /// switch a guest to huge mappings once dirty logging is over. Areas in
+    /// ranges advised with [`Advice::NoHugePage`] are not promoted. Linear
+    /// areas must lie entirely within `range`; other areas are left alone.
+    ///
+    /// The pages are briefly unmapped, so vCPUs must not run meanwhile.
+    ///
+    /// [`Advice::NoHugePage`]: super::Advice::NoHugePage
+    pub fn rebuild_linear_with_huge(
+        &mut self,
+        range: GuestPhysAddrRange,
+        allow_huge: bool,
+    ) -> AxResult {
+        self.unrecorded(|aspace| aspace.rebuild_linear(range, allow_huge))?;
+        self.record(ReplayRecord::RebuildLinear { range, allow_huge });
+        Ok(())
+    }
+
+    fn rebuild_linear(&mut self, range: GuestPhysAddrRange, allow_huge: bool) -> AxResult {
+        self.check_unsealed()?;
+        if !self.layout.contains_range(range) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        let mut rebuilds = Vec::new();
+        for area in self.layout.areas().iter() {
+            let Backend::Linear {
+                pa_va_offset,
+                granularity,
+            } = *area.backend()
+            else {
+                continue;
+            };
+            if area.end() <= range.start || area.start() >= range.end {
+                continue;
+            }
+            if area.start() < range.start || area.end() > range.end {
+                return ax_err!(InvalidInput, "range splits a linear area");
+            }
+            let area_range = GuestPhysAddrRange::new(area.start(), area.end());
+            let max = if allow_huge
+                && !self
+                    .layout
+                    .hints
+                    .overlapping(area_range)
+                    .iter()
+                    .any(|(_, h)| h.huge_pages == HugePagePolicy::Never)
+            {
+                PageSize::Size1G
+            } else {
+                granularity.min()
+            };
+            let new_granularity = MapGranularity::new(granularity.min(), max);
+            if new_granularity != granularity {
+                let paddr = PhysAddr::from(area.start().as_usize().wrapping_sub(pa_va_offset));
+                rebuilds.push((
+                    area_range,
+                    paddr,
+                    area.flags(),
+                    granularity,
+                    new_granularity,
+                ));
+            }
+        }
+
+        for (area_range, paddr, flags, old, new) in rebuilds {
+            let (start, size) = (area_range.start, area_range.size());
+            self.layout
+                .areas_mut()
+                .unmap(start, size, &mut self.state.pt)
+                .map_err(mapping_err_to_ax_err)?;
+            #[cfg(feature = "frame-ownership")]
+            super::ownership::release(
+                self.state.space_id,
+                memory_addr::PhysAddrRange::from_start_size(paddr, size),
+            );
+            // Tables left empty would block the huge entries.
+            self.shrink_page_tables();
+            if let Err(err) = self.try_map_linear(start, paddr, size, flags, new) {
+                self.map_linear_with_granularity(start, paddr, size, flags, old)?;
+                return Err(err.error);
+            }
+        }
+        npt::flush_tlb(None);
+        Ok(())
+    }
+
+    /// Demotes the huge pages of linear areas straddling `start` or
+    /// `start + size`, so that unmapping `[start, start + size)` keeps the
+    /// rest of them mapped.
+    ///
+    /// Each affected page is split one level at a time (1G into 2M pages,
+    /// then the 2M page containing the boundary into 4K pages), down to the
+    /// minimum granularity of its area. Pages not containing a boundary are
+    /// left intact.
+    pub(crate) fn demote_split_points(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        let mut demoted = false;
+        for point in [start, start + size] {
+            let Some(area) = self.layout.find_area(point) else {
+                continue;
+            };
+            let min = area.backend().granularity().min();
+            if area.start() == point
+                || !matches!(area.backend(), Backend::Linear { .. })
+                || !min.is_aligned(point.as_usize())
+            {
+                continue;
+            }
+            while let Ok((paddr, flags, page_size)) = self.state.pt.query(point)
+                && !point.is_aligned(page_size)
+            {
+                let smaller = match page_size {
+                    PageSize::Size1G => PageSize::Size2M,
+                    _ => PageSize::Size4K,
+                };
+                let base = point.align_down(page_size);
+                self.demote_page(base, paddr - (point - base), page_size, smaller, flags)?;
+                demoted = true;
+            }
+        }
+        if demoted {
+            npt::flush_tlb(None);
+        }
+        Ok(())
+    }
+
+    /// Replaces the `size` page at `base` by pages of size `smaller` mapping
+    /// the same host memory, restoring the page on failure.
+    pub(crate) fn demote_page(
+        &mut self,
+        base: GuestPhysAddr,
+        paddr: PhysAddr,
+        size: PageSize,
+        smaller: PageSize,
+        flags: MappingFlags,
+    ) -> AxResult {
+        let pt = &mut self.state.pt;
+        pt.unmap(base).map_err(paging_err_to_ax_err)?.2.ignore();
+        for off in (0..size as usize).step_by(smaller as usize) {
+            match pt.map(base + off, paddr + off, smaller, flags) {
+                Ok(tlb) => tlb.ignore(),
+                Err(err) => {
+                    if off > 0 {
+                        let _ = pt.unmap_region(base, off, false);
+                    }
+                    pt.map(base, paddr, size, flags)
+                        .map_err(paging_err_to_ax_err)?
+                        .ignore();
+                    return Err(paging_err_to_ax_err(err));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that an operation on `[start, start + size)` splits no area at
+    /// a point that is not aligned to its minimum granularity, and no page
+    /// mapped with a huge page.
+    pub(crate) fn check_split_points(&self, start: GuestPhysAddr, size: usize) -> AxResult {
+        for point in [start, start + size] {
+            let Some(area) = self.layout.find_area(point) else {
+                continue;
+            };
+            if area.start() == point {
+                continue;
+            }
+            if !area.backend().granularity().is_aligned(point.as_usize()) {
+                return ax_err!(InvalidInput, "range splits an area below its granularity");
+            }
+            if let Ok((_, _, page_size)) = self.state.pt.query(point)
+                && !point.is_aligned(page_size)
+            {
+                return ax_err!(InvalidInput, "range splits a huge page");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{DEALLOC_FRAMES_COUNT, MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+    use memory_addr::PhysAddr;
+
+    const SIZE_2M: usize = PageSize::Size2M as usize;
+
+    #[test]
+    fn test_granularity_bits() {
+        let g = MapGranularity::new(PageSize::Size4K, PageSize::Size1G);
+        assert!(g.allows(PageSize::Size2M));
+        assert!(!MapGranularity::DEFAULT.allows(PageSize::Size2M));
+        assert_eq!(MapGranularity::DEFAULT.to_bits(), 0);
+        assert_eq!(MapGranularity::from_bits(g.to_bits()), Some(g));
+        assert_eq!(MapGranularity::from_bits(0x0001), None);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_map_linear_granularity() {
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 8 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let exact_2m = MapGranularity::exact(PageSize::Size2M);
+
+        // Not aligned to the minimum granularity.
+        assert_eq!(
+            aspace.map_linear_with_granularity(
+                base + 0x1000,
+                PhysAddr::from(0x1000),
+                SIZE_2M,
+                rw,
+                exact_2m
+            ),
+            Err(AxError::InvalidInput)
+        );
+        aspace
+            .map_linear_with_granularity(
+                base + SIZE_2M,
+                PhysAddr::from(4 * SIZE_2M),
+                2 * SIZE_2M,
+                rw,
+                exact_2m,
+            )
+            .unwrap();
+        let (paddr, _, page_size) = aspace.page_table().query(base + SIZE_2M + 0x3000).unwrap();
+        assert_eq!(page_size, PageSize::Size2M);
+        assert_eq!(paddr, PhysAddr::from(4 * SIZE_2M + 0x3000));
+        assert_eq!(aspace.granularity_at(base + SIZE_2M), Some(exact_2m));
+
+        // Splitting the area below 2M is rejected, at 2M it is fine.
+        assert_eq!(
+            aspace.unmap(base + SIZE_2M, 0x1000),
+            Err(AxError::InvalidInput)
+        );
+        aspace.unmap(base + SIZE_2M, SIZE_2M).unwrap();
+        assert!(aspace.translate(base + SIZE_2M).is_none());
+        assert!(aspace.translate(base + 2 * SIZE_2M).is_some());
+
+        // A 4K-granular area only uses 4K pages, even if 2M aligned.
+        aspace
+            .map_linear(base + 4 * SIZE_2M, PhysAddr::from(0), SIZE_2M, rw)
+            .unwrap();
+        let (_, _, page_size) = aspace.page_table().query(base + 4 * SIZE_2M).unwrap();
+        assert_eq!(page_size, PageSize::Size4K);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_huge_alignment_mismatch() {
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 8 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mismatch = Some(HugeAlignmentMismatch {
+            gpa_align: SIZE_2M,
+            hpa_align: 0x1000,
+        });
+
+        // 2M pages only: the mapping fails, telling why.
+        let exact_2m = MapGranularity::exact(PageSize::Size2M);
+        let err = aspace
+            .try_map_linear(
+                base + SIZE_2M,
+                PhysAddr::from(0x1000),
+                SIZE_2M,
+                rw,
+                exact_2m,
+            )
+            .unwrap_err();
+        assert_eq!(err.error, AxError::InvalidInput);
+        assert_eq!(err.alignment, mismatch);
+
+        // Up to 2M pages: the mapping falls back to 4K pages, counted.
+        let up_to_2m = MapGranularity::new(PageSize::Size4K, PageSize::Size2M);
+        let gpa = base + 2 * SIZE_2M;
+        let hpa = PhysAddr::from(SIZE_2M + 0x1000);
+        assert_eq!(up_to_2m.alignment_mismatch(gpa, hpa, SIZE_2M), mismatch);
+        aspace
+            .map_linear_with_granularity(gpa, hpa, SIZE_2M, rw, up_to_2m)
+            .unwrap();
+        let (_, _, page_size) = aspace.page_table().query(gpa).unwrap();
+        assert_eq!(page_size, PageSize::Size4K);
+        assert_eq!(aspace.huge_alignment_fallbacks(), 1);
+
+        // Ranges without a whole huge page do not count.
+        aspace
+            .map_linear_with_granularity(gpa + SIZE_2M, hpa + SIZE_2M, 0x1000, rw, up_to_2m)
+            .unwrap();
+        assert_eq!(aspace.huge_alignment_fallbacks(), 1);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_host_extent_of() {
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 4 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace
+            .map_linear_with_granularity(
+                base + SIZE_2M,
+                PhysAddr::from(4 * SIZE_2M),
+                SIZE_2M,
+                rw,
+                MapGranularity::exact(PageSize::Size2M),
+            )
+            .unwrap();
+        aspace
+            .map_linear(base + 2 * SIZE_2M, PhysAddr::from(0x10_0000), 0x2000, rw)
+            .unwrap();
+
+        assert_eq!(
+            aspace.host_extent_of(base + SIZE_2M + 0x12345),
+            Some((
+                PhysAddr::from(4 * SIZE_2M),
+                PageSize::Size2M,
+                base + SIZE_2M
+            ))
+        );
+        assert_eq!(
+            aspace.host_extent_of(base + 2 * SIZE_2M + 0x1abc),
+            Some((
+                PhysAddr::from(0x10_1000),
+                PageSize::Size4K,
+                base + 2 * SIZE_2M + 0x1000
+            ))
+        );
+        assert_eq!(aspace.host_extent_of(base), None);
+        assert_eq!(aspace.host_extent_of(base + 4 * SIZE_2M), None);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_alloc_fault_granularity() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace
+            .map_alloc_with_granularity(base, 0x4000, rw, false, MapGranularity::DEFAULT)
+            .unwrap();
+        assert!(aspace.handle_page_fault(base + 0x1000, MappingFlags::READ));
+        assert!(aspace.translate(base).is_none());
+        assert!(aspace.translate(base + 0x1000).is_some());
+
+        // 2M blocks do not fit in the address space at all.
+        assert_eq!(
+            aspace.map_alloc_with_granularity(
+                base + 0x8000,
+                0x8000,
+                rw,
+                false,
+                MapGranularity::exact(PageSize::Size2M)
+            ),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_page_size_policy() {
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 8 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let host = PhysAddr::from(0x4000_0000);
+        aspace
+            .map_linear_with_policy(base, host, SIZE_2M, rw, PageSizePolicy::Prefer2M)
+            .unwrap();
+        aspace
+            .map_linear_with_policy(
+                base + SIZE_2M,
+                host + SIZE_2M,
+                SIZE_2M,
+                rw,
+                PageSizePolicy::Force4K,
+            )
+            .unwrap();
+        let page_size =
+            |aspace: &AddrSpace<MockHal>, gpa| aspace.page_table().query(gpa).unwrap().2;
+        assert_eq!(page_size(&aspace, base), PageSize::Size2M);
+        assert_eq!(page_size(&aspace, base + SIZE_2M), PageSize::Size4K);
+
+        // Advice against huge pages only overrides the automatic policy.
+        let advised = base + 2 * SIZE_2M;
+        aspace
+            .advise(advised, SIZE_2M, crate::Advice::NoHugePage)
+            .unwrap();
+        aspace
+            .map_alloc_with_policy(advised, SIZE_2M, rw, false, PageSizePolicy::Auto)
+            .unwrap();
+        assert_eq!(
+            aspace.granularity_at(advised),
+            Some(MapGranularity::DEFAULT)
+        );
+        let lazy = base + 3 * SIZE_2M;
+        aspace
+            .map_alloc_with_policy(lazy, SIZE_2M, rw, false, PageSizePolicy::Prefer1G)
+            .unwrap();
+        assert_eq!(
+            aspace.granularity_at(lazy),
+            Some(PageSizePolicy::Auto.granularity())
+        );
+        // Lazy areas are faulted in with 4K frames.
+        assert!(aspace.handle_page_fault(lazy, MappingFlags::READ));
+        assert_eq!(page_size(&aspace, lazy), PageSize::Size4K);
+
+        // Huge pages taken over from the page table are not freed, even if
+        // the granularity of the area allows them.
+        let huge = base + 4 * SIZE_2M;
+        aspace
+            .state
+            .pt
+            .map(huge, host + 4 * SIZE_2M, PageSize::Size2M, rw)
+            .unwrap()
+            .ignore();
+        aspace
+            .map_alloc_with_policy(huge, SIZE_2M, rw, true, PageSizePolicy::Prefer2M)
+            .unwrap();
+        assert_eq!(page_size(&aspace, huge), PageSize::Size2M);
+        assert!(matches!(
+            aspace.layout.find_area(huge).unwrap().backend(),
+            Backend::Alloc {
+                dealloc_huge: None,
+                ..
+            }
+        ));
+
+        // Those the area allocated are freed as a whole.
+        MockHal::set_huge_frames(SIZE_2M / PAGE_SIZE);
+        let owned = base + 5 * SIZE_2M;
+        aspace
+            .map_alloc_with_policy(owned, SIZE_2M, rw, true, PageSizePolicy::Prefer2M)
+            .unwrap();
+        assert_eq!(page_size(&aspace, owned), PageSize::Size2M);
+        aspace.unmap(owned, SIZE_2M).unwrap();
+        assert_eq!(
+            DEALLOC_FRAMES_COUNT.load(Ordering::SeqCst),
+            SIZE_2M / PAGE_SIZE
+        );
+
+        // The taken over huge page is left to its owner.
+        let report = aspace.close().unwrap();
+        assert_eq!(report.leaked_frames, SIZE_2M / PAGE_SIZE);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_rebuild_linear_with_huge() {
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 4 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let ram = GuestPhysAddr::from(SIZE_2M);
+        aspace
+            .map_linear(ram, PhysAddr::from(SIZE_2M), 2 * SIZE_2M, rw)
+            .unwrap();
+        aspace
+            .advise(ram + SIZE_2M, SIZE_2M, crate::Advice::NoHugePage)
+            .unwrap();
+        let page_size = |aspace: &AddrSpace<MockHal>, gpa| aspace.state.pt.query(gpa).unwrap().2;
+        let whole = GuestPhysAddrRange::from_start_size(ram, 2 * SIZE_2M);
+
+        // The advice keeps the whole area on small pages.
+        aspace.rebuild_linear_with_huge(whole, true).unwrap();
+        assert_eq!(page_size(&aspace, ram), PageSize::Size4K);
+
+        aspace
+            .advise(ram, 2 * SIZE_2M, crate::Advice::HugePage)
+            .unwrap();
+        aspace.rebuild_linear_with_huge(whole, true).unwrap();
+        assert_eq!(page_size(&aspace, ram), PageSize::Size2M);
+        assert_eq!(page_size(&aspace, ram + SIZE_2M), PageSize::Size2M);
+        assert_eq!(
+            aspace.translate(ram + 0x1234),
+            Some(PhysAddr::from(SIZE_2M + 0x1234))
+        );
+        assert_eq!(aspace.granularity_at(ram).unwrap().max(), PageSize::Size1G);
+
+        aspace.rebuild_linear_with_huge(whole, false).unwrap();
+        assert_eq!(page_size(&aspace, ram + SIZE_2M), PageSize::Size4K);
+        assert_eq!(
+            aspace.translate(ram + SIZE_2M + 0x5000),
+            Some(PhysAddr::from(2 * SIZE_2M + 0x5000))
+        );
+
+        let half = GuestPhysAddrRange::from_start_size(ram, SIZE_2M);
+        assert_eq!(
+            aspace.rebuild_linear_with_huge(half, true),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_punch_hole_in_huge_page() {
+        const SIZE_1G: usize = PageSize::Size1G as usize;
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, SIZE_1G).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let ram = PhysAddr::from(0x4000_0000);
+        aspace
+            .map_linear_with_granularity(
+                base,
+                ram,
+                SIZE_1G,
+                rw,
+                MapGranularity::new(PageSize::Size4K, PageSize::Size1G),
+            )
+            .unwrap();
+        let page_size = |aspace: &AddrSpace<MockHal>, gpa| aspace.state.pt.query(gpa).unwrap().2;
+        assert_eq!(page_size(&aspace, base), PageSize::Size1G);
+
+        let hole = base + 0x3000_5000;
+        aspace.unmap(hole, 0x1000).unwrap();
+        assert_eq!(aspace.translate(hole), None);
+        assert_eq!(aspace.layout.areas().len(), 2);
+        // Only the 2M page containing the hole is split into 4K pages.
+        let hole_2m = hole.align_down(SIZE_2M);
+        assert_eq!(page_size(&aspace, hole_2m), PageSize::Size4K);
+        assert_eq!(page_size(&aspace, hole + 0x1000), PageSize::Size4K);
+        assert_eq!(page_size(&aspace, base), PageSize::Size2M);
+        assert_eq!(page_size(&aspace, hole_2m + SIZE_2M), PageSize::Size2M);
+        for gpa in [base, hole - 0x1000, hole + 0x1000, base + SIZE_1G - 1] {
+            assert_eq!(aspace.translate(gpa), Some(ram + gpa.as_usize()));
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Coarse access heatmaps, e.g., to find the hot spots of guest memory when
+//! choosing what to back with huge pages or which NUMA node to place it on.
+//!
+//! While enabled, each 2M chunk of the guest physical address space counts
+//! the nested page faults handled in it and the pages dirtied in it while
+//! dirty logging is enabled. Both counters only grow until the heatmap is
+//! disabled.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+/// The size of the chunks counted by the heatmap.
+pub const HEATMAP_CHUNK_SIZE: usize = 0x20_0000;
+
+/// The counters of a part of an area inside one chunk, returned by
+/// [`AddrSpace::heatmap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeatmapEntry {
+    /// The part of the area, inside one chunk.
+    pub range: GuestPhysAddrRange,
+    /// The nested page faults handled in the chunk.
+    pub faults: u64,
+    /// The pages dirtied in the chunk while dirty logging was enabled.
+    pub dirty: u64,
+}
+
+#[derive(Default)]
+struct ChunkCounters {
+    faults: AtomicU64,
+    dirty: AtomicU64,
+}
+
+/// The counters of every chunk overlapping a window, keyed by the start of
+/// the chunk.
+#[derive(Default)]
+pub(crate) struct Heatmap {
+    chunks: BTreeMap<GuestPhysAddr, ChunkCounters>,
+}
+
+impl Heatmap {
+    /// Adds counters for the chunks of `window` not counted yet.
+    pub fn add_window(&mut self, window: GuestPhysAddrRange) {
+        let mut chunk = window.start.align_down(HEATMAP_CHUNK_SIZE);
+        while chunk < window.end {
+            self.chunks.entry(chunk).or_default();
+            match chunk.as_usize().checked_add(HEATMAP_CHUNK_SIZE) {
+                Some(next) => chunk = GuestPhysAddr::from_usize(next),
+                None => break,
+            }
+        }
+    }
+
+    fn chunk(&self, gpa: GuestPhysAddr) -> Option<&ChunkCounters> {
+        self.chunks.get(&gpa.align_down(HEATMAP_CHUNK_SIZE))
+    }
+
+    fn count_fault(&self, gpa: GuestPhysAddr) {
+        if let Some(chunk) = self.chunk(gpa) {
+            chunk.faults.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn count_dirty(&self, gpa: GuestPhysAddr) {
+        if let Some(chunk) = self.chunk(gpa) {
+            chunk.dirty.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Counts a nested page fault handled at `gpa`, if the heatmap is
+    /// enabled.
+    pub(crate) fn count_heat_fault(&self, gpa: GuestPhysAddr) {
+        if let Some(heatmap) = &self.state.heatmap {
+            heatmap.count_fault(gpa);
+        }
+    }
+
+    /// Starts counting faults and dirtied pages per 2M chunk, see
+    /// [`AddrSpace::heatmap`]. Does nothing if already enabled.
+    pub fn enable_heatmap(&mut self) {
+        if self.state.heatmap.is_some() {
+            return;
+        }
+        let mut heatmap = Heatmap::default();
+        for window in self.layout.windows() {
+            heatmap.add_window(window);
+        }
+        self.state.heatmap = Some(heatmap);
+    }
+
+    /// Stops counting, and frees the counters.
+    pub fn disable_heatmap(&mut self) {
+        self.state.heatmap = None;
+    }
+
+    /// Returns the counters of the chunks of the areas overlapping `range`,
+    /// one entry per part of an area in a chunk, in ascending order.
+    ///
+    /// The counters belong to the chunks, so areas sharing a chunk report the
+    /// same counters. Returns an empty list if the heatmap is disabled (see
+    /// [`AddrSpace::enable_heatmap`]).
+    pub fn heatmap(&self, range: GuestPhysAddrRange) -> Vec<HeatmapEntry> {
+        let Some(heatmap) = &self.state.heatmap else {
+            return Vec::new();
+        };
+        let mut entries = Vec::new();
+        for area in self.layout.areas_overlapping(range) {
+            let end = area.end().min(range.end);
+            let mut start = area.start().max(range.start);
+            while start < end {
+                let chunk_end = start
+                    .align_down(HEATMAP_CHUNK_SIZE)
+                    .as_usize()
+                    .saturating_add(HEATMAP_CHUNK_SIZE);
+                let part_end = end.min(GuestPhysAddr::from_usize(chunk_end));
+                let (faults, dirty) = heatmap.chunk(start).map_or((0, 0), |chunk| {
+                    (
+                        chunk.faults.load(Ordering::Relaxed),
+                        chunk.dirty.load(Ordering::Relaxed),
+                    )
+                });
+                entries.push(HeatmapEntry {
+                    range: GuestPhysAddrRange::new(start, part_end),
+                    faults,
+                    dirty,
+                });
+                start = part_end;
+            }
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_heatmap() {
+        let chunk = GuestPhysAddr::from(HEATMAP_CHUNK_SIZE);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(chunk - 0x10000, 0x20000).unwrap();
+        let area = GuestPhysAddrRange::new(chunk - 0x2000, chunk + 0x2000);
+        aspace
+            .map_alloc(area.start, area.size(), rw, false)
+            .unwrap();
+        assert!(aspace.heatmap(area).is_empty());
+        aspace.enable_heatmap();
+
+        // Faults are counted in the chunk they hit.
+        assert!(aspace.handle_page_fault(chunk - 0x2000, MappingFlags::READ));
+        assert!(aspace.handle_page_fault(chunk - 0x1000, MappingFlags::WRITE));
+        // Dirtied pages only while logging, including write-protection faults.
+        aspace.enable_dirty_logging().unwrap();
+        assert!(aspace.handle_page_fault(chunk - 0x1000, MappingFlags::WRITE));
+        assert!(aspace.handle_page_fault(chunk, MappingFlags::WRITE));
+        aspace.mark_dirty(chunk, 0x2000);
+
+        let entry = |start, end, faults, dirty| HeatmapEntry {
+            range: GuestPhysAddrRange::new(start, end),
+            faults,
+            dirty,
+        };
+        assert_eq!(
+            aspace.heatmap(area),
+            [entry(area.start, chunk, 3, 1), entry(chunk, area.end, 1, 2)]
+        );
+        // Entries are clipped to the range.
+        assert_eq!(
+            aspace.heatmap(GuestPhysAddrRange::new(chunk - 0x1000, chunk + 0x1000)),
+            [
+                entry(chunk - 0x1000, chunk, 3, 1),
+                entry(chunk, chunk + 0x1000, 1, 2)
+            ]
+        );
+        aspace.disable_heatmap();
+        assert!(aspace.heatmap(area).is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+//! Hypervisor-internal memory placed at fixed guest physical addresses but
+//! invisible to the guest, e.g., trampoline pages, log buffers or doorbells.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::PhysAddr;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend};
+use crate::GuestPhysAddr;
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Maps `[start, start + size)` as a host-only area backed by the host
+    /// memory at `start_paddr`.
+    ///
+    /// The area occupies its range like any other, so nothing else can be
+    /// mapped there, but it is never installed in the nested page table: the
+    /// guest faults on every access, and [`AddrSpace::translate`] returns
+    /// `None`. The memory is only reachable with
+    /// [`AddrSpace::host_only_slice`]. It is reported as reserved in memory
+    /// maps, its permissions cannot be changed, and it is removed with
+    /// [`AddrSpace::unmap`].
+    ///
+    /// This is a linear mapping without any access permission, which
+    /// [`AddrSpace::map_linear`] creates as well.
+    pub fn map_host_only(
+        &mut self,
+        start: GuestPhysAddr,
+        start_paddr: PhysAddr,
+        size: usize,
+    ) -> AxResult {
+        self.map_linear(start, start_paddr, size, MappingFlags::empty())
+    }
+
+    /// Returns whether `gpa` lies in a host-only area.
+    pub fn is_host_only(&self, gpa: GuestPhysAddr) -> bool {
+        self.layout
+            .find_area(gpa)
+            .is_some_and(|area| area.backend().is_host_only(area.flags()))
+    }
+
+    /// Returns the host memory of `[gpa, gpa + len)`, which must lie in a
+    /// single host-only area.
+    ///
+    /// Fails with `InvalidInput` if it does not.
+    pub fn host_only_slice(&self, gpa: GuestPhysAddr, len: usize) -> AxResult<&'static mut [u8]> {
+        let Some(area) = self.layout.find_area(gpa) else {
+            return ax_err!(InvalidInput, "address not mapped");
+        };
+        let Backend::Linear { pa_va_offset, .. } = *area.backend() else {
+            return ax_err!(InvalidInput, "not a host-only area");
+        };
+        if !area.backend().is_host_only(area.flags()) {
+            return ax_err!(InvalidInput, "not a host-only area");
+        }
+        if len > area.end() - gpa {
+            return ax_err!(InvalidInput, "range exceeds the host-only area");
+        }
+        let paddr = PhysAddr::from(gpa.as_usize().wrapping_sub(pa_va_offset));
+        Ok(unsafe { core::slice::from_raw_parts_mut(H::phys_to_virt(paddr).as_mut_ptr(), len) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{BASE_PADDR, MockHal, mock_hal_test};
+    use crate::{GuestPhysAddrRange, MemoryMapEntry, ProtectError, ProtectPolicy, RegionKind};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_host_only_area() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let doorbell = base + 0x4000;
+        aspace
+            .map_host_only(doorbell, PhysAddr::from(BASE_PADDR + 0x8000), 0x2000)
+            .unwrap();
+        assert_eq!(
+            aspace.map_alloc(base, 0x5000, rw, true),
+            Err(AxError::AlreadyExists)
+        );
+        assert!(aspace.is_host_only(doorbell + 0x1fff));
+        assert!(!aspace.is_host_only(base));
+
+        // Invisible to the guest...
+        assert!(aspace.translate(doorbell).is_none());
+        assert!(!aspace.handle_page_fault(doorbell, MappingFlags::READ));
+        assert_eq!(
+            aspace.protect_with_policy(
+                doorbell,
+                0x1000,
+                MappingFlags::READ,
+                ProtectPolicy::SkipHoles
+            ),
+            Err(ProtectError::Other(AxError::PermissionDenied))
+        );
+        assert_eq!(
+            aspace.memory_map(),
+            [MemoryMapEntry {
+                range: GuestPhysAddrRange::from_start_size(doorbell, 0x2000),
+                kind: RegionKind::Reserved,
+            }]
+        );
+
+        // ...but not to the host.
+        aspace.host_only_slice(doorbell + 0x1000, 4).unwrap()[0] = 0x42;
+        assert_eq!(
+            aspace.host_only_slice(doorbell + 0x1000, 1).unwrap(),
+            [0x42]
+        );
+        assert!(aspace.host_only_slice(doorbell + 0x1000, 0x1001).is_err());
+        aspace.map_alloc(base, 0x1000, rw, true).unwrap();
+        assert!(aspace.host_only_slice(base, 1).is_err());
+
+        aspace.unmap(doorbell, 0x2000).unwrap();
+        assert!(!aspace.is_host_only(doorbell));
+    }
+}
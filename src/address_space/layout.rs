@@ -0,0 +1,285 @@
+//! The two halves of an [`AddrSpace`]: the layout chosen by the VMM, and the
+//! state of the pages backing it.
+//!
+//! The layout only changes on explicit (re)mapping, while the page state
+//! changes on every fault. Keeping them apart lets the fault and translation
+//! paths borrow the layout immutably while updating the page table, instead
+//! of copying area data out of `self` first.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use axerrno::{AxError, AxResult};
+use log::Level;
+use memory_addr::{PhysAddr, PhysAddrRange};
+use memory_set::{MemoryArea, MemorySet};
+use page_table_multiarch::PagingHandler;
+
+use super::ReplaySink;
+use super::heatmap::Heatmap;
+use super::range_map::RangeMap;
+use super::rmap::ReverseMap;
+use super::summary::EventCounters;
+use super::throttle::DirtyThrottle;
+use super::{
+    AddrSpace, AreaAttributes, Backend, DeviceRegion, FaultDisposition, RangeHints, RegionKind,
+    SealMode,
+};
+use crate::npt::NestedPageTable as PageTable;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE};
+
+/// The layout of an address space: its areas and the attributes of its
+/// ranges.
+pub(crate) struct Layout<H: PagingHandler> {
+    pub va_range: GuestPhysAddrRange,
+    /// The windows added by [`AddrSpace::extend_va_range`], in the order
+    /// they were added.
+    pub extra_ranges: Vec<GuestPhysAddrRange>,
+    /// Only reachable through [`Layout::areas`] and [`Layout::areas_mut`], so
+    /// that every change invalidates `last_area`.
+    areas: MemorySet<Backend<H>>,
+    /// The area last found by [`Layout::find_area`], or null.
+    last_area: AtomicPtr<MemoryArea<Backend<H>>>,
+    pub sealed: Option<SealMode>,
+    pub mmio_regions: BTreeMap<GuestPhysAddr, GuestPhysAddrRange>,
+    /// The handlers of the MMIO ranges registered with
+    /// [`AddrSpace::register_mmio`], by range start.
+    pub mmio_handlers: BTreeMap<GuestPhysAddr, usize>,
+    /// The DMA windows, by start, with whether they are enabled.
+    pub dma_windows: BTreeMap<GuestPhysAddr, (GuestPhysAddrRange, bool)>,
+    pub hints: RangeMap<RangeHints>,
+    pub region_tags: RangeMap<Option<RegionKind>>,
+    pub dispositions: RangeMap<FaultDisposition>,
+    pub host_ranges: Vec<PhysAddrRange>,
+    /// The attributes of the areas, see [`AddrSpace::set_area_attributes`].
+    pub attributes: RangeMap<AreaAttributes>,
+    /// The ranges reserved with [`AddrSpace::add_reserved_range`].
+    pub reserved: RangeMap<bool>,
+    /// The labeled passthrough regions, see
+    /// [`AddrSpace::label_device_region`].
+    pub device_regions: BTreeMap<GuestPhysAddr, DeviceRegion>,
+    pub replay_sink: Option<Box<dyn ReplaySink>>,
+}
+
+/// The state of the pages of an address space: the nested page table and
+/// the bookkeeping of page-level events.
+pub(crate) struct PageState<H: PagingHandler> {
+    pub pt: PageTable<H>,
+    pub dirty_bitmap: Option<Vec<AtomicU64>>,
+    pub dirty_throttle: Option<DirtyThrottle>,
+    /// The counters of [`AddrSpace::heatmap`], while enabled.
+    pub heatmap: Option<Heatmap>,
+    pub events: EventCounters,
+    pub rmap: Option<ReverseMap>,
+    /// The identifier of the address space in the frame ownership table.
+    #[cfg(feature = "frame-ownership")]
+    pub space_id: usize,
+    /// The pages lost to host memory errors, see [`AddrSpace::poison_frame`].
+    pub poisoned: BTreeSet<GuestPhysAddr>,
+    /// The pages released by the guest, see
+    /// [`AddrSpace::guest_release_pages`].
+    pub ballooned: BTreeSet<GuestPhysAddr>,
+    /// The context owning the address space plus one, or zero if unknown,
+    /// see [`AddrSpace::adopt`].
+    #[cfg(feature = "debug-threads")]
+    pub owner: core::sync::atomic::AtomicUsize,
+}
+
+impl<H: PagingHandler> Layout<H> {
+    pub const fn new(va_range: GuestPhysAddrRange) -> Self {
+        Self {
+            va_range,
+            extra_ranges: Vec::new(),
+            areas: MemorySet::new(),
+            last_area: AtomicPtr::new(ptr::null_mut()),
+            sealed: None,
+            mmio_regions: BTreeMap::new(),
+            mmio_handlers: BTreeMap::new(),
+            dma_windows: BTreeMap::new(),
+            hints: RangeMap::new(),
+            region_tags: RangeMap::new(),
+            dispositions: RangeMap::new(),
+            host_ranges: Vec::new(),
+            attributes: RangeMap::new(),
+            reserved: RangeMap::new(),
+            device_regions: BTreeMap::new(),
+            replay_sink: None,
+        }
+    }
+
+    /// Returns the areas.
+    pub fn areas(&self) -> &MemorySet<Backend<H>> {
+        &self.areas
+    }
+
+    /// Returns the areas for modification.
+    pub fn areas_mut(&mut self) -> &mut MemorySet<Backend<H>> {
+        *self.last_area.get_mut() = ptr::null_mut();
+        &mut self.areas
+    }
+
+    /// Replaces the areas with `areas`, returning the old ones.
+    pub fn replace_areas(&mut self, areas: MemorySet<Backend<H>>) -> MemorySet<Backend<H>> {
+        core::mem::replace(self.areas_mut(), areas)
+    }
+
+    /// Finds the area containing `vaddr`.
+    ///
+    /// Faults and translations tend to hit the same area many times in a
+    /// row (e.g., guest RAM), so the last area found is checked first, before
+    /// the `O(log n)` search. An interval tree would not do better than the
+    /// search, as areas never overlap.
+    pub fn find_area(&self, vaddr: GuestPhysAddr) -> Option<&MemoryArea<Backend<H>>> {
+        let last = self.last_area.load(Ordering::Relaxed);
+        // SAFETY: `last` points into `areas`, which has not changed since,
+        // as that takes `areas_mut`, clearing it.
+        if let Some(area) = unsafe { last.as_ref() }
+            && area.va_range().contains(vaddr)
+        {
+            return Some(area);
+        }
+        let area = self.areas.find(vaddr)?;
+        self.last_area
+            .store(ptr::from_ref(area).cast_mut(), Ordering::Relaxed);
+        Some(area)
+    }
+
+    /// Returns the areas overlapping `range`, in ascending order.
+    ///
+    /// A range inside a single area, as on the fault path, is resolved with
+    /// [`Layout::find_area`] instead of a scan of all areas.
+    pub fn areas_overlapping(
+        &self,
+        range: GuestPhysAddrRange,
+    ) -> impl Iterator<Item = &MemoryArea<Backend<H>>> {
+        let single = self
+            .find_area(range.start)
+            .filter(|area| range.end <= area.end());
+        let scan = single.is_none().then(|| {
+            self.areas
+                .iter()
+                .filter(move |area| area.va_range().overlaps(range))
+        });
+        single.into_iter().chain(scan.into_iter().flatten())
+    }
+
+    /// Returns the valid windows of guest addresses: `va_range` first, then
+    /// the windows added later.
+    pub fn windows(&self) -> impl Iterator<Item = GuestPhysAddrRange> + '_ {
+        core::iter::once(self.va_range).chain(self.extra_ranges.iter().copied())
+    }
+
+    /// Whether `vaddr` lies in a window.
+    pub fn contains(&self, vaddr: GuestPhysAddr) -> bool {
+        self.windows().any(|window| window.contains(vaddr))
+    }
+
+    /// Whether `range` is covered by windows, possibly several adjacent
+    /// ones.
+    pub fn contains_range(&self, range: GuestPhysAddrRange) -> bool {
+        if range.is_empty() {
+            return self.windows().any(|window| window.contains_range(range));
+        }
+        let mut addr = range.start;
+        while addr < range.end {
+            match self.windows().find(|window| window.contains(addr)) {
+                Some(window) => addr = window.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Returns the total number of pages of the windows.
+    pub fn num_pages(&self) -> usize {
+        self.windows().map(|window| window.size() / PAGE_SIZE).sum()
+    }
+
+    /// Returns the index of the page at `vaddr`, counting the pages of the
+    /// windows in [`Layout::windows`] order, or `None` if not in a window.
+    pub fn page_index(&self, vaddr: GuestPhysAddr) -> Option<usize> {
+        let mut first = 0;
+        for window in self.windows() {
+            if window.contains(vaddr) {
+                return Some(first + (vaddr - window.start) / PAGE_SIZE);
+            }
+            first += window.size() / PAGE_SIZE;
+        }
+        None
+    }
+
+    /// Returns the address of the page at `index`, the inverse of
+    /// [`Layout::page_index`].
+    pub fn page_at(&self, mut index: usize) -> Option<GuestPhysAddr> {
+        for window in self.windows() {
+            let pages = window.size() / PAGE_SIZE;
+            if index < pages {
+                return Some(window.start + index * PAGE_SIZE);
+            }
+            index -= pages;
+        }
+        None
+    }
+
+    /// Translates `vaddr` through `pt`, which must be the page table of this
+    /// layout. See [`AddrSpace::translate`].
+    pub fn translate(&self, pt: &PageTable<H>, vaddr: GuestPhysAddr) -> Option<PhysAddr> {
+        if !self.contains(vaddr) {
+            return None;
+        }
+        let (phys_addr, _, _) = pt.query(vaddr).ok()?;
+        if log_enabled!(Level::Debug) {
+            debug!("vaddr {vaddr:?} translate to {phys_addr:?}");
+        }
+        Some(phys_addr)
+    }
+
+    /// See [`AddrSpace::translate_and_get_limit`].
+    pub fn translate_and_get_limit(
+        &self,
+        pt: &PageTable<H>,
+        vaddr: GuestPhysAddr,
+    ) -> Option<(PhysAddr, usize)> {
+        if !self.contains(vaddr) {
+            return None;
+        }
+        let area = self.find_area(vaddr)?;
+        pt.query(vaddr)
+            .map(|(phys_addr, _, _)| (phys_addr, area.size()))
+            .ok()
+    }
+}
+
+impl<H: PagingHandler> PageState<H> {
+    pub fn new() -> AxResult<Self> {
+        #[cfg(feature = "frame-ownership")]
+        let space_id = super::ownership::new_space_id();
+        Ok(Self {
+            pt: PageTable::try_new().map_err(|_| AxError::NoMemory)?,
+            dirty_bitmap: None,
+            dirty_throttle: None,
+            heatmap: None,
+            events: EventCounters::default(),
+            #[cfg(not(feature = "frame-ownership"))]
+            rmap: None,
+            #[cfg(feature = "frame-ownership")]
+            rmap: Some(ReverseMap::owned_by(space_id)),
+            #[cfg(feature = "frame-ownership")]
+            space_id,
+            poisoned: BTreeSet::new(),
+            ballooned: BTreeSet::new(),
+            #[cfg(feature = "debug-threads")]
+            owner: super::threads::current_owner().into(),
+        })
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Borrows the layout immutably and the page state mutably at once.
+    pub(crate) fn split_mut(&mut self) -> (&Layout<H>, &mut PageState<H>) {
+        (&self.layout, &mut self.state)
+    }
+}
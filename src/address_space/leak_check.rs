@@ -0,0 +1,129 @@
+//! A census of the host frames owned by an address space, for leak tests of
+//! hosts (`testing` feature).
+//!
+//! Counting allocations only tells that frames leaked, not where from. Taken
+//! before and after a VM is created and destroyed, the census tells which
+//! guest pages or page tables still hold the frames that did not come back.
+
+use alloc::vec::Vec;
+
+use page_table_entry::GenericPTE;
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, BackendKind};
+use crate::{GuestPhysAddr, HostPhysAddr, PAGE_SIZE, npt};
+
+/// What a frame returned by [`AddrSpace::leak_check`] is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameUse {
+    /// Guest memory at `gpa`, in an area of the given backend.
+    Page {
+        /// The guest physical address the frame is mapped at.
+        gpa: GuestPhysAddr,
+        /// The backend of the area containing `gpa`.
+        backend: BackendKind,
+    },
+    /// A table of the nested page table.
+    PageTable,
+}
+
+/// A host frame owned by an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveFrame {
+    /// The host physical address of the frame.
+    pub hpa: HostPhysAddr,
+    /// The size of the frame, larger than a page for huge pages.
+    pub size: usize,
+    /// What the frame is used for.
+    pub usage: FrameUse,
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns every host frame owned by the address space, and thus freed
+    /// when it is dropped: the pages of allocation areas, the copied pages
+    /// of copy-on-write areas, then the tables of the nested page table.
+    ///
+    /// The host memory of linear areas and the templates of copy-on-write
+    /// areas belong to the caller, and are not listed.
+    pub fn leak_check(&self) -> Vec<LiveFrame> {
+        let mut frames = Vec::new();
+        let root = self.state.pt.root_paddr();
+        npt::tables::for_each_leaf::<H>(root, &mut |start, size, entry| {
+            let gpa = GuestPhysAddr::from(start);
+            let Some(area) = self.layout.find_area(gpa) else {
+                return;
+            };
+            if area.backend().owns_frame(gpa, entry.paddr()) {
+                frames.push(LiveFrame {
+                    hpa: entry.paddr(),
+                    size,
+                    usage: FrameUse::Page {
+                        gpa,
+                        backend: BackendKind::of(area.backend(), area.start()),
+                    },
+                });
+            }
+        });
+        npt::tables::for_each_table::<H>(root, &mut |hpa| {
+            frames.push(LiveFrame {
+                hpa,
+                size: PAGE_SIZE,
+                usage: FrameUse::PageTable,
+            });
+        });
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{ALLOC_COUNT, DEALLOC_COUNT, MockHal, mock_hal_test};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_leak_check() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let template = MockHal::alloc_frame().unwrap();
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x2000, rw, false).unwrap();
+        aspace.map_cow(base + 0x2000, template, 0x2000, rw).unwrap();
+        aspace
+            .map_linear(base + 0x8000, PhysAddr::from(0x40_0000), 0x1000, rw)
+            .unwrap();
+        assert!(aspace.handle_page_fault(base + 0x1000, MappingFlags::WRITE));
+        assert!(aspace.handle_page_fault(base + 0x3000, MappingFlags::WRITE));
+
+        let frames = aspace.leak_check();
+        let pages: Vec<_> = frames
+            .iter()
+            .filter_map(|frame| match frame.usage {
+                FrameUse::Page { gpa, backend } => Some((gpa, backend)),
+                FrameUse::PageTable => None,
+            })
+            .collect();
+        assert_eq!(
+            pages,
+            [
+                (base + 0x1000, BackendKind::Alloc),
+                (
+                    base + 0x3000,
+                    BackendKind::CoW {
+                        template_paddr: template
+                    }
+                ),
+            ]
+        );
+        assert_eq!(frames.len() - pages.len(), aspace.page_table_frames());
+        // The census is what is freed with the address space.
+        let live = ALLOC_COUNT.load(Ordering::SeqCst) - DEALLOC_COUNT.load(Ordering::SeqCst);
+        assert_eq!(frames.len(), live - 1);
+        drop(aspace);
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), frames.len());
+    }
+}
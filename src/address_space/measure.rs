@@ -0,0 +1,198 @@
+//! Measurement of guest memory contents for attestation.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::AddrSpace;
+use crate::{GuestPhysAddrRange, PAGE_SIZE};
+
+/// Contents of a page that is mapped lazily but not faulted in yet.
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// A hash function used to measure guest memory.
+///
+/// The crate does not pick a hash algorithm; deployments plug in whatever
+/// their attestation scheme mandates (e.g., SHA-384 for TDX-like digests).
+pub trait MeasurementHasher {
+    /// The digest produced by the hasher.
+    type Digest;
+
+    /// Feeds `data` into the hasher.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest of the data fed so far, and resets the hasher to
+    /// its initial state.
+    fn finalize_reset(&mut self) -> Self::Digest;
+}
+
+/// A record in the measurement log produced by [`AddrSpace::measure`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementEntry<D> {
+    /// The measured guest physical range.
+    pub range: GuestPhysAddrRange,
+    /// The mapping flags of the range.
+    pub flags: MappingFlags,
+    /// The digest of the contents of the range.
+    pub digest: D,
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Measures the contents of the given guest physical ranges.
+    ///
+    /// Returns a measurement log ordered by guest physical address. A range
+    /// spanning several areas yields one entry per area, so that every entry
+    /// carries a single set of mapping flags. Pages of lazily allocated areas
+    /// that are not faulted in yet are measured as zero-filled, which is what
+    /// the guest would observe.
+    ///
+    /// All ranges must be page-aligned, must not overlap each other and must be
+    /// fully covered by mapped areas, otherwise [`AxError::InvalidInput`] is
+    /// returned.
+    ///
+    /// [`AxError::InvalidInput`]: axerrno::AxError::InvalidInput
+    pub fn measure<M: MeasurementHasher>(
+        &self,
+        ranges: &[GuestPhysAddrRange],
+        hasher: &mut M,
+    ) -> AxResult<Vec<MeasurementEntry<M::Digest>>> {
+        let mut ranges = ranges.to_vec();
+        ranges.sort_by_key(|r| r.start);
+        if ranges.windows(2).any(|w| w[0].overlaps(w[1])) {
+            return ax_err!(InvalidInput, "measured ranges overlap");
+        }
+
+        let mut log = Vec::new();
+        for range in ranges {
+            if !range.start.is_aligned(PAGE_SIZE) || !range.end.is_aligned(PAGE_SIZE) {
+                return ax_err!(InvalidInput, "measured range not aligned");
+            }
+            if !self.layout.contains_range(range) {
+                return ax_err!(InvalidInput, "measured range out of range");
+            }
+
+            let mut covered = range.start;
+            for area in self.layout.areas().iter() {
+                if area.end() <= range.start || area.start() >= range.end {
+                    continue;
+                }
+                if area.start() > covered {
+                    break;
+                }
+                let sub_end = area.end().min(range.end);
+                self.for_each_host_segment(covered, sub_end - covered, |_, paddr, len| {
+                    match paddr {
+                        Some(paddr) => hasher.update(unsafe {
+                            core::slice::from_raw_parts(H::phys_to_virt(paddr).as_ptr(), len)
+                        }),
+                        None => hasher.update(&ZERO_PAGE[..len]),
+                    }
+                    Ok(())
+                })?;
+                log.push(MeasurementEntry {
+                    range: GuestPhysAddrRange::new(covered, sub_end),
+                    flags: area.flags(),
+                    digest: hasher.finalize_reset(),
+                });
+                covered = sub_end;
+            }
+            if covered != range.end {
+                return ax_err!(InvalidInput, "measured range not mapped");
+            }
+        }
+        Ok(log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GuestPhysAddr;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+
+    /// FNV-1a, good enough to detect content changes in tests.
+    struct Fnv(u64);
+
+    impl MeasurementHasher for Fnv {
+        type Digest = u64;
+
+        fn update(&mut self, data: &[u8]) {
+            for &b in data {
+                self.0 = (self.0 ^ b as u64).wrapping_mul(0x100_0000_01b3);
+            }
+        }
+
+        fn finalize_reset(&mut self) -> u64 {
+            core::mem::replace(&mut self.0, 0xcbf2_9ce4_8422_2325)
+        }
+    }
+
+    fn range(start: usize, end: usize) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::new(start.into(), end.into())
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_measure_log() {
+        let mut addr_space =
+            AddrSpace::<MockHal>::new_empty(GuestPhysAddr::from(0x10000), 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let rx = MappingFlags::READ | MappingFlags::EXECUTE;
+        addr_space
+            .map_alloc(0x10000.into(), 0x1000, rx, true)
+            .unwrap();
+        addr_space
+            .map_alloc(0x11000.into(), 0x1000, rw, false)
+            .unwrap();
+
+        let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+        let log = addr_space
+            .measure(&[range(0x10000, 0x12000)], &mut hasher)
+            .unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].range, range(0x10000, 0x11000));
+        assert_eq!(log[0].flags, rx);
+        assert_eq!(log[1].flags, rw);
+        // Both pages read as zeros, whether populated or not.
+        assert_eq!(log[0].digest, log[1].digest);
+
+        addr_space
+            .translated_byte_buffer(0x10000.into(), 1)
+            .unwrap()[0][0] = 0x90;
+        let new_log = addr_space
+            .measure(&[range(0x10000, 0x11000)], &mut hasher)
+            .unwrap();
+        assert_ne!(new_log[0].digest, log[0].digest);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_measure_invalid_ranges() {
+        let mut addr_space =
+            AddrSpace::<MockHal>::new_empty(GuestPhysAddr::from(0x10000), 0x10000).unwrap();
+        addr_space
+            .map_alloc(0x10000.into(), 0x2000, MappingFlags::READ, false)
+            .unwrap();
+        let mut hasher = Fnv(0);
+
+        let overlapping = [range(0x10000, 0x12000), range(0x11000, 0x12000)];
+        assert_eq!(
+            addr_space.measure(&overlapping, &mut hasher),
+            Err(AxError::InvalidInput)
+        );
+        let hole = [range(0x11000, 0x13000)];
+        assert_eq!(
+            addr_space.measure(&hole, &mut hasher),
+            Err(AxError::InvalidInput)
+        );
+        let unaligned = [range(0x10000, 0x10800)];
+        assert_eq!(
+            addr_space.measure(&unaligned, &mut hasher),
+            Err(AxError::InvalidInput)
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Guest memory maps (E820, FDT memory nodes, EFI memory map) derived from
+//! the mapped areas.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::{GuestPhysAddrRange, MemType, PAGE_SIZE};
+
+/// The kind of a guest physical region, as reported in memory maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Usable RAM.
+    Ram,
+    /// Reserved, not usable by the guest OS.
+    Reserved,
+    /// ACPI tables, reclaimable once parsed.
+    AcpiReclaimable,
+    /// ACPI non-volatile storage.
+    AcpiNvs,
+    /// Memory-mapped I/O.
+    Mmio,
+}
+
+/// An entry of the memory map built by [`AddrSpace::memory_map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMapEntry {
+    /// The guest physical range.
+    pub range: GuestPhysAddrRange,
+    /// The kind of the range.
+    pub kind: RegionKind,
+}
+
+/// The format of the table built by [`AddrSpace::generate_memory_map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMapStyle {
+    /// BIOS E820 entries: 20 bytes each, little-endian `u64` address, `u64`
+    /// size and `u32` type.
+    E820,
+    /// The `reg` property of a devicetree `/memory` node, with
+    /// `#address-cells` and `#size-cells` of 2: big-endian `u64` address and
+    /// size pairs. Only RAM is listed.
+    FdtMemoryNodes,
+    /// UEFI `EFI_MEMORY_DESCRIPTOR`s: 40 bytes each, little-endian.
+    Efi,
+}
+
+/// Size of an E820 entry in bytes.
+pub const E820_ENTRY_SIZE: usize = 20;
+/// Size of an `EFI_MEMORY_DESCRIPTOR` in bytes.
+pub const EFI_DESCRIPTOR_SIZE: usize = 40;
+
+const EFI_MEMORY_UC: u64 = 0x1;
+const EFI_MEMORY_WB: u64 = 0x8;
+
+impl RegionKind {
+    const fn e820_type(self) -> u32 {
+        match self {
+            Self::Ram => 1,
+            Self::Reserved | Self::Mmio => 2,
+            Self::AcpiReclaimable => 3,
+            Self::AcpiNvs => 4,
+        }
+    }
+
+    /// Returns the `EFI_MEMORY_TYPE` and the attributes of the region.
+    const fn efi_type(self) -> (u32, u64) {
+        match self {
+            Self::Ram => (7, EFI_MEMORY_WB),
+            Self::Reserved => (0, 0),
+            Self::AcpiReclaimable => (9, EFI_MEMORY_WB),
+            Self::AcpiNvs => (10, EFI_MEMORY_WB),
+            Self::Mmio => (11, EFI_MEMORY_UC),
+        }
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Tags a guest physical range with the kind reported in memory maps.
+    ///
+    /// Without a tag, areas of normal memory are reported as RAM, and device
+    /// areas and reserved MMIO ranges as MMIO. Tags only affect mapped areas,
+    /// except [`RegionKind::Reserved`] and [`RegionKind::Mmio`] tags, which
+    /// also describe holes.
+    pub fn tag_region(&mut self, range: GuestPhysAddrRange, kind: RegionKind) -> AxResult {
+        self.check_tag_range(range)?;
+        self.layout.region_tags.set(range, Some(kind));
+        Ok(())
+    }
+
+    /// Removes the tags of a guest physical range.
+    pub fn untag_region(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        self.check_tag_range(range)?;
+        self.layout.region_tags.set(range, None);
+        Ok(())
+    }
+
+    fn check_tag_range(&self, range: GuestPhysAddrRange) -> AxResult {
+        if !self.layout.contains_range(range) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !range.start.is_aligned(PAGE_SIZE) || !range.end.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        Ok(())
+    }
+
+    /// Returns the memory map of the guest, ordered by address, with adjacent
+    /// ranges of the same kind merged.
+    pub fn memory_map(&self) -> Vec<MemoryMapEntry> {
+        let mut entries = Vec::new();
+        let mut push = |range: GuestPhysAddrRange, kind| {
+            if !range.is_empty() {
+                entries.push(MemoryMapEntry { range, kind });
+            }
+        };
+
+        for area in self.layout.areas().iter() {
+            let default_kind = if area.backend().is_host_only(area.flags()) {
+                RegionKind::Reserved
+            } else if MemType::from_flags(area.flags()) == MemType::Normal {
+                RegionKind::Ram
+            } else {
+                RegionKind::Mmio
+            };
+            let area_range = GuestPhysAddrRange::new(area.start(), area.end());
+            let mut cursor = area.start();
+            for (range, kind) in self.layout.region_tags.overlapping(area_range) {
+                push(GuestPhysAddrRange::new(cursor, range.start), default_kind);
+                push(range, kind.unwrap());
+                cursor = range.end;
+            }
+            push(GuestPhysAddrRange::new(cursor, area.end()), default_kind);
+        }
+        for &range in self.layout.mmio_regions.values() {
+            push(range, RegionKind::Mmio);
+        }
+        for (range, kind) in self.layout.region_tags.iter() {
+            let kind = kind.unwrap();
+            if !matches!(kind, RegionKind::Reserved | RegionKind::Mmio) {
+                continue;
+            }
+            for hole in self.holes(range.start, range.size()) {
+                let mut cursor = hole.start;
+                for mmio in self
+                    .layout
+                    .mmio_regions
+                    .values()
+                    .filter(|r| r.overlaps(hole))
+                {
+                    push(
+                        GuestPhysAddrRange::new(cursor, mmio.start.max(cursor)),
+                        kind,
+                    );
+                    cursor = mmio.end.min(hole.end);
+                }
+                push(GuestPhysAddrRange::new(cursor, hole.end.max(cursor)), kind);
+            }
+        }
+
+        entries.sort_by_key(|e| e.range.start);
+        let mut merged: Vec<MemoryMapEntry> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match merged.last_mut() {
+                Some(last) if last.kind == entry.kind && last.range.end == entry.range.start => {
+                    last.range.end = entry.range.end
+                }
+                _ => merged.push(entry),
+            }
+        }
+        merged
+    }
+
+    /// Encodes the memory map of the guest in the given style.
+    ///
+    /// See [`crate::loader::load_memory_map`] to write it into guest memory.
+    pub fn generate_memory_map(&self, style: MemoryMapStyle) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for entry in self.memory_map() {
+            let start = entry.range.start.as_usize() as u64;
+            let size = entry.range.size() as u64;
+            match style {
+                MemoryMapStyle::E820 => {
+                    buf.extend_from_slice(&start.to_le_bytes());
+                    buf.extend_from_slice(&size.to_le_bytes());
+                    buf.extend_from_slice(&entry.kind.e820_type().to_le_bytes());
+                }
+                MemoryMapStyle::FdtMemoryNodes => {
+                    if entry.kind == RegionKind::Ram {
+                        buf.extend_from_slice(&start.to_be_bytes());
+                        buf.extend_from_slice(&size.to_be_bytes());
+                    }
+                }
+                MemoryMapStyle::Efi => {
+                    let (ty, attr) = entry.kind.efi_type();
+                    buf.extend_from_slice(&ty.to_le_bytes());
+                    buf.extend_from_slice(&0u32.to_le_bytes());
+                    buf.extend_from_slice(&start.to_le_bytes());
+                    buf.extend_from_slice(&0u64.to_le_bytes());
+                    buf.extend_from_slice(&(size / PAGE_SIZE as u64).to_le_bytes());
+                    buf.extend_from_slice(&attr.to_le_bytes());
+                }
+            }
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{GuestPhysAddr, MappingFlags};
+    use axin::axin;
+
+    fn range(start: usize, end: usize) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::new(start.into(), end.into())
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_memory_map() {
+        let mut aspace = AddrSpace::<MockHal>::new_empty(GuestPhysAddr::from(0), 0x20000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(0x0.into(), 0x8000, rw, false).unwrap();
+        aspace.reserve_mmio(range(0xc000, 0xd000)).unwrap();
+        aspace
+            .tag_region(range(0x6000, 0x8000), RegionKind::AcpiReclaimable)
+            .unwrap();
+        aspace
+            .tag_region(range(0xa000, 0x10000), RegionKind::Reserved)
+            .unwrap();
+        // Ignored: RAM cannot be claimed where nothing is mapped.
+        aspace
+            .tag_region(range(0x10000, 0x11000), RegionKind::Ram)
+            .unwrap();
+
+        let entry = |s, e, kind| MemoryMapEntry {
+            range: range(s, e),
+            kind,
+        };
+        assert_eq!(
+            aspace.memory_map(),
+            [
+                entry(0x0, 0x6000, RegionKind::Ram),
+                entry(0x6000, 0x8000, RegionKind::AcpiReclaimable),
+                entry(0xa000, 0xc000, RegionKind::Reserved),
+                entry(0xc000, 0xd000, RegionKind::Mmio),
+                entry(0xd000, 0x10000, RegionKind::Reserved),
+            ]
+        );
+
+        let e820 = aspace.generate_memory_map(MemoryMapStyle::E820);
+        assert_eq!(e820.len(), 5 * E820_ENTRY_SIZE);
+        assert_eq!(&e820[8..16], &0x6000u64.to_le_bytes());
+        assert_eq!(&e820[16..20], &1u32.to_le_bytes());
+        let fdt = aspace.generate_memory_map(MemoryMapStyle::FdtMemoryNodes);
+        assert_eq!(fdt, [0u64.to_be_bytes(), 0x6000u64.to_be_bytes()].concat());
+        let efi = aspace.generate_memory_map(MemoryMapStyle::Efi);
+        assert_eq!(efi.len(), 5 * EFI_DESCRIPTOR_SIZE);
+        assert_eq!(&efi[EFI_DESCRIPTOR_SIZE..][..4], &9u32.to_le_bytes());
+        assert_eq!(&efi[EFI_DESCRIPTOR_SIZE + 24..][..8], &2u64.to_le_bytes());
+
+        aspace.untag_region(range(0x0, 0x20000)).unwrap();
+        assert_eq!(aspace.memory_map().len(), 2);
+    }
+}
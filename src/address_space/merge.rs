@@ -0,0 +1,181 @@
+//! Coalescing of fragmented area lists.
+
+use alloc::vec::Vec;
+
+use axerrno::AxResult;
+use memory_set::{MemoryArea, MemorySet};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend, ReplayRecord};
+use crate::{GuestPhysAddrRange, mapping_err_to_ax_err};
+
+impl<H: PagingHandler> Backend<H> {
+    /// Whether an area with this backend can absorb the area right after it,
+    /// with backend `next`.
+    fn can_merge(&self, next: &Self) -> bool {
+        match (self, next) {
+            (
+                Self::Linear {
+                    pa_va_offset,
+                    granularity,
+                },
+                Self::Linear {
+                    pa_va_offset: next_offset,
+                    granularity: next_granularity,
+                },
+            ) => pa_va_offset == next_offset && granularity == next_granularity,
+            (
+                Self::Alloc {
+                    populate,
+                    granularity,
+                    dealloc_huge,
+                    ..
+                },
+                Self::Alloc {
+                    populate: next_populate,
+                    granularity: next_granularity,
+                    dealloc_huge: next_dealloc_huge,
+                    ..
+                },
+            ) => {
+                populate == next_populate
+                    && granularity == next_granularity
+                    && dealloc_huge.is_some() == next_dealloc_huge.is_some()
+            }
+            (
+                Self::CoW { pa_va_offset },
+                Self::CoW {
+                    pa_va_offset: next_offset,
+                },
+            ) => pa_va_offset == next_offset,
+            _ => false,
+        }
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Coalesces adjacent areas with identical flags and compatible backends
+    /// into single areas, returning the number of areas removed.
+    ///
+    /// Backends are compatible if they are of the same kind with the same
+    /// granularity, and, for linear and copy-on-write areas, map contiguous
+    /// host memory, or, for allocation areas, are both populated or both
+    /// lazy. Areas in different windows of the address space, or with
+    /// different [`AreaAttributes`](super::AreaAttributes), are never merged.
+    ///
+    /// Only the area list changes: the pages are taken over as they are, so
+    /// the guest sees no difference. This speeds up area lookups after many
+    /// incremental mappings (e.g., memory hot-add) fragmented the list.
+    ///
+    /// Fails with `BadState` if the address space is sealed.
+    pub fn merge_adjacent_areas(&mut self) -> AxResult<usize> {
+        self.check_unsealed()?;
+        let mut runs: Vec<(GuestPhysAddrRange, MappingFlags, Backend<H>)> = Vec::new();
+        for area in self.layout.areas().iter() {
+            if let Some((range, flags, backend)) = runs.last_mut()
+                && range.end == area.start()
+                && *flags == area.flags()
+                && backend.can_merge(area.backend())
+                && self.layout.attributes.get(range.start)
+                    == self.layout.attributes.get(area.start())
+                && self
+                    .layout
+                    .contains_range(GuestPhysAddrRange::new(range.start, area.end()))
+            {
+                range.end = area.end();
+                continue;
+            }
+            runs.push((area.va_range(), area.flags(), area.backend().clone()));
+        }
+        let removed = self.layout.areas().len() - runs.len();
+        if removed == 0 {
+            return Ok(0);
+        }
+
+        // The old list is kept until the new one is complete, so a failing
+        // takeover leaves the address space as it was.
+        let mut areas = MemorySet::new();
+        for (range, flags, backend) in runs {
+            let area = MemoryArea::new(range.start, range.size(), flags, backend);
+            areas
+                .map(area, &mut self.state.pt, false)
+                .map_err(mapping_err_to_ax_err)?;
+        }
+        self.layout.replace_areas(areas);
+        self.record(ReplayRecord::MergeAreas);
+        debug!("merge_adjacent_areas: removed {removed} areas");
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{ALLOC_COUNT, DEALLOC_COUNT, MockHal, mock_hal_test};
+    use crate::{GuestPhysAddr, PAGE_SIZE, SealMode};
+    use axerrno::AxError;
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_merge_adjacent_areas() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x20000).unwrap();
+        // Hot-added RAM, page by page.
+        for i in 0..4 {
+            aspace
+                .map_alloc(base + i * PAGE_SIZE, PAGE_SIZE, rw, true)
+                .unwrap();
+        }
+        // Different flags, then a lazy area.
+        aspace
+            .map_alloc(base + 0x4000, PAGE_SIZE, MappingFlags::READ, true)
+            .unwrap();
+        aspace
+            .map_alloc(base + 0x5000, PAGE_SIZE, rw, false)
+            .unwrap();
+        // Contiguous host memory, then a discontiguous one.
+        let ram = PhysAddr::from(0x40_0000);
+        aspace.map_linear(base + 0x8000, ram, 0x1000, rw).unwrap();
+        aspace
+            .map_linear(base + 0x9000, ram + 0x1000, 0x1000, rw)
+            .unwrap();
+        aspace
+            .map_linear(base + 0xa000, ram + 0x3000, 0x1000, rw)
+            .unwrap();
+        let before: Vec<_> = (0..0xb)
+            .map(|i| aspace.translate(base + i * PAGE_SIZE))
+            .collect();
+        let allocated = ALLOC_COUNT.load(Ordering::SeqCst);
+
+        aspace.seal(SealMode::Temporary);
+        assert_eq!(aspace.merge_adjacent_areas(), Err(AxError::BadState));
+        aspace.unseal().unwrap();
+        assert_eq!(aspace.merge_adjacent_areas(), Ok(4));
+        let areas: Vec<_> = aspace.layout.areas().iter().map(|a| a.va_range()).collect();
+        let range = |off, size| GuestPhysAddrRange::from_start_size(base + off, size);
+        assert_eq!(
+            areas,
+            [
+                range(0, 0x4000),
+                range(0x4000, 0x1000),
+                range(0x5000, 0x1000),
+                range(0x8000, 0x2000),
+                range(0xa000, 0x1000),
+            ]
+        );
+        let after: Vec<_> = (0..0xb)
+            .map(|i| aspace.translate(base + i * PAGE_SIZE))
+            .collect();
+        assert_eq!(before, after);
+        assert_eq!(ALLOC_COUNT.load(Ordering::SeqCst), allocated);
+        assert_eq!(aspace.merge_adjacent_areas(), Ok(0));
+
+        // The merged area owns all the frames.
+        aspace.unmap(base, 0x4000).unwrap();
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), 4);
+    }
+}
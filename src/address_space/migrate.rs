@@ -0,0 +1,481 @@
+//! Moving guest pages between host frames.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult, ax_err};
+use memory_addr::{MemoryAddr, PAGE_SIZE_2M, is_aligned};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, AreaAttributes, Backend, HostExtent, ReplayRecord};
+use crate::{
+    AxMmHal, BASE_PAGE_SIZE, GuestPageIter, GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE,
+    PhysFrame, mapping_err_to_ax_err, npt,
+};
+
+impl<H: PagingHandler + AxMmHal> AddrSpace<H> {
+    /// Removes mappings within the specified range, like [`AddrSpace::unmap`],
+    /// but hands the frames backing allocation areas over to the caller
+    /// instead of deallocating them.
+    ///
+    /// The frames are returned in ascending guest address order. Pages that
+    /// were never faulted in have no frame, and frames of linear areas are not
+    /// owned by the address space, so neither are returned. They are followed
+    /// by the host extents that were mapped, as returned by `unmap`.
+    ///
+    /// Fails with `InvalidInput` if an allocation area in the range is backed
+    /// by huge pages, as allocated by [`AddrSpace::map_alloc_with_policy`],
+    /// or if a copy-on-write area in the range has huge pages, which it
+    /// cannot unmap. All checks are done before any frame is detached, so
+    /// that on failure the range is left as it was.
+    pub fn unmap_keep_frames(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+    ) -> AxResult<(Vec<PhysFrame<H>>, Vec<HostExtent>)> {
+        self.check_unsealed()?;
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned(PAGE_SIZE) || !is_aligned(size, PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        self.check_split_points(start, size)?;
+        self.check_no_huge_pages(start, size)?;
+
+        let extents = self.host_extents(start, size);
+        let end = start + size;
+        let mut frames = Vec::new();
+        for area in self.layout.areas().iter() {
+            if area.end() <= start || area.start() >= end {
+                continue;
+            }
+            if !matches!(area.backend(), Backend::Alloc { .. }) {
+                continue;
+            }
+            let sub_start = area.start().max(start);
+            let sub_end = area.end().min(end);
+            for addr in GuestPageIter::new(sub_start, sub_end).unwrap() {
+                // Detach the frame, so that the backend finds nothing to free.
+                if let Ok((paddr, _, tlb)) = self.state.pt.unmap(addr) {
+                    tlb.ignore();
+                    frames.push(unsafe { PhysFrame::from_raw(paddr) });
+                }
+            }
+        }
+        #[cfg(feature = "frame-ownership")]
+        let claims = self.linear_claims(start, size);
+        self.layout
+            .areas_mut()
+            .unmap(start, size, &mut self.state.pt)
+            .map_err(mapping_err_to_ax_err)?;
+        #[cfg(feature = "frame-ownership")]
+        for claim in claims {
+            super::ownership::release(self.state.space_id, claim);
+        }
+        npt::flush_tlb(None);
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        self.layout.attributes.set(range, AreaAttributes::empty());
+        self.drop_device_labels(range);
+        self.rmap_update(start, size);
+        self.clear_poison(start, size);
+        self.clear_ballooned(start, size);
+        self.record(ReplayRecord::Unmap { start, size });
+        Ok((frames, extents))
+    }
+
+    /// Checks that no page of an allocation or copy-on-write area in
+    /// `[start, start + size)` is a huge page, which cannot be handed over
+    /// as a [`PhysFrame`] or unmapped, respectively.
+    fn check_no_huge_pages(&self, start: GuestPhysAddr, size: usize) -> AxResult {
+        let mut huge = false;
+        npt::tables::for_each_leaf_in::<H>(
+            self.state.pt.root_paddr(),
+            start.as_usize(),
+            start.as_usize() + size,
+            &mut |leaf, len, _| {
+                huge |= len != PAGE_SIZE
+                    && matches!(
+                        self.layout
+                            .find_area(GuestPhysAddr::from(leaf))
+                            .map(|a| a.backend()),
+                        Some(Backend::Alloc { .. } | Backend::CoW { .. })
+                    );
+            },
+        );
+        if huge {
+            return ax_err!(InvalidInput, "huge pages cannot be detached");
+        }
+        Ok(())
+    }
+
+    /// Moves the guest page at `gpa` to `new_frame`, and returns the frame
+    /// that backed it before.
+    ///
+    /// The page must belong to an allocation area and be present. It is
+    /// write-protected while its contents are copied, then remapped to the
+    /// new frame with its original flags, so the guest never observes a
+    /// partially copied page. If the page cannot be remapped, its flags are
+    /// restored and `new_frame` is dropped.
+    pub fn migrate_page(
+        &mut self,
+        gpa: GuestPhysAddr,
+        new_frame: PhysFrame<H>,
+    ) -> AxResult<PhysFrame<H>> {
+        self.check_unsealed()?;
+        let gpa = gpa.align_down(PAGE_SIZE);
+        let old_frame = self.move_page(gpa, new_frame)?;
+        self.record(ReplayRecord::Migrate { gpa });
+        Ok(old_frame)
+    }
+
+    /// Moves the page at the page-aligned `gpa` to `new_frame`, see
+    /// [`AddrSpace::migrate_page`], without recording it.
+    fn move_page(&mut self, gpa: GuestPhysAddr, new_frame: PhysFrame<H>) -> AxResult<PhysFrame<H>> {
+        match self.layout.find_area(gpa) {
+            Some(area) if matches!(area.backend(), Backend::Alloc { .. }) => {}
+            _ => return ax_err!(InvalidInput, "page not in an allocation area"),
+        }
+        let Ok((old_paddr, flags, BASE_PAGE_SIZE)) = self.state.pt.query(gpa) else {
+            return ax_err!(BadState, "page not present");
+        };
+
+        let (_, tlb) = self
+            .state
+            .pt
+            .protect(gpa, flags - MappingFlags::WRITE)
+            .map_err(|_| AxError::BadState)?;
+        tlb.ignore();
+        npt::flush_tlb(Some(gpa));
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                <H as PagingHandler>::phys_to_virt(old_paddr).as_ptr(),
+                new_frame.as_mut_ptr(),
+                PAGE_SIZE,
+            );
+        }
+        match self.state.pt.remap(gpa, new_frame.start_paddr(), flags) {
+            Ok((_, tlb)) => {
+                tlb.ignore();
+                new_frame.into_raw();
+            }
+            Err(_) => {
+                if let Ok((_, tlb)) = self.state.pt.protect(gpa, flags) {
+                    tlb.ignore();
+                }
+                npt::flush_tlb(Some(gpa));
+                return ax_err!(BadState, "page cannot be remapped");
+            }
+        }
+        npt::flush_tlb(Some(gpa));
+        self.rmap_update(gpa, PAGE_SIZE);
+        Ok(unsafe { PhysFrame::from_raw(old_paddr) })
+    }
+
+    /// Compacts the pages of allocation areas within the specified range into
+    /// physically contiguous host runs, so that they can later be promoted to
+    /// huge pages.
+    ///
+    /// The range is processed in runs that do not cross a 2M boundary or an
+    /// area boundary. Every run that is not contiguous yet is moved to
+    /// consecutive frames (aligned to 2M for whole 2M runs); pages not
+    /// faulted in yet are populated with zeros, except for those released
+    /// with [`AddrSpace::guest_release_pages`] or poisoned, see
+    /// [`AddrSpace::poison_frame`], which stay unmapped and do not break the
+    /// contiguity of their run. As the pages are later freed
+    /// one by one, the frames are allocated one by one with
+    /// [`AxMmHal::alloc_frame`], and the pass fails with `NoMemory` if the
+    /// handler does not hand out consecutive frames.
+    ///
+    /// Returns the number of pages that were moved or populated. On failure,
+    /// the pages moved so far stay moved, and the frames not used yet are
+    /// freed.
+    pub fn defragment(&mut self, start: GuestPhysAddr, size: usize) -> AxResult<usize> {
+        self.check_unsealed()?;
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned(PAGE_SIZE) || !is_aligned(size, PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let end = start + size;
+        let mut runs = Vec::new();
+        for area in self.layout.areas().iter() {
+            if area.end() <= start || area.start() >= end {
+                continue;
+            }
+            if !matches!(area.backend(), Backend::Alloc { .. }) {
+                continue;
+            }
+            let mut run_start = area.start().max(start);
+            let area_end = area.end().min(end);
+            while run_start < area_end {
+                let run_end = (run_start.align_down(PAGE_SIZE_2M) + PAGE_SIZE_2M).min(area_end);
+                runs.push((run_start, run_end, area.flags()));
+                run_start = run_end;
+            }
+        }
+
+        let mut moved = 0;
+        for (run_start, run_end, flags) in runs {
+            if self.is_contiguous(run_start, run_end) {
+                continue;
+            }
+            let num_frames = (run_end - run_start) / PAGE_SIZE;
+            let align = if num_frames * PAGE_SIZE == PAGE_SIZE_2M {
+                PAGE_SIZE_2M
+            } else {
+                PAGE_SIZE
+            };
+            let frames = Self::alloc_consecutive(num_frames, align)?;
+            for (frame, gpa) in frames
+                .into_iter()
+                .zip(GuestPageIter::new(run_start, run_end).unwrap())
+            {
+                if self.skips_defragment(gpa) {
+                    continue;
+                }
+                if self.state.pt.query(gpa).is_ok() {
+                    drop(self.move_page(gpa, frame)?);
+                } else {
+                    let mut frame = frame;
+                    frame.fill(0);
+                    let (_, tlb) = self
+                        .state
+                        .pt
+                        .remap(gpa, frame.start_paddr(), flags)
+                        .map_err(|_| AxError::BadState)?;
+                    tlb.ignore();
+                    frame.into_raw();
+                    self.mark_dirty(gpa, PAGE_SIZE);
+                }
+                moved += 1;
+            }
+            self.rmap_update(run_start, run_end - run_start);
+        }
+        self.record(ReplayRecord::Defragment { start, size });
+        Ok(moved)
+    }
+
+    /// Allocates `num_frames` frames one by one, and returns them in address
+    /// order if they are consecutive and the first one is aligned to `align`.
+    fn alloc_consecutive(num_frames: usize, align: usize) -> AxResult<Vec<PhysFrame<H>>> {
+        let mut frames = (0..num_frames)
+            .map(|_| PhysFrame::alloc())
+            .collect::<AxResult<Vec<_>>>()?;
+        frames.sort_by_key(|frame| frame.start_paddr());
+        let base = frames[0].start_paddr();
+        let consecutive = base.is_aligned(align)
+            && frames
+                .iter()
+                .enumerate()
+                .all(|(i, frame)| frame.start_paddr() == base + i * PAGE_SIZE);
+        if !consecutive {
+            return ax_err!(NoMemory, "no consecutive frames for defragmentation");
+        }
+        Ok(frames)
+    }
+
+    /// Whether the page at `gpa` is left out of defragmentation, as it must
+    /// stay unmapped: released by the guest balloon, or lost to a host
+    /// memory error.
+    fn skips_defragment(&self, gpa: GuestPhysAddr) -> bool {
+        self.is_ballooned(gpa) || self.is_poisoned(gpa)
+    }
+
+    /// Whether all pages of `[start, end)` but the skipped ones are present
+    /// and mapped to consecutive host frames.
+    fn is_contiguous(&self, start: GuestPhysAddr, end: GuestPhysAddr) -> bool {
+        let mut base = None;
+        GuestPageIter::new(start, end).unwrap().all(|gpa| {
+            if self.skips_defragment(gpa) {
+                return true;
+            }
+            let Ok((paddr, _, _)) = self.state.pt.query(gpa) else {
+                return false;
+            };
+            let expected = paddr.as_usize().wrapping_sub(gpa - start);
+            *base.get_or_insert(expected) == expected
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEALLOC_COUNT, MockHal, mock_hal_test};
+    use crate::{MappingFlags, PageSize, PageSizePolicy};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_unmap_keep_frames() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        aspace.map_alloc(base + 0x2000, 0x2000, rw, false).unwrap();
+        aspace
+            .map_linear(base + 0x4000, PhysAddr::from(0x4000), 0x1000, rw)
+            .unwrap();
+        assert!(aspace.handle_page_fault(base + 0x3000, MappingFlags::WRITE));
+        aspace.translated_byte_buffer(base + 0x1000, 1).unwrap()[0][0] = 0x5a;
+        let expected = [
+            aspace.translate(base + 0x1000).unwrap(),
+            aspace.translate(base + 0x3000).unwrap(),
+        ];
+
+        let (frames, extents) = aspace.unmap_keep_frames(base + 0x1000, 0x4000).unwrap();
+        assert_eq!(
+            frames.iter().map(|f| f.start_paddr()).collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            extents.iter().map(|e| (e.gpa, e.owned)).collect::<Vec<_>>(),
+            [
+                (base + 0x1000, true),
+                (base + 0x3000, true),
+                (base + 0x4000, false)
+            ]
+        );
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), 0);
+        assert!(aspace.translate(base + 0x1000).is_none());
+        assert!(aspace.translate(base + 0x4000).is_none());
+        assert!(aspace.translate(base).is_some());
+        // The contents are preserved in the returned frame.
+        assert_eq!(unsafe { *frames[0].as_mut_ptr() }, 0x5a);
+
+        drop(frames);
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), 2);
+
+        // Huge frames cannot be handed over, the range is left mapped.
+        let huge = GuestPhysAddr::from(0x40_0000);
+        let size_2m = PageSize::Size2M as usize;
+        aspace
+            .extend_va_range(GuestPhysAddrRange::from_start_size(huge, size_2m))
+            .unwrap();
+        MockHal::set_huge_frames(size_2m / PAGE_SIZE);
+        aspace
+            .map_alloc_with_policy(huge, size_2m, rw, true, PageSizePolicy::Prefer2M)
+            .unwrap();
+        let frame = aspace.translate(huge);
+        assert_eq!(
+            aspace.unmap_keep_frames(huge, size_2m).err(),
+            Some(AxError::InvalidInput)
+        );
+        assert_eq!(aspace.translate(huge), frame);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_unmap_keep_frames_checks_first() {
+        let base = GuestPhysAddr::from(0);
+        let size_2m = PageSize::Size2M as usize;
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 4 * size_2m).unwrap();
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        // A copy-on-write area over a huge page it cannot unmap.
+        let cow = base + size_2m;
+        let template = PhysAddr::from(0x4000_0000);
+        aspace
+            .state
+            .pt
+            .map(cow, template, PageSize::Size2M, MappingFlags::READ)
+            .unwrap()
+            .ignore();
+        aspace.map_cow(cow, template, 2 * size_2m, rw).unwrap();
+        let frame = aspace.translate(base);
+
+        assert_eq!(
+            aspace.unmap_keep_frames(base, 2 * size_2m).err(),
+            Some(AxError::InvalidInput)
+        );
+        // Nothing was detached nor freed.
+        assert_eq!(aspace.translate(base), frame);
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), 0);
+        assert_eq!(aspace.layout.areas().len(), 2);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_migrate_and_defragment() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x4000, rw, false).unwrap();
+        assert!(aspace.handle_page_fault(base, MappingFlags::WRITE));
+        assert!(aspace.handle_page_fault(base + 0x2000, MappingFlags::WRITE));
+        aspace.translated_byte_buffer(base, 1).unwrap()[0][0] = 0x11;
+        aspace.translated_byte_buffer(base + 0x2000, 1).unwrap()[0][0] = 0x22;
+
+        // Migrating a single page keeps its contents and flags.
+        let old = aspace.translate(base).unwrap();
+        let new_frame = PhysFrame::<MockHal>::alloc().unwrap();
+        let new = new_frame.start_paddr();
+        let returned = aspace.migrate_page(base + 0x10, new_frame).unwrap();
+        assert_eq!(returned.start_paddr(), old);
+        assert_eq!(
+            aspace.page_table().query(base).unwrap(),
+            (new, rw, BASE_PAGE_SIZE)
+        );
+        assert_eq!(aspace.translated_byte_buffer(base, 1).unwrap()[0][0], 0x11);
+        drop(returned);
+        assert!(
+            aspace
+                .migrate_page(base + 0x1000, PhysFrame::alloc().unwrap())
+                .is_err()
+        );
+
+        assert_eq!(aspace.defragment(base, 0x4000).unwrap(), 4);
+        let first = aspace.translate(base).unwrap();
+        for i in 1..4 {
+            assert_eq!(
+                aspace.translate(base + i * 0x1000),
+                Some(first + i * 0x1000)
+            );
+        }
+        assert_eq!(aspace.translated_byte_buffer(base, 1).unwrap()[0][0], 0x11);
+        assert_eq!(
+            aspace.translated_byte_buffer(base + 0x2000, 1).unwrap()[0][0],
+            0x22
+        );
+        assert_eq!(
+            aspace.translated_byte_buffer(base + 0x1000, 1).unwrap()[0][0],
+            0
+        );
+        // Already contiguous, nothing to do.
+        assert_eq!(aspace.defragment(base, 0x4000).unwrap(), 0);
+
+        // Sealed address spaces keep their frames, the new one is freed.
+        aspace.seal(crate::SealMode::Temporary);
+        let freed = DEALLOC_COUNT.load(Ordering::SeqCst);
+        assert_eq!(
+            aspace
+                .migrate_page(base, PhysFrame::alloc().unwrap())
+                .map(|_| ()),
+            Err(AxError::BadState)
+        );
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), freed + 1);
+        assert_eq!(aspace.defragment(base, 0x4000), Err(AxError::BadState));
+        assert_eq!(aspace.translate(base), Some(first));
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_defragment_skips_released_pages() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x4000, rw, false).unwrap();
+        assert!(aspace.handle_page_fault(base + 0x1000, MappingFlags::WRITE));
+        aspace.guest_release_pages(&[base + 0x2000]).unwrap();
+
+        // Ballooned pages are not handed a frame behind the balloon's back.
+        assert_eq!(aspace.defragment(base, 0x4000).unwrap(), 3);
+        assert_eq!(aspace.translate(base + 0x2000), None);
+        assert!(aspace.is_ballooned(base + 0x2000));
+        assert_eq!(aspace.defragment(base, 0x4000).unwrap(), 0);
+    }
+}
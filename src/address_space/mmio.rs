@@ -0,0 +1,254 @@
+//! Guest physical ranges reserved for emulated MMIO.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use memory_set::MemoryArea;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend, MmioRoute, ReplayRecord};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, mapping_err_to_ax_err};
+
+/// An MMIO range registered with [`AddrSpace::register_mmio`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioRegion {
+    /// The guest physical range.
+    pub range: GuestPhysAddrRange,
+    /// The handler the faults in the range are dispatched to.
+    pub handler_id: usize,
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Reserves a guest physical range for emulated MMIO.
+    ///
+    /// Reserved ranges are never mapped: `map_*` calls overlapping them fail
+    /// with [`AxError::AlreadyExists`], and page faults inside them are not
+    /// handled, so they always reach the VMM's device emulation. This guards
+    /// against guest RAM accidentally covering windows such as the local
+    /// APIC page (see [`crate::irqchip`]).
+    ///
+    /// [`AxError::AlreadyExists`]: axerrno::AxError::AlreadyExists
+    pub fn reserve_mmio(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        self.check_unsealed()?;
+        if range.is_empty() || !self.layout.contains_range(range) {
+            return ax_err!(InvalidInput, "MMIO range out of range");
+        }
+        if !range.start.is_aligned(PAGE_SIZE) || !range.end.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "MMIO range not aligned");
+        }
+        if self.layout.areas().overlaps(range) {
+            return ax_err!(AlreadyExists, "MMIO range overlaps a mapped area");
+        }
+        self.check_reserved_overlap(range.start, range.size())?;
+        self.check_mmio_overlap(range.start, range.size())?;
+        self.layout.mmio_regions.insert(range.start, range);
+        Ok(())
+    }
+
+    /// Reserves a guest physical range for emulated MMIO like
+    /// [`AddrSpace::reserve_mmio`], dispatching its faults to `handler_id`.
+    ///
+    /// The faults in the range are reported as [`PageFaultResult::Mmio`]
+    /// with an [`MmioRoute`] giving the handler and the offset of the access
+    /// in the range, so that the VMM can call the device emulation without
+    /// looking the address up again.
+    ///
+    /// [`PageFaultResult::Mmio`]: super::PageFaultResult::Mmio
+    pub fn register_mmio(&mut self, range: GuestPhysAddrRange, handler_id: usize) -> AxResult {
+        self.reserve_mmio(range)?;
+        self.layout.mmio_handlers.insert(range.start, handler_id);
+        Ok(())
+    }
+
+    /// Returns the MMIO ranges registered with [`AddrSpace::register_mmio`],
+    /// in ascending order.
+    pub fn registered_mmio(&self) -> impl Iterator<Item = MmioRegion> + '_ {
+        self.layout
+            .mmio_handlers
+            .iter()
+            .map(|(start, &handler_id)| MmioRegion {
+                range: self.layout.mmio_regions[start],
+                handler_id,
+            })
+    }
+
+    /// Releases an MMIO range previously reserved with
+    /// [`AddrSpace::reserve_mmio`] or [`AddrSpace::register_mmio`].
+    pub fn release_mmio(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        self.check_unsealed()?;
+        match self.layout.mmio_regions.get(&range.start) {
+            Some(r) if *r == range => {
+                self.layout.mmio_regions.remove(&range.start);
+                self.layout.mmio_handlers.remove(&range.start);
+                Ok(())
+            }
+            _ => ax_err!(NotFound, "MMIO range not reserved"),
+        }
+    }
+
+    /// Returns whether the given guest physical address lies in a reserved
+    /// MMIO range.
+    pub fn is_mmio(&self, gpa: GuestPhysAddr) -> bool {
+        self.layout
+            .mmio_regions
+            .range(..=gpa)
+            .next_back()
+            .is_some_and(|(_, r)| r.contains(gpa))
+    }
+
+    /// Returns an iterator over the reserved MMIO ranges, in ascending order.
+    pub fn mmio_regions(&self) -> impl Iterator<Item = GuestPhysAddrRange> + '_ {
+        self.layout.mmio_regions.values().copied()
+    }
+
+    /// Maps a single 4K trap page at `gpa` to the host frame `hpa`.
+    ///
+    /// Unlike other mappings, a trap page may be placed inside a reserved MMIO
+    /// range, e.g., the VMX APIC-access page installed at the local APIC
+    /// window, or a per-vCPU page whose accesses the hardware intercepts. The
+    /// rest of the MMIO range stays reserved. The page is removed with
+    /// [`AddrSpace::unmap`].
+    pub fn map_trap_page(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: PhysAddr,
+        flags: MappingFlags,
+    ) -> AxResult {
+        self.check_unsealed()?;
+        if !self.contains_range(gpa, PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !gpa.is_aligned(PAGE_SIZE) || !hpa.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        self.check_reserved_overlap(gpa, PAGE_SIZE)?;
+        self.check_dma_windows_enabled(gpa, PAGE_SIZE)?;
+        let offset = gpa.as_usize().wrapping_sub(hpa.as_usize());
+        let area = MemoryArea::new(gpa, PAGE_SIZE, flags, Backend::new_linear(offset));
+        self.layout
+            .areas_mut()
+            .map(area, &mut self.state.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        self.record(ReplayRecord::MapTrapPage { gpa, hpa, flags });
+        Ok(())
+    }
+
+    /// Returns where to dispatch an MMIO access at `gpa`.
+    pub(crate) fn mmio_route(&self, gpa: GuestPhysAddr) -> Option<MmioRoute> {
+        let (start, range) = self.layout.mmio_regions.range(..=gpa).next_back()?;
+        let &handler_id = self.layout.mmio_handlers.get(start)?;
+        range.contains(gpa).then(|| MmioRoute {
+            handler_id,
+            offset: gpa - range.start,
+        })
+    }
+
+    pub(crate) fn check_mmio_overlap(&self, start: GuestPhysAddr, size: usize) -> AxResult {
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        let overlaps = self
+            .layout
+            .mmio_regions
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, r)| r.overlaps(range));
+        if overlaps {
+            return ax_err!(AlreadyExists, "range overlaps a reserved MMIO range");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::PageFaultResult;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{FaultContext, irqchip};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_lapic_window_reserved() {
+        let lapic = irqchip::x86_lapic_range();
+        let base = lapic.start - 0x4000;
+        let mut addr_space = AddrSpace::<MockHal>::new_empty(base, 0x8000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+
+        addr_space.reserve_mmio(lapic).unwrap();
+        assert!(addr_space.is_mmio(lapic.start + 0x10));
+        assert!(!addr_space.is_mmio(lapic.end));
+        assert_eq!(addr_space.reserve_mmio(lapic), Err(AxError::AlreadyExists));
+
+        // Guest RAM may not cover the APIC page.
+        assert_eq!(
+            addr_space.map_alloc(base, 0x8000, rw, false),
+            Err(AxError::AlreadyExists)
+        );
+        addr_space.map_alloc(base, 0x4000, rw, false).unwrap();
+        assert!(!addr_space.handle_page_fault(lapic.start, MappingFlags::READ));
+
+        // But the APIC-access page can be installed there.
+        let access_page = PhysAddr::from(0x1_0000_0000);
+        addr_space
+            .map_trap_page(lapic.start, access_page, rw)
+            .unwrap();
+        assert_eq!(addr_space.translate(lapic.start), Some(access_page));
+
+        addr_space.unmap(lapic.start, PAGE_SIZE).unwrap();
+        addr_space.release_mmio(lapic).unwrap();
+        assert!(!addr_space.is_mmio(lapic.start));
+        assert_eq!(addr_space.release_mmio(lapic), Err(AxError::NotFound));
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_register_mmio() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut addr_space = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let uart = GuestPhysAddrRange::from_start_size(base + 0x2000, 0x1000);
+        let rtc = GuestPhysAddrRange::from_start_size(base + 0x4000, 0x2000);
+        addr_space.register_mmio(uart, 1).unwrap();
+        addr_space.register_mmio(rtc, 2).unwrap();
+        addr_space
+            .reserve_mmio(GuestPhysAddrRange::from_start_size(base + 0x8000, 0x1000))
+            .unwrap();
+        assert_eq!(
+            addr_space.register_mmio(uart, 3),
+            Err(AxError::AlreadyExists)
+        );
+
+        let mut fault = |gpa, access| match addr_space.handle_page_fault_result(
+            gpa,
+            access,
+            &FaultContext::NONE,
+        ) {
+            PageFaultResult::Mmio(access) => access.route,
+            result => panic!("unexpected {result:?}"),
+        };
+        assert_eq!(
+            fault(base + 0x5010, MappingFlags::WRITE),
+            Some(MmioRoute {
+                handler_id: 2,
+                offset: 0x1010
+            })
+        );
+        assert_eq!(
+            fault(base + 0x2004, MappingFlags::READ),
+            Some(MmioRoute {
+                handler_id: 1,
+                offset: 4
+            })
+        );
+        // Plain reserved ranges are left to the VMM.
+        assert_eq!(fault(base + 0x8000, MappingFlags::READ), None);
+
+        addr_space.release_mmio(uart).unwrap();
+        assert_eq!(
+            addr_space.registered_mmio().collect::<alloc::vec::Vec<_>>(),
+            [MmioRegion {
+                range: rtc,
+                handler_id: 2
+            }]
+        );
+    }
+}
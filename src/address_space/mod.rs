@@ -2,69 +2,277 @@ use alloc::vec::Vec;
 use core::fmt;
 
 use axerrno::{AxError, AxResult, ax_err};
-use memory_addr::{MemoryAddr, PhysAddr, is_aligned_4k};
-use memory_set::{MemoryArea, MemorySet};
+use log::Level;
+use memory_addr::{MemoryAddr, PhysAddr, is_aligned};
+use memory_set::MemoryArea;
 use page_table_multiarch::PagingHandler;
 
-use crate::npt::NestedPageTable as PageTable;
-use crate::{GuestPhysAddr, GuestPhysAddrRange, mapping_err_to_ax_err};
+use crate::addr::{check_max_gpa, checked_range};
+use crate::npt::{self, NestedPageTable as PageTable};
+use crate::{
+    EPTTranslator, FaultContext, GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr, MappingFlags,
+    MemType, PAGE_SIZE, PageSize, mapping_err_to_ax_err, paging_err_to_ax_err,
+};
 
+mod advise;
+mod attributes;
 mod backend;
-
+mod balloon;
+mod batch;
+mod convert;
+mod cow;
+mod diff;
+mod dirty;
+mod dma_window;
+mod extents;
+mod fault;
+#[cfg(feature = "verify-frames")]
+mod frame_check;
+mod gpa_allocator;
+mod granularity;
+mod heatmap;
+mod host_only;
+mod layout;
+#[cfg(any(test, feature = "testing"))]
+mod leak_check;
+mod measure;
+mod memory_map;
+mod merge;
+mod migrate;
+mod mmio;
+#[cfg(feature = "frame-ownership")]
+mod ownership;
+mod paranoid;
+mod passthrough;
+mod poison;
+mod protect;
+mod range_map;
+mod replace;
+mod replay;
+mod reserved;
+mod rmap;
+mod shared;
+mod snapshot;
+mod state;
+mod stats;
+#[cfg(test)]
+mod stress;
+mod summary;
+mod teardown;
+mod threads;
+mod throttle;
+mod windows;
+mod zero;
+
+pub use advise::{AccessPattern, Advice, HugePagePolicy, RangeHints};
+pub use attributes::AreaAttributes;
+#[doc(hidden)]
 pub use backend::Backend;
-pub use page_table_entry::MappingFlags;
+pub use convert::BackendKind;
+pub use diff::{LayoutDiff, PageDiff};
+pub use dma_window::DmaWindow;
+pub use extents::HostExtent;
+pub use fault::{FaultDisposition, MmioAccess, MmioRoute, PageFaultResult};
+#[cfg(feature = "verify-frames")]
+pub use frame_check::{FrameVerifier, PoisonFilled, ZeroFilled, set_frame_verifier};
+pub use gpa_allocator::GpaAllocator;
+pub use granularity::{HugeAlignmentMismatch, MapGranularity, PageSizePolicy};
+pub use heatmap::{HEATMAP_CHUNK_SIZE, HeatmapEntry};
+#[cfg(any(test, feature = "testing"))]
+pub use leak_check::{FrameUse, LiveFrame};
+pub use measure::{MeasurementEntry, MeasurementHasher};
+pub use memory_map::{
+    E820_ENTRY_SIZE, EFI_DESCRIPTOR_SIZE, MemoryMapEntry, MemoryMapStyle, RegionKind,
+};
+pub use mmio::MmioRegion;
+#[cfg(all(test, feature = "frame-ownership"))]
+pub(crate) use ownership::reset_claims;
+#[cfg(feature = "frame-ownership")]
+pub use ownership::{FrameOwner, frame_owner};
+pub use passthrough::{ACPI_CRS_SIZE, DeviceIdentity, DeviceRegion, PciBdf};
+pub use protect::{ProtectError, ProtectPolicy};
+pub use replay::{RecordingAccessor, ReplayRecord, ReplaySink, replay};
+pub use rmap::ReverseMapping;
+pub use shared::SharedRegion;
+pub use snapshot::{AddrSpaceSnapshot, AreaSnapshot};
+pub use state::AreaDescription;
+pub use stats::AddrSpaceStats;
+pub use summary::AddrSpaceSummary;
+pub use teardown::TeardownReport;
+#[cfg(feature = "debug-threads")]
+pub use threads::{ContextProbe, set_context_probe};
+pub use throttle::{DirtyRateHook, DirtyRateLimit};
+
+use layout::{Layout, PageState};
+
+/// How a sealed [`AddrSpace`] can be brought back to the mutable state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SealMode {
+    /// The address space can be unsealed again with [`AddrSpace::unseal`].
+    Temporary,
+    /// The address space stays sealed until it is dropped.
+    Permanent,
+}
+
+/// The failure of [`AddrSpace::try_map_linear`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapLinearError {
+    /// The cause of the failure.
+    pub error: AxError,
+    /// The number of bytes before `failed_at`, i.e., the progress made before
+    /// the failure. Any pages mapped there were removed again.
+    pub mapped: usize,
+    /// The guest physical address that could not be mapped.
+    pub failed_at: GuestPhysAddr,
+    /// Why the host address is misaligned, if the mapping failed because the
+    /// minimum granularity is a huge page size the host address is not
+    /// aligned like the guest address to.
+    pub alignment: Option<HugeAlignmentMismatch>,
+}
 
 /// The virtual memory address space.
+///
+/// Operations on `[start, start + size)` fail with
+/// [`AxError::InvalidInput`] if `start + size` overflows. A zero `size` is
+/// rejected by the `map_*` methods, as an area cannot be empty, and makes
+/// the other range operations succeed without doing anything.
 pub struct AddrSpace<H: PagingHandler> {
-    va_range: GuestPhysAddrRange,
-    areas: MemorySet<Backend<H>>,
-    pt: PageTable<H>,
+    layout: Layout<H>,
+    state: PageState<H>,
 }
 
 impl<H: PagingHandler> AddrSpace<H> {
     /// Returns the address space base.
+    ///
+    /// This is the base of the initial window, windows added with
+    /// [`AddrSpace::extend_va_range`] are returned by
+    /// [`AddrSpace::va_ranges`].
     pub const fn base(&self) -> GuestPhysAddr {
-        self.va_range.start
+        self.layout.va_range.start
     }
 
-    /// Returns the address space end.
+    /// Returns the address space end, i.e., the end of the initial window.
     pub const fn end(&self) -> GuestPhysAddr {
-        self.va_range.end
+        self.layout.va_range.end
     }
 
-    /// Returns the address space size.
+    /// Returns the address space size, i.e., the size of the initial window.
     pub fn size(&self) -> usize {
-        self.va_range.size()
+        self.layout.va_range.size()
+    }
+
+    /// Returns the size of the smallest page of the address space, which all
+    /// mappings are aligned to. This is [`PAGE_SIZE`](crate::PAGE_SIZE).
+    pub const fn page_size(&self) -> usize {
+        PAGE_SIZE
     }
 
     /// Returns the reference to the inner page table.
     pub const fn page_table(&self) -> &PageTable<H> {
-        &self.pt
+        &self.state.pt
     }
 
     /// Returns the root physical address of the inner page table.
     pub const fn page_table_root(&self) -> PhysAddr {
-        self.pt.root_paddr()
+        self.state.pt.root_paddr()
+    }
+
+    /// Returns words 2 and 3 of an SMMUv3 stream table entry giving a device
+    /// passed through to the guest the translations of this address space,
+    /// tagged with `vmid`. See [`npt::smmu_ste_s2`].
+    #[cfg(target_arch = "aarch64")]
+    pub fn smmu_ste_s2(&self, vmid: u16) -> [u64; 2] {
+        npt::smmu_ste_s2(self.page_table_root(), vmid, npt::gpa_bits()).unwrap()
+    }
+
+    /// Returns the number of frames used by the nested page table, including
+    /// the root and all intermediate tables.
+    pub fn page_table_frames(&self) -> usize {
+        npt::tables::count_frames::<H>(self.state.pt.root_paddr())
+    }
+
+    /// Frees the intermediate tables of the nested page table that no longer
+    /// map anything, e.g., after large unmaps, and returns how many frames
+    /// were reclaimed.
+    ///
+    /// Tables covering a mapped area are kept, since lazily allocated areas
+    /// rely on them to be faulted in later.
+    pub fn shrink_page_tables(&mut self) -> usize {
+        let areas = &self.layout.areas();
+        let in_use = |start: usize, size: usize| {
+            let range = GuestPhysAddrRange::from_start_size(start.into(), size);
+            areas.overlaps(range)
+        };
+        let freed = npt::tables::shrink::<H>(self.state.pt.root_paddr(), &in_use);
+        if freed > 0 {
+            npt::flush_tlb(None);
+        }
+        freed
     }
 
     /// Checks if the address space contains the given address range.
+    ///
+    /// Ranges whose end overflows are never contained.
     pub fn contains_range(&self, start: GuestPhysAddr, size: usize) -> bool {
-        self.va_range
-            .contains_range(GuestPhysAddrRange::from_start_size(start, size))
+        checked_range(start, size).is_ok_and(|range| self.layout.contains_range(range))
     }
 
     /// Creates a new empty address space.
+    ///
+    /// Fails with [`AxError::InvalidInput`] if `base + size` overflows, or
+    /// exceeds [`npt::max_supported_gpa`].
     pub fn new_empty(base: GuestPhysAddr, size: usize) -> AxResult<Self> {
+        let range = checked_range(base, size)?;
+        check_max_gpa(range)?;
         Ok(Self {
-            va_range: GuestPhysAddrRange::from_start_size(base, size),
-            areas: MemorySet::new(),
-            pt: PageTable::try_new().map_err(|_| AxError::NoMemory)?,
+            layout: Layout::new(range),
+            state: PageState::new()?,
         })
     }
 
+    /// Seals the address space, freezing its layout.
+    ///
+    /// While sealed, every operation that changes the mappings (`map_*`,
+    /// `unmap`, `clear`) fails with [`AxError::BadState`]. Page faults on
+    /// lazily allocated areas are still handled, as they do not change the
+    /// layout seen by the guest.
+    ///
+    /// Sealing an already sealed address space only upgrades the mode, a
+    /// [`SealMode::Permanent`] seal is never downgraded.
+    pub fn seal(&mut self, mode: SealMode) {
+        if self.layout.sealed != Some(SealMode::Permanent) {
+            self.layout.sealed = Some(mode);
+        }
+    }
+
+    /// Unseals the address space sealed with [`SealMode::Temporary`].
+    ///
+    /// Returns [`AxError::BadState`] if the address space is sealed
+    /// permanently.
+    pub fn unseal(&mut self) -> AxResult {
+        if self.layout.sealed == Some(SealMode::Permanent) {
+            return ax_err!(BadState, "address space is permanently sealed");
+        }
+        self.layout.sealed = None;
+        Ok(())
+    }
+
+    /// Returns whether the address space is sealed.
+    pub const fn is_sealed(&self) -> bool {
+        self.layout.sealed.is_some()
+    }
+
+    fn check_unsealed(&self) -> AxResult {
+        if self.is_sealed() {
+            return ax_err!(BadState, "address space is sealed");
+        }
+        Ok(())
+    }
+
     /// Add a new linear mapping.
     ///
-    /// See [`Backend`] for more details about the mapping backends.
+    /// The target physical frames are contiguous and owned by the caller, the
+    /// guest address `start_vaddr + off` is mapped to `start_paddr + off`.
     ///
     /// The `flags` parameter indicates the mapping permissions and attributes.
     pub fn map_linear(
@@ -74,24 +282,164 @@ impl<H: PagingHandler> AddrSpace<H> {
         size: usize,
         flags: MappingFlags,
     ) -> AxResult {
-        if !self.contains_range(start_vaddr, size) {
-            return ax_err!(InvalidInput, "address out of range");
+        self.map_linear_with_granularity(
+            start_vaddr,
+            start_paddr,
+            size,
+            flags,
+            MapGranularity::DEFAULT,
+        )
+    }
+
+    /// Add a new linear mapping with the given [`MapGranularity`].
+    ///
+    /// The addresses and the size must be aligned to the minimum granularity.
+    /// The region is mapped with the largest page sizes the granularity and
+    /// the alignment allow.
+    pub fn map_linear_with_granularity(
+        &mut self,
+        start_vaddr: GuestPhysAddr,
+        start_paddr: PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        granularity: MapGranularity,
+    ) -> AxResult {
+        self.try_map_linear(start_vaddr, start_paddr, size, flags, granularity)
+            .map_err(|err| err.error)
+    }
+
+    /// Like [`AddrSpace::map_linear_with_granularity`], but reports where the
+    /// mapping failed.
+    ///
+    /// On failure, the pages mapped before the failure point are removed
+    /// again, so the address space is left as it was.
+    pub fn try_map_linear(
+        &mut self,
+        start_vaddr: GuestPhysAddr,
+        start_paddr: PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        granularity: MapGranularity,
+    ) -> Result<(), MapLinearError> {
+        let fail = |error, failed_at: GuestPhysAddr| MapLinearError {
+            error,
+            mapped: failed_at - start_vaddr,
+            failed_at,
+            alignment: None,
+        };
+        self.check_context("try_map_linear");
+        let check = || -> AxResult {
+            self.check_unsealed()?;
+            if size == 0 {
+                return ax_err!(InvalidInput, "empty mapping");
+            }
+            if !self.contains_range(start_vaddr, size) {
+                return ax_err!(InvalidInput, "address out of range");
+            }
+            if !granularity.is_aligned(start_vaddr.as_usize()) || !granularity.is_aligned(size) {
+                return ax_err!(InvalidInput, "address not aligned");
+            }
+            self.check_reserved_overlap(start_vaddr, size)?;
+            self.check_dma_windows_enabled(start_vaddr, size)?;
+            self.check_mmio_overlap(start_vaddr, size)
+        };
+        check().map_err(|err| fail(err, start_vaddr))?;
+        let mismatch = granularity.alignment_mismatch(start_vaddr, start_paddr, size);
+        if !granularity.is_aligned(start_paddr.as_usize()) {
+            warn!("try_map_linear: {start_paddr:?} not aligned to {granularity:?}: {mismatch:?}");
+            return Err(MapLinearError {
+                alignment: mismatch,
+                ..fail(AxError::InvalidInput, start_vaddr)
+            });
         }
-        if !start_vaddr.is_aligned_4k() || !start_paddr.is_aligned_4k() || !is_aligned_4k(size) {
-            return ax_err!(InvalidInput, "address not aligned");
+        let range = GuestPhysAddrRange::from_start_size(start_vaddr, size);
+        if let Some(area) = self
+            .layout
+            .areas()
+            .iter()
+            .find(|a| a.start() < range.end && a.end() > range.start)
+        {
+            warn!("try_map_linear: overlaps the area at {:?}", area.start());
+            return Err(fail(AxError::AlreadyExists, area.start().max(start_vaddr)));
         }
 
-        let offset = start_vaddr.as_usize() - start_paddr.as_usize();
-        let area = MemoryArea::new(start_vaddr, size, flags, Backend::new_linear(offset));
-        self.areas
-            .map(area, &mut self.pt, false)
-            .map_err(mapping_err_to_ax_err)?;
+        #[cfg(feature = "frame-ownership")]
+        let claim = {
+            let claim = memory_addr::PhysAddrRange::from_start_size(start_paddr, size);
+            let owner = ownership::FrameOwner {
+                space: self.state.space_id,
+                gpa: start_vaddr,
+            };
+            ownership::claim(claim, owner).map_err(|err| fail(err, start_vaddr))?;
+            claim
+        };
+        let offset = start_vaddr.as_usize().wrapping_sub(start_paddr.as_usize());
+        let backend = Backend::new_linear(offset).with_granularity(granularity);
+        // Map the pages first to find the failure point. The area then takes
+        // them over, as it does for an adopted page table.
+        if !backend.is_host_only(flags)
+            && self.state.pt.query(start_vaddr).is_err()
+            && let Err((failed_at, err)) =
+                backend.map_linear_pages(start_vaddr, size, flags, &mut self.state.pt, offset)
+        {
+            Backend::rollback_linear(start_vaddr, failed_at, &mut self.state.pt);
+            #[cfg(feature = "frame-ownership")]
+            ownership::release(self.state.space_id, claim);
+            return Err(fail(paging_err_to_ax_err(err), failed_at));
+        }
+        let area = MemoryArea::new(start_vaddr, size, flags, backend);
+        if let Err(err) = self.layout.areas_mut().map(area, &mut self.state.pt, false) {
+            #[cfg(feature = "frame-ownership")]
+            ownership::release(self.state.space_id, claim);
+            return Err(fail(mapping_err_to_ax_err(err), start_vaddr));
+        }
+        if let Some(mismatch) = mismatch {
+            warn!("try_map_linear: {start_vaddr:?} mapped with smaller pages: {mismatch:?}");
+            self.state.events.count_huge_fallback();
+        }
+        self.mark_dirty(start_vaddr, size);
+        self.record(ReplayRecord::MapLinear {
+            start: start_vaddr,
+            paddr: start_paddr,
+            size,
+            flags,
+            granularity,
+        });
         Ok(())
     }
 
+    /// Add an identity (GPA == HPA) mapping for a passthrough device window.
+    ///
+    /// The window is mapped readable and writable with the given memory type,
+    /// which must not be [`MemType::Normal`]. No frame is allocated. Returns
+    /// [`AxError::AlreadyExists`] if the window overlaps any existing area,
+    /// e.g., guest RAM.
+    pub fn map_identity_device(
+        &mut self,
+        range: GuestPhysAddrRange,
+        mem_type: MemType,
+    ) -> AxResult {
+        if mem_type == MemType::Normal {
+            return ax_err!(InvalidInput, "device window must not be normal memory");
+        }
+        if self.layout.areas().overlaps(range) {
+            return ax_err!(AlreadyExists, "device window overlaps an existing area");
+        }
+        let flags = mem_type.apply(MappingFlags::READ | MappingFlags::WRITE);
+        self.map_linear(
+            range.start,
+            PhysAddr::from_usize(range.start.as_usize()),
+            range.size(),
+            flags,
+        )
+    }
+
     /// Add a new allocation mapping.
     ///
-    /// See [`Backend`] for more details about the mapping backends.
+    /// The target physical frames are allocated from `H` and owned by the
+    /// address space. If `populate` is `true`, they are all allocated now,
+    /// otherwise they are allocated on demand by
+    /// [`AddrSpace::handle_page_fault`].
     ///
     /// The `flags` parameter indicates the mapping permissions and attributes.
     pub fn map_alloc(
@@ -101,41 +449,157 @@ impl<H: PagingHandler> AddrSpace<H> {
         flags: MappingFlags,
         populate: bool,
     ) -> AxResult {
+        self.map_alloc_with_granularity(start, size, flags, populate, MapGranularity::DEFAULT)
+    }
+
+    /// Add a new allocation mapping with the given [`MapGranularity`].
+    ///
+    /// The address and the size must be aligned to the minimum granularity.
+    pub fn map_alloc_with_granularity(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        populate: bool,
+        granularity: MapGranularity,
+    ) -> AxResult {
+        let backend = Backend::new_alloc(populate).with_granularity(granularity);
+        self.map_alloc_backend(start, size, flags, backend)
+    }
+
+    /// Adds the allocation area `[start, start + size)` with `backend`.
+    pub(crate) fn map_alloc_backend(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        backend: Backend<H>,
+    ) -> AxResult {
+        self.check_context("map_alloc");
+        let granularity = backend.granularity();
+        let populate = matches!(backend, Backend::Alloc { populate: true, .. });
+        self.check_map_alloc(start, size, granularity)?;
+
+        let area = MemoryArea::new(start, size, flags, backend);
+        self.layout
+            .areas_mut()
+            .map(area, &mut self.state.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        self.mark_dirty(start, size);
+        self.rmap_update(start, size);
+        self.record(ReplayRecord::MapAlloc {
+            start,
+            size,
+            flags,
+            populate,
+            granularity,
+        });
+        Ok(())
+    }
+
+    /// Checks that an allocation area can be mapped at `[start, start + size)`
+    /// with `granularity`, except for overlaps with other areas.
+    pub(crate) fn check_map_alloc(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        granularity: MapGranularity,
+    ) -> AxResult {
+        self.check_unsealed()?;
+        if size == 0 {
+            return ax_err!(InvalidInput, "empty mapping");
+        }
         if !self.contains_range(start, size) {
             return ax_err!(
                 InvalidInput,
-                alloc::format!("address [{:?}~{:?}] out of range", start, start + size).as_str()
+                alloc::format!("address [{start:?}, +{size:#x}) out of range").as_str()
             );
         }
-        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+        if !granularity.is_aligned(start.as_usize()) || !granularity.is_aligned(size) {
             return ax_err!(InvalidInput, "address not aligned");
         }
-
-        let area = MemoryArea::new(start, size, flags, Backend::new_alloc(populate));
-        self.areas
-            .map(area, &mut self.pt, false)
-            .map_err(mapping_err_to_ax_err)?;
-        Ok(())
+        self.check_reserved_overlap(start, size)?;
+        self.check_dma_windows_enabled(start, size)?;
+        self.check_mmio_overlap(start, size)
     }
 
     /// Removes mappings within the specified virtual address range.
-    pub fn unmap(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+    ///
+    /// Huge pages of linear areas straddling the range boundaries are split
+    /// into smaller pages first, so punching a hole into a huge mapping keeps
+    /// the surrounding memory mapped.
+    ///
+    /// Returns the host extents that were mapped in the range, so that
+    /// tables mirroring the mappings, e.g., of an IOMMU, can be invalidated
+    /// exactly. Pages of allocation areas that were never faulted in have no
+    /// extent.
+    pub fn unmap(&mut self, start: GuestPhysAddr, size: usize) -> AxResult<Vec<HostExtent>> {
+        self.check_context("unmap");
+        self.check_unsealed()?;
         if !self.contains_range(start, size) {
             return ax_err!(InvalidInput, "address out of range");
         }
-        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+        if !start.is_aligned(PAGE_SIZE) || !is_aligned(size, PAGE_SIZE) {
             return ax_err!(InvalidInput, "address not aligned");
         }
-
-        self.areas
-            .unmap(start, size, &mut self.pt)
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        self.demote_split_points(start, size)?;
+        self.check_split_points(start, size)?;
+
+        let extents = self.host_extents(start, size);
+        #[cfg(feature = "frame-ownership")]
+        let claims = self.linear_claims(start, size);
+        self.layout
+            .areas_mut()
+            .unmap(start, size, &mut self.state.pt)
             .map_err(mapping_err_to_ax_err)?;
-        Ok(())
+        #[cfg(feature = "frame-ownership")]
+        for claim in claims {
+            ownership::release(self.state.space_id, claim);
+        }
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        self.layout.attributes.set(range, AreaAttributes::empty());
+        self.drop_device_labels(range);
+        self.rmap_update(start, size);
+        self.clear_poison(start, size);
+        self.clear_ballooned(start, size);
+        self.record(ReplayRecord::Unmap { start, size });
+        Ok(extents)
     }
 
-    /// Removes all mappings in the address space.
-    pub fn clear(&mut self) {
-        self.areas.clear(&mut self.pt).unwrap();
+    /// Removes all mappings in the address space, but the areas with
+    /// [`AreaAttributes::PERSISTENT`].
+    ///
+    /// Areas are removed in ascending address order, then pages mapped
+    /// outside of any area, e.g., left by an operation that failed halfway,
+    /// are removed as well, so that clearing twice is the same as clearing
+    /// once.
+    ///
+    /// Fails with `BadState`, removing nothing, if the address space is
+    /// sealed. Areas failing to unmap and stray mappings are logged, see
+    /// [`AddrSpace::close`] to get them reported instead.
+    pub fn clear(&mut self) -> AxResult {
+        self.check_context("clear");
+        self.check_unsealed()?;
+        let report = self.unrecorded(|aspace| {
+            if aspace.layout.attributes.iter().next().is_some() {
+                aspace.clear_non_persistent()
+            } else {
+                aspace.teardown()
+            }
+        });
+        self.record(ReplayRecord::Clear);
+        if !report.is_clean() {
+            warn!(
+                "AddrSpace::clear() left {} failed areas, {} leaked frames, {} stray mappings",
+                report.failed_areas.len(),
+                report.leaked_frames,
+                report.stray_mappings.len()
+            );
+        }
+        Ok(())
     }
 
     /// Handles a page fault at the given address.
@@ -145,49 +609,199 @@ impl<H: PagingHandler> AddrSpace<H> {
     /// Returns `true` if the page fault is handled successfully (not a real
     /// fault).
     pub fn handle_page_fault(&mut self, vaddr: GuestPhysAddr, access_flags: MappingFlags) -> bool {
-        if !self.va_range.contains(vaddr) {
-            return false;
+        self.handle_page_fault_with_context(vaddr, access_flags, &FaultContext::NONE)
+    }
+
+    /// Handles a page fault at the given address, coming from `ctx`.
+    ///
+    /// The context is used to attribute dirtied pages (see
+    /// [`AddrSpace::set_dirty_rate_limit`]) and in log messages.
+    pub fn handle_page_fault_with_context(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        access_flags: MappingFlags,
+        ctx: &FaultContext,
+    ) -> bool {
+        self.handle_page_fault_result(vaddr, access_flags, ctx) == PageFaultResult::Handled
+    }
+
+    /// Like [`AddrSpace::handle_page_fault_with_context`], but tells why a
+    /// fault was not handled.
+    ///
+    /// This is the single decision path of page faults: poisoned pages come
+    /// first, then the [`FaultDisposition`] of the faulting address decides
+    /// whether the area backend resolves the fault.
+    pub fn handle_page_fault_result(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        access_flags: MappingFlags,
+        ctx: &FaultContext,
+    ) -> PageFaultResult {
+        self.check_context("handle_page_fault");
+        if self.is_poisoned(vaddr) {
+            warn!("{ctx}: access to poisoned page {vaddr:?} ({access_flags:?})");
+            return PageFaultResult::HwPoisoned;
         }
-        if let Some(area) = self.areas.find(vaddr) {
-            let orig_flags = area.flags();
-            if !orig_flags.contains(access_flags) {
-                return false;
+        if self.is_ballooned(vaddr) {
+            warn!("{ctx}: access to released page {vaddr:?} ({access_flags:?})");
+            return PageFaultResult::Guard;
+        }
+        if self.in_disabled_dma_window(vaddr) {
+            debug!("{ctx}: access to disabled DMA window at {vaddr:?} ({access_flags:?})");
+            return PageFaultResult::DmaDisabled;
+        }
+        let result = match self.fault_disposition(vaddr) {
+            FaultDisposition::Mmio => PageFaultResult::Mmio(MmioAccess {
+                gpa: vaddr,
+                is_write: access_flags.contains(MappingFlags::WRITE),
+                width: None,
+                route: self.mmio_route(vaddr),
+            }),
+            FaultDisposition::Guard => PageFaultResult::Guard,
+            FaultDisposition::Rom if access_flags.contains(MappingFlags::WRITE) => {
+                PageFaultResult::RomWrite
             }
-            area.backend()
-                .handle_page_fault(vaddr, orig_flags, &mut self.pt)
-        } else {
-            false
+            FaultDisposition::Resolve | FaultDisposition::Rom => {
+                if self.try_handle_page_fault(vaddr, access_flags, ctx) {
+                    return PageFaultResult::Handled;
+                }
+                PageFaultResult::Unhandled
+            }
+        };
+        debug!("{ctx}: nested page fault at {vaddr:?} ({access_flags:?}): {result:?}");
+        result
+    }
+
+    fn try_handle_page_fault(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        access_flags: MappingFlags,
+        ctx: &FaultContext,
+    ) -> bool {
+        if !self.layout.contains(vaddr) {
+            return false;
+        }
+        let Some(area) = self.layout.find_area(vaddr) else {
+            return false;
+        };
+        let orig_flags = area.flags();
+        if !orig_flags.contains(access_flags) {
+            return false;
+        }
+        if access_flags.contains(MappingFlags::WRITE) && self.handle_dirty_fault(ctx.vcpu_id, vaddr)
+        {
+            self.state.events.count_fault();
+            self.count_heat_fault(vaddr);
+            return true;
         }
+        let (layout, state) = self.split_mut();
+        let Some(area) = layout.find_area(vaddr) else {
+            return false;
+        };
+        let backend = area.backend();
+        if !backend.handle_page_fault(vaddr, orig_flags, &mut state.pt, ctx) {
+            return false;
+        }
+        state.events.count_fault();
+        let block = backend.granularity().min() as usize;
+        self.count_heat_fault(vaddr);
+        self.drop_poisoned(vaddr.align_down(block), block);
+        self.mark_dirty(vaddr.align_down(block), block);
+        self.rmap_update(vaddr.align_down(block), block);
+        self.fault_around(vaddr);
+        true
+    }
+
+    /// Flushes the TLB entries of the nested page table for `range`, or all
+    /// entries if `range` is `None`.
+    ///
+    /// The `AddrSpace` methods flush the TLB themselves. This is for entries
+    /// changed through lower-level APIs, e.g., [`AddrSpace::page_table`]
+    /// users or an adopted page table. Small ranges are flushed page by page
+    /// with the finest invalidation of the architecture, so that the guest
+    /// keeps the rest of its TLB entries.
+    pub fn flush_tlb(&self, range: Option<GuestPhysAddrRange>) {
+        npt::flush_tlb_range(range)
+    }
+
+    /// Makes earlier host writes to the write-combining `range`, e.g., a
+    /// framebuffer shared with the guest, visible to the guest and to
+    /// devices, with [`barrier::wc_flush`](crate::barrier::wc_flush).
+    ///
+    /// Fails with `InvalidInput` if any part of `range` is not mapped with
+    /// [`MemType::WriteCombining`].
+    pub fn flush_wc(&self, range: GuestPhysAddrRange) -> AxResult {
+        if range.is_empty()
+            || !self.contains_range(range.start, range.size())
+            || !self.holes(range.start, range.size()).is_empty()
+            || self
+                .layout
+                .areas_overlapping(range)
+                .any(|area| MemType::from_flags(area.flags()) != MemType::WriteCombining)
+        {
+            return ax_err!(InvalidInput, "not a write-combining range");
+        }
+        crate::barrier::wc_flush();
+        Ok(())
     }
 
     /// Translates the given `VirtAddr` into `PhysAddr`.
     ///
     /// Returns `None` if the virtual address is out of range or not mapped.
     pub fn translate(&self, vaddr: GuestPhysAddr) -> Option<PhysAddr> {
-        if !self.va_range.contains(vaddr) {
-            return None;
+        self.layout.translate(&self.state.pt, vaddr)
+    }
+
+    /// Like [`AddrSpace::translate`], but only queries the nested page table,
+    /// for hot paths such as virtqueue processing.
+    ///
+    /// Nothing is logged and `vaddr` is not checked against the windows of
+    /// the address space, which never differs from `translate` as no page is
+    /// mapped outside of them.
+    #[inline]
+    pub fn translate_fast(&self, vaddr: GuestPhysAddr) -> Option<PhysAddr> {
+        self.state.pt.query(vaddr).ok().map(|(paddr, _, _)| paddr)
+    }
+
+    /// Returns whether `gpa` is mapped with all of the `access` flags, for
+    /// [`GuestMemoryAccessor::allows_access`](crate::GuestMemoryAccessor::allows_access).
+    ///
+    /// Pages of lazily allocated areas that are not faulted in yet are
+    /// allowed the flags of their area.
+    pub fn allows_access(&self, gpa: GuestPhysAddr, access: MappingFlags) -> bool {
+        match self.state.pt.query(gpa) {
+            Ok((_, flags, _)) => flags.contains(access),
+            Err(_) => self
+                .layout
+                .find_area(gpa)
+                .is_some_and(|area| area.flags().contains(access)),
         }
-        self.pt
-            .query(vaddr)
-            .map(|(phys_addr, _, _)| {
-                debug!("vaddr {vaddr:?} translate to {phys_addr:?}");
-                phys_addr
-            })
-            .ok()
+    }
+
+    /// Returns whether `gpa` is in a page of a lazily allocated area that has
+    /// not been faulted in yet.
+    ///
+    /// Such a page is not mapped, so [`AddrSpace::translate`] returns `None`
+    /// as for unmapped pages, but its first access is expected to fault.
+    pub fn is_lazy_placeholder(&self, gpa: GuestPhysAddr) -> bool {
+        self.layout.contains(gpa) && npt::is_lazy_placeholder(&self.state.pt, gpa)
     }
 
     /// Translate&Copy the given `VirtAddr` with LENGTH len to a mutable u8 Vec through page table.
     ///
     /// Returns `None` if the virtual address is out of range or not mapped.
+    /// With the `paranoid` feature, also returns `None` if a page is mapped
+    /// to host memory not given to the address space, see
+    /// [`AddrSpace::allow_host_range`].
     pub fn translated_byte_buffer(
         &self,
         vaddr: GuestPhysAddr,
         len: usize,
     ) -> Option<Vec<&'static mut [u8]>> {
-        if !self.va_range.contains(vaddr) {
+        if !self.layout.contains(vaddr) {
             return None;
         }
-        if let Some(area) = self.areas.find(vaddr) {
+        if let Some(area) = self.layout.find_area(vaddr) {
             if len > area.size() {
                 warn!(
                     "AddrSpace translated_byte_buffer len {:#x} exceeds area length {:#x}",
@@ -200,18 +814,25 @@ impl<H: PagingHandler> AddrSpace<H> {
             let mut start = vaddr;
             let end = start + len;
 
-            debug!(
-                "start {:?} end {:?} area size {:#x}",
-                start,
-                end,
-                area.size()
-            );
+            if log_enabled!(Level::Debug) {
+                debug!(
+                    "start {:?} end {:?} area size {:#x}",
+                    start,
+                    end,
+                    area.size()
+                );
+            }
 
             let mut v = Vec::new();
             while start < end {
-                let (start_paddr, _, page_size) = self.page_table().query(start).unwrap();
+                let (start_paddr, _, page_size) = self.page_table().query(start).ok()?;
                 let mut end_va = start.align_down(page_size) + page_size.into();
                 end_va = end_va.min(end);
+                #[cfg(feature = "paranoid")]
+                if !self.host_range_allowed(area.backend(), start, start_paddr, end_va - start) {
+                    warn!("translated_byte_buffer: {start:?} maps to foreign {start_paddr:?}");
+                    return None;
+                }
 
                 v.push(unsafe {
                     core::slice::from_raw_parts_mut(
@@ -227,38 +848,93 @@ impl<H: PagingHandler> AddrSpace<H> {
         }
     }
 
+    /// Walks `[start, start + size)` and calls `f` on every piece of it that is
+    /// contiguous in host physical memory.
+    ///
+    /// `f` receives the guest address and the length of the piece, together
+    /// with the host physical address it is mapped to, or `None` if the piece
+    /// is not mapped (e.g., not yet faulted in). Unmapped pieces never cross a
+    /// page boundary.
+    pub(crate) fn for_each_host_segment(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        mut f: impl FnMut(GuestPhysAddr, Option<PhysAddr>, usize) -> AxResult,
+    ) -> AxResult {
+        let end = start.as_usize() + size;
+        let mut addr = start;
+        while addr.as_usize() < end {
+            let (paddr, page_end) = match self.state.pt.query(addr) {
+                Ok((paddr, _, page_size)) => (
+                    Some(paddr),
+                    addr.align_down(page_size).as_usize() + page_size as usize,
+                ),
+                Err(_) => (None, addr.align_down(PAGE_SIZE).as_usize() + PAGE_SIZE),
+            };
+            let len = page_end.min(end) - addr.as_usize();
+            f(addr, paddr, len)?;
+            addr += len;
+        }
+        Ok(())
+    }
+
     /// Translates the given `VirtAddr` into `PhysAddr`,
     /// and returns the size of the `MemoryArea` corresponding to the target vaddr.
     ///
     /// Returns `None` if the virtual address is out of range or not mapped.
     pub fn translate_and_get_limit(&self, vaddr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
-        if !self.va_range.contains(vaddr) {
+        self.layout.translate_and_get_limit(&self.state.pt, vaddr)
+    }
+
+    /// Returns the page mapping `gpa`: the host address of its start, its
+    /// size, and its guest base address.
+    ///
+    /// With 2M and 1G mappings, this gives the exact host range that a
+    /// per-page operation (e.g., dirty tracking or a cache flush) affects.
+    /// Returns `None` if `gpa` is out of range or not mapped.
+    pub fn host_extent_of(
+        &self,
+        gpa: GuestPhysAddr,
+    ) -> Option<(HostPhysAddr, PageSize, GuestPhysAddr)> {
+        if !self.layout.contains(gpa) {
             return None;
         }
-        if let Some(area) = self.areas.find(vaddr) {
-            self.pt
-                .query(vaddr)
-                .map(|(phys_addr, _, _)| (phys_addr, area.size()))
-                .ok()
-        } else {
-            None
-        }
+        let (paddr, _, page_size) = self.state.pt.query(gpa).ok()?;
+        let base = gpa.align_down(page_size);
+        Some((paddr - (gpa - base), page_size, base))
+    }
+}
+
+impl<H: PagingHandler> EPTTranslator for AddrSpace<H> {
+    fn guest_phys_to_host_phys(&self, gpa: GuestPhysAddr) -> Option<HostPhysAddr> {
+        self.translate(gpa)
     }
 }
 
 impl<H: PagingHandler> fmt::Debug for AddrSpace<H> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("AddrSpace")
-            .field("va_range", &self.va_range)
-            .field("page_table_root", &self.pt.root_paddr())
-            .field("areas", &self.areas)
+            .field("va_range", &self.layout.va_range)
+            .field("extra_ranges", &self.layout.extra_ranges)
+            .field("page_table_root", &self.state.pt.root_paddr())
+            .field("sealed", &self.layout.sealed)
+            .field("areas", &self.layout.areas())
+            .field("mmio_regions", &self.layout.mmio_regions.values())
             .finish()
     }
 }
 
 impl<H: PagingHandler> Drop for AddrSpace<H> {
     fn drop(&mut self) {
-        self.clear();
+        let report = self.teardown();
+        if !report.is_clean() {
+            warn!(
+                "AddrSpace dropped with {} failed areas, {} leaked frames, {} stray mappings",
+                report.failed_areas.len(),
+                report.leaked_frames,
+                report.stray_mappings.len()
+            );
+        }
     }
 }
 
@@ -447,6 +1123,52 @@ mod tests {
         assert!(after_unmap_deallocs > before_unmap_deallocs);
     }
 
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_range_extremes() {
+        let (mut addr_space, base, size) = setup_test_addr_space();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+
+        // Zero-sized ranges.
+        assert_eq!(
+            addr_space.map_alloc(base, 0, rw, true),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            addr_space.map_linear(base, PhysAddr::from(0x1000), 0, rw),
+            Err(AxError::InvalidInput)
+        );
+        addr_space.map_alloc(base, 0x1000, rw, true).unwrap();
+        addr_space.unmap(base, 0).unwrap();
+        assert!(addr_space.translate(base).is_some());
+
+        // Mappings ending exactly at the end of the address space.
+        let last = base + size - 0x1000;
+        addr_space.map_alloc(last, 0x1000, rw, true).unwrap();
+        assert!(addr_space.translate(last + 0xfff).is_some());
+        addr_space.unmap(last, 0x1000).unwrap();
+
+        // Ranges whose end overflows.
+        assert!(!addr_space.contains_range(base, usize::MAX));
+        assert_eq!(
+            addr_space.map_alloc(base, usize::MAX & !0xfff, rw, false),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            addr_space.unmap(base, usize::MAX & !0xfff),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(addr_space.holes(last, usize::MAX).len(), 1);
+
+        // Address spaces up to the highest translatable page.
+        let top = npt::max_supported_gpa() + 1;
+        let high = AddrSpace::<MockHal>::new_empty(top - 0x2000, 0x2000).unwrap();
+        assert_eq!(high.end(), top);
+        assert!(high.contains_range(top - 0x1000, 0x1000));
+        assert!(!high.contains_range(top - 0x1000, usize::MAX - 0x1000));
+        assert!(AddrSpace::<MockHal>::new_empty(top, 0x1000).is_err());
+    }
+
     #[test]
     #[axin(decorator(mock_hal_test))]
     fn test_clear() {
@@ -471,7 +1193,7 @@ mod tests {
         let before_clear_deallocs = DEALLOC_COUNT.load(Ordering::SeqCst);
 
         // Clear all mappings
-        addr_space.clear();
+        addr_space.clear().unwrap();
 
         // Verify all mappings are removed
         assert!(addr_space.translate(vaddr1).is_none());
@@ -499,14 +1221,22 @@ mod tests {
         let paddr = addr_space.translate(vaddr).expect("Translation failed");
         assert!(paddr.as_usize() >= BASE_PADDR);
         assert!(paddr.as_usize() < BASE_PADDR + MEMORY_LEN);
+        assert_eq!(
+            addr_space.translate_fast(vaddr + 0x123),
+            Some(paddr + 0x123)
+        );
+        let translator: &dyn EPTTranslator = &addr_space;
+        assert_eq!(translator.guest_phys_to_host_phys(vaddr), Some(paddr));
 
         // Verify unmapped address translation fails
         let unmapped_vaddr = GuestPhysAddr::from_usize(0x19000);
         assert!(addr_space.translate(unmapped_vaddr).is_none());
+        assert!(addr_space.translate_fast(unmapped_vaddr).is_none());
 
         // Verify out-of-range address translation fails
         let out_of_range = GuestPhysAddr::from_usize(0x30000);
         assert!(addr_space.translate(out_of_range).is_none());
+        assert!(addr_space.translate_fast(out_of_range).is_none());
     }
 
     #[test]
@@ -585,4 +1315,273 @@ mod tests {
         let out_of_range = GuestPhysAddr::from_usize(0x30000);
         assert!(addr_space.translate_and_get_limit(out_of_range).is_none());
     }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_seal_unseal() {
+        let (mut addr_space, base, _size) = setup_test_addr_space();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+        addr_space.map_alloc(base, 0x1000, flags, true).unwrap();
+        addr_space.seal(SealMode::Temporary);
+        assert!(addr_space.is_sealed());
+
+        // Every layout mutation is rejected while sealed.
+        assert_eq!(
+            addr_space.map_alloc(base + 0x1000, 0x1000, flags, true),
+            Err(AxError::BadState)
+        );
+        assert_eq!(
+            addr_space.map_linear(base + 0x2000, PhysAddr::from(0x2000), 0x1000, flags),
+            Err(AxError::BadState)
+        );
+        assert_eq!(addr_space.unmap(base, 0x1000), Err(AxError::BadState));
+        assert_eq!(addr_space.clear(), Err(AxError::BadState));
+        assert!(addr_space.translate(base).is_some());
+
+        addr_space.unseal().unwrap();
+        assert!(!addr_space.is_sealed());
+        addr_space.unmap(base, 0x1000).unwrap();
+        assert!(addr_space.translate(base).is_none());
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_seal_permanent() {
+        let (mut addr_space, base, _size) = setup_test_addr_space();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+        addr_space.map_alloc(base, 0x1000, flags, false).unwrap();
+        addr_space.seal(SealMode::Permanent);
+        // A temporary seal never downgrades a permanent one.
+        addr_space.seal(SealMode::Temporary);
+        assert_eq!(addr_space.unseal(), Err(AxError::BadState));
+        assert!(addr_space.is_sealed());
+
+        // Lazy faults are still served for a sealed address space.
+        assert!(addr_space.handle_page_fault(base, MappingFlags::READ));
+        assert!(addr_space.translate(base).is_some());
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_map_identity_device() {
+        let (mut addr_space, base, _size) = setup_test_addr_space();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        addr_space.map_alloc(base, 0x2000, flags, false).unwrap();
+
+        let uart = GuestPhysAddrRange::from_start_size(base + 0x8000, 0x1000);
+        addr_space
+            .map_identity_device(uart, MemType::Device)
+            .unwrap();
+        let (paddr, mapped_flags, _) = addr_space.page_table().query(uart.start).unwrap();
+        assert_eq!(paddr.as_usize(), uart.start.as_usize());
+        assert_eq!(MemType::from_flags(mapped_flags), MemType::Device);
+
+        // Overlapping guest RAM is rejected.
+        let overlapping = GuestPhysAddrRange::from_start_size(base + 0x1000, 0x2000);
+        assert_eq!(
+            addr_space.map_identity_device(overlapping, MemType::Device),
+            Err(AxError::AlreadyExists)
+        );
+        let ram = GuestPhysAddrRange::from_start_size(base + 0x4000, 0x1000);
+        assert_eq!(
+            addr_space.map_identity_device(ram, MemType::Normal),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_shrink_page_tables() {
+        const GB: usize = 0x4000_0000;
+        let mut addr_space = AddrSpace::<MockHal>::new_empty(GB.into(), 4 * GB).unwrap();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        assert_eq!(addr_space.page_table_frames(), 1);
+
+        let vaddr1 = GuestPhysAddr::from_usize(GB);
+        let vaddr2 = GuestPhysAddr::from_usize(2 * GB);
+        let lazy = GuestPhysAddr::from_usize(3 * GB);
+        addr_space
+            .map_linear(vaddr1, PhysAddr::from(0), 0x1000, flags)
+            .unwrap();
+        let one_mapping = addr_space.page_table_frames();
+        addr_space
+            .map_linear(vaddr2, PhysAddr::from(0x1000), 0x1000, flags)
+            .unwrap();
+        addr_space.map_alloc(lazy, 0x1000, flags, false).unwrap();
+        let frames = addr_space.page_table_frames();
+        assert!(frames > one_mapping);
+        assert_eq!(addr_space.shrink_page_tables(), 0);
+
+        // The tables of the unmapped region are reclaimed, not the others.
+        addr_space.unmap(vaddr2, 0x1000).unwrap();
+        assert_eq!(addr_space.page_table_frames(), frames);
+        let freed = addr_space.shrink_page_tables();
+        assert!(freed > 0);
+        assert_eq!(addr_space.page_table_frames(), frames - freed);
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), freed);
+        assert!(addr_space.translate(vaddr1).is_some());
+        assert!(addr_space.handle_page_fault(lazy, MappingFlags::WRITE));
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_high_memory_only() {
+        const GB: usize = 0x4000_0000;
+        const TB: usize = 1 << 40;
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        // Nothing below 1T, 4T above.
+        let base = GuestPhysAddr::from(TB);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 4 * TB).unwrap();
+        assert!(aspace.translate(GuestPhysAddr::from(0)).is_none());
+
+        // Passthrough RAM mapped to host memory far below it, with 1G pages.
+        let hpa = PhysAddr::from(GB);
+        let huge = MapGranularity::new(PageSize::Size4K, PageSize::Size1G);
+        aspace
+            .map_linear_with_granularity(base, hpa, 2 * TB, rw, huge)
+            .unwrap();
+        assert_eq!(
+            aspace.translate(base + (2 * TB - 1)),
+            Some(hpa + (2 * TB - 1))
+        );
+        assert!(aspace.page_table_frames() < 8);
+
+        // Allocated, then lazily allocated RAM at the very top.
+        let top = aspace.end() - 0x20_0000;
+        let ram = top - 0x2000;
+        aspace.map_alloc(ram, 0x2000, rw, true).unwrap();
+        aspace.map_alloc(top, 0x20_0000, rw, false).unwrap();
+        assert!(aspace.handle_page_fault(aspace.end() - 1, MappingFlags::WRITE));
+        assert!(aspace.is_lazy_placeholder(top));
+        let buf = aspace.translated_byte_buffer(ram + 0xffe, 4).unwrap();
+        assert_eq!(buf.iter().map(|chunk| chunk.len()).sum::<usize>(), 4);
+
+        // Reserved ranges cost nothing per page of the extent.
+        let trampoline = GuestPhysAddrRange::from_start_size(base + 3 * TB, 0x1000);
+        aspace.add_reserved_range(trampoline).unwrap();
+        assert_eq!(
+            aspace.map_alloc(base + 3 * TB - 0x1000, 0x2000, rw, false),
+            Err(AxError::AddrInUse)
+        );
+        assert_eq!(
+            aspace.memory_map()[0].range,
+            GuestPhysAddrRange::from_start_size(base, 2 * TB)
+        );
+
+        aspace.unmap(base, 2 * TB).unwrap();
+        assert!(aspace.translate(base).is_none());
+        assert!(aspace.translate(ram).is_some());
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_try_map_linear() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut addr_space = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        // A stray page table entry, not covered by any area.
+        addr_space
+            .state
+            .pt
+            .map(
+                base + 0x2000,
+                PhysAddr::from(0x9000),
+                PageSize::Size4K,
+                flags,
+            )
+            .unwrap()
+            .ignore();
+
+        let err = addr_space
+            .try_map_linear(
+                base,
+                PhysAddr::from(0x0),
+                0x4000,
+                flags,
+                MapGranularity::DEFAULT,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err,
+            MapLinearError {
+                error: AxError::AlreadyExists,
+                mapped: 0x2000,
+                failed_at: base + 0x2000,
+                alignment: None,
+            }
+        );
+        // The partial progress is rolled back, the stray entry is kept.
+        assert!(addr_space.translate(base).is_none());
+        assert!(addr_space.translate(base + 0x1000).is_none());
+        assert_eq!(addr_space.translate(base + 0x2000), Some(0x9000.into()));
+        assert!(addr_space.layout.areas().is_empty());
+
+        addr_space
+            .map_linear(base + 0x8000, PhysAddr::from(0x8000), 0x1000, flags)
+            .unwrap();
+        let err = addr_space
+            .try_map_linear(
+                base + 0x4000,
+                PhysAddr::from(0x4000),
+                0x8000,
+                flags,
+                MapGranularity::DEFAULT,
+            )
+            .unwrap_err();
+        assert_eq!(err.failed_at, base + 0x8000);
+        assert_eq!(err.mapped, 0x4000);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_area_cache() {
+        let base = GuestPhysAddr::from(0x10000);
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        let mut addr_space = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        addr_space.map_alloc(base, 0x1000, flags, false).unwrap();
+        addr_space
+            .map_alloc(base + 0x1000, 0x1000, flags, false)
+            .unwrap();
+        let range = |off, size| GuestPhysAddrRange::from_start_size(base + off, size);
+        let find =
+            |aspace: &AddrSpace<MockHal>, gpa| aspace.layout.find_area(gpa).map(|a| a.va_range());
+
+        assert_eq!(find(&addr_space, base + 0x10), Some(range(0, 0x1000)));
+        assert_eq!(find(&addr_space, base + 0x20), Some(range(0, 0x1000)));
+        // Every change of the areas drops the cached one.
+        addr_space.merge_adjacent_areas().unwrap();
+        assert_eq!(find(&addr_space, base + 0x10), Some(range(0, 0x2000)));
+        addr_space.unmap(base, 0x1000).unwrap();
+        assert_eq!(find(&addr_space, base + 0x10), None);
+        assert_eq!(
+            find(&addr_space, base + 0x1010),
+            Some(range(0x1000, 0x1000))
+        );
+        addr_space.clear().unwrap();
+        assert_eq!(find(&addr_space, base + 0x1010), None);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_flush_wc() {
+        let base = GuestPhysAddr::from(0xfe00_0000);
+        let mut addr_space = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let fb = GuestPhysAddrRange::from_start_size(base, 0x2000);
+        let mmio = GuestPhysAddrRange::from_start_size(base + 0x2000, 0x1000);
+        addr_space
+            .map_identity_device(fb, MemType::WriteCombining)
+            .unwrap();
+        addr_space
+            .map_identity_device(mmio, MemType::Device)
+            .unwrap();
+
+        addr_space.flush_wc(fb).unwrap();
+        let across = GuestPhysAddrRange::from_start_size(base + 0x1000, 0x2000);
+        let hole = GuestPhysAddrRange::from_start_size(base + 0x3000, 0x1000);
+        for range in [across, hole] {
+            assert_eq!(addr_space.flush_wc(range), Err(AxError::InvalidInput));
+        }
+    }
 }
@@ -0,0 +1,225 @@
+//! Host-wide ownership of the host frames mapped into address spaces, to
+//! catch a frame mapped twice (`frame-ownership` feature).
+//!
+//! Silently mapping the same host memory into two VMs, or twice into one,
+//! corrupts guest memory in ways that are hard to trace back. With this
+//! feature, every address space claims the host memory it maps in a global
+//! table:
+//!
+//! - A linear area claims its whole host range when it is mapped, and fails
+//!   with [`AxError::ResourceBusy`] if any part of it is already claimed.
+//! - The frames of allocation areas are claimed as they are mapped, tracked
+//!   through the reverse map, which is always enabled with this feature.
+//!   They come from the paging handler, so a conflict there means that the
+//!   handler gave out a frame also mapped by a linear area, which is logged
+//!   as an error.
+//!
+//! Claims are released when the memory is unmapped, and when the address
+//! space is cleared or dropped.
+//!
+//! [`AxError::ResourceBusy`]: axerrno::AxError::ResourceBusy
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{PhysAddr, PhysAddrRange};
+use page_table_multiarch::PagingHandler;
+use spin::Mutex;
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+/// The owner of a host frame, returned by [`frame_owner`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameOwner {
+    /// The [`AddrSpace::space_id`] of the owning address space.
+    pub space: usize,
+    /// The guest physical address the frame is mapped at.
+    pub gpa: GuestPhysAddr,
+}
+
+/// A claimed host range, keyed by its start in [`CLAIMS`].
+struct Claim {
+    end: PhysAddr,
+    /// The owner of the start of the range.
+    owner: FrameOwner,
+}
+
+static NEXT_SPACE_ID: AtomicUsize = AtomicUsize::new(1);
+static CLAIMS: Mutex<BTreeMap<PhysAddr, Claim>> = Mutex::new(BTreeMap::new());
+
+/// Returns the address space owning the host frame containing `hpa`, and
+/// the guest address `hpa` is mapped at.
+pub fn frame_owner(hpa: PhysAddr) -> Option<FrameOwner> {
+    let claims = CLAIMS.lock();
+    let (&start, claim) = claims.range(..=hpa).next_back()?;
+    (hpa < claim.end).then(|| FrameOwner {
+        space: claim.owner.space,
+        gpa: claim.owner.gpa + (hpa - start),
+    })
+}
+
+pub(crate) fn new_space_id() -> usize {
+    NEXT_SPACE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Claims `range`, mapped at `owner.gpa`, for `owner.space`.
+pub(crate) fn claim(range: PhysAddrRange, owner: FrameOwner) -> AxResult {
+    let mut claims = CLAIMS.lock();
+    if let Some((&start, claim)) = claims.range(..range.end).next_back()
+        && claim.end > range.start
+    {
+        warn!(
+            "host range {range:?} of space {} at {:?} is already owned by space {} at {:?}",
+            owner.space,
+            owner.gpa,
+            claim.owner.space,
+            claim.owner.gpa + (range.start.max(start) - start),
+        );
+        return ax_err!(ResourceBusy, "host frame already owned");
+    }
+    claims.insert(
+        range.start,
+        Claim {
+            end: range.end,
+            owner,
+        },
+    );
+    Ok(())
+}
+
+/// Releases the parts of `range` claimed by `space`, splitting the claims
+/// straddling its boundaries.
+pub(crate) fn release(space: usize, range: PhysAddrRange) {
+    let mut claims = CLAIMS.lock();
+    let overlapping: Vec<PhysAddr> = claims
+        .range(..range.end)
+        .rev()
+        .take_while(|(_, claim)| claim.end > range.start)
+        .filter(|(_, claim)| claim.owner.space == space)
+        .map(|(&start, _)| start)
+        .collect();
+    for start in overlapping {
+        let claim = claims.remove(&start).unwrap();
+        if start < range.start {
+            let head = Claim {
+                end: range.start,
+                owner: claim.owner,
+            };
+            claims.insert(start, head);
+        }
+        if claim.end > range.end {
+            let owner = FrameOwner {
+                space,
+                gpa: claim.owner.gpa + (range.end - start),
+            };
+            claims.insert(
+                range.end,
+                Claim {
+                    end: claim.end,
+                    owner,
+                },
+            );
+        }
+    }
+}
+
+/// Releases everything claimed by `space`.
+pub(crate) fn release_all(space: usize) {
+    CLAIMS.lock().retain(|_, claim| claim.owner.space != space);
+}
+
+/// Forgets all claims.
+#[cfg(test)]
+pub(crate) fn reset_claims() {
+    CLAIMS.lock().clear();
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns the identifier of the address space in the host-wide frame
+    /// ownership table, see [`frame_owner`].
+    pub fn space_id(&self) -> usize {
+        self.state.space_id
+    }
+
+    /// Returns the host ranges of the linear areas in `[start, start +
+    /// size)`, to be released once unmapped.
+    pub(crate) fn linear_claims(&self, start: GuestPhysAddr, size: usize) -> Vec<PhysAddrRange> {
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        self.layout
+            .areas()
+            .iter()
+            .filter(|area| area.va_range().overlaps(range))
+            .filter_map(|area| match *area.backend() {
+                Backend::Linear { pa_va_offset, .. } => {
+                    let gpa = area.start().max(range.start);
+                    let len = area.end().min(range.end) - gpa;
+                    let hpa = PhysAddr::from(gpa.as_usize().wrapping_sub(pa_va_offset));
+                    Some(PhysAddrRange::from_start_size(hpa, len))
+                }
+                // The template of copy-on-write areas is shared, never claimed.
+                Backend::Alloc { .. } | Backend::CoW { .. } => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{BASE_PADDR, MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_frame_ownership() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut vm1 = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let mut vm2 = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let ram = PhysAddr::from(BASE_PADDR + 0x10_0000);
+
+        vm1.map_linear(base, ram, 0x4000, rw).unwrap();
+        assert_eq!(
+            frame_owner(ram + 0x1234),
+            Some(FrameOwner {
+                space: vm1.space_id(),
+                gpa: base + 0x1234,
+            })
+        );
+        // Neither another VM nor another area of the same VM can map it.
+        assert_eq!(
+            vm2.map_linear(base, ram + 0x3000, 0x2000, rw),
+            Err(AxError::ResourceBusy)
+        );
+        assert_eq!(
+            vm1.map_linear(base + 0x8000, ram + 0x3000, 0x1000, rw),
+            Err(AxError::ResourceBusy)
+        );
+
+        // Punching a hole releases only the hole.
+        vm1.unmap(base + 0x1000, 0x1000).unwrap();
+        assert_eq!(frame_owner(ram + 0x1000), None);
+        assert_eq!(frame_owner(ram + 0x2000).unwrap().gpa, base + 0x2000);
+        vm2.map_linear(base, ram + 0x1000, 0x1000, rw).unwrap();
+        assert_eq!(frame_owner(ram + 0x1000).unwrap().space, vm2.space_id());
+
+        // Allocated frames are claimed as they are mapped.
+        vm2.map_alloc(base + 0x4000, 0x2000, rw, false).unwrap();
+        assert!(vm2.handle_page_fault(base + 0x5000, MappingFlags::READ));
+        let frame = vm2.translate(base + 0x5000).unwrap();
+        assert_eq!(frame_owner(frame).unwrap().gpa, base + 0x5000);
+        vm2.unmap(base + 0x4000, 0x2000).unwrap();
+        assert_eq!(frame_owner(frame), None);
+
+        drop(vm1);
+        assert_eq!(frame_owner(ram), None);
+        assert!(frame_owner(ram + 0x1000).is_some());
+        drop(vm2);
+        assert_eq!(frame_owner(ram + 0x1000), None);
+    }
+}
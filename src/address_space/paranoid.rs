@@ -0,0 +1,103 @@
+//! Verification of the host memory exposed by the byte-buffer APIs.
+//!
+//! A corrupted nested page table could make
+//! [`AddrSpace::translated_byte_buffer`] hand out arbitrary host memory to
+//! code acting on behalf of the guest. With the `paranoid` feature, every
+//! host address is checked against the memory the address space was given
+//! before being exposed.
+
+use memory_addr::{PhysAddr, PhysAddrRange};
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, Backend};
+use crate::GuestPhysAddr;
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Registers `range` as host memory the paging handler may allocate the
+    /// frames of this address space from.
+    ///
+    /// With the `paranoid` feature, once a range is registered, pages of
+    /// allocation areas are only exposed by the byte-buffer APIs if they lie
+    /// in a registered range. Pages of linear areas are always checked
+    /// against the host range of their area instead.
+    pub fn allow_host_range(&mut self, range: PhysAddrRange) {
+        self.layout.host_ranges.push(range);
+    }
+
+    /// Whether `[paddr, paddr + len)`, found in the page table for `gpa` in
+    /// an area with `backend`, is host memory given to the address space.
+    #[cfg_attr(not(feature = "paranoid"), allow(dead_code))]
+    pub(crate) fn host_range_allowed(
+        &self,
+        backend: &Backend<H>,
+        gpa: GuestPhysAddr,
+        paddr: PhysAddr,
+        len: usize,
+    ) -> bool {
+        match *backend {
+            Backend::Linear { pa_va_offset, .. } => {
+                paddr.as_usize() == gpa.as_usize().wrapping_sub(pa_va_offset)
+            }
+            // The template of a copy-on-write area, given by the caller.
+            Backend::CoW { .. } if !backend.owns_frame(gpa, paddr) => true,
+            Backend::Alloc { .. } | Backend::CoW { .. } if self.layout.host_ranges.is_empty() => {
+                true
+            }
+            Backend::Alloc { .. } | Backend::CoW { .. } => {
+                let Some(range) = PhysAddrRange::try_from_start_size(paddr, len) else {
+                    return false;
+                };
+                self.layout
+                    .host_ranges
+                    .iter()
+                    .any(|r| r.contains_range(range))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "paranoid"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{BASE_PADDR, MEMORY_LEN, MockHal, mock_hal_test};
+    use crate::{MappingFlags, PageSize};
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_paranoid_byte_buffer() {
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10_0000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        aspace.allow_host_range(PhysAddrRange::from_start_size(
+            PhysAddr::from(BASE_PADDR),
+            MEMORY_LEN,
+        ));
+        assert!(aspace.translated_byte_buffer(base, 0x2000).is_some());
+
+        // A linear page redirected to another host frame.
+        let ram = PhysAddr::from(BASE_PADDR);
+        aspace.map_linear(base + 0x4000, ram, 0x1000, rw).unwrap();
+        assert!(aspace.translated_byte_buffer(base + 0x4000, 1).is_some());
+        aspace.state.pt.unmap(base + 0x4000).unwrap().2.ignore();
+        aspace
+            .state
+            .pt
+            .map(base + 0x4000, ram + 0x1000, PageSize::Size4K, rw)
+            .unwrap()
+            .ignore();
+        assert!(aspace.translated_byte_buffer(base + 0x4000, 1).is_none());
+
+        // An allocated page redirected outside the registered ranges.
+        aspace.state.pt.unmap(base).unwrap().2.ignore();
+        aspace
+            .state
+            .pt
+            .map(base, PhysAddr::from(0x8000_0000), PageSize::Size4K, rw)
+            .unwrap()
+            .ignore();
+        assert!(aspace.translated_byte_buffer(base, 1).is_none());
+        assert!(aspace.translated_byte_buffer(base + 0x1000, 1).is_some());
+    }
+}
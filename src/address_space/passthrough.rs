@@ -0,0 +1,292 @@
+//! Labels of passthrough device regions, and the firmware descriptions
+//! (devicetree reserved-memory nodes, ACPI `_CRS` resources) generated
+//! from them.
+//!
+//! A label records which device an identity-mapped region belongs to. It
+//! lives next to the mapping granting the guest access to the device, and
+//! is dropped with it, so the firmware tables given to the guest never
+//! describe a region the guest cannot reach.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, MemType, PAGE_SIZE};
+
+/// The address of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciBdf {
+    /// The PCI segment group.
+    pub segment: u16,
+    /// The bus number.
+    pub bus: u8,
+    /// The device number, below 32.
+    pub device: u8,
+    /// The function number, below 8.
+    pub function: u8,
+}
+
+impl PciBdf {
+    /// Creates the address of a PCI function.
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Returns the value of the ACPI `_ADR` object of the function.
+    pub const fn acpi_adr(self) -> u32 {
+        ((self.device as u32) << 16) | self.function as u32
+    }
+}
+
+impl fmt::Display for PciBdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// The device a passthrough region belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceIdentity {
+    /// A platform device, by its devicetree `compatible` string.
+    Compatible(String),
+    /// A PCI function.
+    Pci(PciBdf),
+}
+
+/// A labeled passthrough region, see [`AddrSpace::label_device_region`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRegion {
+    /// The guest physical range, equal to the host physical range.
+    pub range: GuestPhysAddrRange,
+    /// The memory type the region is mapped with.
+    pub mem_type: MemType,
+    /// The device the region belongs to.
+    pub identity: DeviceIdentity,
+}
+
+/// Size of the resource template returned by [`DeviceRegion::acpi_crs`].
+pub const ACPI_CRS_SIZE: usize = 48;
+
+impl DeviceRegion {
+    /// Returns the devicetree source of a child node of `/reserved-memory`
+    /// (with `#address-cells` and `#size-cells` of 2) describing the region.
+    pub fn reserved_memory_node(&self) -> String {
+        let start = self.range.start.as_usize() as u64;
+        let size = self.range.size() as u64;
+        let mut node = String::new();
+        let _ = writeln!(node, "passthrough@{start:x} {{");
+        match &self.identity {
+            DeviceIdentity::Compatible(compatible) => {
+                let _ = writeln!(node, "\tcompatible = \"{compatible}\";");
+            }
+            DeviceIdentity::Pci(bdf) => {
+                let _ = writeln!(node, "\t/* PCI {bdf} */");
+            }
+        }
+        let _ = writeln!(
+            node,
+            "\treg = <{:#x} {:#x} {:#x} {:#x}>;",
+            start >> 32,
+            start as u32,
+            size >> 32,
+            size as u32
+        );
+        node.push_str("\tno-map;\n};\n");
+        node
+    }
+
+    /// Returns an ACPI resource template for the `_CRS` object of the
+    /// device: a read-write QWord memory descriptor of the region, followed
+    /// by an end tag.
+    pub fn acpi_crs(&self) -> Vec<u8> {
+        let start = self.range.start.as_usize() as u64;
+        let size = self.range.size() as u64;
+        // _MEM: non-cacheable, cacheable or write-combining.
+        let cacheability = match self.mem_type {
+            MemType::Device | MemType::Uncached => 0,
+            MemType::WriteCombining => 2,
+            _ => 1,
+        };
+        let mut crs = Vec::with_capacity(ACPI_CRS_SIZE);
+        crs.push(0x8a);
+        crs.extend_from_slice(&43u16.to_le_bytes());
+        // Memory range, consumed by the device at fixed addresses.
+        crs.extend_from_slice(&[0x00, 0x0d, (cacheability << 1) | 1]);
+        for field in [0, start, start + size - 1, 0, size] {
+            crs.extend_from_slice(&field.to_le_bytes());
+        }
+        crs.extend_from_slice(&[0x79, 0x00]);
+        crs
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Labels the passthrough region `range` with the device it belongs to.
+    ///
+    /// The region must be mapped by identity device mappings (see
+    /// [`AddrSpace::map_identity_device`]), and the label is dropped when
+    /// any part of it is unmapped. Fails with `InvalidInput` if it is not,
+    /// or with `AlreadyExists` if it overlaps a labeled region.
+    pub fn label_device_region(
+        &mut self,
+        range: GuestPhysAddrRange,
+        identity: DeviceIdentity,
+    ) -> AxResult {
+        if range.is_empty()
+            || !range.start.is_aligned(PAGE_SIZE)
+            || !range.end.is_aligned(PAGE_SIZE)
+            || !self.holes(range.start, range.size()).is_empty()
+        {
+            return ax_err!(InvalidInput, "device region not mapped");
+        }
+        let mut mem_type = None;
+        for area in self.layout.areas_overlapping(range) {
+            let area_type = MemType::from_flags(area.flags());
+            if !matches!(
+                area.backend(),
+                Backend::Linear {
+                    pa_va_offset: 0,
+                    ..
+                }
+            ) || area_type == MemType::Normal
+                || mem_type.is_some_and(|t| t != area_type)
+            {
+                return ax_err!(InvalidInput, "not an identity-mapped device region");
+            }
+            mem_type = Some(area_type);
+        }
+        if self.device_regions_overlapping(range).next().is_some() {
+            return ax_err!(AlreadyExists, "device region already labeled");
+        }
+        self.layout.device_regions.insert(
+            range.start,
+            DeviceRegion {
+                range,
+                mem_type: mem_type.unwrap(),
+                identity,
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns the labeled region containing `gpa`.
+    pub fn device_region(&self, gpa: GuestPhysAddr) -> Option<&DeviceRegion> {
+        let (_, region) = self.layout.device_regions.range(..=gpa).next_back()?;
+        region.range.contains(gpa).then_some(region)
+    }
+
+    /// Returns the labeled regions, in ascending address order.
+    pub fn device_regions(&self) -> impl Iterator<Item = &DeviceRegion> {
+        self.layout.device_regions.values()
+    }
+
+    fn device_regions_overlapping(
+        &self,
+        range: GuestPhysAddrRange,
+    ) -> impl Iterator<Item = &DeviceRegion> {
+        self.layout
+            .device_regions
+            .range(..range.end)
+            .rev()
+            .map(|(_, region)| region)
+            .take_while(move |region| region.range.end > range.start)
+    }
+
+    /// Drops the labels of the regions overlapping `range`, which is being
+    /// unmapped.
+    pub(crate) fn drop_device_labels(&mut self, range: GuestPhysAddrRange) {
+        let doomed: Vec<GuestPhysAddr> = self
+            .device_regions_overlapping(range)
+            .map(|region| region.range.start)
+            .collect();
+        for start in doomed {
+            self.layout.device_regions.remove(&start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_device_regions() {
+        let base = GuestPhysAddr::from(0xfe00_0000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let uart = GuestPhysAddrRange::from_start_size(base, 0x1000);
+        let bar = GuestPhysAddrRange::from_start_size(base + 0x4000, 0x2000);
+        aspace.map_identity_device(uart, MemType::Device).unwrap();
+        aspace
+            .map_identity_device(bar, MemType::WriteCombining)
+            .unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base + 0x8000, 0x1000, rw, false).unwrap();
+
+        let pl011 = DeviceIdentity::Compatible("arm,pl011".into());
+        let gpu = DeviceIdentity::Pci(PciBdf::new(0, 1, 0x1f, 3));
+        aspace.label_device_region(uart, pl011.clone()).unwrap();
+        aspace.label_device_region(bar, gpu).unwrap();
+        // RAM, holes and labeled regions are rejected.
+        let ram = GuestPhysAddrRange::from_start_size(base + 0x8000, 0x1000);
+        let hole = GuestPhysAddrRange::from_start_size(base + 0x5000, 0x2000);
+        let sub = GuestPhysAddrRange::from_start_size(base + 0x5000, 0x1000);
+        assert_eq!(
+            aspace.label_device_region(ram, pl011.clone()),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            aspace.label_device_region(hole, pl011.clone()),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            aspace.label_device_region(sub, pl011),
+            Err(AxError::AlreadyExists)
+        );
+
+        let uart_region = aspace.device_region(base + 0x800).unwrap();
+        assert_eq!(uart_region.mem_type, MemType::Device);
+        assert_eq!(
+            uart_region.reserved_memory_node(),
+            "passthrough@fe000000 {\n\tcompatible = \"arm,pl011\";\n\
+             \treg = <0x0 0xfe000000 0x0 0x1000>;\n\tno-map;\n};\n"
+        );
+        let bar_region = aspace.device_region(base + 0x5000).unwrap();
+        assert!(
+            bar_region
+                .reserved_memory_node()
+                .contains("/* PCI 0000:01:1f.3 */")
+        );
+        let crs = bar_region.acpi_crs();
+        assert_eq!(crs.len(), ACPI_CRS_SIZE);
+        assert_eq!(&crs[..6], &[0x8a, 43, 0, 0, 0x0d, 0x05]);
+        assert_eq!(&crs[14..22], &0xfe00_4000u64.to_le_bytes());
+        assert_eq!(&crs[22..30], &0xfe00_5fffu64.to_le_bytes());
+        assert_eq!(&crs[38..46], &0x2000u64.to_le_bytes());
+        assert_eq!(&crs[46..], &[0x79, 0]);
+        assert_eq!(PciBdf::new(0, 1, 0x1f, 3).acpi_adr(), 0x1f_0003);
+
+        // Revoking part of the access drops the label.
+        aspace.unmap(base + 0x5000, 0x1000).unwrap();
+        assert_eq!(aspace.device_region(base + 0x4000), None);
+        assert_eq!(aspace.device_regions().count(), 1);
+    }
+}
@@ -0,0 +1,176 @@
+//! Handling host memory errors (e.g., machine checks or synchronous external
+//! aborts) on frames backing guest memory, so that corrupt data is never fed
+//! to the guest.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::{PageSize, PagingHandler};
+
+use super::{AddrSpace, Backend};
+use crate::{
+    AxMmHal, BASE_PAGE_SIZE, GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, PhysFrame, npt,
+    paging_err_to_ax_err,
+};
+
+impl<H: PagingHandler + AxMmHal> AddrSpace<H> {
+    /// Handles a hardware memory error in the host frame containing `hpa`.
+    ///
+    /// Every guest page backed by the frame, as found by
+    /// [`AddrSpace::reverse_lookup`], is unmapped; huge pages are split first
+    /// so that only the failing base page is lost. A page of an allocation
+    /// area is remapped to `replacement` if given, whose contents are left
+    /// as they are (e.g., zeroed or recovered by the caller). Other pages are
+    /// marked as poisoned: faults on them return
+    /// [`PageFaultResult::HwPoisoned`] until they are unmapped.
+    ///
+    /// The failing frame is never returned to the paging handler. Returns the
+    /// affected guest pages, or fails with `NotFound` if the frame backs
+    /// none.
+    ///
+    /// [`PageFaultResult::HwPoisoned`]: super::PageFaultResult::HwPoisoned
+    pub fn poison_frame(
+        &mut self,
+        hpa: PhysAddr,
+        mut replacement: Option<PhysFrame<H>>,
+    ) -> AxResult<Vec<GuestPhysAddr>> {
+        let frame = hpa.align_down(PAGE_SIZE);
+        let mut affected = Vec::new();
+        while let Some(mapping) = self.reverse_lookup(frame) {
+            let gpa = mapping.gpa;
+            while let Ok((paddr, flags, page_size)) = self.state.pt.query(gpa)
+                && page_size != BASE_PAGE_SIZE
+            {
+                let smaller = match page_size {
+                    PageSize::Size1G => PageSize::Size2M,
+                    _ => PageSize::Size4K,
+                };
+                let base = gpa.align_down(page_size);
+                self.demote_page(base, paddr - (gpa - base), page_size, smaller, flags)?;
+            }
+            let owned = matches!(
+                self.layout.find_area(gpa).map(|area| area.backend()),
+                Some(Backend::Alloc { .. })
+            );
+            let pt = &mut self.state.pt;
+            match replacement.take() {
+                Some(new_frame) if owned => {
+                    let (_, flags, _) = pt.query(gpa).map_err(paging_err_to_ax_err)?;
+                    pt.remap(gpa, new_frame.start_paddr(), flags)
+                        .map_err(paging_err_to_ax_err)?
+                        .1
+                        .ignore();
+                    new_frame.into_raw();
+                    self.mark_dirty(gpa, PAGE_SIZE);
+                }
+                unused => {
+                    replacement = unused;
+                    pt.unmap(gpa).map_err(paging_err_to_ax_err)?.2.ignore();
+                    self.state.poisoned.insert(gpa);
+                }
+            }
+            npt::flush_tlb(None);
+            self.rmap_update(gpa, PAGE_SIZE);
+            warn!("poisoned host frame {frame:?} was mapped at {gpa:?}");
+            affected.push(gpa);
+        }
+        if affected.is_empty() {
+            return ax_err!(NotFound, "frame not mapped in the address space");
+        }
+        Ok(affected)
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns whether the page at `gpa` was lost to a host memory error, see
+    /// [`AddrSpace::poison_frame`].
+    pub fn is_poisoned(&self, gpa: GuestPhysAddr) -> bool {
+        !self.state.poisoned.is_empty() && self.state.poisoned.contains(&gpa.align_down(PAGE_SIZE))
+    }
+
+    /// Forgets the poisoned pages in `[start, start + size)`, once their
+    /// areas are unmapped.
+    pub(crate) fn clear_poison(&mut self, start: GuestPhysAddr, size: usize) {
+        if !self.state.poisoned.is_empty() {
+            let range = GuestPhysAddrRange::from_start_size(start, size);
+            self.state.poisoned.retain(|&gpa| !range.contains(gpa));
+        }
+    }
+
+    /// Unmaps again the poisoned pages in `[start, start + size)` that a
+    /// fault on a neighbouring page of the same block populated.
+    pub(crate) fn drop_poisoned(&mut self, start: GuestPhysAddr, size: usize) {
+        if self.state.poisoned.is_empty() {
+            return;
+        }
+        for &gpa in self.state.poisoned.range(start..start + size) {
+            if let Ok((frame, _, tlb)) = self.state.pt.unmap(gpa) {
+                tlb.flush();
+                H::dealloc_frame(frame);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEALLOC_COUNT, MockHal, mock_hal_test};
+    use crate::{FaultContext, MappingFlags, PageFaultResult};
+    use axerrno::AxError;
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_poison_frame() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x4000, rw, true).unwrap();
+        aspace
+            .map_linear(base + 0x8000, PhysAddr::from(0x40_0000), 0x2000, rw)
+            .unwrap();
+        aspace.enable_reverse_map();
+        let failing = aspace.translate(base + 0x1000).unwrap();
+
+        assert_eq!(
+            aspace.poison_frame(failing + 0x123, None),
+            Ok(alloc::vec![base + 0x1000])
+        );
+        assert!(aspace.is_poisoned(base + 0x1fff));
+        assert!(aspace.translate(base + 0x1000).is_none());
+        assert_eq!(
+            aspace.handle_page_fault_result(base + 0x1000, MappingFlags::READ, &FaultContext::NONE),
+            PageFaultResult::HwPoisoned
+        );
+        assert!(!aspace.handle_page_fault(base + 0x1000, MappingFlags::READ));
+        // The failing frame is not reused.
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), 0);
+        assert_eq!(aspace.poison_frame(failing, None), Err(AxError::NotFound));
+        // Nor is the page given a new frame by defragmentation.
+        assert_eq!(aspace.defragment(base, 0x4000), Ok(3));
+        assert!(aspace.translate(base + 0x1000).is_none());
+
+        // A recoverable page is remapped to the replacement frame.
+        let failing = aspace.translate(base + 0x2000).unwrap();
+        let new_frame = PhysFrame::<MockHal>::alloc_zero().unwrap();
+        let new = new_frame.start_paddr();
+        aspace.poison_frame(failing, Some(new_frame)).unwrap();
+        assert_eq!(aspace.translate(base + 0x2000), Some(new));
+        assert!(!aspace.is_poisoned(base + 0x2000));
+
+        // Linear pages are never replaced, and can still be unmapped.
+        let new_frame = PhysFrame::<MockHal>::alloc().unwrap();
+        assert_eq!(
+            aspace.poison_frame(PhysAddr::from(0x40_1000), Some(new_frame)),
+            Ok(alloc::vec![base + 0x9000])
+        );
+        assert!(aspace.translate(base + 0x8000).is_some());
+        aspace.unmap(base + 0x8000, 0x2000).unwrap();
+        aspace.unmap(base, 0x4000).unwrap();
+        assert!(!aspace.is_poisoned(base + 0x1000));
+        assert!(!aspace.is_poisoned(base + 0x9000));
+    }
+}
@@ -0,0 +1,252 @@
+//! Changing the permissions of mapped regions.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use axerrno::{AxError, AxResult, ax_err};
+use memory_addr::{MemoryAddr, is_aligned};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, ReplayRecord};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, mapping_err_to_ax_err, npt};
+
+/// How [`AddrSpace::protect_with_policy`] treats parts of the range that are
+/// not covered by any area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectPolicy {
+    /// Holes are skipped, only the mapped parts are updated.
+    SkipHoles,
+    /// The whole operation fails, without any change, if there is a hole.
+    FailOnHole,
+}
+
+/// Error returned by [`AddrSpace::protect_with_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectError {
+    /// The range contains a hole, and [`ProtectPolicy::FailOnHole`] was
+    /// requested. Carries the first unmapped sub-range.
+    Hole(GuestPhysAddrRange),
+    /// Any other error.
+    Other(AxError),
+}
+
+impl From<AxError> for ProtectError {
+    fn from(err: AxError) -> Self {
+        Self::Other(err)
+    }
+}
+
+impl From<ProtectError> for AxError {
+    fn from(err: ProtectError) -> Self {
+        match err {
+            ProtectError::Hole(_) => AxError::BadAddress,
+            ProtectError::Other(err) => err,
+        }
+    }
+}
+
+impl fmt::Display for ProtectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Hole(range) => write!(f, "unmapped hole {range:?}"),
+            Self::Other(err) => write!(f, "{err:?}"),
+        }
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns the sub-ranges of `[start, start + size)` that are not covered
+    /// by any area, in ascending order.
+    pub fn holes(&self, start: GuestPhysAddr, size: usize) -> Vec<GuestPhysAddrRange> {
+        let end = start.as_usize().saturating_add(size);
+        let range = GuestPhysAddrRange::new(start, end.into());
+        let mut holes = Vec::new();
+        let mut cursor = range.start;
+        for area in self.layout.areas().iter() {
+            if area.end() <= cursor {
+                continue;
+            }
+            if area.start() >= range.end {
+                break;
+            }
+            if area.start() > cursor {
+                holes.push(GuestPhysAddrRange::new(cursor, area.start()));
+            }
+            cursor = area.end();
+        }
+        if cursor < range.end {
+            holes.push(GuestPhysAddrRange::new(cursor, range.end));
+        }
+        holes
+    }
+
+    /// Changes the mapping flags of `[start, start + size)` to `new_flags`,
+    /// e.g., to make guest memory read-only for introspection.
+    ///
+    /// The range must be page-aligned, inside the address space and fully
+    /// mapped, otherwise fails with `InvalidInput`, or with `BadAddress` if
+    /// it contains a hole. See [`AddrSpace::protect_with_policy`].
+    pub fn protect(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        new_flags: MappingFlags,
+    ) -> AxResult {
+        self.protect_with_policy(start, size, new_flags, ProtectPolicy::FailOnHole)
+            .map_err(Into::into)
+    }
+
+    /// Changes the mapping flags of `[start, start + size)` to `new_flags`.
+    ///
+    /// Both the flags of the covered areas (splitting them if needed) and the
+    /// entries in the nested page table are updated, then the TLB is flushed.
+    /// Pages of lazily allocated areas that are not faulted in yet are left
+    /// alone in the page table; they get `new_flags` when faulted in.
+    ///
+    /// Parts of the range not covered by any area are handled according to
+    /// `policy`. While dirty logging is enabled, writable pages of normal
+    /// memory stay write-protected until written, as the other logged pages.
+    pub fn protect_with_policy(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        new_flags: MappingFlags,
+        policy: ProtectPolicy,
+    ) -> Result<(), ProtectError> {
+        self.check_context("protect");
+        self.check_unsealed()?;
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range").map_err(Into::into);
+        }
+        if !start.is_aligned(PAGE_SIZE) || !is_aligned(size, PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned").map_err(Into::into);
+        }
+        self.check_split_points(start, size)?;
+        if policy == ProtectPolicy::FailOnHole
+            && let Some(&hole) = self.holes(start, size).first()
+        {
+            return Err(ProtectError::Hole(hole));
+        }
+
+        let end = start + size;
+        if self
+            .layout
+            .areas()
+            .iter()
+            .any(|a| a.start() < end && a.end() > start && a.backend().is_host_only(a.flags()))
+        {
+            return ax_err!(PermissionDenied, "range overlaps a host-only area")
+                .map_err(Into::into);
+        }
+        let sub_ranges: Vec<_> = self
+            .layout
+            .areas()
+            .iter()
+            .filter(|a| a.start() < end && a.end() > start)
+            .map(|a| (a.start().max(start), a.end().min(end)))
+            .collect();
+        for (sub_start, sub_end) in sub_ranges {
+            self.layout
+                .areas_mut()
+                .protect(
+                    sub_start,
+                    sub_end - sub_start,
+                    |_| Some(new_flags),
+                    &mut self.state.pt,
+                )
+                .map_err(mapping_err_to_ax_err)?;
+        }
+        self.write_protect_logged(GuestPhysAddrRange::new(start, end));
+        npt::flush_tlb(None);
+        self.record(ReplayRecord::Protect {
+            start,
+            size,
+            flags: new_flags,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_protect_policies() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        // A lazy area: its pages are not present yet.
+        aspace.map_alloc(base + 0x3000, 0x2000, rw, false).unwrap();
+
+        let holes = aspace.holes(base, 0x6000);
+        assert_eq!(
+            holes,
+            [
+                GuestPhysAddrRange::from_start_size(base + 0x2000, 0x1000),
+                GuestPhysAddrRange::from_start_size(base + 0x5000, 0x1000),
+            ]
+        );
+
+        assert_eq!(
+            aspace.protect_with_policy(base, 0x5000, MappingFlags::READ, ProtectPolicy::FailOnHole),
+            Err(ProtectError::Hole(holes[0]))
+        );
+        // Nothing changed.
+        assert_eq!(aspace.page_table().query(base).unwrap().1, rw);
+
+        aspace
+            .protect_with_policy(
+                base + 0x1000,
+                0x3000,
+                MappingFlags::READ,
+                ProtectPolicy::SkipHoles,
+            )
+            .unwrap();
+        assert_eq!(aspace.page_table().query(base).unwrap().1, rw);
+        assert_eq!(
+            aspace.page_table().query(base + 0x1000).unwrap().1,
+            MappingFlags::READ
+        );
+        // The area is split, and lazy pages fault in with the new flags.
+        assert_eq!(aspace.layout.areas().len(), 4);
+        assert!(!aspace.handle_page_fault(base + 0x3000, MappingFlags::WRITE));
+        assert!(aspace.handle_page_fault(base + 0x3000, MappingFlags::READ));
+        assert_eq!(
+            aspace.page_table().query(base + 0x3000).unwrap().1,
+            MappingFlags::READ
+        );
+        assert!(aspace.handle_page_fault(base + 0x4000, MappingFlags::WRITE));
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_protect() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        assert_eq!(
+            aspace.protect(base + 0x800, 0x1000, MappingFlags::READ),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            aspace.protect(base, 0x3000, MappingFlags::READ),
+            Err(AxError::BadAddress)
+        );
+        aspace.protect(base, 0x2000, MappingFlags::READ).unwrap();
+        assert!(!aspace.allows_access(base, MappingFlags::WRITE));
+        assert!(!aspace.handle_page_fault(base, MappingFlags::WRITE));
+
+        // Making pages writable again while logging keeps them logged.
+        aspace.enable_dirty_logging().unwrap();
+        aspace.protect(base, 0x2000, rw).unwrap();
+        assert!(!aspace.allows_access(base, MappingFlags::WRITE));
+        assert!(aspace.handle_page_fault(base, MappingFlags::WRITE));
+        assert_eq!(aspace.take_dirty_pages(), [base]);
+    }
+}
@@ -0,0 +1,146 @@
+//! Values attached to disjoint guest physical ranges.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+/// A map from disjoint guest physical ranges to values.
+///
+/// Addresses not covered by any range have the default value, ranges whose
+/// value becomes the default are dropped.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RangeMap<V> {
+    /// `start -> (end, value)`.
+    entries: BTreeMap<GuestPhysAddr, (GuestPhysAddr, V)>,
+}
+
+impl<V: Copy + Default + PartialEq> RangeMap<V> {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the value at `gpa`.
+    pub fn get(&self, gpa: GuestPhysAddr) -> V {
+        self.entries
+            .range(..=gpa)
+            .next_back()
+            .filter(|(_, (end, _))| *end > gpa)
+            .map(|(_, &(_, value))| value)
+            .unwrap_or_default()
+    }
+
+    /// Returns the ranges with a non-default value overlapping `range`,
+    /// clipped to it, in ascending order.
+    pub fn overlapping(&self, range: GuestPhysAddrRange) -> Vec<(GuestPhysAddrRange, V)> {
+        let first = self
+            .entries
+            .range(..range.start)
+            .next_back()
+            .map_or(range.start, |(&start, _)| start);
+        self.entries
+            .range(first..range.end)
+            .filter(|(_, (end, _))| *end > range.start)
+            .map(|(&start, &(end, value))| {
+                let clipped = GuestPhysAddrRange::new(start.max(range.start), end.min(range.end));
+                (clipped, value)
+            })
+            .collect()
+    }
+
+    /// Iterates over all ranges with a non-default value.
+    pub fn iter(&self) -> impl Iterator<Item = (GuestPhysAddrRange, V)> + '_ {
+        self.entries
+            .iter()
+            .map(|(&start, &(end, value))| (GuestPhysAddrRange::new(start, end), value))
+    }
+
+    /// Applies `f` to the values of `range`, splitting the existing ranges at
+    /// its boundaries.
+    pub fn update(&mut self, range: GuestPhysAddrRange, f: impl Fn(&mut V)) {
+        // Split the ranges crossing the boundaries.
+        for point in [range.start, range.end] {
+            let crossing = self
+                .entries
+                .range(..point)
+                .next_back()
+                .filter(|(_, (end, _))| *end > point)
+                .map(|(&start, &(end, value))| (start, end, value));
+            if let Some((start, end, value)) = crossing {
+                self.entries.insert(start, (point, value));
+                self.entries.insert(point, (end, value));
+            }
+        }
+        // Fill the gaps with default values, and update everything inside.
+        let mut cursor = range.start;
+        let inside: Vec<_> = self
+            .entries
+            .range(range.start..range.end)
+            .map(|(&start, &(end, _))| (start, end))
+            .collect();
+        for (start, end) in inside {
+            if start > cursor {
+                self.entries.insert(cursor, (start, V::default()));
+            }
+            cursor = end;
+        }
+        if cursor < range.end {
+            self.entries.insert(cursor, (range.end, V::default()));
+        }
+        let mut emptied = Vec::new();
+        for (&start, (_, value)) in self.entries.range_mut(range.start..range.end) {
+            f(value);
+            if *value == V::default() {
+                emptied.push(start);
+            }
+        }
+        for start in emptied {
+            self.entries.remove(&start);
+        }
+    }
+
+    /// Sets the value of `range`.
+    pub fn set(&mut self, range: GuestPhysAddrRange, value: V) {
+        self.update(range, |v| *v = value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: usize, end: usize) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::new(start.into(), end.into())
+    }
+
+    #[test]
+    fn test_range_map_update() {
+        let mut map = RangeMap::<(u8, u8)>::new();
+        map.update(range(0x1000, 0x5000), |v| v.0 = 1);
+        map.update(range(0x3000, 0x8000), |v| v.1 = 2);
+        let entries: Vec<_> = map.iter().collect();
+        assert_eq!(
+            entries,
+            [
+                (range(0x1000, 0x3000), (1, 0)),
+                (range(0x3000, 0x5000), (1, 2)),
+                (range(0x5000, 0x8000), (0, 2)),
+            ]
+        );
+        assert_eq!(map.get(0x4fff.into()), (1, 2));
+        assert_eq!(map.get(0x8000.into()), (0, 0));
+        assert_eq!(
+            map.overlapping(range(0x2000, 0x4000)),
+            [
+                (range(0x2000, 0x3000), (1, 0)),
+                (range(0x3000, 0x4000), (1, 2)),
+            ]
+        );
+
+        // Resetting to the default removes the ranges.
+        map.set(range(0x0, 0x8000), (0, 0));
+        assert_eq!(map.iter().count(), 0);
+    }
+}
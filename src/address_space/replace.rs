@@ -0,0 +1,118 @@
+//! Mappings replacing the areas they overlap, e.g., for firmware re-layout
+//! or to shadow a ROM over guest RAM.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::AddrSpace;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Adds a new linear mapping like [`AddrSpace::map_linear`], unmapping
+    /// the parts of the areas it overlaps first.
+    ///
+    /// The arguments are checked before anything is unmapped, but the
+    /// unmapped parts are not restored if the mapping itself fails.
+    pub fn map_linear_replace(
+        &mut self,
+        start_vaddr: GuestPhysAddr,
+        start_paddr: PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> AxResult {
+        if !start_paddr.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        self.unmap_for_replace(start_vaddr, size)?;
+        self.map_linear(start_vaddr, start_paddr, size, flags)
+    }
+
+    /// Adds a new allocation mapping like [`AddrSpace::map_alloc`],
+    /// unmapping the parts of the areas it overlaps first.
+    ///
+    /// See [`AddrSpace::map_linear_replace`].
+    pub fn map_alloc_replace(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        populate: bool,
+    ) -> AxResult {
+        self.unmap_for_replace(start, size)?;
+        self.map_alloc(start, size, flags, populate)
+    }
+
+    /// Checks that `[start, start + size)` can be mapped once the areas it
+    /// overlaps are gone, and unmaps them.
+    fn unmap_for_replace(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        self.check_unsealed()?;
+        if size == 0 {
+            return ax_err!(InvalidInput, "empty mapping");
+        }
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        self.check_reserved_overlap(start, size)?;
+        self.check_mmio_overlap(start, size)?;
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        if !self.layout.areas().overlaps(range) {
+            return Ok(());
+        }
+        self.unmap(start, size).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_map_replace() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let rom = PhysAddr::from(0x40_0000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x4000, rw, true).unwrap();
+        aspace
+            .reserve_mmio(GuestPhysAddrRange::from_start_size(base + 0x8000, 0x1000))
+            .unwrap();
+
+        // Shadow a ROM in the middle of RAM, splitting it.
+        aspace
+            .map_linear_replace(base + 0x1000, rom, 0x1000, MappingFlags::READ)
+            .unwrap();
+        assert_eq!(aspace.translate(base + 0x1000), Some(rom));
+        assert!(aspace.translate(base).is_some());
+        assert!(aspace.translate(base + 0x2000).is_some());
+        assert_eq!(aspace.layout.areas().len(), 3);
+
+        // Invalid mappings leave the areas alone.
+        assert_eq!(
+            aspace.map_alloc_replace(base, 0x9000, rw, false),
+            Err(AxError::AlreadyExists)
+        );
+        assert_eq!(
+            aspace.map_linear_replace(base, rom + 0x800, 0x1000, rw),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(aspace.translate(base + 0x1000), Some(rom));
+
+        // Re-layout across areas and holes.
+        aspace.map_alloc_replace(base, 0x6000, rw, false).unwrap();
+        assert_eq!(aspace.layout.areas().len(), 1);
+        assert_eq!(aspace.translate(base + 0x1000), None);
+        // Over a hole, it is a plain mapping.
+        aspace
+            .map_alloc_replace(base + 0xc000, 0x1000, rw, false)
+            .unwrap();
+        assert_eq!(aspace.layout.areas().len(), 2);
+    }
+}
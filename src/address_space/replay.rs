@@ -0,0 +1,675 @@
+//! Record and replay of guest memory mutations, to reproduce guest memory
+//! corruption deterministically.
+//!
+//! While a [`ReplaySink`] is installed with [`AddrSpace::set_replay_sink`],
+//! every successful mapping mutation of the address space is recorded to it.
+//! Device backends writing guest memory through a [`RecordingAccessor`]
+//! record their writes to the same sink. The log kept by the sink can then be
+//! applied to a fresh address space with [`replay`].
+
+use alloc::boxed::Box;
+
+use axerrno::{AxError, AxResult};
+use memory_addr::PhysAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, BackendKind, MapGranularity, ProtectPolicy};
+use crate::addr::checked_range;
+use crate::{
+    AxMmHal, GuestAddrRangeExt, GuestMemoryAccessor, GuestPhysAddr, GuestPhysAddrRange,
+    MappingFlags, MisalignedPolicy, PartialTransfer, PhysFrame,
+};
+
+/// A mutation of guest memory, recorded to a [`ReplaySink`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayRecord<'a> {
+    /// `data` was written at `gpa`.
+    Write {
+        /// The guest physical address written.
+        gpa: GuestPhysAddr,
+        /// The bytes written.
+        data: &'a [u8],
+    },
+    /// A linear mapping was added with [`AddrSpace::try_map_linear`] or one
+    /// of its variants.
+    MapLinear {
+        /// The first guest physical address.
+        start: GuestPhysAddr,
+        /// The host physical address `start` is mapped to.
+        paddr: PhysAddr,
+        /// The size of the mapping.
+        size: usize,
+        /// The mapping flags.
+        flags: MappingFlags,
+        /// The granularity of the mapping.
+        granularity: MapGranularity,
+    },
+    /// An allocation mapping was added with
+    /// [`AddrSpace::map_alloc_with_granularity`] or one of its variants.
+    MapAlloc {
+        /// The first guest physical address.
+        start: GuestPhysAddr,
+        /// The size of the mapping.
+        size: usize,
+        /// The mapping flags.
+        flags: MappingFlags,
+        /// Whether the frames were allocated at once.
+        populate: bool,
+        /// The granularity of the mapping.
+        granularity: MapGranularity,
+    },
+    /// A copy-on-write mapping was added with [`AddrSpace::map_cow`].
+    MapCoW {
+        /// The first guest physical address.
+        start: GuestPhysAddr,
+        /// The host physical address of the template of `start`.
+        paddr: PhysAddr,
+        /// The size of the mapping.
+        size: usize,
+        /// The mapping flags.
+        flags: MappingFlags,
+    },
+    /// A range was unmapped with [`AddrSpace::unmap`].
+    Unmap {
+        /// The first guest physical address.
+        start: GuestPhysAddr,
+        /// The size of the range.
+        size: usize,
+    },
+    /// The flags of a range were changed with
+    /// [`AddrSpace::protect_with_policy`].
+    Protect {
+        /// The first guest physical address.
+        start: GuestPhysAddr,
+        /// The size of the range.
+        size: usize,
+        /// The new flags.
+        flags: MappingFlags,
+    },
+    /// A trap page was mapped with [`AddrSpace::map_trap_page`].
+    MapTrapPage {
+        /// The guest physical address of the page.
+        gpa: GuestPhysAddr,
+        /// The host physical address it is mapped to.
+        hpa: PhysAddr,
+        /// The mapping flags.
+        flags: MappingFlags,
+    },
+    /// The areas were removed with [`AddrSpace::clear`].
+    Clear,
+    /// A page was moved to a new frame with [`AddrSpace::migrate_page`].
+    Migrate {
+        /// The guest physical address of the page.
+        gpa: GuestPhysAddr,
+    },
+    /// A range was compacted with [`AddrSpace::defragment`].
+    Defragment {
+        /// The first guest physical address.
+        start: GuestPhysAddr,
+        /// The size of the range.
+        size: usize,
+    },
+    /// The backing storage of a range was changed with
+    /// [`AddrSpace::convert_area`].
+    Convert {
+        /// The range converted.
+        range: GuestPhysAddrRange,
+        /// The new backing storage.
+        to: BackendKind,
+    },
+    /// Adjacent areas were merged with [`AddrSpace::merge_adjacent_areas`].
+    MergeAreas,
+    /// The linear areas of a range were remapped with
+    /// [`AddrSpace::rebuild_linear_with_huge`].
+    RebuildLinear {
+        /// The range rebuilt.
+        range: GuestPhysAddrRange,
+        /// Whether huge pages were allowed.
+        allow_huge: bool,
+    },
+    /// The copy-on-write pages of a range were reverted with
+    /// [`AddrSpace::revert_cow`].
+    RevertCoW {
+        /// The range reverted.
+        range: GuestPhysAddrRange,
+    },
+    /// Pages were released by the guest with
+    /// [`AddrSpace::guest_release_pages`].
+    Release {
+        /// The pages, as given by the guest.
+        pages: &'a [GuestPhysAddr],
+    },
+    /// Pages were taken back with [`AddrSpace::guest_reclaim_pages`].
+    Reclaim {
+        /// The pages, as given by the guest.
+        pages: &'a [GuestPhysAddr],
+    },
+}
+
+/// Receives the [`ReplayRecord`]s of an address space and its accessors,
+/// e.g., to append them to a ring buffer dumped on a crash report.
+pub trait ReplaySink: Send + Sync {
+    /// Called after each successful mutation, in order.
+    fn record(&self, record: &ReplayRecord<'_>);
+}
+
+/// A [`GuestMemoryAccessor`] forwarding to another one, and recording the
+/// bytes written through it to a [`ReplaySink`].
+///
+/// Only the writes of [`GuestMemoryAccessor::write_obj`],
+/// [`GuestMemoryAccessor::write_buffer`] and
+/// [`GuestMemoryAccessor::write_all_at`] are recorded: memory written
+/// through an address translated with
+/// [`GuestMemoryAccessor::translate_and_get_limit`] is not, and should be
+/// reported with [`ReplaySink::record`] by the caller.
+pub struct RecordingAccessor<'a, A: GuestMemoryAccessor> {
+    inner: &'a A,
+    sink: &'a dyn ReplaySink,
+}
+
+impl<'a, A: GuestMemoryAccessor> RecordingAccessor<'a, A> {
+    /// Creates an accessor to `inner` recording its writes to `sink`.
+    pub fn new(inner: &'a A, sink: &'a dyn ReplaySink) -> Self {
+        Self { inner, sink }
+    }
+}
+
+impl<A: GuestMemoryAccessor> GuestMemoryAccessor for RecordingAccessor<'_, A> {
+    fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        self.inner.translate_and_get_limit(guest_addr)
+    }
+
+    fn write_obj<V: Copy>(&self, guest_addr: GuestPhysAddr, val: V) -> AxResult<()> {
+        self.inner.write_obj(guest_addr, val)?;
+        // The object may have padding, so the bytes are read back from guest
+        // memory, where the whole object was just written.
+        let (host_addr, _) = self
+            .inner
+            .translate_and_get_limit(guest_addr)
+            .ok_or(AxError::InvalidInput)?;
+        let data = unsafe {
+            core::slice::from_raw_parts(host_addr.as_usize() as *const u8, size_of::<V>())
+        };
+        self.sink.record(&ReplayRecord::Write {
+            gpa: guest_addr,
+            data,
+        });
+        Ok(())
+    }
+
+    fn write_buffer(&self, guest_addr: GuestPhysAddr, buffer: &[u8]) -> AxResult<()> {
+        self.inner.write_buffer(guest_addr, buffer)?;
+        self.sink.record(&ReplayRecord::Write {
+            gpa: guest_addr,
+            data: buffer,
+        });
+        Ok(())
+    }
+
+    /// Records the bytes written before a hole too.
+    fn write_all_at(
+        &self,
+        guest_addr: GuestPhysAddr,
+        buffer: &[u8],
+    ) -> Result<(), PartialTransfer> {
+        let res = self.inner.write_all_at(guest_addr, buffer);
+        let transferred = res
+            .err()
+            .map_or(buffer.len(), |partial| partial.transferred);
+        if transferred > 0 {
+            self.sink.record(&ReplayRecord::Write {
+                gpa: guest_addr,
+                data: &buffer[..transferred],
+            });
+        }
+        res
+    }
+
+    fn mark_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+        self.inner.mark_dirty(guest_addr, len)
+    }
+
+    fn write_barrier(&self) {
+        self.inner.write_barrier()
+    }
+
+    fn read_barrier(&self) {
+        self.inner.read_barrier()
+    }
+
+    fn flush_wc(&self, guest_addr: GuestPhysAddr, len: usize) {
+        self.inner.flush_wc(guest_addr, len)
+    }
+
+    fn misaligned_policy(&self) -> MisalignedPolicy {
+        self.inner.misaligned_policy()
+    }
+
+    fn allows_access(&self, guest_addr: GuestPhysAddr, access: MappingFlags) -> bool {
+        self.inner.allows_access(guest_addr, access)
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Records the mapping mutations of the address space to `sink` from now
+    /// on, or stops recording if `sink` is `None`.
+    pub fn set_replay_sink(&mut self, sink: Option<Box<dyn ReplaySink>>) {
+        self.layout.replay_sink = sink;
+    }
+
+    /// Records `record` to the replay sink, if any.
+    pub(crate) fn record(&self, record: ReplayRecord<'_>) {
+        if let Some(sink) = &self.layout.replay_sink {
+            sink.record(&record);
+        }
+    }
+
+    /// Runs `f` without recording, for mutations recorded as a whole rather
+    /// than as the mutations they are built on.
+    pub(crate) fn unrecorded<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let sink = self.layout.replay_sink.take();
+        let res = f(self);
+        self.layout.replay_sink = sink;
+        res
+    }
+}
+
+/// Applies the records of `log` to `aspace`, in order.
+///
+/// Allocation mappings get new frames, while linear and copy-on-write
+/// mappings are re-created to their recorded host addresses, which must thus
+/// be valid in the replaying environment. Writes to pages of lazily allocated
+/// areas fault them in first, as do writes to pages of copy-on-write areas
+/// still mapped to their template. Migrated pages are moved to new frames
+/// too.
+///
+/// Stops at the first record that fails to apply, and returns its error.
+pub fn replay<'a, H: PagingHandler + AxMmHal>(
+    log: impl IntoIterator<Item = ReplayRecord<'a>>,
+    aspace: &mut AddrSpace<H>,
+) -> AxResult {
+    for (i, record) in log.into_iter().enumerate() {
+        let res = match record {
+            ReplayRecord::Write { gpa, data } => replay_write(aspace, gpa, data),
+            ReplayRecord::MapLinear {
+                start,
+                paddr,
+                size,
+                flags,
+                granularity,
+            } => aspace
+                .try_map_linear(start, paddr, size, flags, granularity)
+                .map_err(|err| err.error),
+            ReplayRecord::MapAlloc {
+                start,
+                size,
+                flags,
+                populate,
+                granularity,
+            } => aspace.map_alloc_with_granularity(start, size, flags, populate, granularity),
+            ReplayRecord::MapCoW {
+                start,
+                paddr,
+                size,
+                flags,
+            } => aspace.map_cow(start, paddr, size, flags),
+            ReplayRecord::Unmap { start, size } => aspace.unmap(start, size).map(|_| ()),
+            ReplayRecord::Protect { start, size, flags } => aspace
+                .protect_with_policy(start, size, flags, ProtectPolicy::SkipHoles)
+                .map_err(Into::into),
+            ReplayRecord::MapTrapPage { gpa, hpa, flags } => aspace.map_trap_page(gpa, hpa, flags),
+            ReplayRecord::Clear => aspace.clear(),
+            ReplayRecord::Migrate { gpa } => PhysFrame::alloc()
+                .and_then(|frame| aspace.migrate_page(gpa, frame))
+                .map(drop),
+            ReplayRecord::Defragment { start, size } => aspace.defragment(start, size).map(drop),
+            ReplayRecord::Convert { range, to } => aspace.convert_area(range, to),
+            ReplayRecord::MergeAreas => aspace.merge_adjacent_areas().map(drop),
+            ReplayRecord::RebuildLinear { range, allow_huge } => {
+                aspace.rebuild_linear_with_huge(range, allow_huge)
+            }
+            ReplayRecord::RevertCoW { range } => aspace.revert_cow(range).map(drop),
+            ReplayRecord::Release { pages } => aspace.guest_release_pages(pages).map(drop),
+            ReplayRecord::Reclaim { pages } => aspace.guest_reclaim_pages(pages).map(drop),
+        };
+        if let Err(err) = res {
+            warn!("replay: record {i} ({record:?}) failed: {err:?}");
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn replay_write<H: PagingHandler>(
+    aspace: &mut AddrSpace<H>,
+    gpa: GuestPhysAddr,
+    data: &[u8],
+) -> AxResult {
+    for page in checked_range(gpa, data.len())?.pages() {
+        if (aspace.translate(page).is_none() || aspace.is_cow_shared(page))
+            && !aspace.handle_page_fault(page, MappingFlags::WRITE)
+        {
+            return Err(AxError::BadAddress);
+        }
+    }
+    let bufs = aspace
+        .translated_byte_buffer(gpa, data.len())
+        .ok_or(AxError::BadAddress)?;
+    let mut rest = data;
+    for buf in bufs {
+        let (head, tail) = rest.split_at(buf.len());
+        buf.copy_from_slice(head);
+        rest = tail;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use axin::axin;
+    use page_table_multiarch::PageSize;
+    use spin::Mutex;
+
+    #[derive(Clone, Default)]
+    struct LogSink(Arc<Mutex<Vec<ReplayRecord<'static>>>>);
+
+    impl ReplaySink for LogSink {
+        fn record(&self, record: &ReplayRecord<'_>) {
+            let record = match *record {
+                ReplayRecord::Write { gpa, data } => ReplayRecord::Write {
+                    gpa,
+                    data: data.to_vec().leak(),
+                },
+                ReplayRecord::MapLinear {
+                    start,
+                    paddr,
+                    size,
+                    flags,
+                    granularity,
+                } => ReplayRecord::MapLinear {
+                    start,
+                    paddr,
+                    size,
+                    flags,
+                    granularity,
+                },
+                ReplayRecord::MapAlloc {
+                    start,
+                    size,
+                    flags,
+                    populate,
+                    granularity,
+                } => ReplayRecord::MapAlloc {
+                    start,
+                    size,
+                    flags,
+                    populate,
+                    granularity,
+                },
+                ReplayRecord::MapCoW {
+                    start,
+                    paddr,
+                    size,
+                    flags,
+                } => ReplayRecord::MapCoW {
+                    start,
+                    paddr,
+                    size,
+                    flags,
+                },
+                ReplayRecord::Unmap { start, size } => ReplayRecord::Unmap { start, size },
+                ReplayRecord::Protect { start, size, flags } => {
+                    ReplayRecord::Protect { start, size, flags }
+                }
+                ReplayRecord::MapTrapPage { gpa, hpa, flags } => {
+                    ReplayRecord::MapTrapPage { gpa, hpa, flags }
+                }
+                ReplayRecord::Clear => ReplayRecord::Clear,
+                ReplayRecord::Migrate { gpa } => ReplayRecord::Migrate { gpa },
+                ReplayRecord::Defragment { start, size } => {
+                    ReplayRecord::Defragment { start, size }
+                }
+                ReplayRecord::Convert { range, to } => ReplayRecord::Convert { range, to },
+                ReplayRecord::MergeAreas => ReplayRecord::MergeAreas,
+                ReplayRecord::RebuildLinear { range, allow_huge } => {
+                    ReplayRecord::RebuildLinear { range, allow_huge }
+                }
+                ReplayRecord::RevertCoW { range } => ReplayRecord::RevertCoW { range },
+                ReplayRecord::Release { pages } => ReplayRecord::Release {
+                    pages: pages.to_vec().leak(),
+                },
+                ReplayRecord::Reclaim { pages } => ReplayRecord::Reclaim {
+                    pages: pages.to_vec().leak(),
+                },
+            };
+            self.0.lock().push(record);
+        }
+    }
+
+    struct Accessor<'a>(&'a AddrSpace<MockHal>);
+
+    impl GuestMemoryAccessor for Accessor<'_> {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            let (paddr, limit) = self.0.translate_and_get_limit(guest_addr)?;
+            Some((
+                <MockHal as PagingHandler>::phys_to_virt(paddr)
+                    .as_usize()
+                    .into(),
+                limit,
+            ))
+        }
+    }
+
+    fn read(aspace: &AddrSpace<MockHal>, gpa: GuestPhysAddr) -> u64 {
+        Accessor(aspace).read_obj(gpa).unwrap()
+    }
+
+    /// The areas of `aspace`, and the state of each of its pages.
+    #[allow(clippy::type_complexity)]
+    fn layout(
+        aspace: &AddrSpace<MockHal>,
+    ) -> (
+        Vec<(
+            GuestPhysAddrRange,
+            MappingFlags,
+            BackendKind,
+            MapGranularity,
+        )>,
+        Vec<(bool, bool, bool)>,
+    ) {
+        let areas = aspace
+            .layout
+            .areas()
+            .iter()
+            .map(|area| {
+                (
+                    area.va_range(),
+                    area.flags(),
+                    BackendKind::of(area.backend(), area.start()),
+                    area.backend().granularity(),
+                )
+            })
+            .collect();
+        let pages = GuestPhysAddrRange::from_start_size(aspace.base(), aspace.size())
+            .pages()
+            .map(|gpa| {
+                (
+                    aspace.translate(gpa).is_some(),
+                    aspace.is_lazy_placeholder(gpa),
+                    aspace.is_ballooned(gpa),
+                )
+            })
+            .collect();
+        (areas, pages)
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_record_replay() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let sink = LogSink::default();
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.set_replay_sink(Some(Box::new(sink.clone())));
+        aspace.map_alloc(base, 0x3000, rw, true).unwrap();
+        aspace.map_alloc(base + 0x4000, 0x1000, rw, false).unwrap();
+        {
+            let accessor = Accessor(&aspace);
+            let recording = RecordingAccessor::new(&accessor, &sink);
+            recording
+                .write_obj(base + 0x8, 0x1122_3344_5566_7788u64)
+                .unwrap();
+            recording.write_buffer(base + 0x1ffc, &[0xaa; 8]).unwrap();
+            // Reads are not recorded.
+            recording.read_obj::<u32>(base).unwrap();
+        }
+        aspace.unmap(base + 0x2000, 0x1000).unwrap();
+        aspace
+            .protect_with_policy(base, 0x1000, MappingFlags::READ, ProtectPolicy::SkipHoles)
+            .unwrap();
+        // Failed mutations are not recorded.
+        assert!(aspace.map_alloc(base, 0x1000, rw, false).is_err());
+        let log = sink.0.lock().clone();
+        assert_eq!(log.len(), 6);
+        assert_eq!(
+            log[2],
+            ReplayRecord::Write {
+                gpa: base + 0x8,
+                data: &0x1122_3344_5566_7788u64.to_le_bytes(),
+            }
+        );
+
+        let mut replayed = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        replay(log.iter().copied(), &mut replayed).unwrap();
+        assert_eq!(read(&replayed, base + 0x8), 0x1122_3344_5566_7788);
+        assert_eq!(read(&replayed, base + 0x1ff8), read(&aspace, base + 0x1ff8));
+        assert!(replayed.translate(base + 0x2000).is_none());
+        assert!(replayed.is_lazy_placeholder(base + 0x4000));
+        assert_eq!(
+            replayed.page_table().query(base).unwrap().1,
+            MappingFlags::READ
+        );
+
+        // Replaying again fails on the first mapping, which already exists.
+        assert!(replay(log.iter().copied(), &mut replayed).is_err());
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_replay_layout_mutations() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let ram = PhysAddr::from(0x40_0000);
+        let sink = LogSink::default();
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.set_replay_sink(Some(Box::new(sink.clone())));
+        aspace.map_alloc(base + 0xf000, 0x1000, rw, false).unwrap();
+        aspace.clear().unwrap();
+
+        aspace.map_linear(base, ram, 0x1000, rw).unwrap();
+        aspace
+            .map_linear(base + 0x1000, ram + 0x1000, 0x1000, rw)
+            .unwrap();
+        assert_eq!(aspace.merge_adjacent_areas().unwrap(), 1);
+        let linear = GuestPhysAddrRange::from_start_size(base, 0x2000);
+        aspace.rebuild_linear_with_huge(linear, false).unwrap();
+
+        aspace.map_alloc(base + 0x2000, 0x2000, rw, false).unwrap();
+        aspace
+            .guest_release_pages(&[base + 0x2000, base + 0x3000])
+            .unwrap();
+        aspace.guest_reclaim_pages(&[base + 0x3000]).unwrap();
+        aspace.map_alloc(base + 0x4000, 0x1000, rw, false).unwrap();
+        aspace.unmap_keep_frames(base + 0x4000, 0x1000).unwrap();
+        aspace.map_alloc(base + 0x5000, 0x1000, rw, false).unwrap();
+        // The contents are copied to the target, which must be real memory.
+        let target = PhysFrame::<MockHal>::alloc().unwrap();
+        let to = BackendKind::Linear {
+            start_paddr: target.start_paddr(),
+        };
+        let converted = GuestPhysAddrRange::from_start_size(base + 0x5000, 0x1000);
+        aspace.convert_area(converted, to).unwrap();
+        aspace
+            .map_trap_page(base + 0x6000, ram + 0x6000, rw)
+            .unwrap();
+
+        let template = PhysFrame::<MockHal>::alloc_zero().unwrap();
+        aspace
+            .map_cow(base + 0x7000, template.start_paddr(), 0x2000, rw)
+            .unwrap();
+        assert!(aspace.handle_page_fault(base + 0x7000, MappingFlags::WRITE));
+        let cow = GuestPhysAddrRange::from_start_size(base + 0x7000, 0x1000);
+        assert_eq!(aspace.revert_cow(cow).unwrap(), 1);
+
+        let log = sink.0.lock().clone();
+        assert!(log.contains(&ReplayRecord::Clear));
+        assert!(log.contains(&ReplayRecord::Convert {
+            range: converted,
+            to
+        }));
+        // Recorded as a whole only.
+        assert!(!log.iter().any(|record| matches!(
+            record,
+            ReplayRecord::MapLinear { start, .. } if *start == base + 0x5000
+        )));
+        assert!(log.contains(&ReplayRecord::Unmap {
+            start: base + 0x4000,
+            size: 0x1000
+        }));
+
+        // The host memory of the linear areas can only be claimed once.
+        let expected = layout(&aspace);
+        drop(aspace);
+        let mut replayed = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        replay(log.iter().copied(), &mut replayed).unwrap();
+        assert_eq!(layout(&replayed), expected);
+        assert_eq!(
+            replayed.granularity_at(base),
+            Some(MapGranularity::new(PageSize::Size4K, PageSize::Size4K))
+        );
+        assert_eq!(replayed.ballooned_pages(), 1);
+        assert_eq!(
+            replayed.translate(base + 0x7000),
+            Some(template.start_paddr())
+        );
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_replay_migrations() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let sink = LogSink::default();
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.set_replay_sink(Some(Box::new(sink.clone())));
+        aspace.map_alloc(base, 0x2000, rw, false).unwrap();
+        assert_eq!(aspace.defragment(base, 0x2000).unwrap(), 2);
+        drop(
+            aspace
+                .migrate_page(base + 0x1000, PhysFrame::alloc().unwrap())
+                .unwrap(),
+        );
+
+        let log = sink.0.lock().clone();
+        // The pages moved by the defragmentation are not recorded one by one.
+        assert_eq!(
+            log[1..],
+            [
+                ReplayRecord::Defragment {
+                    start: base,
+                    size: 0x2000
+                },
+                ReplayRecord::Migrate { gpa: base + 0x1000 },
+            ]
+        );
+
+        let mut replayed = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        replay(log.iter().copied(), &mut replayed).unwrap();
+        assert_eq!(layout(&replayed), layout(&aspace));
+        assert_ne!(replayed.translate(base), aspace.translate(base));
+    }
+}
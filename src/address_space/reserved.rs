@@ -0,0 +1,130 @@
+//! Guest physical ranges that must never be mapped, e.g., the hypervisor's
+//! own trampoline placed in guest space, or holes mandated by the platform.
+//!
+//! Unlike the MMIO ranges of [`AddrSpace::reserve_mmio`], reserved ranges
+//! cannot be released. They are kept as a map of ranges, so their cost does
+//! not grow with the extent of the address space.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Permanently reserves `range`: mapping any page of it fails with
+    /// [`AxError::AddrInUse`] from then on.
+    ///
+    /// `range` must be non-empty, page-aligned and inside the address space.
+    /// Fails with [`AxError::AlreadyExists`] if it overlaps a mapped area.
+    /// Reserving a range again, or one overlapping another reserved range,
+    /// is allowed.
+    ///
+    /// [`AxError::AddrInUse`]: axerrno::AxError::AddrInUse
+    /// [`AxError::AlreadyExists`]: axerrno::AxError::AlreadyExists
+    pub fn add_reserved_range(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        self.check_unsealed()?;
+        if range.is_empty() || !self.layout.contains_range(range) {
+            return ax_err!(InvalidInput, "reserved range out of range");
+        }
+        if !range.start.is_aligned(PAGE_SIZE) || !range.end.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "reserved range not aligned");
+        }
+        if self.layout.areas().overlaps(range) {
+            return ax_err!(AlreadyExists, "reserved range overlaps a mapped area");
+        }
+        self.layout.reserved.set(range, true);
+        Ok(())
+    }
+
+    /// Returns whether `gpa` lies in a permanently reserved range.
+    pub fn is_reserved(&self, gpa: GuestPhysAddr) -> bool {
+        self.layout.reserved.get(gpa)
+    }
+
+    /// Returns the permanently reserved ranges, merged where adjacent, in
+    /// ascending order.
+    pub fn reserved_ranges(&self) -> Vec<GuestPhysAddrRange> {
+        let mut ranges: Vec<GuestPhysAddrRange> = Vec::new();
+        for (range, _) in self.layout.reserved.iter() {
+            match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        }
+        ranges
+    }
+
+    /// Fails with `AddrInUse` if `[start, start + size)` overlaps a
+    /// permanently reserved range.
+    pub(crate) fn check_reserved_overlap(&self, start: GuestPhysAddr, size: usize) -> AxResult {
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        if let Some((reserved, _)) = self.layout.reserved.overlapping(range).first() {
+            warn!("mapping [{start:?}, +{size:#x}) overlaps the reserved range {reserved:?}");
+            return ax_err!(AddrInUse, "range overlaps a permanently reserved range");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_reserved_ranges() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x2000, rw, false).unwrap();
+        let trampoline = GuestPhysAddrRange::from_start_size(base + 0x4000, 0x2000);
+        aspace.add_reserved_range(trampoline).unwrap();
+        assert_eq!(
+            aspace.add_reserved_range(GuestPhysAddrRange::from_start_size(base, 0x1000)),
+            Err(AxError::AlreadyExists)
+        );
+        assert!(aspace.is_reserved(base + 0x5fff));
+        assert!(!aspace.is_reserved(base + 0x6000));
+
+        assert_eq!(
+            aspace.map_alloc(base + 0x2000, 0x3000, rw, true),
+            Err(AxError::AddrInUse)
+        );
+        assert_eq!(
+            aspace.map_linear(base + 0x5000, PhysAddr::from(0x5000), 0x1000, rw),
+            Err(AxError::AddrInUse)
+        );
+        assert_eq!(aspace.reserve_mmio(trampoline), Err(AxError::AddrInUse));
+        aspace.map_alloc(base + 0x2000, 0x2000, rw, true).unwrap();
+
+        // Reserved pages of an added window are tracked too.
+        let window = GuestPhysAddrRange::from_start_size(base + 0x10000, 0x2000);
+        aspace.extend_va_range(window).unwrap();
+        aspace
+            .add_reserved_range(GuestPhysAddrRange::from_start_size(window.start, 0x1000))
+            .unwrap();
+        aspace
+            .add_reserved_range(GuestPhysAddrRange::from_start_size(base + 0x6000, 0x1000))
+            .unwrap();
+        assert_eq!(
+            aspace.reserved_ranges(),
+            [
+                GuestPhysAddrRange::from_start_size(base + 0x4000, 0x3000),
+                GuestPhysAddrRange::from_start_size(window.start, 0x1000),
+            ]
+        );
+        assert_eq!(
+            aspace.map_alloc(window.start, 0x2000, rw, false),
+            Err(AxError::AddrInUse)
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! The reverse map from host frames to guest pages, e.g., for host memory
+//! error (MCE, SEA) handling to find the guest page backed by a failing
+//! frame.
+//!
+//! Linear areas are resolved from their offsets, so only the frames of
+//! allocation areas are indexed, and only while the reverse map is enabled.
+
+use alloc::collections::BTreeMap;
+
+#[cfg(feature = "frame-ownership")]
+use memory_addr::PhysAddrRange;
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE};
+
+/// The guest page backed by a host frame, returned by
+/// [`AddrSpace::reverse_lookup`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReverseMapping {
+    /// The guest physical address mapped to the looked up host address.
+    pub gpa: GuestPhysAddr,
+    /// The range of the area containing `gpa`.
+    pub area: GuestPhysAddrRange,
+}
+
+/// The frames of allocation areas, indexed both ways. Each mapped page, huge
+/// or not, has one entry keyed by its first address.
+#[derive(Default)]
+pub(crate) struct ReverseMap {
+    by_host: BTreeMap<PhysAddr, (GuestPhysAddr, usize)>,
+    by_guest: BTreeMap<GuestPhysAddr, (PhysAddr, usize)>,
+    /// The address space claiming the indexed frames in the frame ownership
+    /// table.
+    #[cfg(feature = "frame-ownership")]
+    owner: Option<usize>,
+}
+
+impl ReverseMap {
+    /// Creates a reverse map claiming the frames it indexes for `space`.
+    #[cfg(feature = "frame-ownership")]
+    pub fn owned_by(space: usize) -> Self {
+        Self {
+            owner: Some(space),
+            ..Default::default()
+        }
+    }
+
+    fn insert(&mut self, gpa: GuestPhysAddr, hpa: PhysAddr, size: usize) {
+        #[cfg(feature = "frame-ownership")]
+        if let Some(space) = self.owner {
+            let range = PhysAddrRange::from_start_size(hpa, size);
+            if super::ownership::claim(range, super::ownership::FrameOwner { space, gpa }).is_err()
+            {
+                error!("allocated frame {hpa:?} mapped at {gpa:?} is owned by another mapping");
+            }
+        }
+        self.by_host.insert(hpa, (gpa, size));
+        self.by_guest.insert(gpa, (hpa, size));
+    }
+
+    /// Removes all pages, without releasing their frames.
+    pub fn clear(&mut self) {
+        self.by_host.clear();
+        self.by_guest.clear();
+    }
+
+    /// Removes the pages overlapping `range`.
+    fn remove(&mut self, range: GuestPhysAddrRange) {
+        while let Some((&gpa, &(hpa, size))) = self.by_guest.range(..range.end).next_back() {
+            if gpa + size <= range.start {
+                break;
+            }
+            self.by_guest.remove(&gpa);
+            self.by_host.remove(&hpa);
+            #[cfg(feature = "frame-ownership")]
+            if let Some(space) = self.owner {
+                super::ownership::release(space, PhysAddrRange::from_start_size(hpa, size));
+            }
+        }
+    }
+
+    fn lookup(&self, hpa: PhysAddr) -> Option<GuestPhysAddr> {
+        let (&start, &(gpa, size)) = self.by_host.range(..=hpa).next_back()?;
+        (hpa < start + size).then(|| gpa + (hpa - start))
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Starts maintaining the reverse map of the frames of allocation areas,
+    /// indexing the frames mapped so far.
+    pub fn enable_reverse_map(&mut self) {
+        if self.state.rmap.is_some() {
+            return;
+        }
+        self.state.rmap = Some(ReverseMap::default());
+        let windows: alloc::vec::Vec<_> = self.layout.windows().collect();
+        for window in windows {
+            self.rmap_update(window.start, window.size());
+        }
+    }
+
+    /// Stops maintaining the reverse map, and frees it.
+    ///
+    /// Does nothing with the `frame-ownership` feature, which needs the
+    /// reverse map to track the frames of allocation areas.
+    pub fn disable_reverse_map(&mut self) {
+        #[cfg(not(feature = "frame-ownership"))]
+        {
+            self.state.rmap = None;
+        }
+    }
+
+    /// Returns the guest page mapped to the host physical address `hpa`.
+    ///
+    /// Frames of linear areas are always found, while frames of allocation
+    /// areas are only found while the reverse map is enabled (see
+    /// [`AddrSpace::enable_reverse_map`]). If a frame is mapped at several
+    /// guest addresses, e.g., by aliasing linear areas, any of them is
+    /// returned.
+    pub fn reverse_lookup(&self, hpa: PhysAddr) -> Option<ReverseMapping> {
+        let mapping = |gpa: GuestPhysAddr| {
+            let area = self.layout.find_area(gpa)?;
+            (self.translate_fast(gpa) == Some(hpa)).then(|| ReverseMapping {
+                gpa,
+                area: area.va_range(),
+            })
+        };
+        if let Some(gpa) = self.state.rmap.as_ref().and_then(|rmap| rmap.lookup(hpa))
+            && let Some(found) = mapping(gpa)
+        {
+            return Some(found);
+        }
+        self.layout
+            .areas()
+            .iter()
+            .find_map(|area| match *area.backend() {
+                Backend::Linear { pa_va_offset, .. } | Backend::CoW { pa_va_offset } => {
+                    let gpa = GuestPhysAddr::from_usize(hpa.as_usize().wrapping_add(pa_va_offset));
+                    area.va_range()
+                        .contains(gpa)
+                        .then(|| mapping(gpa))
+                        .flatten()
+                }
+                Backend::Alloc { .. } => None,
+            })
+    }
+
+    /// Re-indexes the owned frames of allocation and copy-on-write areas in
+    /// `[start, start + size)` after its mappings changed. Does nothing if
+    /// the reverse map is disabled.
+    pub(crate) fn rmap_update(&mut self, start: GuestPhysAddr, size: usize) {
+        let Some(rmap) = &mut self.state.rmap else {
+            return;
+        };
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        rmap.remove(range);
+        for area in self.layout.areas_overlapping(range) {
+            if matches!(area.backend(), Backend::Linear { .. }) {
+                continue;
+            }
+            let mut addr = area.start().max(range.start).align_down(PAGE_SIZE);
+            let end = area.end().min(range.end);
+            while addr < end {
+                let Ok((hpa, _, page_size)) = self.state.pt.query(addr) else {
+                    addr += PAGE_SIZE;
+                    continue;
+                };
+                let page_start = addr.align_down(page_size);
+                let size = usize::from(page_size);
+                if !area.backend().owns_frame(addr, hpa) {
+                    addr = page_start + size;
+                    continue;
+                }
+                // A huge page straddling `range` was removed too.
+                rmap.remove(GuestPhysAddrRange::from_start_size(page_start, size));
+                rmap.insert(page_start, hpa - (addr - page_start), size);
+                addr = page_start + size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_reverse_lookup() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        aspace.map_alloc(base + 0x2000, 0x2000, rw, false).unwrap();
+        let linear = GuestPhysAddrRange::from_start_size(base + 0x8000, 0x2000);
+        aspace
+            .map_linear(linear.start, PhysAddr::from(0x40_0000), 0x2000, rw)
+            .unwrap();
+        let populated = aspace.translate(base + 0x1000).unwrap();
+
+        // Linear areas are resolved without the reverse map.
+        assert_eq!(
+            aspace.reverse_lookup(PhysAddr::from(0x40_1234)),
+            Some(ReverseMapping {
+                gpa: base + 0x9234,
+                area: linear,
+            })
+        );
+        // The frame ownership table keeps the reverse map enabled.
+        #[cfg(not(feature = "frame-ownership"))]
+        assert_eq!(aspace.reverse_lookup(populated), None);
+
+        aspace.enable_reverse_map();
+        assert_eq!(
+            aspace.reverse_lookup(populated + 0x10).map(|m| m.gpa),
+            Some(base + 0x1010)
+        );
+        // Frames faulted in later are indexed.
+        assert!(aspace.handle_page_fault(base + 0x3000, MappingFlags::WRITE));
+        let faulted = aspace.translate(base + 0x3000).unwrap();
+        assert_eq!(
+            aspace.reverse_lookup(faulted),
+            Some(ReverseMapping {
+                gpa: base + 0x3000,
+                area: GuestPhysAddrRange::from_start_size(base + 0x2000, 0x2000),
+            })
+        );
+
+        // And unmapped frames are forgotten.
+        aspace.unmap(base + 0x2000, 0x2000).unwrap();
+        assert_eq!(aspace.reverse_lookup(faulted), None);
+        assert!(aspace.reverse_lookup(populated).is_some());
+        assert_eq!(aspace.reverse_lookup(PhysAddr::from(0x40_2000)), None);
+    }
+}
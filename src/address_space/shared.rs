@@ -0,0 +1,174 @@
+//! Regions shared between the hypervisor and the guest.
+
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::{MemoryAddr, align_up_4k};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::AddrSpace;
+use crate::{AxMmHal, GuestPhysAddr, HostPhysAddr, PAGE_SIZE};
+
+/// A region of host memory holding a `T`, mapped into the guest with
+/// [`AddrSpace::map_shared_region`], e.g., the rings of a paravirtual port.
+///
+/// The region is physically contiguous and mapped linearly as normal
+/// (write-back, cache-coherent) memory, so the guest sees it at
+/// [`SharedRegion::gpa`] while the hypervisor accesses it through
+/// [`SharedRegion::as_ptr`]. Its frames are never moved nor reclaimed by the
+/// address space.
+///
+/// The frames are only freed by [`AddrSpace::unmap_shared_region`]. A region
+/// dropped while still mapped is leaked, as the guest can still access it.
+pub struct SharedRegion<H: AxMmHal, T> {
+    gpa: GuestPhysAddr,
+    paddr: HostPhysAddr,
+    size: usize,
+    _marker: PhantomData<(fn() -> H, T)>,
+}
+
+impl<H: AxMmHal, T> SharedRegion<H, T> {
+    /// Returns the guest physical address of the region.
+    pub const fn gpa(&self) -> GuestPhysAddr {
+        self.gpa
+    }
+
+    /// Returns the host physical address of the region.
+    pub const fn host_paddr(&self) -> HostPhysAddr {
+        self.paddr
+    }
+
+    /// Returns the size of the region, i.e., the size of `T` rounded up to
+    /// whole pages.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a host pointer to the shared `T`.
+    ///
+    /// The guest may access the region concurrently, so accesses through the
+    /// pointer should be volatile or atomic.
+    pub fn as_ptr(&self) -> *mut T {
+        H::phys_to_virt(self.paddr).as_mut_ptr() as *mut T
+    }
+
+    /// Returns a reference to the shared `T`.
+    ///
+    /// # Safety
+    ///
+    /// The guest may write anything to the region at any time, so `T` must
+    /// be valid for every bit pattern and tolerate concurrent modification,
+    /// e.g., be made of atomics only.
+    pub unsafe fn view(&self) -> &T {
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<H: PagingHandler + AxMmHal> AddrSpace<H> {
+    /// Allocates a zeroed, physically contiguous region large enough for a
+    /// `T`, and maps it at `gpa` with read and write permissions.
+    ///
+    /// This is the usual setup of host-guest shared rings: the guest is told
+    /// the returned [`SharedRegion::gpa`], and the hypervisor accesses the
+    /// rings through the typed host view.
+    ///
+    /// `gpa` must be page-aligned, and `T` must not be zero-sized nor aligned
+    /// to more than a page. Fails with `NoMemory` if no contiguous frames are
+    /// available, or with the error of [`AddrSpace::map_linear`].
+    pub fn map_shared_region<T>(&mut self, gpa: GuestPhysAddr) -> AxResult<SharedRegion<H, T>> {
+        if size_of::<T>() == 0 || align_of::<T>() > PAGE_SIZE {
+            return ax_err!(InvalidInput, "unsupported shared region type");
+        }
+        if !gpa.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        let size = align_up_4k(size_of::<T>());
+        let num_frames = size / PAGE_SIZE;
+        let paddr = <H as AxMmHal>::alloc_frames(num_frames, PAGE_SIZE)
+            .ok_or_else(|| ax_err_type!(NoMemory, "no contiguous frames for shared region"))?;
+        unsafe {
+            core::ptr::write_bytes(<H as AxMmHal>::phys_to_virt(paddr).as_mut_ptr(), 0, size)
+        };
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        if let Err(err) = self.map_linear(gpa, paddr, size, flags) {
+            <H as AxMmHal>::dealloc_frames(paddr, num_frames);
+            return Err(err);
+        }
+        Ok(SharedRegion {
+            gpa,
+            paddr,
+            size,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Unmaps a region mapped by [`AddrSpace::map_shared_region`], and frees
+    /// its frames.
+    ///
+    /// If unmapping fails, the region stays mapped and its frames are leaked.
+    pub fn unmap_shared_region<T>(&mut self, region: SharedRegion<H, T>) -> AxResult {
+        match self.translate(region.gpa) {
+            Some(paddr) if paddr == region.paddr => {}
+            _ => return ax_err!(InvalidInput, "region not mapped in this address space"),
+        }
+        self.unmap(region.gpa, region.size)?;
+        <H as AxMmHal>::dealloc_frames(region.paddr, region.size / PAGE_SIZE);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEALLOC_COUNT, MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    #[repr(C)]
+    struct Ring {
+        head: AtomicU32,
+        tail: AtomicU32,
+        slots: [AtomicU32; 1024],
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_shared_region() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let gpa = base + 0x2000;
+        let ring = aspace.map_shared_region::<Ring>(gpa).unwrap();
+        assert_eq!(ring.size(), 0x2000);
+        assert_eq!(
+            aspace.translate(gpa + 0x1004),
+            Some(ring.host_paddr() + 0x1004)
+        );
+
+        let view = unsafe { ring.view() };
+        assert_eq!(view.slots[1023].load(Ordering::Relaxed), 0);
+        view.head.store(0x1234_5678, Ordering::Relaxed);
+        // The guest sees the host writes, and conversely.
+        let mut bufs = aspace.translated_byte_buffer(gpa, 8).unwrap();
+        assert_eq!(bufs[0][..4], 0x1234_5678u32.to_le_bytes());
+        bufs[0][4] = 0x42;
+        assert_eq!(view.tail.load(Ordering::Relaxed), 0x42);
+
+        // Overlapping an existing mapping: the frames are freed.
+        let deallocs = DEALLOC_COUNT.load(Ordering::SeqCst);
+        assert_eq!(
+            aspace.map_shared_region::<Ring>(gpa + 0x1000).err(),
+            Some(AxError::AlreadyExists)
+        );
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), deallocs + 2);
+        assert_eq!(
+            aspace.map_shared_region::<()>(base).err(),
+            Some(AxError::InvalidInput)
+        );
+
+        aspace.unmap_shared_region(ring).unwrap();
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), deallocs + 4);
+        assert_eq!(aspace.translate(gpa), None);
+    }
+}
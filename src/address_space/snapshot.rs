@@ -0,0 +1,269 @@
+//! Checkpoints of address spaces, e.g., to save a VM and restore it later.
+//!
+//! A snapshot describes the layout of an address space: its windows, its
+//! areas, and its MMIO and reserved ranges. Together with the contents of
+//! the pages owned by the address space, it rebuilds an identical address
+//! space with [`AddrSpace::restore`]. Unlike
+//! [`AddrSpace::export_state`], nothing of the original is reused, so a
+//! snapshot can be restored several times, or after the original is gone.
+//!
+//! The host memory of linear areas and the templates of copy-on-write areas
+//! are not part of the snapshot: they are owned by the caller, and must be
+//! at the same host addresses when restoring.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{
+    AddrSpace, AreaAttributes, Backend, BackendKind, MapGranularity, MmioRegion, SealMode,
+};
+use crate::{GuestPageIter, GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE};
+
+/// An area of an [`AddrSpaceSnapshot`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AreaSnapshot {
+    /// The guest physical range of the area.
+    pub range: GuestPhysAddrRange,
+    /// The mapping flags of the area.
+    pub flags: MappingFlags,
+    /// Where the pages of the area come from.
+    pub backend: BackendKind,
+    /// Whether the frames of a [`BackendKind::Alloc`] area are allocated up
+    /// front rather than on demand.
+    pub populate: bool,
+    /// The granularity the area is mapped with.
+    pub granularity: MapGranularity,
+    /// The attributes of the area.
+    pub attributes: AreaAttributes,
+}
+
+/// The layout of an address space, returned by [`AddrSpace::snapshot`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddrSpaceSnapshot {
+    /// The windows of the address space, the initial one first, see
+    /// [`AddrSpace::va_ranges`].
+    pub windows: Vec<GuestPhysAddrRange>,
+    /// The areas, in ascending address order.
+    pub areas: Vec<AreaSnapshot>,
+    /// The ranges reserved for MMIO.
+    pub mmio_regions: Vec<GuestPhysAddrRange>,
+    /// The MMIO ranges registered with a handler, among `mmio_regions`.
+    pub mmio_handlers: Vec<MmioRegion>,
+    /// The ranges reserved with [`AddrSpace::add_reserved_range`].
+    pub reserved: Vec<GuestPhysAddrRange>,
+    /// How the address space is sealed, if it is.
+    pub sealed: Option<SealMode>,
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Describes the layout of the address space, see
+    /// [`AddrSpace::restore`].
+    pub fn snapshot(&self) -> AddrSpaceSnapshot {
+        let areas = self
+            .layout
+            .areas()
+            .iter()
+            .map(|area| {
+                let backend = area.backend();
+                AreaSnapshot {
+                    range: area.va_range(),
+                    flags: area.flags(),
+                    backend: BackendKind::of(backend, area.start()),
+                    populate: matches!(backend, Backend::Alloc { populate: true, .. }),
+                    granularity: backend.granularity(),
+                    attributes: self.area_attributes(area.start()),
+                }
+            })
+            .collect();
+        AddrSpaceSnapshot {
+            windows: self.layout.windows().collect(),
+            areas,
+            mmio_regions: self.mmio_regions().collect(),
+            mmio_handlers: self.registered_mmio().collect(),
+            reserved: self.reserved_ranges(),
+            sealed: self.layout.sealed,
+        }
+    }
+
+    /// Returns the pages owned by the address space with their contents, in
+    /// ascending address order: the pages of allocation areas faulted in so
+    /// far, and the pages of copy-on-write areas copied from their template.
+    ///
+    /// The guest must not run while the pages are read.
+    pub fn snapshot_pages(&self) -> impl Iterator<Item = (GuestPhysAddr, &[u8])> + '_ {
+        self.layout
+            .areas()
+            .iter()
+            .filter(|area| !matches!(area.backend(), Backend::Linear { .. }))
+            .flat_map(move |area| {
+                GuestPageIter::new(area.start(), area.end())
+                    .unwrap()
+                    .filter_map(move |gpa| {
+                        let (hpa, _, _) = self.state.pt.query(gpa).ok()?;
+                        area.backend().owns_frame(gpa, hpa).then(|| {
+                            let ptr = H::phys_to_virt(hpa).as_ptr();
+                            // SAFETY: the frame is owned by the address space.
+                            (gpa, unsafe { core::slice::from_raw_parts(ptr, PAGE_SIZE) })
+                        })
+                    })
+            })
+    }
+
+    /// Builds an address space with the layout of `snapshot`, filling the
+    /// pages given by `pages` as [`AddrSpace::snapshot_pages`] returned
+    /// them. The other pages of allocation areas are zero, and the other
+    /// pages of copy-on-write areas shared with their template.
+    ///
+    /// Fails with `InvalidInput` if `snapshot` has no window, or if a page
+    /// is not a whole page of an allocation or copy-on-write area, or as
+    /// the methods rebuilding the layout. With the `frame-ownership`
+    /// feature, linear areas cannot be restored while the original address
+    /// space still maps them.
+    pub fn restore<'a>(
+        snapshot: &AddrSpaceSnapshot,
+        pages: impl IntoIterator<Item = (GuestPhysAddr, &'a [u8])>,
+    ) -> AxResult<Self> {
+        let Some((first, others)) = snapshot.windows.split_first() else {
+            return ax_err!(InvalidInput, "snapshot without window");
+        };
+        let mut aspace = Self::new_empty(first.start, first.size())?;
+        for &window in others {
+            aspace.extend_va_range(window)?;
+        }
+        for area in &snapshot.areas {
+            let (start, size) = (area.range.start, area.range.size());
+            match area.backend {
+                BackendKind::Linear { start_paddr } => aspace.map_linear_with_granularity(
+                    start,
+                    start_paddr,
+                    size,
+                    area.flags,
+                    area.granularity,
+                )?,
+                BackendKind::Alloc => aspace.map_alloc_with_granularity(
+                    start,
+                    size,
+                    area.flags,
+                    area.populate,
+                    area.granularity,
+                )?,
+                BackendKind::CoW { template_paddr } => {
+                    aspace.map_cow(start, template_paddr, size, area.flags)?
+                }
+            }
+            aspace.set_area_attributes(start, area.attributes)?;
+        }
+        for &range in &snapshot.mmio_regions {
+            match snapshot.mmio_handlers.iter().find(|r| r.range == range) {
+                Some(region) => aspace.register_mmio(range, region.handler_id)?,
+                None => aspace.reserve_mmio(range)?,
+            }
+        }
+        for &range in &snapshot.reserved {
+            aspace.add_reserved_range(range)?;
+        }
+        for (gpa, contents) in pages {
+            let owned = aspace
+                .layout
+                .find_area(gpa)
+                .is_some_and(|area| !matches!(area.backend(), Backend::Linear { .. }));
+            if !owned || !gpa.is_aligned(PAGE_SIZE) || contents.len() != PAGE_SIZE {
+                return ax_err!(InvalidInput, "bad snapshot page");
+            }
+            crate::loader::load_bytes(&mut aspace, gpa, contents)?;
+        }
+        if let Some(mode) = snapshot.sealed {
+            aspace.seal(mode);
+        }
+        Ok(aspace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_snapshot_restore() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let frames = [(); 2].map(|_| MockHal::alloc_frame().unwrap());
+
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x1000, rw, true).unwrap();
+        aspace.map_alloc(base + 0x1000, 0x2000, rw, false).unwrap();
+        aspace
+            .map_cow(base + 0x3000, frames[0], 0x1000, rw)
+            .unwrap();
+        aspace
+            .map_cow(base + 0x4000, frames[1], 0x1000, rw)
+            .unwrap();
+        aspace
+            .map_linear(base + 0x8000, PhysAddr::from(0x40_0000), 0x1000, rw)
+            .unwrap();
+        aspace
+            .set_area_attributes(base + 0x8000, AreaAttributes::PERSISTENT)
+            .unwrap();
+        aspace
+            .register_mmio(
+                GuestPhysAddrRange::from_start_size(base + 0xa000, 0x1000),
+                7,
+            )
+            .unwrap();
+        aspace
+            .add_reserved_range(GuestPhysAddrRange::from_start_size(base + 0xc000, 0x1000))
+            .unwrap();
+        aspace
+            .extend_va_range(GuestPhysAddrRange::from_start_size(base + 0x20000, 0x1000))
+            .unwrap();
+        // Guest writes to the populated page and to a template page.
+        aspace.translated_byte_buffer(base, 1).unwrap()[0][0] = 1;
+        assert!(aspace.handle_page_fault(base + 0x3000, MappingFlags::WRITE));
+        aspace.translated_byte_buffer(base + 0x3000, 1).unwrap()[0][0] = 3;
+        aspace.seal(SealMode::Temporary);
+
+        // Save, and restore once the original is gone.
+        let snapshot = aspace.snapshot();
+        let pages: Vec<_> = aspace
+            .snapshot_pages()
+            .map(|(gpa, contents)| (gpa, contents.to_vec()))
+            .collect();
+        drop(aspace);
+        assert_eq!(
+            pages.iter().map(|(gpa, _)| *gpa).collect::<Vec<_>>(),
+            [base, base + 0x3000]
+        );
+        let pages = pages.iter().map(|(gpa, contents)| (*gpa, &contents[..]));
+        let restored = AddrSpace::<MockHal>::restore(&snapshot, pages).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+        let read = |gpa| restored.translated_byte_buffer(gpa, 1).unwrap()[0][0];
+        assert_eq!([read(base), read(base + 0x3000)], [1, 3]);
+        // Lazy pages stay lazy, the untouched template page shared.
+        assert_eq!(restored.translate(base + 0x1000), None);
+        assert!(!restored.is_cow_shared(base + 0x3000));
+        assert_eq!(restored.translate(base + 0x4000), Some(frames[1]));
+        assert_eq!(
+            restored.translate(base + 0x8000),
+            Some(PhysAddr::from(0x40_0000))
+        );
+
+        // Pages outside of owned areas are rejected.
+        let page = [0; PAGE_SIZE];
+        let layout = AddrSpaceSnapshot {
+            areas: Vec::new(),
+            ..snapshot
+        };
+        assert_eq!(
+            AddrSpace::<MockHal>::restore(&layout, [(base, &page[..])]).err(),
+            Some(AxError::InvalidInput)
+        );
+    }
+}
@@ -0,0 +1,997 @@
+//! Export and import of address space metadata for warm restarts.
+//!
+//! The exported state only contains metadata: the address range and the
+//! windows added to it, the root of the nested page table, the areas with
+//! their flags and backends, the reserved MMIO ranges, the permanently
+//! reserved ranges, the fault dispositions, the region tags, the device
+//! labels, the DMA windows, the poisoned and ballooned pages, and the table
+//! of frames owned by allocation areas.
+//! Page contents and the page table itself stay in host memory, so a
+//! hypervisor restarting itself (kexec-like) can re-attach to running guests.
+//!
+//! All values are encoded as little-endian `u64` words.
+//!
+//! Page tables built by someone else, e.g., firmware or an earlier boot
+//! stage, have no exported state. They can be taken over with
+//! [`AddrSpace::adopt_existing_root`] from a description of their areas.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use memory_set::MemoryArea;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{
+    AddrSpace, AreaAttributes, Backend, BackendKind, DeviceIdentity, FaultDisposition,
+    MapGranularity, PciBdf, RegionKind, SealMode,
+};
+use crate::npt::{GenericPTE, NestedPageTable as PageTable, tables};
+use crate::{
+    AxMmHal, GuestPhysAddr, GuestPhysAddrRange, PAGE_SIZE, checked_range, mapping_err_to_ax_err,
+};
+
+const STATE_MAGIC: u64 = u64::from_le_bytes(*b"AXASPACE");
+/// Version 2 added the windows of [`AddrSpace::extend_va_range`], version 3
+/// the ranges of [`AddrSpace::add_reserved_range`], version 4 the
+/// [`AreaAttributes`], version 5 the handlers of
+/// [`AddrSpace::register_mmio`], version 6 the fault dispositions, region
+/// tags, DMA windows and poisoned pages, version 7 whether allocation
+/// areas own their huge frames, version 8 the pages released by
+/// [`AddrSpace::guest_release_pages`], version 9 the labels of
+/// [`AddrSpace::label_device_region`]. Older states are still imported.
+const STATE_VERSION: u64 = 9;
+
+const BACKEND_LINEAR: u64 = 0;
+const BACKEND_ALLOC: u64 = 1;
+const BACKEND_COW: u64 = 2;
+/// Set next to `populate` for allocation areas freeing their huge frames,
+/// see [`AddrSpace::map_alloc_with_policy`].
+const ALLOC_OWNS_HUGE: u64 = 2;
+/// The [`AreaAttributes`] are stored above the granularity.
+const ATTRIBUTES_SHIFT: u64 = 32;
+/// The fault dispositions, stored as their index.
+const DISPOSITIONS: [FaultDisposition; 4] = [
+    FaultDisposition::Resolve,
+    FaultDisposition::Mmio,
+    FaultDisposition::Guard,
+    FaultDisposition::Rom,
+];
+/// A device label of a platform device, followed by its `compatible`
+/// string.
+const DEVICE_COMPATIBLE: u64 = 0;
+/// A device label of a PCI function, followed by its address.
+const DEVICE_PCI: u64 = 1;
+/// The region kinds, stored as their index.
+const REGION_KINDS: [RegionKind; 5] = [
+    RegionKind::Ram,
+    RegionKind::Reserved,
+    RegionKind::AcpiReclaimable,
+    RegionKind::AcpiNvs,
+    RegionKind::Mmio,
+];
+
+/// An area of a page table given to [`AddrSpace::adopt_existing_root`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AreaDescription {
+    /// The guest physical range of the area.
+    pub range: GuestPhysAddrRange,
+    /// The mapping flags of the area.
+    pub flags: MappingFlags,
+    /// Where the pages of the area come from.
+    ///
+    /// Frames mapped in [`BackendKind::Alloc`] areas, and those of
+    /// [`BackendKind::CoW`] areas other than the template, are owned by the
+    /// address space afterwards, and pages not mapped yet are allocated on
+    /// demand.
+    pub backend: BackendKind,
+}
+
+struct StateWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl StateWriter<'_> {
+    fn put(&mut self, val: u64) -> AxResult {
+        let dst = self
+            .buf
+            .get_mut(self.pos..self.pos + 8)
+            .ok_or(AxError::InvalidInput)?;
+        dst.copy_from_slice(&val.to_le_bytes());
+        self.pos += 8;
+        Ok(())
+    }
+
+    /// Writes the length of `bytes`, then `bytes` padded to whole words.
+    fn put_bytes(&mut self, bytes: &[u8]) -> AxResult {
+        self.put(bytes.len() as u64)?;
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.put(u64::from_le_bytes(word))?;
+        }
+        Ok(())
+    }
+}
+
+struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl StateReader<'_> {
+    fn get(&mut self) -> AxResult<u64> {
+        let src = self
+            .buf
+            .get(self.pos..self.pos + 8)
+            .ok_or(AxError::InvalidData)?;
+        self.pos += 8;
+        Ok(u64::from_le_bytes(src.try_into().unwrap()))
+    }
+
+    fn get_usize(&mut self) -> AxResult<usize> {
+        self.get()?.try_into().map_err(|_| AxError::InvalidData)
+    }
+
+    /// Reads bytes written by [`StateWriter::put_bytes`].
+    fn get_bytes(&mut self) -> AxResult<Vec<u8>> {
+        let len = self.get_usize()?;
+        if len > self.buf.len() {
+            return Err(AxError::InvalidData);
+        }
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let word = self.get()?.to_le_bytes();
+            let n = (len - bytes.len()).min(8);
+            bytes.extend_from_slice(&word[..n]);
+        }
+        Ok(bytes)
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns the frames owned by allocation and copy-on-write areas as
+    /// `(gpa, hpa)` pairs.
+    fn owned_frames(&self) -> Vec<(GuestPhysAddr, PhysAddr)> {
+        let mut frames = Vec::new();
+        for area in self.layout.areas().iter() {
+            if let Backend::Alloc { .. } | Backend::CoW { .. } = area.backend() {
+                let _ = self.for_each_host_segment(area.start(), area.size(), |gpa, hpa, _| {
+                    if let Some(hpa) = hpa
+                        && area.backend().owns_frame(gpa, hpa)
+                    {
+                        frames.push((gpa, hpa));
+                    }
+                    Ok(())
+                });
+            }
+        }
+        frames
+    }
+
+    /// Returns the number of bytes [`AddrSpace::export_state`] needs.
+    pub fn export_state_len(&self) -> usize {
+        let words = 8
+            + 5 * self.layout.areas().len()
+            + 1
+            + 2 * self.layout.mmio_regions.len()
+            + 1
+            + 2 * self.layout.extra_ranges.len()
+            + 1
+            + 2 * self.reserved_ranges().len()
+            + 1
+            + 2 * self.layout.mmio_handlers.len()
+            + 1
+            + 3 * self.layout.dispositions.iter().count()
+            + 1
+            + 3 * self.layout.region_tags.iter().count()
+            + 1
+            + self
+                .device_regions()
+                .map(|region| match &region.identity {
+                    DeviceIdentity::Compatible(compatible) => 4 + compatible.len().div_ceil(8),
+                    DeviceIdentity::Pci(_) => 4,
+                })
+                .sum::<usize>()
+            + 1
+            + 3 * self.layout.dma_windows.len()
+            + 1
+            + self.state.poisoned.len()
+            + 1
+            + self.state.ballooned.len()
+            + 1
+            + 2 * self.owned_frames().len();
+        words * 8
+    }
+
+    /// Serializes the metadata of the address space into `buf`.
+    ///
+    /// Returns the number of bytes written, or [`AxError::InvalidInput`] if
+    /// `buf` is smaller than [`AddrSpace::export_state_len`].
+    ///
+    /// After exporting, the caller normally [`core::mem::forget`]s the address
+    /// space so that the page table and the guest frames survive the restart.
+    pub fn export_state(&self, buf: &mut [u8]) -> AxResult<usize> {
+        let mut w = StateWriter { buf, pos: 0 };
+        w.put(STATE_MAGIC)?;
+        w.put(STATE_VERSION)?;
+        w.put(self.layout.va_range.start.as_usize() as u64)?;
+        w.put(self.layout.va_range.size() as u64)?;
+        w.put(self.state.pt.root_paddr().as_usize() as u64)?;
+        w.put(match self.layout.sealed {
+            None => 0,
+            Some(SealMode::Temporary) => 1,
+            Some(SealMode::Permanent) => 2,
+        })?;
+        w.put(0)?; // reserved
+        w.put(self.layout.areas().len() as u64)?;
+        for area in self.layout.areas().iter() {
+            w.put(area.start().as_usize() as u64)?;
+            w.put(area.size() as u64)?;
+            w.put(area.flags().bits() as u64)?;
+            // The granularity is stored above the backend kind.
+            let granularity = area.backend().granularity().to_bits() << 8;
+            let attrs = (self.area_attributes(area.start()).bits() as u64) << ATTRIBUTES_SHIFT;
+            match *area.backend() {
+                Backend::Linear { pa_va_offset, .. } => {
+                    w.put(BACKEND_LINEAR | granularity | attrs)?;
+                    w.put(pa_va_offset as u64)?;
+                }
+                Backend::Alloc {
+                    populate,
+                    dealloc_huge,
+                    ..
+                } => {
+                    w.put(BACKEND_ALLOC | granularity | attrs)?;
+                    let owns_huge = if dealloc_huge.is_some() {
+                        ALLOC_OWNS_HUGE
+                    } else {
+                        0
+                    };
+                    w.put(populate as u64 | owns_huge)?;
+                }
+                Backend::CoW { pa_va_offset } => {
+                    w.put(BACKEND_COW | granularity | attrs)?;
+                    w.put(pa_va_offset as u64)?;
+                }
+            }
+        }
+        w.put(self.layout.mmio_regions.len() as u64)?;
+        for range in self.layout.mmio_regions.values() {
+            w.put(range.start.as_usize() as u64)?;
+            w.put(range.size() as u64)?;
+        }
+        w.put(self.layout.extra_ranges.len() as u64)?;
+        for range in &self.layout.extra_ranges {
+            w.put(range.start.as_usize() as u64)?;
+            w.put(range.size() as u64)?;
+        }
+        let reserved = self.reserved_ranges();
+        w.put(reserved.len() as u64)?;
+        for range in reserved {
+            w.put(range.start.as_usize() as u64)?;
+            w.put(range.size() as u64)?;
+        }
+        w.put(self.layout.mmio_handlers.len() as u64)?;
+        for (start, &handler_id) in &self.layout.mmio_handlers {
+            w.put(start.as_usize() as u64)?;
+            w.put(handler_id as u64)?;
+        }
+        w.put(self.layout.dispositions.iter().count() as u64)?;
+        for (range, disposition) in self.layout.dispositions.iter() {
+            w.put(range.start.as_usize() as u64)?;
+            w.put(range.size() as u64)?;
+            w.put(DISPOSITIONS.iter().position(|&d| d == disposition).unwrap() as u64)?;
+        }
+        w.put(self.layout.region_tags.iter().count() as u64)?;
+        for (range, kind) in self.layout.region_tags.iter() {
+            let kind = kind.expect("untagged ranges are not stored");
+            w.put(range.start.as_usize() as u64)?;
+            w.put(range.size() as u64)?;
+            w.put(REGION_KINDS.iter().position(|&k| k == kind).unwrap() as u64)?;
+        }
+        w.put(self.device_regions().count() as u64)?;
+        for region in self.device_regions() {
+            w.put(region.range.start.as_usize() as u64)?;
+            w.put(region.range.size() as u64)?;
+            match &region.identity {
+                DeviceIdentity::Compatible(compatible) => {
+                    w.put(DEVICE_COMPATIBLE)?;
+                    w.put_bytes(compatible.as_bytes())?;
+                }
+                DeviceIdentity::Pci(bdf) => {
+                    w.put(DEVICE_PCI)?;
+                    w.put(
+                        ((bdf.segment as u64) << 24)
+                            | ((bdf.bus as u64) << 16)
+                            | ((bdf.device as u64) << 8)
+                            | bdf.function as u64,
+                    )?;
+                }
+            }
+        }
+        w.put(self.layout.dma_windows.len() as u64)?;
+        for &(range, enabled) in self.layout.dma_windows.values() {
+            w.put(range.start.as_usize() as u64)?;
+            w.put(range.size() as u64)?;
+            w.put(enabled as u64)?;
+        }
+        w.put(self.state.poisoned.len() as u64)?;
+        for gpa in &self.state.poisoned {
+            w.put(gpa.as_usize() as u64)?;
+        }
+        w.put(self.state.ballooned.len() as u64)?;
+        for gpa in &self.state.ballooned {
+            w.put(gpa.as_usize() as u64)?;
+        }
+        let frames = self.owned_frames();
+        w.put(frames.len() as u64)?;
+        for (gpa, hpa) in frames {
+            w.put(gpa.as_usize() as u64)?;
+            w.put(hpa.as_usize() as u64)?;
+        }
+        Ok(w.pos)
+    }
+}
+
+impl<H: PagingHandler + AxMmHal> AddrSpace<H> {
+    /// Re-creates an address space from the metadata written by
+    /// [`AddrSpace::export_state`], re-attaching to the existing page table
+    /// and guest frames.
+    ///
+    /// The root entries of the old page table are moved into a newly
+    /// allocated root, so [`AddrSpace::page_table_root`] changes and must be
+    /// reprogrammed (EPTP, VTTBR, hgatp) before the guest resumes. The frame
+    /// ownership table is verified against the page table before anything is
+    /// taken over; on failure, the old page table is left untouched.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must describe an address space that was exported by the same
+    /// kind of page table and then forgotten, so that its page table and
+    /// frames are now owned by nobody. They are owned by the returned address
+    /// space afterwards.
+    pub unsafe fn import_state(buf: &[u8]) -> AxResult<Self> {
+        let mut r = StateReader { buf, pos: 0 };
+        if r.get()? != STATE_MAGIC {
+            return ax_err!(InvalidData, "bad address space state header");
+        }
+        let version = r.get()?;
+        if !(1..=STATE_VERSION).contains(&version) {
+            return ax_err!(InvalidData, "unsupported address space state version");
+        }
+        let base = GuestPhysAddr::from_usize(r.get_usize()?);
+        let size = r.get_usize()?;
+        let old_root = PhysAddr::from_usize(r.get_usize()?);
+        let sealed = match r.get()? {
+            0 => None,
+            1 => Some(SealMode::Temporary),
+            2 => Some(SealMode::Permanent),
+            _ => return ax_err!(InvalidData, "bad seal mode"),
+        };
+        r.get()?; // reserved
+        let mut areas = Vec::new();
+        for _ in 0..r.get()? {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            let size = r.get_usize()?;
+            let flags = MappingFlags::from_bits_retain(r.get_usize()?);
+            let kind = r.get()?;
+            let backend = match (kind & 0xff, r.get_usize()?) {
+                (BACKEND_LINEAR, offset) => Backend::new_linear(offset),
+                (BACKEND_ALLOC, word) => Backend::Alloc {
+                    populate: word & 1 != 0,
+                    granularity: MapGranularity::DEFAULT,
+                    dealloc_huge: (word & ALLOC_OWNS_HUGE as usize != 0)
+                        .then_some(<H as AxMmHal>::dealloc_frames),
+                    _phantom: core::marker::PhantomData,
+                },
+                (BACKEND_COW, offset) => Backend::new_cow(offset),
+                _ => return ax_err!(InvalidData, "bad backend kind"),
+            };
+            let Some(granularity) = MapGranularity::from_bits((kind >> 8) & 0xffff) else {
+                return ax_err!(InvalidData, "bad granularity");
+            };
+            let Some(attrs) = AreaAttributes::from_bits((kind >> ATTRIBUTES_SHIFT) as u32) else {
+                return ax_err!(InvalidData, "bad area attributes");
+            };
+            let backend = backend.with_granularity(granularity);
+            areas.push((MemoryArea::new(start, size, flags, backend), attrs));
+        }
+        let mut mmio_regions = Vec::new();
+        for _ in 0..r.get()? {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            let size = r.get_usize()?;
+            let Some(range) = GuestPhysAddrRange::try_from_start_size(start, size) else {
+                return ax_err!(InvalidData, "bad mmio region");
+            };
+            mmio_regions.push(range);
+        }
+        let mut extra_ranges = Vec::new();
+        for _ in 0..if version >= 2 { r.get()? } else { 0 } {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            let size = r.get_usize()?;
+            let Some(range) = GuestPhysAddrRange::try_from_start_size(start, size) else {
+                return ax_err!(InvalidData, "bad address window");
+            };
+            extra_ranges.push(range);
+        }
+        let mut reserved = Vec::new();
+        for _ in 0..if version >= 3 { r.get()? } else { 0 } {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            let size = r.get_usize()?;
+            let Some(range) = GuestPhysAddrRange::try_from_start_size(start, size) else {
+                return ax_err!(InvalidData, "bad reserved range");
+            };
+            reserved.push(range);
+        }
+        let mut mmio_handlers = Vec::new();
+        for _ in 0..if version >= 5 { r.get()? } else { 0 } {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            if !mmio_regions.iter().any(|range| range.start == start) {
+                return ax_err!(InvalidData, "bad mmio handler");
+            }
+            mmio_handlers.push((start, r.get_usize()?));
+        }
+        let mut dispositions = Vec::new();
+        for _ in 0..if version >= 6 { r.get()? } else { 0 } {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            let size = r.get_usize()?;
+            let Some(range) = GuestPhysAddrRange::try_from_start_size(start, size) else {
+                return ax_err!(InvalidData, "bad fault disposition range");
+            };
+            let Some(&disposition) = DISPOSITIONS.get(r.get_usize()?) else {
+                return ax_err!(InvalidData, "bad fault disposition");
+            };
+            dispositions.push((range, disposition));
+        }
+        let mut region_tags = Vec::new();
+        for _ in 0..if version >= 6 { r.get()? } else { 0 } {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            let size = r.get_usize()?;
+            let Some(range) = GuestPhysAddrRange::try_from_start_size(start, size) else {
+                return ax_err!(InvalidData, "bad region tag range");
+            };
+            let Some(&kind) = REGION_KINDS.get(r.get_usize()?) else {
+                return ax_err!(InvalidData, "bad region kind");
+            };
+            region_tags.push((range, kind));
+        }
+        let mut device_regions = Vec::new();
+        for _ in 0..if version >= 9 { r.get()? } else { 0 } {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            let size = r.get_usize()?;
+            let Some(range) = GuestPhysAddrRange::try_from_start_size(start, size) else {
+                return ax_err!(InvalidData, "bad device region");
+            };
+            let identity = match r.get()? {
+                DEVICE_COMPATIBLE => match String::from_utf8(r.get_bytes()?) {
+                    Ok(compatible) => DeviceIdentity::Compatible(compatible),
+                    Err(_) => return ax_err!(InvalidData, "bad device compatible string"),
+                },
+                DEVICE_PCI => {
+                    let bdf = r.get()?;
+                    if bdf >> 40 != 0 {
+                        return ax_err!(InvalidData, "bad PCI address");
+                    }
+                    DeviceIdentity::Pci(PciBdf::new(
+                        (bdf >> 24) as u16,
+                        (bdf >> 16) as u8,
+                        (bdf >> 8) as u8,
+                        bdf as u8,
+                    ))
+                }
+                _ => return ax_err!(InvalidData, "bad device identity"),
+            };
+            device_regions.push((range, identity));
+        }
+        let mut dma_windows = Vec::new();
+        for _ in 0..if version >= 6 { r.get()? } else { 0 } {
+            let start = GuestPhysAddr::from_usize(r.get_usize()?);
+            let size = r.get_usize()?;
+            let Some(range) = GuestPhysAddrRange::try_from_start_size(start, size) else {
+                return ax_err!(InvalidData, "bad DMA window");
+            };
+            dma_windows.push((range, r.get()? != 0));
+        }
+        let mut poisoned = Vec::new();
+        for _ in 0..if version >= 6 { r.get()? } else { 0 } {
+            let gpa = GuestPhysAddr::from_usize(r.get_usize()?);
+            if !gpa.is_aligned(PAGE_SIZE) {
+                return ax_err!(InvalidData, "bad poisoned page");
+            }
+            poisoned.push(gpa);
+        }
+        let mut ballooned = Vec::new();
+        for _ in 0..if version >= 8 { r.get()? } else { 0 } {
+            let gpa = GuestPhysAddr::from_usize(r.get_usize()?);
+            if !gpa.is_aligned(PAGE_SIZE) {
+                return ax_err!(InvalidData, "bad ballooned page");
+            }
+            ballooned.push(gpa);
+        }
+        let mut frames = Vec::new();
+        for _ in 0..r.get()? {
+            let gpa = GuestPhysAddr::from_usize(r.get_usize()?);
+            frames.push((gpa, PhysAddr::from_usize(r.get_usize()?)));
+        }
+        if !old_root.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidData, "bad page table root");
+        }
+
+        let mut aspace = unsafe {
+            Self::take_over_root(base, size, old_root, |pt| {
+                let consistent = frames
+                    .iter()
+                    .all(|&(gpa, hpa)| matches!(pt.query(gpa), Ok((paddr, _, _)) if paddr == hpa));
+                if !consistent {
+                    return ax_err!(InvalidData, "frame table does not match the page table");
+                }
+                Ok(())
+            })?
+        };
+
+        for (area, attrs) in areas {
+            let range = area.va_range();
+            aspace
+                .layout
+                .areas_mut()
+                .map(area, &mut aspace.state.pt, false)
+                .map_err(mapping_err_to_ax_err)?;
+            aspace.layout.attributes.set(range, attrs);
+        }
+        for range in mmio_regions {
+            aspace.layout.mmio_regions.insert(range.start, range);
+        }
+        aspace.layout.mmio_handlers.extend(mmio_handlers);
+        for (range, disposition) in dispositions {
+            aspace.layout.dispositions.set(range, disposition);
+        }
+        for (range, kind) in region_tags {
+            aspace.layout.region_tags.set(range, Some(kind));
+        }
+        for (range, enabled) in dma_windows {
+            aspace
+                .layout
+                .dma_windows
+                .insert(range.start, (range, enabled));
+        }
+        // Populated areas have just allocated frames for the poisoned pages.
+        for &gpa in &poisoned {
+            if let Ok((frame, _, tlb)) = aspace.state.pt.unmap(gpa) {
+                tlb.ignore();
+                <H as PagingHandler>::dealloc_frame(frame);
+            }
+        }
+        aspace.state.poisoned.extend(poisoned);
+        aspace.state.ballooned.extend(ballooned);
+        aspace.layout.extra_ranges = extra_ranges;
+        for range in reserved {
+            aspace
+                .add_reserved_range(range)
+                .map_err(|_| AxError::InvalidData)?;
+        }
+        for (range, identity) in device_regions {
+            aspace
+                .label_device_region(range, identity)
+                .map_err(|_| AxError::InvalidData)?;
+        }
+        aspace.layout.sealed = sealed;
+        Ok(aspace)
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Takes over the nested page table rooted at `root_paddr`, built by
+    /// someone else (firmware, an earlier hypervisor stage), with the given
+    /// areas.
+    ///
+    /// The page table is verified against `areas` before anything is taken
+    /// over, and rejected with [`AxError::InvalidData`] if:
+    ///
+    /// - a page is mapped outside of the areas,
+    /// - a page is mapped with other access permissions (read, write,
+    ///   execute) than the flags of its area,
+    /// - a page of a linear area is not mapped, or not to the expected
+    ///   physical address,
+    /// - a page of an allocation or copy-on-write area is mapped with a huge
+    ///   page.
+    ///
+    /// The areas must be page-aligned, non-empty, non-overlapping, and lie
+    /// within `[base, base + size)`, or [`AxError::InvalidInput`] is returned.
+    /// On failure, the page table is left untouched.
+    ///
+    /// As with [`AddrSpace::import_state`], the root entries are moved into a
+    /// newly allocated root, and `root_paddr` is freed.
+    ///
+    /// # Safety
+    ///
+    /// The page table must be of the kind used by this architecture, and
+    /// owned by nobody. Its tables, its root, and the frames mapped in
+    /// allocation areas must be freeable by `H`; they are owned by the
+    /// returned address space afterwards.
+    pub unsafe fn adopt_existing_root(
+        base: GuestPhysAddr,
+        size: usize,
+        root_paddr: PhysAddr,
+        areas: &[AreaDescription],
+    ) -> AxResult<Self> {
+        let va_range = checked_range(base, size)?;
+        let mut sorted: Vec<_> = areas.to_vec();
+        sorted.sort_unstable_by_key(|area| area.range.start);
+        for (i, area) in sorted.iter().enumerate() {
+            let range = area.range;
+            if range.is_empty()
+                || !range.start.is_aligned(PAGE_SIZE)
+                || !range.end.is_aligned(PAGE_SIZE)
+                || !va_range.contains_range(range)
+                || sorted
+                    .get(i + 1)
+                    .is_some_and(|next| next.range.start < range.end)
+            {
+                return ax_err!(InvalidInput, "bad area description");
+            }
+        }
+        if !root_paddr.is_aligned(PAGE_SIZE) {
+            return ax_err!(InvalidInput, "bad page table root");
+        }
+
+        let mut aspace = unsafe {
+            Self::take_over_root(base, size, root_paddr, |pt| {
+                Self::check_adopted(pt, &sorted)
+            })?
+        };
+        for area in sorted {
+            let backend = match area.backend {
+                BackendKind::Linear { start_paddr } => Backend::new_linear(
+                    area.range
+                        .start
+                        .as_usize()
+                        .wrapping_sub(start_paddr.as_usize()),
+                ),
+                BackendKind::Alloc => Backend::new_alloc(false),
+                BackendKind::CoW { template_paddr } => Backend::new_cow(
+                    area.range
+                        .start
+                        .as_usize()
+                        .wrapping_sub(template_paddr.as_usize()),
+                ),
+            };
+            let area = MemoryArea::new(area.range.start, area.range.size(), area.flags, backend);
+            aspace
+                .layout
+                .areas_mut()
+                .map(area, &mut aspace.state.pt, false)
+                .map_err(mapping_err_to_ax_err)?;
+        }
+        Ok(aspace)
+    }
+
+    /// Verifies the mappings of `pt` against `areas`, sorted by address, for
+    /// [`AddrSpace::adopt_existing_root`].
+    fn check_adopted(pt: &PageTable<H>, areas: &[AreaDescription]) -> AxResult {
+        let access = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+        // The number of bytes mapped in each area.
+        let mut mapped = alloc::vec![0; areas.len()];
+        let mut res = Ok(());
+        tables::for_each_leaf::<H>(pt.root_paddr(), &mut |start, len, entry| {
+            if res.is_err() {
+                return;
+            }
+            let start = GuestPhysAddr::from_usize(start);
+            let index = areas.partition_point(|area| area.range.start <= start);
+            let area = index.checked_sub(1).map(|i| (i, &areas[i]));
+            let Some((i, area)) = area.filter(|(_, area)| {
+                area.range
+                    .contains_range(GuestPhysAddrRange::from_start_size(start, len))
+            }) else {
+                warn!("adopt: {start:?} is mapped outside of the areas");
+                res = ax_err!(InvalidData, "page mapped outside of the areas");
+                return;
+            };
+            // Pages still mapped to the template of a copy-on-write area are
+            // read-only.
+            let flags = match area.backend {
+                BackendKind::CoW { template_paddr }
+                    if entry.paddr() == template_paddr + (start - area.range.start) =>
+                {
+                    area.flags - MappingFlags::WRITE
+                }
+                _ => area.flags,
+            };
+            let consistent = entry.flags() & access == flags & access
+                && match area.backend {
+                    BackendKind::Linear { start_paddr } => {
+                        entry.paddr() == start_paddr + (start - area.range.start)
+                    }
+                    BackendKind::Alloc | BackendKind::CoW { .. } => len == PAGE_SIZE,
+                };
+            if !consistent {
+                warn!("adopt: {start:?} does not match {area:?}");
+                res = ax_err!(InvalidData, "page does not match its area");
+            }
+            mapped[i] += len;
+        });
+        res?;
+        for (area, mapped) in areas.iter().zip(mapped) {
+            if matches!(area.backend, BackendKind::Linear { .. }) && mapped != area.range.size() {
+                return ax_err!(InvalidData, "linear area not fully mapped");
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates an address space whose root holds the entries of `old_root`,
+    /// if `verify` accepts them, and frees `old_root`.
+    ///
+    /// On failure, the tables below `old_root` are left untouched.
+    unsafe fn take_over_root(
+        base: GuestPhysAddr,
+        size: usize,
+        old_root: PhysAddr,
+        verify: impl FnOnce(&PageTable<H>) -> AxResult,
+    ) -> AxResult<Self> {
+        let aspace = Self::new_empty(base, size)?;
+        let new_root_ptr = H::phys_to_virt(aspace.state.pt.root_paddr()).as_mut_ptr();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                H::phys_to_virt(old_root).as_ptr(),
+                new_root_ptr,
+                PAGE_SIZE,
+            );
+        }
+        if let Err(err) = verify(&aspace.state.pt) {
+            // Forget the borrowed entries so that only the new root is freed.
+            unsafe { core::ptr::write_bytes(new_root_ptr, 0, PAGE_SIZE) };
+            return Err(err);
+        }
+        H::dealloc_frame(old_root);
+        Ok(aspace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        ALLOC_COUNT, DEALLOC_COUNT, DEALLOC_FRAMES_COUNT, MockHal, mock_hal_test,
+    };
+    use crate::{MemType, MmioRegion, PageSize, PageSizePolicy};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_export_import_round_trip() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        aspace.map_alloc(base + 0x2000, 0x2000, rw, false).unwrap();
+        assert!(aspace.handle_page_fault(base + 0x2000, MappingFlags::WRITE));
+        aspace
+            .map_linear(base + 0x8000, PhysAddr::from(0x8000), 0x1000, rw)
+            .unwrap();
+        let mmio = GuestPhysAddrRange::from_start_size(base + 0xa000, 0x1000);
+        aspace.register_mmio(mmio, 3).unwrap();
+        let window = GuestPhysAddrRange::from_start_size(base + 0x20000, 0x1000);
+        aspace.extend_va_range(window).unwrap();
+        let reserved = GuestPhysAddrRange::from_start_size(window.start, 0x1000);
+        aspace.add_reserved_range(reserved).unwrap();
+        aspace.translated_byte_buffer(base, 1).unwrap()[0][0] = 0x42;
+        aspace
+            .set_area_attributes(base + 0x8000, AreaAttributes::PERSISTENT)
+            .unwrap();
+        let guard = GuestPhysAddrRange::from_start_size(base + 0xc000, 0x1000);
+        aspace
+            .set_fault_disposition(guard, FaultDisposition::Guard)
+            .unwrap();
+        aspace.tag_region(guard, RegionKind::Reserved).unwrap();
+        let dma = GuestPhysAddrRange::from_start_size(base + 0xe000, 0x1000);
+        let dma_window = aspace.register_dma_window(dma).unwrap();
+        aspace.disable_dma_window(&dma_window).unwrap();
+        aspace.enable_reverse_map();
+        let lost = aspace.translate(base + 0x1000).unwrap();
+        assert_eq!(aspace.poison_frame(lost, None), Ok([base + 0x1000].into()));
+        assert_eq!(aspace.guest_release_pages(&[base + 0x3000]), Ok(1));
+        let uart = GuestPhysAddrRange::from_start_size(base + 0xb000, 0x1000);
+        let pl011 = DeviceIdentity::Compatible("arm,pl011".into());
+        aspace.map_identity_device(uart, MemType::Device).unwrap();
+        aspace.label_device_region(uart, pl011.clone()).unwrap();
+        let bar = GuestPhysAddrRange::from_start_size(base + 0xd000, 0x1000);
+        let gpu = DeviceIdentity::Pci(PciBdf::new(1, 2, 0x1f, 7));
+        aspace
+            .map_identity_device(bar, MemType::WriteCombining)
+            .unwrap();
+        aspace.label_device_region(bar, gpu.clone()).unwrap();
+        let before: Vec<_> = (0..5)
+            .map(|i| aspace.translate(base + i * 0x1000))
+            .collect();
+
+        let mut buf = [0u8; 1024];
+        let len = aspace.export_state(&mut buf).unwrap();
+        assert_eq!(len, aspace.export_state_len());
+        assert_eq!(
+            aspace.export_state(&mut buf[..len - 1]),
+            Err(AxError::InvalidInput)
+        );
+        core::mem::forget(aspace);
+
+        let deallocs = DEALLOC_COUNT.load(Ordering::SeqCst);
+        let mut aspace = unsafe { AddrSpace::<MockHal>::import_state(&buf[..len]) }.unwrap();
+        // Only the old root and the frame populated at the poisoned page are
+        // released, guest frames are kept.
+        assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), deallocs + 2);
+        let after: Vec<_> = (0..5)
+            .map(|i| aspace.translate(base + i * 0x1000))
+            .collect();
+        assert_eq!(before, after);
+        assert_eq!(after[3], None);
+        assert_eq!(aspace.translated_byte_buffer(base, 1).unwrap()[0][0], 0x42);
+        assert!(aspace.is_mmio(base + 0xa000));
+        assert_eq!(
+            aspace.registered_mmio().collect::<Vec<_>>(),
+            [MmioRegion {
+                range: mmio,
+                handler_id: 3
+            }]
+        );
+        assert_eq!(aspace.layout.areas().len(), 5);
+        assert_eq!(aspace.layout.extra_ranges, [window]);
+        assert_eq!(aspace.reserved_ranges(), [reserved]);
+        assert_eq!(
+            aspace.area_attributes(base + 0x8000),
+            AreaAttributes::PERSISTENT
+        );
+        assert_eq!(aspace.area_attributes(base), AreaAttributes::empty());
+        assert_eq!(
+            aspace.fault_disposition(guard.start),
+            FaultDisposition::Guard
+        );
+        assert_eq!(
+            aspace.layout.region_tags.iter().collect::<Vec<_>>(),
+            [(guard, Some(RegionKind::Reserved))]
+        );
+        assert_eq!(aspace.is_dma_window_enabled(&dma_window), Ok(false));
+        assert!(aspace.is_poisoned(base + 0x1000));
+        assert!(!aspace.is_poisoned(base));
+        assert_eq!(aspace.ballooned_pages(), 1);
+        assert_eq!(
+            aspace
+                .device_regions()
+                .map(|region| (region.range, region.identity.clone()))
+                .collect::<Vec<_>>(),
+            [(uart, pl011), (bar, gpu)]
+        );
+        assert!(aspace.is_ballooned(base + 0x3000));
+        assert!(!aspace.handle_page_fault(base + 0x3000, MappingFlags::WRITE));
+        assert_eq!(aspace.translate(base + 0x3000), None);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_export_import_huge_frames() {
+        let base = GuestPhysAddr::from(0);
+        let size_2m = PageSize::Size2M as usize;
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 2 * size_2m).unwrap();
+        MockHal::set_huge_frames(size_2m / PAGE_SIZE);
+        aspace
+            .map_alloc_with_policy(base, size_2m, rw, true, PageSizePolicy::Prefer2M)
+            .unwrap();
+        let mut buf = [0u8; 512];
+        let len = aspace.export_state(&mut buf).unwrap();
+        core::mem::forget(aspace);
+
+        // The imported area still frees the huge frame it allocated.
+        let mut aspace = unsafe { AddrSpace::<MockHal>::import_state(&buf[..len]) }.unwrap();
+        assert_eq!(aspace.page_table().query(base).unwrap().2, PageSize::Size2M);
+        aspace.unmap(base, size_2m).unwrap();
+        assert_eq!(
+            DEALLOC_FRAMES_COUNT.load(Ordering::SeqCst),
+            size_2m / PAGE_SIZE
+        );
+        assert_eq!(aspace.close().unwrap().leaked_frames, 0);
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_import_rejects_bad_state() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace
+            .map_alloc(base, 0x1000, MappingFlags::READ, true)
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let len = aspace.export_state(&mut buf).unwrap();
+
+        // Corrupt the frame table: the recorded frame no longer matches.
+        buf[len - 8] ^= 0x10;
+        let res = unsafe { AddrSpace::<MockHal>::import_state(&buf[..len]) };
+        assert_eq!(res.err(), Some(AxError::InvalidData));
+        // The original address space is left intact.
+        assert!(aspace.translate(base).is_some());
+
+        buf[0] = 0;
+        let res = unsafe { AddrSpace::<MockHal>::import_state(&buf[..len]) };
+        assert_eq!(res.err(), Some(AxError::InvalidData));
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_adopt_existing_root() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let linear_paddr = PhysAddr::from(0x20_0000);
+        // A page table built by someone else.
+        let mut pt = PageTable::<MockHal>::try_new().unwrap();
+        for i in 0..2 {
+            pt.map(
+                base + i * PAGE_SIZE,
+                linear_paddr + i * PAGE_SIZE,
+                PageSize::Size4K,
+                rw,
+            )
+            .unwrap()
+            .ignore();
+        }
+        let frame = <MockHal as PagingHandler>::alloc_frame().unwrap();
+        pt.map(base + 0x4000, frame, PageSize::Size4K, rw)
+            .unwrap()
+            .ignore();
+        let root = pt.root_paddr();
+        core::mem::forget(pt);
+
+        let linear = AreaDescription {
+            range: GuestPhysAddrRange::from_start_size(base, 0x2000),
+            flags: rw,
+            backend: BackendKind::Linear {
+                start_paddr: linear_paddr,
+            },
+        };
+        let alloc = AreaDescription {
+            range: GuestPhysAddrRange::from_start_size(base + 0x4000, 0x2000),
+            flags: rw,
+            backend: BackendKind::Alloc,
+        };
+        let adopt = |areas: &[AreaDescription]| unsafe {
+            AddrSpace::<MockHal>::adopt_existing_root(base, 0x10000, root, areas)
+        };
+        let shifted = AreaDescription {
+            backend: BackendKind::Linear {
+                start_paddr: linear_paddr + PAGE_SIZE,
+            },
+            ..linear
+        };
+        let read_only = AreaDescription {
+            flags: MappingFlags::READ,
+            ..alloc
+        };
+        let partial = AreaDescription {
+            range: GuestPhysAddrRange::from_start_size(base, 0x3000),
+            ..linear
+        };
+        assert_eq!(adopt(&[linear]).err(), Some(AxError::InvalidData));
+        assert_eq!(adopt(&[shifted, alloc]).err(), Some(AxError::InvalidData));
+        assert_eq!(
+            adopt(&[linear, read_only]).err(),
+            Some(AxError::InvalidData)
+        );
+        assert_eq!(adopt(&[partial, alloc]).err(), Some(AxError::InvalidData));
+        assert_eq!(adopt(&[linear, linear]).err(), Some(AxError::InvalidInput));
+
+        let mut aspace = adopt(&[alloc, linear]).unwrap();
+        assert_eq!(aspace.translate(base + 0x1008), Some(linear_paddr + 0x1008));
+        assert_eq!(aspace.translate(base + 0x4000), Some(frame));
+        assert_eq!(aspace.translate(base + 0x5000), None);
+        assert!(aspace.handle_page_fault(base + 0x5000, MappingFlags::WRITE));
+        assert_eq!(aspace.layout.areas().len(), 2);
+        // The adopted tables and frames are freed with the address space.
+        drop(aspace);
+        assert_eq!(
+            ALLOC_COUNT.load(Ordering::SeqCst),
+            DEALLOC_COUNT.load(Ordering::SeqCst)
+        );
+    }
+}
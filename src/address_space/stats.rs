@@ -0,0 +1,132 @@
+//! Memory accounting of address spaces, for host memory accounting and
+//! balloon policies.
+
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddr, PAGE_SIZE, PageSize, npt};
+
+/// Page-table statistics of an address space, built by
+/// [`AddrSpace::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AddrSpaceStats {
+    /// Bytes of the areas backed by host memory owned by the caller.
+    pub linear_bytes: usize,
+    /// Bytes of the areas backed by frames allocated on demand or up front.
+    pub alloc_bytes: usize,
+    /// Bytes of the copy-on-write areas.
+    pub cow_bytes: usize,
+    /// Number of pages mapped with 4K pages.
+    pub mappings_4k: usize,
+    /// Number of pages mapped with 2M pages.
+    pub mappings_2m: usize,
+    /// Number of pages mapped with 1G pages.
+    pub mappings_1g: usize,
+    /// Host frames used by the tables of the nested page table.
+    pub page_table_frames: usize,
+    /// Pages of allocation areas not faulted in yet, which consume no host
+    /// memory until the guest touches them.
+    pub unfaulted_pages: usize,
+    /// Pages of allocation areas released by the guest, see
+    /// [`AddrSpace::guest_release_pages`].
+    pub ballooned_pages: usize,
+}
+
+impl AddrSpaceStats {
+    /// Bytes of guest memory mapped by the areas, of any backend.
+    pub const fn mapped_bytes(&self) -> usize {
+        self.linear_bytes + self.alloc_bytes + self.cow_bytes
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns the page-table statistics of the address space.
+    ///
+    /// This walks the whole nested page table, so it is meant for periodic
+    /// accounting rather than for hot paths.
+    pub fn stats(&self) -> AddrSpaceStats {
+        let mut stats = AddrSpaceStats {
+            page_table_frames: self.page_table_frames(),
+            ballooned_pages: self.state.ballooned.len(),
+            ..Default::default()
+        };
+        for area in self.layout.areas().iter() {
+            match area.backend() {
+                Backend::Linear { .. } => stats.linear_bytes += area.size(),
+                Backend::Alloc { .. } => stats.alloc_bytes += area.size(),
+                Backend::CoW { .. } => stats.cow_bytes += area.size(),
+            }
+        }
+        let mut faulted_bytes = 0;
+        npt::tables::for_each_leaf::<H>(self.state.pt.root_paddr(), &mut |start, size, _| {
+            match size {
+                s if s == PageSize::Size1G as usize => stats.mappings_1g += 1,
+                s if s == PageSize::Size2M as usize => stats.mappings_2m += 1,
+                _ => stats.mappings_4k += 1,
+            }
+            let in_alloc = self
+                .layout
+                .find_area(GuestPhysAddr::from(start))
+                .is_some_and(|area| matches!(area.backend(), Backend::Alloc { .. }));
+            if in_alloc {
+                faulted_bytes += size;
+            }
+        });
+        stats.unfaulted_pages =
+            ((stats.alloc_bytes - faulted_bytes) / PAGE_SIZE).saturating_sub(stats.ballooned_pages);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{MapGranularity, MappingFlags};
+    use axin::axin;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_stats() {
+        const SIZE_2M: usize = 0x20_0000;
+        let base = GuestPhysAddr::from(0);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let template = MockHal::alloc_frame().unwrap();
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 2 * SIZE_2M).unwrap();
+        aspace
+            .map_linear_with_granularity(
+                base,
+                PhysAddr::from(0),
+                SIZE_2M,
+                rw,
+                MapGranularity::exact(PageSize::Size2M),
+            )
+            .unwrap();
+        aspace.map_alloc(base + SIZE_2M, 0x8000, rw, false).unwrap();
+        aspace
+            .map_cow(base + SIZE_2M + 0x8000, template, 0x2000, rw)
+            .unwrap();
+        assert!(aspace.handle_page_fault(base + SIZE_2M, MappingFlags::WRITE));
+        aspace
+            .guest_release_pages(&[base + SIZE_2M + 0x1000, base + SIZE_2M + 0x2000])
+            .unwrap();
+
+        let stats = aspace.stats();
+        assert_eq!(
+            stats,
+            AddrSpaceStats {
+                linear_bytes: SIZE_2M,
+                alloc_bytes: 0x8000,
+                cow_bytes: 0x2000,
+                mappings_4k: 3,
+                mappings_2m: 1,
+                mappings_1g: 0,
+                page_table_frames: aspace.page_table_frames(),
+                unfaulted_pages: 5,
+                ballooned_pages: 2,
+            }
+        );
+        assert_eq!(stats.mapped_bytes(), SIZE_2M + 0xa000);
+    }
+}
@@ -0,0 +1,252 @@
+//! Stress tests of [`AddrSpace`] with thousands of areas and random
+//! operations (std only).
+//!
+//! They need more memory than [`MockHal`](crate::test_utils::MockHal) has,
+//! so frames come from the host heap, with host physical addresses equal to
+//! host virtual ones.
+
+extern crate std;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use std::alloc::{Layout, alloc_zeroed, dealloc};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use memory_addr::{PhysAddr, VirtAddr};
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::{GuestPhysAddr, MappingFlags};
+
+const PAGE_SIZE: usize = 0x1000;
+const FRAME: Layout = match Layout::from_size_align(PAGE_SIZE, PAGE_SIZE) {
+    Ok(layout) => layout,
+    Err(_) => panic!(),
+};
+/// Linear areas map to fake host memory above the heap, never accessed.
+const LINEAR_HPA_OFFSET: usize = 0x100_0000_0000;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicUsize = AtomicUsize::new(0);
+/// Keeps the tests from running concurrently, which would skew the frame
+/// counts and the timings.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+struct HeapHal;
+
+impl PagingHandler for HeapHal {
+    fn alloc_frame() -> Option<PhysAddr> {
+        let ptr = unsafe { alloc_zeroed(FRAME) };
+        if ptr.is_null() {
+            return None;
+        }
+        ALLOCATED.fetch_add(1, Ordering::Relaxed);
+        Some(PhysAddr::from(ptr as usize))
+    }
+
+    fn dealloc_frame(paddr: PhysAddr) {
+        FREED.fetch_add(1, Ordering::Relaxed);
+        unsafe { dealloc(paddr.as_usize() as *mut u8, FRAME) }
+    }
+
+    fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+        VirtAddr::from(paddr.as_usize())
+    }
+}
+
+/// A xorshift generator, so that failures are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Linear,
+    Populated,
+    Lazy,
+}
+
+/// The expected state of an area slot.
+#[derive(Debug, Clone)]
+struct Slot {
+    start: GuestPhysAddr,
+    kind: Option<Kind>,
+    /// The pages of a lazy area that were faulted in.
+    faulted: Vec<bool>,
+}
+
+/// Every slot spans this many pages: up to `MAX_AREA_PAGES` mapped, then a
+/// hole.
+const SLOT_PAGES: usize = 4;
+const MAX_AREA_PAGES: usize = 3;
+const BASE: usize = 0x4000_0000;
+
+fn rw() -> MappingFlags {
+    MappingFlags::READ | MappingFlags::WRITE
+}
+
+fn map_slot(aspace: &mut AddrSpace<HeapHal>, slot: &mut Slot, kind: Kind, pages: usize) {
+    let size = pages * PAGE_SIZE;
+    match kind {
+        Kind::Linear => {
+            let hpa = PhysAddr::from(slot.start.as_usize() + LINEAR_HPA_OFFSET);
+            aspace.map_linear(slot.start, hpa, size, rw()).unwrap()
+        }
+        Kind::Populated => aspace.map_alloc(slot.start, size, rw(), true).unwrap(),
+        Kind::Lazy => aspace.map_alloc(slot.start, size, rw(), false).unwrap(),
+    }
+    slot.kind = Some(kind);
+    slot.faulted = vec![false; pages];
+}
+
+fn random_kind(rng: &mut Rng) -> Kind {
+    [Kind::Linear, Kind::Populated, Kind::Lazy][rng.below(3)]
+}
+
+/// Creates `areas` areas of random kinds and sizes, one per slot.
+fn setup(areas: usize, rng: &mut Rng) -> (AddrSpace<HeapHal>, Vec<Slot>) {
+    let size = areas * SLOT_PAGES * PAGE_SIZE;
+    let mut aspace = AddrSpace::<HeapHal>::new_empty(GuestPhysAddr::from(BASE), size).unwrap();
+    let mut slots = Vec::with_capacity(areas);
+    for i in 0..areas {
+        let mut slot = Slot {
+            start: GuestPhysAddr::from(BASE + i * SLOT_PAGES * PAGE_SIZE),
+            kind: None,
+            faulted: Vec::new(),
+        };
+        let kind = random_kind(rng);
+        map_slot(&mut aspace, &mut slot, kind, 1 + rng.below(MAX_AREA_PAGES));
+        slots.push(slot);
+    }
+    (aspace, slots)
+}
+
+/// Checks the translation of page `page` of `slot` against the model.
+fn check_page(aspace: &AddrSpace<HeapHal>, slot: &Slot, page: usize) {
+    let gpa = slot.start + page * PAGE_SIZE;
+    let mapped = page < slot.faulted.len();
+    let translated = aspace.translate(gpa);
+    match slot.kind {
+        Some(Kind::Linear) if mapped => assert_eq!(
+            translated,
+            Some(PhysAddr::from(gpa.as_usize() + LINEAR_HPA_OFFSET))
+        ),
+        Some(Kind::Populated) if mapped => assert!(translated.is_some(), "{gpa:?}"),
+        Some(Kind::Lazy) if mapped => {
+            assert_eq!(translated.is_some(), slot.faulted[page], "{gpa:?}");
+            assert_eq!(aspace.is_lazy_placeholder(gpa), !slot.faulted[page]);
+        }
+        _ => assert_eq!(translated, None, "{gpa:?}"),
+    }
+}
+
+#[test]
+fn test_stress_random_operations() {
+    const AREAS: usize = 4096;
+    const OPS: usize = 200_000;
+    let _serial = SERIAL.lock().unwrap();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let freed = FREED.load(Ordering::Relaxed);
+    let (mut aspace, mut slots) = setup(AREAS, &mut rng);
+
+    for _ in 0..OPS {
+        let slot = &mut slots[rng.below(AREAS)];
+        let page = rng.below(SLOT_PAGES);
+        let gpa = slot.start + page * PAGE_SIZE;
+        match rng.below(100) {
+            // Translations dominate, as in device emulation.
+            0..60 => check_page(&aspace, slot, page),
+            60..95 => {
+                let mapped = page < slot.faulted.len();
+                let lazy = slot.kind == Some(Kind::Lazy) && mapped;
+                let access = [MappingFlags::READ, MappingFlags::WRITE][rng.below(2)];
+                assert_eq!(aspace.handle_page_fault(gpa, access), lazy, "{gpa:?}");
+                if lazy {
+                    slot.faulted[page] = true;
+                }
+            }
+            // Churn: unmap the area and map another one in its place.
+            _ => {
+                if slot.kind.is_some() {
+                    let size = slot.faulted.len() * PAGE_SIZE;
+                    aspace.unmap(slot.start, size).unwrap();
+                    slot.kind = None;
+                    slot.faulted.clear();
+                } else {
+                    let kind = random_kind(&mut rng);
+                    map_slot(&mut aspace, slot, kind, 1 + rng.below(MAX_AREA_PAGES));
+                }
+            }
+        }
+    }
+
+    for slot in &slots {
+        for page in 0..SLOT_PAGES {
+            check_page(&aspace, slot, page);
+        }
+    }
+    let mapped = slots.iter().filter(|slot| slot.kind.is_some()).count();
+    assert_eq!(aspace.summary(Duration::ZERO).areas, mapped);
+    assert_eq!(aspace.layout.areas().len(), mapped);
+    drop(aspace);
+    assert_eq!(
+        ALLOCATED.load(Ordering::Relaxed) - allocated,
+        FREED.load(Ordering::Relaxed) - freed
+    );
+}
+
+/// Returns the time taken by `ops` faults on already present lazy pages,
+/// spread over `areas` areas, which is dominated by area lookups.
+fn scattered_fault_time(areas: usize, ops: usize) -> Duration {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let size = areas * SLOT_PAGES * PAGE_SIZE;
+    let mut aspace = AddrSpace::<HeapHal>::new_empty(GuestPhysAddr::from(BASE), size).unwrap();
+    let starts: Vec<_> = (0..areas)
+        .map(|i| GuestPhysAddr::from(BASE + i * SLOT_PAGES * PAGE_SIZE))
+        .collect();
+    for &start in &starts {
+        aspace.map_alloc(start, PAGE_SIZE, rw(), false).unwrap();
+        assert!(aspace.handle_page_fault(start, MappingFlags::READ));
+    }
+    let gpas: Vec<_> = (0..ops).map(|_| starts[rng.below(areas)]).collect();
+    let started = Instant::now();
+    for &gpa in &gpas {
+        assert!(aspace.handle_page_fault(gpa, MappingFlags::READ));
+    }
+    started.elapsed()
+}
+
+#[test]
+fn test_stress_area_lookup_scaling() {
+    const OPS: usize = 50_000;
+    let _serial = SERIAL.lock().unwrap();
+    // The best of a few runs, to filter out scheduling noise.
+    let best = |areas| {
+        (0..3)
+            .map(|_| scattered_fault_time(areas, OPS))
+            .min()
+            .unwrap()
+    };
+    let small = best(512);
+    let large = best(8192);
+    // 16 times more areas: a logarithmic lookup barely slows down, a linear
+    // one slows down about 16 times.
+    assert!(
+        large < small * 4,
+        "area lookup does not scale: {small:?} for 512 areas, {large:?} for 8192"
+    );
+}
@@ -0,0 +1,168 @@
+//! One-line status summaries for host consoles.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::PAGE_SIZE;
+
+/// Event counters of an address space.
+#[derive(Debug, Default)]
+pub(crate) struct EventCounters {
+    /// Page faults handled so far.
+    faults: AtomicU64,
+    /// The value of `faults` at the last summary.
+    last_faults: AtomicU64,
+    /// The time of the last summary, in nanoseconds.
+    last_time_ns: AtomicU64,
+    /// Linear mappings that fell back to smaller pages.
+    huge_fallbacks: AtomicU64,
+}
+
+impl EventCounters {
+    pub(crate) fn count_fault(&self) {
+        self.faults.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_huge_fallback(&self) {
+        self.huge_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn huge_fallbacks(&self) -> u64 {
+        self.huge_fallbacks.load(Ordering::Relaxed)
+    }
+}
+
+/// A snapshot of an address space, built by [`AddrSpace::summary`].
+///
+/// Its [`Display`](fmt::Display) output is a single line, e.g.
+/// `12.5 MiB resident, 3 areas, 2 huge pages, 40 faults/s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddrSpaceSummary {
+    /// Bytes of guest memory currently backed by host memory.
+    pub resident_bytes: usize,
+    /// Number of mapped areas.
+    pub areas: usize,
+    /// Number of pages mapped with a page size larger than [`PAGE_SIZE`].
+    pub huge_pages: usize,
+    /// Page faults handled since the previous summary.
+    pub faults: u64,
+    /// Page faults handled per second since the previous summary.
+    pub faults_per_sec: u64,
+}
+
+impl fmt::Display for AddrSpaceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} MiB resident, {} areas, {} huge pages, {} faults/s",
+            self.resident_bytes as f64 / (1024.0 * 1024.0),
+            self.areas,
+            self.huge_pages,
+            self.faults_per_sec,
+        )
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns a summary of the address space for periodic status prints.
+    ///
+    /// `now` is the current time from any monotonic clock; the fault rate is
+    /// computed over the time since the previous call (or since `now` was
+    /// zero, for the first call).
+    pub fn summary(&self, now: Duration) -> AddrSpaceSummary {
+        let mut resident_bytes = 0;
+        let mut huge_pages = 0;
+        for area in self.layout.areas().iter() {
+            let mut gpa = area.start();
+            while gpa < area.end() {
+                gpa = match self.state.pt.query(gpa) {
+                    Ok((_, _, page_size)) => {
+                        let size = page_size as usize;
+                        let page_end = gpa.align_down(page_size) + size;
+                        resident_bytes += page_end.min(area.end()) - gpa;
+                        if size > PAGE_SIZE {
+                            huge_pages += 1;
+                        }
+                        page_end
+                    }
+                    Err(_) => gpa + PAGE_SIZE,
+                };
+            }
+        }
+
+        let now_ns = now.as_nanos() as u64;
+        let total = self.state.events.faults.load(Ordering::Relaxed);
+        let faults = total - self.state.events.last_faults.swap(total, Ordering::Relaxed);
+        let elapsed_ns = now_ns.saturating_sub(
+            self.state
+                .events
+                .last_time_ns
+                .swap(now_ns, Ordering::Relaxed),
+        );
+        let faults_per_sec = if elapsed_ns == 0 {
+            0
+        } else {
+            (faults as u128 * 1_000_000_000 / elapsed_ns as u128) as u64
+        };
+        AddrSpaceSummary {
+            resident_bytes,
+            areas: self.layout.areas().len(),
+            huge_pages,
+            faults,
+            faults_per_sec,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{GuestPhysAddr, MapGranularity, MappingFlags, PageSize};
+    use alloc::string::ToString;
+    use axin::axin;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_summary() {
+        const SIZE_2M: usize = 0x20_0000;
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 4 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace
+            .map_linear_with_granularity(
+                base,
+                PhysAddr::from(0),
+                SIZE_2M,
+                rw,
+                MapGranularity::exact(PageSize::Size2M),
+            )
+            .unwrap();
+        aspace.map_alloc(base + SIZE_2M, 0x4000, rw, false).unwrap();
+        assert!(aspace.handle_page_fault(base + SIZE_2M, MappingFlags::READ));
+        assert!(aspace.handle_page_fault(base + SIZE_2M + 0x1000, MappingFlags::READ));
+
+        let summary = aspace.summary(Duration::from_millis(500));
+        assert_eq!(
+            summary,
+            AddrSpaceSummary {
+                resident_bytes: SIZE_2M + 0x2000,
+                areas: 2,
+                huge_pages: 1,
+                faults: 2,
+                faults_per_sec: 4,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "2.0 MiB resident, 2 areas, 1 huge pages, 4 faults/s"
+        );
+        assert_eq!(aspace.summary(Duration::from_secs(1)).faults, 0);
+    }
+}
@@ -0,0 +1,231 @@
+//! Tearing down address spaces without panicking.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxError, AxResult};
+use memory_addr::PhysAddr;
+use memory_set::MemorySet;
+use page_table_entry::GenericPTE;
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use super::range_map::RangeMap;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, npt};
+
+/// The outcome of tearing down an address space, returned by
+/// [`AddrSpace::close`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TeardownReport {
+    /// Number of areas unmapped cleanly.
+    pub areas_unmapped: usize,
+    /// Areas that could not be unmapped cleanly, with the cause. Their pages
+    /// were removed from the page table regardless.
+    pub failed_areas: Vec<(GuestPhysAddrRange, AxError)>,
+    /// Number of base frames owned by the address space that could not be
+    /// returned to the paging handler.
+    pub leaked_frames: usize,
+    /// Pages found mapped in the nested page table outside of any area, e.g.,
+    /// left by an operation that failed halfway, with the host address they
+    /// were mapped to. They were removed, but their frames, whose owner is
+    /// unknown, were not freed.
+    pub stray_mappings: Vec<(GuestPhysAddrRange, PhysAddr)>,
+}
+
+impl TeardownReport {
+    /// Whether every area was unmapped, every owned frame freed, and no
+    /// stray mapping found.
+    pub fn is_clean(&self) -> bool {
+        self.failed_areas.is_empty() && self.leaked_frames == 0 && self.stray_mappings.is_empty()
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Unmaps everything and destroys the address space, reporting failures
+    /// instead of panicking.
+    ///
+    /// Areas are unmapped one by one in ascending address order. A failing
+    /// area does not stop the teardown: it is recorded in the report along
+    /// with the frames it leaked. The whole nested page table is then walked
+    /// for pages still mapped outside of any area, which are removed and
+    /// reported as well. Sealing is ignored.
+    pub fn close(mut self) -> AxResult<TeardownReport> {
+        Ok(self.teardown())
+    }
+
+    /// Removes all areas, see [`AddrSpace::close`].
+    pub(crate) fn teardown(&mut self) -> TeardownReport {
+        let mut report = TeardownReport::default();
+        let areas = self.layout.replace_areas(MemorySet::new());
+        for area in areas.iter() {
+            let leaked = area
+                .backend()
+                .release(area.start(), area.size(), &mut self.state.pt);
+            if leaked == 0 {
+                report.areas_unmapped += 1;
+                continue;
+            }
+            warn!(
+                "teardown: [{:?}, {:?}) leaked {} frames",
+                area.start(),
+                area.end(),
+                leaked
+            );
+            report
+                .failed_areas
+                .push((area.va_range(), AxError::BadState));
+            report.leaked_frames += leaked;
+        }
+        self.remove_stray_mappings(&mut report);
+        npt::flush_tlb(None);
+        if let Some(rmap) = &mut self.state.rmap {
+            rmap.clear();
+        }
+        #[cfg(feature = "frame-ownership")]
+        super::ownership::release_all(self.state.space_id);
+        self.state.poisoned.clear();
+        self.state.ballooned.clear();
+        self.layout.attributes = RangeMap::new();
+        self.layout.device_regions.clear();
+        report
+    }
+
+    /// Removes the pages mapped outside of any area, recording them in
+    /// `report`. The caller must flush the TLB.
+    pub(crate) fn remove_stray_mappings(&mut self, report: &mut TeardownReport) {
+        let mut stray = Vec::new();
+        npt::tables::for_each_leaf::<H>(self.state.pt.root_paddr(), &mut |start, size, entry| {
+            let range = GuestPhysAddrRange::from_start_size(GuestPhysAddr::from(start), size);
+            if !self.layout.areas().overlaps(range) {
+                stray.push((range, entry.paddr()));
+            }
+        });
+        for (range, paddr) in stray {
+            warn!("stray mapping {range:?} -> {paddr:?} removed");
+            if let Ok((_, _, tlb)) = self.state.pt.unmap(range.start) {
+                tlb.ignore();
+            }
+            report.stray_mappings.push((range, paddr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{ALLOC_COUNT, DEALLOC_COUNT, MockHal, mock_hal_test};
+    use crate::{GuestPhysAddr, MappingFlags, PAGE_SIZE, PageSize};
+    use axin::axin;
+    use core::sync::atomic::Ordering;
+    use memory_addr::PhysAddr;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_close() {
+        const SIZE_2M: usize = 0x20_0000;
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 4 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        aspace
+            .map_linear(base + SIZE_2M, PhysAddr::from(0x8000_0000), 0x1000, rw)
+            .unwrap();
+        // An adopted huge page, which the paging handler cannot free.
+        let broken = GuestPhysAddrRange::from_start_size(base + 2 * SIZE_2M, SIZE_2M);
+        aspace
+            .state
+            .pt
+            .map(
+                broken.start,
+                PhysAddr::from(0x4000_0000),
+                PageSize::Size2M,
+                rw,
+            )
+            .unwrap()
+            .ignore();
+        aspace.map_alloc(broken.start, SIZE_2M, rw, true).unwrap();
+
+        let report = aspace.close().unwrap();
+        assert_eq!(
+            report,
+            TeardownReport {
+                areas_unmapped: 2,
+                failed_areas: alloc::vec![(broken, AxError::BadState)],
+                leaked_frames: SIZE_2M / PAGE_SIZE,
+                stray_mappings: Vec::new(),
+            }
+        );
+        assert!(!report.is_clean());
+        // Everything else, page tables included, was freed.
+        assert_eq!(
+            DEALLOC_COUNT.load(Ordering::SeqCst),
+            ALLOC_COUNT.load(Ordering::SeqCst)
+        );
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_clear_stray_mappings() {
+        const SIZE_2M: usize = 0x20_0000;
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 4 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        let shared = base + SIZE_2M;
+        aspace
+            .map_linear(shared, PhysAddr::from(0x8000_0000), 0x1000, rw)
+            .unwrap();
+        aspace
+            .set_area_attributes(shared, crate::AreaAttributes::PERSISTENT)
+            .unwrap();
+        // Pages left behind by operations that failed halfway: a base page
+        // next to an area, and a huge page.
+        let page = GuestPhysAddrRange::from_start_size(base + 0x8000, PAGE_SIZE);
+        let huge = GuestPhysAddrRange::from_start_size(base + 3 * SIZE_2M, SIZE_2M);
+        for (range, hpa, size) in [
+            (page, 0x4000_0000, PageSize::Size4K),
+            (huge, 0xc000_0000, PageSize::Size2M),
+        ] {
+            aspace
+                .state
+                .pt
+                .map(range.start, PhysAddr::from(hpa), size, rw)
+                .unwrap()
+                .ignore();
+        }
+
+        // Persistent areas are kept, stray pages are not.
+        aspace.clear().unwrap();
+        assert!(aspace.translate(page.start).is_none());
+        assert!(aspace.translate(huge.start).is_none());
+        assert!(aspace.translate(base).is_none());
+        assert_eq!(aspace.translate(shared), Some(PhysAddr::from(0x8000_0000)));
+        aspace.clear().unwrap();
+        assert_eq!(aspace.translate(shared), Some(PhysAddr::from(0x8000_0000)));
+
+        aspace
+            .state
+            .pt
+            .map(
+                page.start,
+                PhysAddr::from(0x4000_0000),
+                PageSize::Size4K,
+                rw,
+            )
+            .unwrap()
+            .ignore();
+        let report = aspace.close().unwrap();
+        assert_eq!(
+            report,
+            TeardownReport {
+                areas_unmapped: 1,
+                failed_areas: Vec::new(),
+                leaked_frames: 0,
+                stray_mappings: alloc::vec![(page, PhysAddr::from(0x4000_0000))],
+            }
+        );
+        assert_eq!(
+            DEALLOC_COUNT.load(Ordering::SeqCst),
+            ALLOC_COUNT.load(Ordering::SeqCst)
+        );
+    }
+}
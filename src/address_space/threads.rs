@@ -0,0 +1,202 @@
+//! The thread model of [`AddrSpace`].
+//!
+//! An address space is [`Send`] and [`Sync`]:
+//!
+//! - It can be moved to another thread or CPU, e.g., when the VM it belongs
+//!   to is rescheduled.
+//! - Methods taking `&self` (translations, guest memory accessors, dirty
+//!   marking, counters) can run concurrently, including from interrupt
+//!   handlers. The state they update is atomic: the dirty bitmap, the event
+//!   counters and the area lookup cache.
+//! - Methods taking `&mut self` need exclusive access, usually through the
+//!   lock of the VM. They allocate and free frames and flush TLBs, so they
+//!   must not run in interrupt handlers.
+//!
+//! The last rule and the ownership of the address space are checked at
+//! runtime with the `debug-threads` feature. The host tells which context
+//! the code runs in through the [`ContextProbe`] installed with
+//! [`set_context_probe`]. The context creating an address space (or the
+//! first mutating it, if the probe is installed later) owns it, and
+//! mutating it from another context or from an interrupt handler panics.
+//! Handing it over on purpose is done with [`AddrSpace::adopt`].
+
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use super::layout::{Layout, PageState};
+
+// SAFETY: `H` only provides static functions, no value of it is stored, so
+// whether it is `Send` does not matter. The fields are `Send` for any `Send`
+// handler, which is checked below.
+unsafe impl<H: PagingHandler> Send for AddrSpace<H> {}
+
+// SAFETY: as for `Send`. Shared references only read the fields, or update
+// atomics, which is checked below as the fields being `Sync`.
+unsafe impl<H: PagingHandler> Sync for AddrSpace<H> {}
+
+/// Fails to compile if a field of an address space stops being `Send` or
+/// `Sync`, which would make the impls above unsound.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+
+    #[allow(dead_code)]
+    const fn check<H: PagingHandler + Send + Sync>() {
+        assert_send_sync::<Layout<H>>();
+        assert_send_sync::<PageState<H>>();
+    }
+};
+
+#[cfg(feature = "debug-threads")]
+pub(crate) use debug::current_owner;
+#[cfg(feature = "debug-threads")]
+pub use debug::{ContextProbe, set_context_probe};
+
+#[cfg(feature = "debug-threads")]
+mod debug {
+    use core::sync::atomic::Ordering;
+
+    use axerrno::{AxResult, ax_err};
+    use lazyinit::LazyInit;
+    use page_table_multiarch::PagingHandler;
+
+    use super::AddrSpace;
+
+    /// Tells which context the host code runs in.
+    pub trait ContextProbe: Sync {
+        /// Identifies the current thread, or the current CPU if the host
+        /// does not schedule threads.
+        fn current_context(&self) -> usize;
+        /// Whether the current code runs in an interrupt handler.
+        fn in_interrupt(&self) -> bool;
+    }
+
+    static PROBE: LazyInit<&'static dyn ContextProbe> = LazyInit::new();
+
+    /// Installs the probe used to check the thread model.
+    ///
+    /// Fails with `AlreadyExists` if a probe is already installed. Until
+    /// then, nothing is checked.
+    pub fn set_context_probe(probe: &'static dyn ContextProbe) -> AxResult {
+        match PROBE.call_once(|| probe) {
+            Some(_) => Ok(()),
+            None => ax_err!(AlreadyExists, "context probe already installed"),
+        }
+    }
+
+    /// Returns the encoding of the current context in `PageState::owner`,
+    /// zero if unknown.
+    pub(crate) fn current_owner() -> usize {
+        PROBE.get().map_or(0, |probe| probe.current_context() + 1)
+    }
+
+    impl<H: PagingHandler> AddrSpace<H> {
+        /// Returns the context owning the address space, if known.
+        pub fn owner_context(&self) -> Option<usize> {
+            self.state.owner.load(Ordering::Relaxed).checked_sub(1)
+        }
+
+        /// Makes the current context the owner of the address space, after
+        /// it was handed over from another one on purpose.
+        pub fn adopt(&mut self) {
+            *self.state.owner.get_mut() = current_owner();
+        }
+
+        /// Panics if the mutating operation `op` runs in an interrupt
+        /// handler, or in another context than the owner.
+        pub(crate) fn check_context(&self, op: &str) {
+            let Some(probe) = PROBE.get() else {
+                return;
+            };
+            if probe.in_interrupt() {
+                panic!("AddrSpace::{op}() called from an interrupt handler");
+            }
+            let current = probe.current_context() + 1;
+            match self.state.owner.compare_exchange(
+                0,
+                current,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {}
+                Err(owner) if owner == current => {}
+                Err(owner) => panic!(
+                    "AddrSpace::{op}() called from context {}, but the address space is \
+                     owned by context {} (see AddrSpace::adopt)",
+                    current - 1,
+                    owner - 1,
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "debug-threads"))]
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Checks the context of a mutating operation, with `debug-threads`.
+    #[inline(always)]
+    pub(crate) fn check_context(&self, _op: &str) {}
+}
+
+#[cfg(all(test, feature = "debug-threads"))]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{GuestPhysAddr, MappingFlags};
+    use axin::axin;
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+
+    static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+    std::thread_local! {
+        static THREAD: usize = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+        static IN_IRQ: Cell<bool> = const { Cell::new(false) };
+    }
+
+    struct ThreadProbe;
+
+    impl ContextProbe for ThreadProbe {
+        fn current_context(&self) -> usize {
+            THREAD.with(|id| *id)
+        }
+
+        fn in_interrupt(&self) -> bool {
+            IN_IRQ.with(Cell::get)
+        }
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_debug_threads() {
+        let _ = set_context_probe(&ThreadProbe);
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let owner = ThreadProbe.current_context();
+        assert_eq!(aspace.owner_context(), Some(owner));
+        aspace.map_alloc(base, 0x1000, rw, true).unwrap();
+
+        // Mutating from an interrupt handler, reading is fine.
+        IN_IRQ.with(|irq| irq.set(true));
+        assert!(aspace.translate(base).is_some());
+        let result = catch_unwind(AssertUnwindSafe(|| aspace.unmap(base, 0x1000)));
+        IN_IRQ.with(|irq| irq.set(false));
+        assert!(result.is_err());
+
+        // Mutating from another thread, until it adopts the address space.
+        let aspace = std::thread::spawn(move || {
+            let mut aspace = aspace;
+            let result = catch_unwind(AssertUnwindSafe(|| aspace.unmap(base, 0x1000)));
+            assert!(result.is_err());
+            aspace.adopt();
+            aspace.unmap(base, 0x1000).unwrap();
+            aspace
+        })
+        .join()
+        .unwrap();
+        assert_ne!(aspace.owner_context(), Some(owner));
+    }
+}
@@ -0,0 +1,172 @@
+//! Dirty-rate throttling for auto-converging live migration.
+//!
+//! A guest dirtying pages faster than they can be sent never lets a
+//! migration converge. The VMM can limit the rate at which pages are dirtied
+//! with [`AddrSpace::set_dirty_rate_limit`]: every write fault taken for
+//! dirty logging beyond the limit is reported to a [`DirtyRateHook`], which
+//! typically stalls the faulting vCPU for a while.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::PAGE_SIZE;
+
+/// Receives the write faults of vCPUs exceeding the dirty rate limit.
+pub trait DirtyRateHook: Send + Sync {
+    /// Returns the current time from any monotonic clock.
+    fn now(&self) -> Duration;
+
+    /// Called on a dirty-logging write fault of `vcpu` (`None` if the fault
+    /// was not attributed) when it has dirtied more than the allowed number
+    /// of pages in the current window. `pages_per_sec` is its rate over the
+    /// window so far.
+    ///
+    /// Called from the fault path, with the address space mutably borrowed.
+    fn rate_exceeded(&self, vcpu: Option<usize>, pages_per_sec: u64);
+}
+
+/// A limit on the rate at which pages are dirtied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRateLimit {
+    /// The number of base pages each vCPU may dirty per second.
+    pub pages_per_sec: u64,
+    /// The window the rate is measured over. Shorter windows react faster,
+    /// but are more sensitive to bursts.
+    pub window: Duration,
+}
+
+/// The dirty rate limit of an address space and the rate of each vCPU.
+pub(crate) struct DirtyThrottle {
+    limit: DirtyRateLimit,
+    hook: Box<dyn DirtyRateHook>,
+    /// The start of the current window and the pages dirtied in it, by vCPU.
+    windows: BTreeMap<Option<usize>, (Duration, u64)>,
+}
+
+impl DirtyThrottle {
+    /// Accounts `pages` dirtied by `vcpu`, and calls the hook if the limit is
+    /// exceeded.
+    fn account(&mut self, vcpu: Option<usize>, pages: u64) {
+        let now = self.hook.now();
+        let (start, dirtied) = self.windows.entry(vcpu).or_insert((now, 0));
+        if now.saturating_sub(*start) >= self.limit.window {
+            *start = now;
+            *dirtied = 0;
+        }
+        *dirtied += pages;
+        let budget =
+            self.limit.pages_per_sec as u128 * self.limit.window.as_nanos() / 1_000_000_000;
+        if *dirtied as u128 <= budget {
+            return;
+        }
+        let elapsed = now.saturating_sub(*start).as_nanos().max(1);
+        let rate = (*dirtied as u128 * 1_000_000_000 / elapsed).min(u64::MAX as u128) as u64;
+        self.hook.rate_exceeded(vcpu, rate);
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Limits the rate at which each vCPU dirties pages while dirty logging
+    /// is enabled, reporting the faults beyond the limit to `hook`.
+    ///
+    /// Only guest writes are accounted, as the first write to each page
+    /// faults for dirty logging. The faults are attributed to the
+    /// [`FaultContext::vcpu_id`](crate::FaultContext::vcpu_id) given to
+    /// [`AddrSpace::handle_page_fault_with_context`].
+    pub fn set_dirty_rate_limit(&mut self, limit: DirtyRateLimit, hook: Box<dyn DirtyRateHook>) {
+        self.state.dirty_throttle = Some(DirtyThrottle {
+            limit,
+            hook,
+            windows: BTreeMap::new(),
+        });
+    }
+
+    /// Removes the dirty rate limit.
+    pub fn clear_dirty_rate_limit(&mut self) {
+        self.state.dirty_throttle = None;
+    }
+
+    /// Accounts `size` bytes dirtied by a write fault of `vcpu`.
+    pub(crate) fn account_dirty_fault(&mut self, vcpu: Option<usize>, size: usize) {
+        if let Some(throttle) = &mut self.state.dirty_throttle {
+            throttle.account(vcpu, (size / PAGE_SIZE) as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{FaultContext, GuestPhysAddr, MappingFlags};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use axin::axin;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use spin::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        now_ms: AtomicU64,
+        exceeded: Mutex<Vec<(Option<usize>, u64)>>,
+    }
+
+    impl DirtyRateHook for Arc<Recorder> {
+        fn now(&self) -> Duration {
+            Duration::from_millis(self.now_ms.load(Ordering::Relaxed))
+        }
+
+        fn rate_exceeded(&self, vcpu: Option<usize>, pages_per_sec: u64) {
+            self.exceeded.lock().push((vcpu, pages_per_sec));
+        }
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_dirty_rate_limit() {
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x8000, rw, true).unwrap();
+        aspace.enable_dirty_logging().unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let limit = DirtyRateLimit {
+            pages_per_sec: 20,
+            window: Duration::from_millis(100),
+        };
+        aspace.set_dirty_rate_limit(limit, Box::new(recorder.clone()));
+        let fault = |aspace: &mut AddrSpace<MockHal>, vcpu, gpa| {
+            aspace.handle_page_fault_with_context(
+                gpa,
+                MappingFlags::WRITE,
+                &FaultContext::vcpu(vcpu),
+            )
+        };
+
+        // Two pages per window and vCPU are allowed.
+        recorder.now_ms.store(1000, Ordering::Relaxed);
+        assert!(fault(&mut aspace, 0, base));
+        assert!(fault(&mut aspace, 1, base + 0x1000));
+        assert!(fault(&mut aspace, 0, base + 0x2000));
+        // Already writable, so not accounted.
+        assert!(!fault(&mut aspace, 0, base + 0x2000));
+        assert!(recorder.exceeded.lock().is_empty());
+
+        recorder.now_ms.store(1050, Ordering::Relaxed);
+        assert!(fault(&mut aspace, 0, base + 0x3000));
+        assert!(aspace.handle_page_fault(base + 0x4000, MappingFlags::WRITE));
+        assert_eq!(*recorder.exceeded.lock(), [(Some(0), 60)]);
+
+        // A new window starts.
+        recorder.now_ms.store(1100, Ordering::Relaxed);
+        assert!(fault(&mut aspace, 0, base + 0x5000));
+        assert_eq!(recorder.exceeded.lock().len(), 1);
+
+        aspace.clear_dirty_rate_limit();
+        assert_eq!(Arc::strong_count(&recorder), 1);
+    }
+}
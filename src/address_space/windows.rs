@@ -0,0 +1,109 @@
+//! Windows of guest addresses added after construction, e.g., for memory
+//! hot-add above the initial range.
+
+use core::sync::atomic::AtomicU64;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::addr::check_max_gpa;
+use crate::{GuestPhysAddrRange, PAGE_SIZE};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Adds `range` to the valid guest addresses, e.g., to hot-add RAM above
+    /// 4G to a guest booted with low memory only.
+    ///
+    /// The new window can then be mapped like the initial one. Windows may
+    /// be adjacent, in which case a mapping can span several of them, but
+    /// must not overlap. `range` must be non-empty, page-aligned, and not
+    /// extend beyond [`npt::max_supported_gpa`](crate::npt::max_supported_gpa).
+    ///
+    /// Fails with [`AxError::BadState`](axerrno::AxError::BadState) if the
+    /// address space is sealed, and with
+    /// [`AxError::AlreadyExists`](axerrno::AxError::AlreadyExists) if `range`
+    /// overlaps an existing window.
+    pub fn extend_va_range(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        self.check_unsealed()?;
+        if range.is_empty()
+            || !range.start.is_aligned(PAGE_SIZE)
+            || !range.end.is_aligned(PAGE_SIZE)
+        {
+            return ax_err!(InvalidInput, "window empty or not aligned");
+        }
+        check_max_gpa(range)?;
+        if self.layout.windows().any(|window| window.overlaps(range)) {
+            return ax_err!(AlreadyExists, "window overlaps the address space");
+        }
+        self.layout.extra_ranges.push(range);
+        // The pages of the new window come last in the page bitmaps.
+        let words = self.layout.num_pages().div_ceil(64);
+        if let Some(bitmap) = &mut self.state.dirty_bitmap {
+            bitmap.resize_with(words, || AtomicU64::new(0));
+        }
+        if let Some(heatmap) = &mut self.state.heatmap {
+            heatmap.add_window(range);
+        }
+        Ok(())
+    }
+
+    /// Returns the windows of valid guest addresses: the initial one, then
+    /// those added with [`AddrSpace::extend_va_range`], in order.
+    pub fn va_ranges(&self) -> impl Iterator<Item = GuestPhysAddrRange> + '_ {
+        self.layout.windows()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{GuestPhysAddr, MappingFlags};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_extend_va_range() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x4000).unwrap();
+        let high = GuestPhysAddrRange::from_start_size(GuestPhysAddr::from(0x1_0000_0000), 0x2000);
+        assert!(aspace.map_alloc(high.start, 0x1000, rw, false).is_err());
+        aspace.enable_dirty_logging().unwrap();
+
+        aspace.extend_va_range(high).unwrap();
+        assert_eq!(
+            aspace.extend_va_range(GuestPhysAddrRange::from_start_size(base + 0x3000, 0x2000)),
+            Err(AxError::AlreadyExists)
+        );
+        // Adjacent to the initial window, below it.
+        let low = GuestPhysAddrRange::from_start_size(base - 0x1000, 0x1000);
+        aspace.extend_va_range(low).unwrap();
+        assert_eq!(aspace.va_ranges().count(), 3);
+        // Beyond the width of the nested page table.
+        let beyond =
+            GuestPhysAddrRange::from_start_size(crate::npt::max_supported_gpa() + 1, 0x1000);
+        assert_eq!(aspace.extend_va_range(beyond), Err(AxError::InvalidInput));
+        assert_eq!(aspace.size(), 0x4000);
+
+        aspace.map_alloc(high.start, 0x2000, rw, false).unwrap();
+        assert!(aspace.handle_page_fault(high.start + 0x1000, MappingFlags::WRITE));
+        assert!(aspace.translate(high.start + 0x1000).is_some());
+        // A mapping spanning two adjacent windows.
+        aspace.map_alloc(low.start, 0x2000, rw, true).unwrap();
+        assert!(aspace.contains_range(low.start, 0x5000));
+        assert!(!aspace.contains_range(low.start, 0x6000));
+
+        // Pages mapped while logging are dirty, in address order.
+        let dirty = [low.start, base, high.start, high.start + 0x1000];
+        assert_eq!(aspace.take_dirty_pages(), dirty);
+        aspace.mark_dirty(base - 1, 2);
+        assert_eq!(aspace.take_dirty_pages(), [low.start, base]);
+
+        aspace.seal(crate::SealMode::Temporary);
+        let next = GuestPhysAddrRange::from_start_size(high.end, 0x1000);
+        assert_eq!(aspace.extend_va_range(next), Err(AxError::BadState));
+    }
+}
@@ -0,0 +1,191 @@
+//! Bulk zeroing of guest memory, e.g., to clear RAM on guest reboot.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, AreaAttributes, Backend};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, MemType, loader};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Zeroes the guest memory in `range`.
+    ///
+    /// The range is resolved to host extents, each cleared at once (so huge
+    /// pages are cleared with a single `memset`), instead of byte by byte
+    /// through an accessor. Pages that are not mapped, e.g., lazily allocated
+    /// pages not faulted in yet, already read as zeros and are skipped, as
+    /// are the holes between areas. The zeroed pages are marked dirty.
+    ///
+    /// Fails with `InvalidInput` if `range` is not inside the address space,
+    /// and with `PermissionDenied` before zeroing anything if a page is
+    /// still mapped to the template of a copy-on-write area (see
+    /// [`AddrSpace::revert_cow`] instead). With the `paranoid` feature, fails with `BadAddress` before zeroing
+    /// anything if a page is mapped to host memory not given to the address
+    /// space.
+    pub fn zero_range(&self, range: GuestPhysAddrRange) -> AxResult {
+        if !self.layout.contains_range(range) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if self.has_cow_shared(range) {
+            return ax_err!(
+                PermissionDenied,
+                "range shared with a copy-on-write template"
+            );
+        }
+        #[cfg(feature = "paranoid")]
+        self.for_each_host_segment(range.start, range.size(), |gpa, paddr, len| {
+            match (paddr, self.layout.find_area(gpa)) {
+                (Some(paddr), Some(area))
+                    if !self.host_range_allowed(area.backend(), gpa, paddr, len) =>
+                {
+                    warn!("zero_range: {gpa:?} maps to foreign {paddr:?}");
+                    ax_err!(BadAddress, "page mapped to foreign host memory")
+                }
+                _ => Ok(()),
+            }
+        })?;
+        self.for_each_host_segment(range.start, range.size(), |gpa, paddr, len| {
+            if let Some(paddr) = paddr {
+                unsafe { core::ptr::write_bytes(H::phys_to_virt(paddr).as_mut_ptr(), 0, len) };
+                self.mark_dirty(gpa, len);
+            }
+            Ok(())
+        })
+    }
+
+    /// Resets the contents of guest RAM for a guest reboot, keeping the
+    /// layout of the address space.
+    ///
+    /// Every area of normal memory that is not
+    /// [`AreaAttributes::PERSISTENT`] is reset: lazily allocated pages are
+    /// released back to the lazy state, as are the pages released by the
+    /// guest with [`AddrSpace::guest_release_pages`], copy-on-write areas
+    /// are reverted to their template, other pages are zeroed. Device
+    /// areas, reserved MMIO ranges and persistent areas are left intact.
+    /// `images` are then loaded again with [`loader::load_bytes`], each as
+    /// the guest address it goes at and its contents, and the dirty pages
+    /// are forgotten, so that the guest restarts from a clean state.
+    ///
+    /// Fails with `BadState` if the address space is sealed, or with the
+    /// first error of loading an image.
+    pub fn reset_ram_contents(&mut self, images: &[(GuestPhysAddr, &[u8])]) -> AxResult {
+        self.check_unsealed()?;
+        let ram: Vec<(GuestPhysAddrRange, Backend<H>)> = self
+            .layout
+            .areas()
+            .iter()
+            .filter(|area| MemType::from_flags(area.flags()) == MemType::Normal)
+            .filter(|area| {
+                !self
+                    .area_attributes(area.start())
+                    .contains(AreaAttributes::PERSISTENT)
+            })
+            .map(|area| (area.va_range(), area.backend().clone()))
+            .collect();
+        for (range, backend) in ram {
+            self.clear_ballooned(range.start, range.size());
+            match backend {
+                Backend::Alloc {
+                    populate: false, ..
+                } => self.release_lazy(range)?,
+                Backend::CoW { .. } => {
+                    self.revert_cow(range)?;
+                }
+                _ => self.zero_range(range)?,
+            }
+        }
+        for &(gpa, data) in images {
+            loader::load_bytes(self, gpa, data)?;
+        }
+        self.take_dirty_pages();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MappingFlags;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_zero_range() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x3000, rw, true).unwrap();
+        aspace.map_alloc(base + 0x4000, 0x2000, rw, false).unwrap();
+        for page in [0, 0x1000, 0x2000] {
+            for buf in aspace.translated_byte_buffer(base + page, 0x1000).unwrap() {
+                buf.fill(0xa5);
+            }
+        }
+        aspace.enable_dirty_logging().unwrap();
+
+        // Covers the hole at 0x3000 and the lazy pages after it.
+        let range = GuestPhysAddrRange::from_start_size(base + 0x800, 0x5000);
+        aspace.zero_range(range).unwrap();
+        let bytes =
+            |gpa: GuestPhysAddr, len| aspace.translated_byte_buffer(gpa, len).unwrap()[0].to_vec();
+        assert!(bytes(base, 0x800).iter().all(|&b| b == 0xa5));
+        assert!(bytes(base + 0x800, 0x800).iter().all(|&b| b == 0));
+        assert!(bytes(base + 0x2000, 0x1000).iter().all(|&b| b == 0));
+        assert!(aspace.translate(base + 0x4000).is_none());
+        assert_eq!(
+            aspace.take_dirty_pages(),
+            [base, base + 0x1000, base + 0x2000]
+        );
+
+        assert_eq!(
+            aspace.zero_range(GuestPhysAddrRange::from_start_size(base + 0xf000, 0x2000)),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_reset_ram_contents() {
+        let base = GuestPhysAddr::from(0x10000);
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        aspace.map_alloc(base + 0x4000, 0x2000, rw, false).unwrap();
+        assert!(aspace.handle_page_fault(base + 0x5000, MappingFlags::WRITE));
+        // A shared info page, kept across reboots.
+        let shared = base + 0x8000;
+        aspace.map_alloc(shared, 0x1000, rw, true).unwrap();
+        aspace
+            .set_area_attributes(shared, AreaAttributes::PERSISTENT)
+            .unwrap();
+        let mmio = GuestPhysAddrRange::from_start_size(base + 0xa000, 0x1000);
+        aspace.reserve_mmio(mmio).unwrap();
+        for gpa in [base, base + 0x1000, base + 0x5000, shared] {
+            aspace.translated_byte_buffer(gpa, 0x1000).unwrap()[0].fill(0xa5);
+        }
+        aspace.enable_dirty_logging().unwrap();
+        let frame = aspace.translate(base).unwrap();
+
+        let kernel = [1, 2, 3, 4];
+        aspace
+            .reset_ram_contents(&[(base + 0xffe, &kernel)])
+            .unwrap();
+        let bytes =
+            |gpa: GuestPhysAddr, len| aspace.translated_byte_buffer(gpa, len).unwrap()[0].to_vec();
+        assert!(bytes(base, 0xffe).iter().all(|&b| b == 0));
+        assert_eq!(bytes(base + 0xffe, 2), [1, 2]);
+        assert_eq!(bytes(base + 0x1000, 3), [3, 4, 0]);
+        assert_eq!(aspace.translate(base), Some(frame));
+        assert!(aspace.translate(base + 0x5000).is_none());
+        assert!(aspace.is_lazy_placeholder(base + 0x5000));
+        assert!(bytes(shared, 0x1000).iter().all(|&b| b == 0xa5));
+        assert!(aspace.is_mmio(mmio.start));
+        assert!(aspace.take_dirty_pages().is_empty());
+
+        aspace.seal(crate::SealMode::Temporary);
+        assert_eq!(aspace.reset_ram_contents(&[]), Err(AxError::BadState));
+    }
+}
@@ -0,0 +1,184 @@
+//! Fixed-capacity area storage, usable without a heap.
+//!
+//! Static partitioning hypervisors and early boot stages know their guest
+//! layout up front and map it linearly. [`AreaTable`] keeps such a layout in
+//! an inline array sized at compile time instead of a heap-backed
+//! `MemorySet`.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+
+use crate::{GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr, MappingFlags, PAGE_SIZE};
+
+/// A guest physical range mapped linearly to host memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StaticArea {
+    /// The guest physical range.
+    pub range: GuestPhysAddrRange,
+    /// The host physical address the start of the range is mapped to.
+    pub start_paddr: HostPhysAddr,
+    /// The mapping flags of the range.
+    pub flags: MappingFlags,
+}
+
+impl StaticArea {
+    /// Creates an area mapping `size` bytes from `start` to `start_paddr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start + size` overflows.
+    pub fn new(
+        start: GuestPhysAddr,
+        start_paddr: HostPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> Self {
+        Self {
+            range: GuestPhysAddrRange::from_start_size(start, size),
+            start_paddr,
+            flags,
+        }
+    }
+
+    /// Returns the host physical address `gpa` is mapped to, or `None` if
+    /// `gpa` is outside the area.
+    pub fn translate(&self, gpa: GuestPhysAddr) -> Option<HostPhysAddr> {
+        self.range
+            .contains(gpa)
+            .then(|| self.start_paddr + (gpa - self.range.start))
+    }
+}
+
+/// Up to `N` non-overlapping [`StaticArea`]s, sorted by address.
+#[derive(Debug, Clone)]
+pub struct AreaTable<const N: usize> {
+    areas: [Option<StaticArea>; N],
+    len: usize,
+}
+
+impl<const N: usize> AreaTable<N> {
+    /// Creates an empty table.
+    pub const fn new() -> Self {
+        Self {
+            areas: [None; N],
+            len: 0,
+        }
+    }
+
+    /// Returns the number of areas.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the table contains no area.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximum number of areas, `N`.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Adds `area` to the table.
+    ///
+    /// Fails with `InvalidInput` if the area is empty or not page aligned,
+    /// with `AlreadyExists` if it overlaps an existing area, and with
+    /// `NoMemory` if the table is full.
+    pub fn insert(&mut self, area: StaticArea) -> AxResult {
+        if area.range.is_empty()
+            || !area.range.start.is_aligned(PAGE_SIZE)
+            || !area.range.end.is_aligned(PAGE_SIZE)
+            || !area.start_paddr.is_aligned(PAGE_SIZE)
+        {
+            return ax_err!(InvalidInput, "area empty or not aligned");
+        }
+        if self.iter().any(|a| a.range.overlaps(area.range)) {
+            return ax_err!(AlreadyExists, "area overlaps an existing one");
+        }
+        if self.len == N {
+            return ax_err!(NoMemory, "area table full");
+        }
+        let pos = self
+            .iter()
+            .position(|a| a.range.start > area.range.start)
+            .unwrap_or(self.len);
+        self.areas[pos..=self.len].rotate_right(1);
+        self.areas[pos] = Some(area);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes and returns the area starting at `start`.
+    pub fn remove(&mut self, start: GuestPhysAddr) -> Option<StaticArea> {
+        let pos = self.iter().position(|a| a.range.start == start)?;
+        let area = self.areas[pos].take();
+        self.areas[pos..self.len].rotate_left(1);
+        self.len -= 1;
+        area
+    }
+
+    /// Returns the area containing `gpa`.
+    pub fn find(&self, gpa: GuestPhysAddr) -> Option<&StaticArea> {
+        self.iter().find(|a| a.range.contains(gpa))
+    }
+
+    /// Returns the host physical address `gpa` is mapped to.
+    pub fn translate(&self, gpa: GuestPhysAddr) -> Option<HostPhysAddr> {
+        self.find(gpa)?.translate(gpa)
+    }
+
+    /// Returns an iterator over the areas, in ascending address order.
+    pub fn iter(&self) -> impl Iterator<Item = &StaticArea> {
+        self.areas[..self.len].iter().flatten()
+    }
+}
+
+impl<const N: usize> Default for AreaTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axerrno::AxError;
+
+    #[test]
+    fn test_area_table() {
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let area = |gpa: usize, size| {
+            StaticArea::new(gpa.into(), HostPhysAddr::from(gpa + 0x8000_0000), size, rw)
+        };
+        let mut table = AreaTable::<2>::new();
+        table.insert(area(0x4000, 0x1000)).unwrap();
+        table.insert(area(0x1000, 0x2000)).unwrap();
+        assert!(
+            table
+                .iter()
+                .map(|a| a.range.start.as_usize())
+                .eq([0x1000, 0x4000])
+        );
+        assert_eq!(table.insert(area(0x8000, 0x1000)), Err(AxError::NoMemory));
+        assert_eq!(
+            table.translate(0x2010.into()),
+            Some(HostPhysAddr::from(0x8000_2010))
+        );
+        assert_eq!(table.translate(0x3000.into()), None);
+
+        assert_eq!(table.remove(0x1000.into()), Some(area(0x1000, 0x2000)));
+        assert_eq!(table.remove(0x1000.into()), None);
+        assert_eq!(
+            table.insert(area(0x4000, 0x2000)),
+            Err(AxError::AlreadyExists)
+        );
+        assert_eq!(
+            table.insert(area(0x1800, 0x1000)),
+            Err(AxError::InvalidInput)
+        );
+        table.insert(area(0x2000, 0x2000)).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.find(0x3fff.into()), Some(&area(0x2000, 0x2000)));
+    }
+}
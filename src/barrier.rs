@@ -0,0 +1,147 @@
+//! Memory barriers for guest-shared memory.
+//!
+//! Volatile accesses are not reordered by the compiler, but the CPU may still
+//! make them visible to other CPUs, or to devices, in a different order. The
+//! `smp_*` barriers order accesses as seen by other CPUs, e.g., vCPUs of a
+//! guest reading a virtqueue, and the `dma_*` barriers also order them as seen
+//! by DMA-capable devices. Both follow the Linux semantics of the same names.
+//! [`wc_flush`] makes stores to write-combining memory, e.g., a framebuffer,
+//! visible at once rather than whenever the buffers happen to drain.
+//!
+//! They are meant to be called from the
+//! [`GuestMemoryAccessor::write_barrier`](crate::GuestMemoryAccessor::write_barrier)
+//! and [`GuestMemoryAccessor::read_barrier`](crate::GuestMemoryAccessor::read_barrier)
+//! hooks.
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        // Loads are not reordered with loads, nor stores with stores (TSO).
+        use core::sync::atomic::{Ordering, compiler_fence};
+
+        /// Orders earlier stores before later stores, as seen by other CPUs.
+        #[inline]
+        pub fn smp_wmb() {
+            compiler_fence(Ordering::Release);
+        }
+
+        /// Orders earlier loads before later loads, as seen by other CPUs.
+        #[inline]
+        pub fn smp_rmb() {
+            compiler_fence(Ordering::Acquire);
+        }
+
+        /// Orders earlier stores before later stores, as seen by devices.
+        #[inline]
+        pub fn dma_wmb() {
+            compiler_fence(Ordering::Release);
+        }
+
+        /// Orders earlier loads before later loads, as seen by devices.
+        #[inline]
+        pub fn dma_rmb() {
+            compiler_fence(Ordering::Acquire);
+        }
+
+        /// Drains the write-combining buffers, making earlier stores to
+        /// write-combining memory visible to other CPUs and devices.
+        #[inline]
+        pub fn wc_flush() {
+            unsafe { core::arch::asm!("sfence", options(nostack, preserves_flags)) };
+        }
+    } else if #[cfg(target_arch = "aarch64")] {
+        /// Orders earlier stores before later stores, as seen by other CPUs.
+        #[inline]
+        pub fn smp_wmb() {
+            unsafe { core::arch::asm!("dmb ishst", options(nostack, preserves_flags)) };
+        }
+
+        /// Orders earlier loads before later loads, as seen by other CPUs.
+        #[inline]
+        pub fn smp_rmb() {
+            unsafe { core::arch::asm!("dmb ishld", options(nostack, preserves_flags)) };
+        }
+
+        /// Orders earlier stores before later stores, as seen by devices.
+        #[inline]
+        pub fn dma_wmb() {
+            unsafe { core::arch::asm!("dmb oshst", options(nostack, preserves_flags)) };
+        }
+
+        /// Orders earlier loads before later loads, as seen by devices.
+        #[inline]
+        pub fn dma_rmb() {
+            unsafe { core::arch::asm!("dmb oshld", options(nostack, preserves_flags)) };
+        }
+
+        /// Drains the write-combining buffers, making earlier stores to
+        /// write-combining memory visible to other CPUs and devices.
+        #[inline]
+        pub fn wc_flush() {
+            unsafe { core::arch::asm!("dsb st", options(nostack, preserves_flags)) };
+        }
+    } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
+        /// Orders earlier stores before later stores, as seen by other CPUs.
+        #[inline]
+        pub fn smp_wmb() {
+            unsafe { core::arch::asm!("fence w, w", options(nostack)) };
+        }
+
+        /// Orders earlier loads before later loads, as seen by other CPUs.
+        #[inline]
+        pub fn smp_rmb() {
+            unsafe { core::arch::asm!("fence r, r", options(nostack)) };
+        }
+
+        /// Orders earlier stores before later stores, as seen by devices.
+        #[inline]
+        pub fn dma_wmb() {
+            unsafe { core::arch::asm!("fence ow, ow", options(nostack)) };
+        }
+
+        /// Orders earlier loads before later loads, as seen by devices.
+        #[inline]
+        pub fn dma_rmb() {
+            unsafe { core::arch::asm!("fence ir, ir", options(nostack)) };
+        }
+
+        /// Drains the write-combining buffers, making earlier stores to
+        /// write-combining memory visible to other CPUs and devices.
+        #[inline]
+        pub fn wc_flush() {
+            unsafe { core::arch::asm!("fence ow, ow", "fence w, w", options(nostack)) };
+        }
+    } else {
+        use core::sync::atomic::{Ordering, fence};
+
+        /// Orders earlier stores before later stores, as seen by other CPUs.
+        #[inline]
+        pub fn smp_wmb() {
+            fence(Ordering::Release);
+        }
+
+        /// Orders earlier loads before later loads, as seen by other CPUs.
+        #[inline]
+        pub fn smp_rmb() {
+            fence(Ordering::Acquire);
+        }
+
+        /// Orders earlier stores before later stores, as seen by devices.
+        #[inline]
+        pub fn dma_wmb() {
+            fence(Ordering::SeqCst);
+        }
+
+        /// Orders earlier loads before later loads, as seen by devices.
+        #[inline]
+        pub fn dma_rmb() {
+            fence(Ordering::SeqCst);
+        }
+
+        /// Drains the write-combining buffers, making earlier stores to
+        /// write-combining memory visible to other CPUs and devices.
+        #[inline]
+        pub fn wc_flush() {
+            fence(Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Micro-benchmarks of the mapping and fault paths.
+//!
+//! The benchmarks run in a `no_std` environment: time is read from a
+//! caller-provided clock (e.g., a cycle counter such as `rdtsc` or
+//! `cntvct_el0`), and the results are returned as [`BenchResult`]s instead of
+//! being printed.
+//!
+//! Allocation areas are always backed by 4K frames, so 2M and 1G pages are
+//! exercised through linear mappings.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::PhysAddr;
+use page_table_multiarch::{PageSize, PagingHandler};
+
+use crate::{AddrSpace, GuestPhysAddr, MapGranularity, MappingFlags};
+
+/// The operation measured by a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchKind {
+    /// Creating a populated allocation mapping.
+    MapAllocPopulate,
+    /// Handling page faults on a lazy allocation mapping, one per page.
+    LazyFault,
+    /// Translating guest physical addresses.
+    Translate,
+    /// Tearing down a mapping.
+    Unmap,
+    /// Finding the area of an address among many areas, looking up each
+    /// area several times in a row, as faults on guest RAM do.
+    AreaLookupRepeated,
+    /// Finding the area of an address among many areas, never the same area
+    /// twice in a row.
+    AreaLookupScattered,
+}
+
+/// The result of a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    /// The measured operation.
+    pub kind: BenchKind,
+    /// The page size of the mappings involved.
+    pub page_size: PageSize,
+    /// The number of pages (or translations) processed.
+    pub ops: usize,
+    /// The elapsed time, in ticks of the clock.
+    pub ticks: u64,
+}
+
+impl BenchResult {
+    /// Returns the average number of ticks per operation.
+    pub const fn ticks_per_op(&self) -> u64 {
+        if self.ops == 0 {
+            0
+        } else {
+            self.ticks / self.ops as u64
+        }
+    }
+}
+
+/// The parameters of a benchmark run.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchConfig {
+    /// The number of pages of each size to map, at most 512.
+    pub pages: usize,
+    /// How many times every page is translated in the translation benchmarks.
+    pub translate_rounds: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            pages: 4,
+            translate_rounds: 64,
+        }
+    }
+}
+
+const SIZE_4K: usize = PageSize::Size4K as usize;
+const SIZE_1G: usize = PageSize::Size1G as usize;
+
+/// Runs all benchmarks on a fresh address space of page table `H`.
+///
+/// `now` returns the current time in arbitrary ticks. 4K mappings are placed
+/// in the first 1G of guest physical memory, 2M ones in the second 1G, and 1G
+/// ones above. Linear mappings are identity mappings, their memory is never
+/// accessed.
+///
+/// `2 * config.pages` frames are allocated from `H`, plus the frames of the
+/// page table. The area lookup benchmarks run on `config.pages` areas.
+pub fn run<H: PagingHandler>(
+    config: &BenchConfig,
+    mut now: impl FnMut() -> u64,
+) -> AxResult<Vec<BenchResult>> {
+    let pages = config.pages;
+    if pages == 0 || pages > 512 {
+        return ax_err!(InvalidInput, "unsupported number of benchmark pages");
+    }
+    let mut aspace =
+        AddrSpace::<H>::new_empty(GuestPhysAddr::from(0), 2 * SIZE_1G + pages * SIZE_1G)?;
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let mut results = Vec::new();
+    let mut record = |kind, page_size, ops, ticks| {
+        results.push(BenchResult {
+            kind,
+            page_size,
+            ops,
+            ticks,
+        })
+    };
+
+    // Allocation mappings, 4K only.
+    let populated = GuestPhysAddr::from(0);
+    let t = now();
+    aspace.map_alloc(populated, pages * SIZE_4K, rw, true)?;
+    record(
+        BenchKind::MapAllocPopulate,
+        PageSize::Size4K,
+        pages,
+        now() - t,
+    );
+
+    let lazy = populated + pages * SIZE_4K;
+    aspace.map_alloc(lazy, pages * SIZE_4K, rw, false)?;
+    let mut ticks = 0;
+    for i in 0..pages {
+        let t = now();
+        let handled = aspace.handle_page_fault(lazy + i * SIZE_4K, MappingFlags::WRITE);
+        ticks += now() - t;
+        if !handled {
+            return ax_err!(NoMemory, "lazy fault not handled");
+        }
+    }
+    record(BenchKind::LazyFault, PageSize::Size4K, pages, ticks);
+
+    let t = now();
+    aspace.unmap(populated, 2 * pages * SIZE_4K)?;
+    record(BenchKind::Unmap, PageSize::Size4K, 2 * pages, now() - t);
+
+    // Linear mappings of every page size.
+    for (page_size, base) in [
+        (PageSize::Size4K, 2 * pages * SIZE_4K),
+        (PageSize::Size2M, SIZE_1G),
+        (PageSize::Size1G, 2 * SIZE_1G),
+    ] {
+        let start = GuestPhysAddr::from(base);
+        let size = pages * page_size as usize;
+        aspace.map_linear_with_granularity(
+            start,
+            PhysAddr::from(base),
+            size,
+            rw,
+            MapGranularity::exact(page_size),
+        )?;
+
+        let t = now();
+        for _ in 0..config.translate_rounds {
+            for i in 0..pages {
+                core::hint::black_box(aspace.translate(start + i * page_size as usize));
+            }
+        }
+        let ops = config.translate_rounds * pages;
+        record(BenchKind::Translate, page_size, ops, now() - t);
+
+        let t = now();
+        aspace.unmap(start, size)?;
+        record(BenchKind::Unmap, page_size, pages, now() - t);
+    }
+
+    // One lazy area per page, e.g., many small device regions.
+    for i in 0..pages {
+        aspace.map_alloc(populated + i * SIZE_4K, SIZE_4K, rw, false)?;
+    }
+    let ops = config.translate_rounds * pages;
+    let t = now();
+    for i in 0..pages {
+        for _ in 0..config.translate_rounds {
+            core::hint::black_box(aspace.granularity_at(populated + i * SIZE_4K));
+        }
+    }
+    record(
+        BenchKind::AreaLookupRepeated,
+        PageSize::Size4K,
+        ops,
+        now() - t,
+    );
+    let t = now();
+    for _ in 0..config.translate_rounds {
+        for i in 0..pages {
+            core::hint::black_box(aspace.granularity_at(populated + i * SIZE_4K));
+        }
+    }
+    record(
+        BenchKind::AreaLookupScattered,
+        PageSize::Size4K,
+        ops,
+        now() - t,
+    );
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_bench_run() {
+        let mut clock = 0;
+        let config = BenchConfig::default();
+        let results = run::<MockHal>(&config, || {
+            clock += 1;
+            clock
+        })
+        .unwrap();
+        assert_eq!(results.len(), 11);
+        assert_eq!(results[0].kind, BenchKind::MapAllocPopulate);
+        assert!(results.iter().all(|r| r.ticks > 0));
+        let translate_1g = results
+            .iter()
+            .find(|r| r.kind == BenchKind::Translate && r.page_size == PageSize::Size1G)
+            .unwrap();
+        assert_eq!(translate_1g.ops, config.pages * config.translate_rounds);
+
+        assert!(run::<MockHal>(&BenchConfig { pages: 0, ..config }, || 0).is_err());
+    }
+}
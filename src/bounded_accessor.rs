@@ -0,0 +1,170 @@
+//! A cap on the length of single guest memory accesses.
+//!
+//! Device emulation often copies as many bytes as a guest-controlled length
+//! field says. Handing it a [`BoundedAccessor`] turns a bogus length into an
+//! error instead of a multi-gigabyte copy inside the hypervisor.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::PhysAddr;
+
+use crate::{GuestMemoryAccessor, GuestPhysAddr, MappingFlags, MisalignedPolicy, PartialTransfer};
+
+/// A [`GuestMemoryAccessor`] forwarding to another one, rejecting every
+/// access larger than a maximum length.
+///
+/// Rejected accesses fail with [`AxError::OutOfRange`], so that callers can
+/// tell them apart from translation failures (`InvalidInput`). Addresses
+/// translated directly with [`GuestMemoryAccessor::translate_and_get_limit`]
+/// get their limit capped to the maximum length.
+///
+/// [`AxError::OutOfRange`]: axerrno::AxError::OutOfRange
+pub struct BoundedAccessor<'a, A: GuestMemoryAccessor> {
+    inner: &'a A,
+    max_len: usize,
+}
+
+impl<'a, A: GuestMemoryAccessor> BoundedAccessor<'a, A> {
+    /// Creates an accessor to `inner` rejecting accesses of more than
+    /// `max_len` bytes.
+    pub const fn new(inner: &'a A, max_len: usize) -> Self {
+        Self { inner, max_len }
+    }
+
+    /// Returns the maximum length of a single access.
+    pub const fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    fn check(&self, guest_addr: GuestPhysAddr, len: usize) -> AxResult {
+        if len > self.max_len {
+            warn!(
+                "rejected access of {len:#x} bytes at {guest_addr:?}, the maximum is {:#x}",
+                self.max_len
+            );
+            return ax_err!(OutOfRange, "access too long");
+        }
+        Ok(())
+    }
+}
+
+impl<A: GuestMemoryAccessor> GuestMemoryAccessor for BoundedAccessor<'_, A> {
+    fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        let (host_addr, limit) = self.inner.translate_and_get_limit(guest_addr)?;
+        Some((host_addr, limit.min(self.max_len)))
+    }
+
+    fn read_obj<V: Copy>(&self, guest_addr: GuestPhysAddr) -> AxResult<V> {
+        self.check(guest_addr, size_of::<V>())?;
+        self.inner.read_obj(guest_addr)
+    }
+
+    fn write_obj<V: Copy>(&self, guest_addr: GuestPhysAddr, val: V) -> AxResult<()> {
+        self.check(guest_addr, size_of::<V>())?;
+        self.inner.write_obj(guest_addr, val)
+    }
+
+    fn read_buffer(&self, guest_addr: GuestPhysAddr, buffer: &mut [u8]) -> AxResult<()> {
+        self.check(guest_addr, buffer.len())?;
+        self.inner.read_buffer(guest_addr, buffer)
+    }
+
+    fn write_buffer(&self, guest_addr: GuestPhysAddr, buffer: &[u8]) -> AxResult<()> {
+        self.check(guest_addr, buffer.len())?;
+        self.inner.write_buffer(guest_addr, buffer)
+    }
+
+    fn read_exact_at(
+        &self,
+        guest_addr: GuestPhysAddr,
+        buffer: &mut [u8],
+    ) -> Result<(), PartialTransfer> {
+        self.check(guest_addr, buffer.len())
+            .map_err(|error| PartialTransfer::none(guest_addr, error))?;
+        self.inner.read_exact_at(guest_addr, buffer)
+    }
+
+    fn write_all_at(
+        &self,
+        guest_addr: GuestPhysAddr,
+        buffer: &[u8],
+    ) -> Result<(), PartialTransfer> {
+        self.check(guest_addr, buffer.len())
+            .map_err(|error| PartialTransfer::none(guest_addr, error))?;
+        self.inner.write_all_at(guest_addr, buffer)
+    }
+
+    fn mark_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+        self.inner.mark_dirty(guest_addr, len)
+    }
+
+    fn write_barrier(&self) {
+        self.inner.write_barrier()
+    }
+
+    fn read_barrier(&self) {
+        self.inner.read_barrier()
+    }
+
+    fn flush_wc(&self, guest_addr: GuestPhysAddr, len: usize) {
+        self.inner.flush_wc(guest_addr, len)
+    }
+
+    fn misaligned_policy(&self) -> MisalignedPolicy {
+        self.inner.misaligned_policy()
+    }
+
+    fn allows_access(&self, guest_addr: GuestPhysAddr, access: MappingFlags) -> bool {
+        self.inner.allows_access(guest_addr, access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axerrno::AxError;
+    use core::cell::UnsafeCell;
+
+    /// Maps guest addresses `[0, 0x100)` to its buffer.
+    struct BufTranslator(UnsafeCell<[u8; 0x100]>);
+
+    impl GuestMemoryAccessor for BufTranslator {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            let gpa = guest_addr.as_usize();
+            (gpa < 0x100).then(|| (PhysAddr::from(self.0.get() as usize + gpa), 0x100 - gpa))
+        }
+    }
+
+    #[test]
+    fn test_bounded_accessor() {
+        let mem = BufTranslator(UnsafeCell::new([0; 0x100]));
+        let accessor = BoundedAccessor::new(&mem, 0x10);
+        let gpa = GuestPhysAddr::from(0x20);
+
+        accessor.write_buffer(gpa, &[0x5a; 0x10]).unwrap();
+        assert_eq!(accessor.read_obj::<u32>(gpa), Ok(0x5a5a_5a5a));
+        assert_eq!(
+            accessor.write_buffer(gpa, &[0; 0x11]),
+            Err(AxError::OutOfRange)
+        );
+        let mut buf = [0; 0x20];
+        assert_eq!(
+            accessor.read_buffer(gpa, &mut buf),
+            Err(AxError::OutOfRange)
+        );
+        assert_eq!(
+            accessor.read_obj::<[u8; 0x20]>(gpa),
+            Err(AxError::OutOfRange)
+        );
+        // Translation failures are reported as before.
+        assert_eq!(
+            accessor.read_obj::<u8>(GuestPhysAddr::from(0x100)),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            accessor
+                .translate_and_get_limit(gpa)
+                .map(|(_, limit)| limit),
+            Some(0x10)
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Streaming decompression of guest images, see
+//! [`loader::populate_from_compressed`](crate::loader::populate_from_compressed).
+//!
+//! A [`Decompressor`] writes its output to a [`DecompressSink`], which keeps
+//! the whole output reachable: back-references are resolved against data
+//! already written (e.g., to guest memory) instead of a private window, so
+//! no decompressed staging buffer is needed. [`Lz4Frame`] is built in,
+//! other formats (e.g., zstd) are plugged in by implementing the trait.
+
+use axerrno::{AxResult, ax_err};
+
+/// The destination of a [`Decompressor`].
+pub trait DecompressSink {
+    /// Appends `data` to the output.
+    fn write(&mut self, data: &[u8]) -> AxResult;
+
+    /// Appends `len` bytes copied from `distance` bytes before the end of
+    /// the output. The source may overlap the bytes being appended, as if
+    /// they were copied one by one.
+    ///
+    /// Fails with `InvalidData` if `distance` is zero or exceeds the output
+    /// written so far.
+    fn copy_back(&mut self, distance: usize, len: usize) -> AxResult;
+}
+
+/// A decompressor for one compression format.
+pub trait Decompressor {
+    /// Decompresses `input` into `out`.
+    ///
+    /// Fails with `InvalidData` if `input` is malformed or truncated, or
+    /// with the first error of `out`.
+    fn decompress(&mut self, input: &[u8], out: &mut dyn DecompressSink) -> AxResult;
+}
+
+const LZ4_MAGIC: u32 = 0x184d_2204;
+const LZ4_MIN_MATCH: usize = 4;
+
+/// The LZ4 frame format, as produced by `lz4` and `lz4frame`.
+///
+/// Skippable frames and concatenated frames are supported. Checksums are
+/// skipped but not verified, and frames using a preset dictionary are
+/// rejected.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Lz4Frame;
+
+impl Decompressor for Lz4Frame {
+    fn decompress(&mut self, input: &[u8], out: &mut dyn DecompressSink) -> AxResult {
+        let mut input = Input(input);
+        while !input.0.is_empty() {
+            let magic = input.u32()?;
+            if magic & 0xffff_fff0 == 0x184d_2a50 {
+                let len = input.u32()? as usize;
+                input.take(len)?;
+                continue;
+            }
+            if magic != LZ4_MAGIC {
+                return ax_err!(InvalidData, "bad LZ4 frame magic");
+            }
+            let flags = input.byte()?;
+            if flags >> 6 != 0b01 {
+                return ax_err!(InvalidData, "unsupported LZ4 frame version");
+            }
+            if flags & 0x1 != 0 {
+                return ax_err!(InvalidData, "LZ4 dictionaries are not supported");
+            }
+            let block_checksum = flags & 0x10 != 0;
+            // Block descriptor, content size and header checksum.
+            input.take(2 + if flags & 0x8 != 0 { 8 } else { 0 })?;
+            loop {
+                let header = input.u32()?;
+                if header == 0 {
+                    break;
+                }
+                let data = input.take((header & 0x7fff_ffff) as usize)?;
+                if header & 0x8000_0000 != 0 {
+                    out.write(data)?;
+                } else {
+                    lz4_block(data, out)?;
+                }
+                if block_checksum {
+                    input.take(4)?;
+                }
+            }
+            if flags & 0x4 != 0 {
+                input.take(4)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes an LZ4 block, whose matches may reach into previous blocks.
+fn lz4_block(block: &[u8], out: &mut dyn DecompressSink) -> AxResult {
+    let mut input = Input(block);
+    loop {
+        let token = input.byte()?;
+        let literals = input.length(token >> 4)?;
+        out.write(input.take(literals)?)?;
+        // The last sequence has no match.
+        if input.0.is_empty() {
+            return Ok(());
+        }
+        let distance = u16::from_le_bytes([input.byte()?, input.byte()?]) as usize;
+        let len = input.length(token & 0xf)? + LZ4_MIN_MATCH;
+        out.copy_back(distance, len)?;
+    }
+}
+
+/// A cursor over compressed data.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> AxResult<&'a [u8]> {
+        if len > self.0.len() {
+            return ax_err!(InvalidData, "truncated compressed data");
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> AxResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> AxResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Reads an LZ4 length whose 4-bit start is `nibble`, extended by
+    /// bytes while the previous part is saturated.
+    fn length(&mut self, nibble: u8) -> AxResult<usize> {
+        let mut len = nibble as usize;
+        if nibble == 0xf {
+            loop {
+                let byte = self.byte()?;
+                len += byte as usize;
+                if byte != 0xff {
+                    break;
+                }
+            }
+        }
+        Ok(len)
+    }
+}
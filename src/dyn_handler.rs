@@ -0,0 +1,181 @@
+//! Paging handlers selected at runtime.
+//!
+//! Address spaces take their paging handler as a type parameter, which
+//! requires one monomorphization per frame allocator. Host binaries switching
+//! allocators at runtime, e.g., from an early bootstrap allocator to the full
+//! buddy allocator, can instead use [`DynPagingHandler`], which forwards to
+//! the [`PagingHandlerDyn`] bound last with [`DynPagingHandler::bind`].
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use lazyinit::LazyInit;
+use page_table_multiarch::PagingHandler;
+
+use crate::{HostPhysAddr, HostVirtAddr};
+
+/// The object-safe counterpart of [`PagingHandler`].
+pub trait PagingHandlerDyn: Sync {
+    /// Allocates a 4K frame, see [`PagingHandler::alloc_frame`].
+    fn alloc_frame(&self) -> Option<HostPhysAddr>;
+    /// Frees a frame, see [`PagingHandler::dealloc_frame`].
+    fn dealloc_frame(&self, paddr: HostPhysAddr);
+    /// Returns the host virtual address of `paddr`, see
+    /// [`PagingHandler::phys_to_virt`].
+    fn phys_to_virt(&self, paddr: HostPhysAddr) -> HostVirtAddr;
+}
+
+/// A [`PagingHandlerDyn`] forwarding to the static paging handler `H`.
+pub struct StaticHandler<H>(PhantomData<fn() -> H>);
+
+impl<H> StaticHandler<H> {
+    /// Creates the handler, usually in a `static`.
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<H> Default for StaticHandler<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: PagingHandler> PagingHandlerDyn for StaticHandler<H> {
+    fn alloc_frame(&self) -> Option<HostPhysAddr> {
+        H::alloc_frame()
+    }
+
+    fn dealloc_frame(&self, paddr: HostPhysAddr) {
+        H::dealloc_frame(paddr)
+    }
+
+    fn phys_to_virt(&self, paddr: HostPhysAddr) -> HostVirtAddr {
+        H::phys_to_virt(paddr)
+    }
+}
+
+const MAX_BINDINGS: usize = DynPagingHandler::MAX_BINDINGS;
+
+static BINDINGS: [LazyInit<&'static dyn PagingHandlerDyn>; MAX_BINDINGS] =
+    [const { LazyInit::new() }; MAX_BINDINGS];
+/// The number of slots of `BINDINGS` taken.
+static NEXT_BINDING: AtomicUsize = AtomicUsize::new(0);
+/// The index of the current binding plus one, or zero if none.
+static CURRENT_BINDING: AtomicUsize = AtomicUsize::new(0);
+
+/// A [`PagingHandler`] forwarding to the [`PagingHandlerDyn`] bound at the
+/// time of each call.
+///
+/// Frames are always freed through the current handler, which may not be the
+/// one that allocated them: a new handler must accept the frames of the
+/// previous ones, and translate their addresses the same way.
+pub struct DynPagingHandler;
+
+impl DynPagingHandler {
+    /// The maximum number of calls to [`DynPagingHandler::bind`].
+    pub const MAX_BINDINGS: usize = 8;
+
+    /// Makes `handler` the current handler.
+    ///
+    /// Bindings are never freed, so this fails with `NoMemory` after
+    /// [`Self::MAX_BINDINGS`] calls. Concurrent calls race on which handler
+    /// ends up current.
+    pub fn bind(handler: &'static dyn PagingHandlerDyn) -> AxResult {
+        let index = NEXT_BINDING.fetch_add(1, Ordering::AcqRel);
+        if index >= MAX_BINDINGS {
+            NEXT_BINDING.store(MAX_BINDINGS, Ordering::Release);
+            return ax_err!(NoMemory, "too many paging handler bindings");
+        }
+        BINDINGS[index].init_once(handler);
+        CURRENT_BINDING.store(index + 1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the current handler, if any.
+    pub fn current() -> Option<&'static dyn PagingHandlerDyn> {
+        match CURRENT_BINDING.load(Ordering::Acquire) {
+            0 => None,
+            index => BINDINGS[index - 1].get().copied(),
+        }
+    }
+
+    fn expect_current() -> &'static dyn PagingHandlerDyn {
+        Self::current().expect("no paging handler bound")
+    }
+}
+
+impl PagingHandler for DynPagingHandler {
+    /// Fails if no handler is bound.
+    fn alloc_frame() -> Option<HostPhysAddr> {
+        Self::current()?.alloc_frame()
+    }
+
+    fn dealloc_frame(paddr: HostPhysAddr) {
+        Self::expect_current().dealloc_frame(paddr)
+    }
+
+    fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+        Self::expect_current().phys_to_virt(paddr)
+    }
+}
+
+/// An address space whose paging handler is selected at runtime.
+#[cfg(feature = "alloc")]
+pub type DynAddrSpace = crate::AddrSpace<DynPagingHandler>;
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use crate::{GuestPhysAddr, MappingFlags};
+    use axin::axin;
+
+    /// Counts the frames allocated through it.
+    struct CountingHandler(AtomicUsize);
+
+    impl PagingHandlerDyn for CountingHandler {
+        fn alloc_frame(&self) -> Option<HostPhysAddr> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            <MockHal as PagingHandler>::alloc_frame()
+        }
+
+        fn dealloc_frame(&self, paddr: HostPhysAddr) {
+            <MockHal as PagingHandler>::dealloc_frame(paddr)
+        }
+
+        fn phys_to_virt(&self, paddr: HostPhysAddr) -> HostVirtAddr {
+            <MockHal as PagingHandler>::phys_to_virt(paddr)
+        }
+    }
+
+    static EARLY: StaticHandler<MockHal> = StaticHandler::new();
+    static COUNTING: CountingHandler = CountingHandler(AtomicUsize::new(0));
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_rebind() {
+        DynPagingHandler::bind(&EARLY).unwrap();
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = DynAddrSpace::new_empty(base, 0x10_0000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x2000, rw, true).unwrap();
+        assert_eq!(COUNTING.0.load(Ordering::Relaxed), 0);
+
+        DynPagingHandler::bind(&COUNTING).unwrap();
+        aspace.map_alloc(base + 0x2000, 0x1000, rw, true).unwrap();
+        assert_eq!(COUNTING.0.load(Ordering::Relaxed), 1);
+        // Frames allocated before the rebinding are freed by the new handler.
+        drop(aspace);
+
+        for _ in 2..MAX_BINDINGS {
+            DynPagingHandler::bind(&COUNTING).unwrap();
+        }
+        assert!(DynPagingHandler::bind(&EARLY).is_err());
+        assert!(core::ptr::addr_eq(
+            DynPagingHandler::current().unwrap(),
+            &COUNTING
+        ));
+    }
+}
@@ -2,25 +2,53 @@ use core::marker::PhantomData;
 
 use axerrno::{AxResult, ax_err_type};
 
-pub(crate) use memory_addr::PAGE_SIZE_4K as PAGE_SIZE;
+use memory_addr::{PAGE_SIZE_1G, PAGE_SIZE_2M};
 
-use crate::{AxMmHal, HostPhysAddr};
+use crate::{AxMmHal, HostPhysAddr, PAGE_SIZE};
 
-/// A physical frame which will be automatically deallocated when dropped.
+/// A 4 KiB physical frame which will be automatically deallocated when dropped.
 ///
-/// The frame is allocated using the [`AxMmHal`] implementation. The size of the frame is likely to
-/// be 4 KiB but the actual size is determined by the [`AxMmHal`] implementation.
+/// The frame is allocated using the [`AxMmHal`] implementation.
+pub type PhysFrame<H> = PhysFrameSized<H, PAGE_SIZE>;
+
+/// A 2 MiB physical frame which will be automatically deallocated when dropped.
+///
+/// Requires an [`AxMmHal::alloc_frames`] implementation that supports
+/// contiguous allocation.
+pub type PhysFrame2M<H> = PhysFrameSized<H, PAGE_SIZE_2M>;
+
+/// A 1 GiB physical frame which will be automatically deallocated when dropped.
+///
+/// Requires an [`AxMmHal::alloc_frames`] implementation that supports
+/// contiguous allocation.
+pub type PhysFrame1G<H> = PhysFrameSized<H, PAGE_SIZE_1G>;
+
+/// A physically contiguous frame of `SIZE` bytes which will be automatically
+/// deallocated when dropped.
+///
+/// `SIZE` must be a multiple of 4 KiB. The start address of the frame is
+/// aligned to `SIZE`, so a [`PhysFrame2M`] or [`PhysFrame1G`] can back a huge
+/// page mapping directly.
 #[derive(Debug)]
-pub struct PhysFrame<H: AxMmHal> {
+pub struct PhysFrameSized<H: AxMmHal, const SIZE: usize> {
     start_paddr: Option<HostPhysAddr>,
     _marker: PhantomData<H>,
 }
 
-impl<H: AxMmHal> PhysFrame<H> {
-    /// Allocate a [`PhysFrame`].
+impl<H: AxMmHal, const SIZE: usize> PhysFrameSized<H, SIZE> {
+    const NUM_4K_FRAMES: usize = {
+        assert!(SIZE > 0 && SIZE % PAGE_SIZE == 0);
+        SIZE / PAGE_SIZE
+    };
+
+    /// Allocate a [`PhysFrameSized`].
     pub fn alloc() -> AxResult<Self> {
-        let start_paddr = H::alloc_frame()
-            .ok_or_else(|| ax_err_type!(NoMemory, "allocate physical frame failed"))?;
+        let start_paddr = if Self::NUM_4K_FRAMES == 1 {
+            H::alloc_frame()
+        } else {
+            H::alloc_frames(Self::NUM_4K_FRAMES, SIZE)
+        }
+        .ok_or_else(|| ax_err_type!(NoMemory, "allocate physical frame failed"))?;
         assert_ne!(start_paddr.as_usize(), 0);
         Ok(Self {
             start_paddr: Some(start_paddr),
@@ -28,19 +56,19 @@ impl<H: AxMmHal> PhysFrame<H> {
         })
     }
 
-    /// Allocate a [`PhysFrame`] and fill it with zeros.
+    /// Allocate a [`PhysFrameSized`] and fill it with zeros.
     pub fn alloc_zero() -> AxResult<Self> {
         let mut f = Self::alloc()?;
         f.fill(0);
         Ok(f)
     }
 
-    /// Create an uninitialized [`PhysFrame`].
+    /// Create an uninitialized [`PhysFrameSized`].
     ///
     /// # Safety
     ///
-    /// The caller must ensure that the [`PhysFrame`] is only used as a placeholder and never
-    /// accessed.
+    /// The caller must ensure that the [`PhysFrameSized`] is only used as a
+    /// placeholder and never accessed.
     pub const unsafe fn uninit() -> Self {
         Self {
             start_paddr: None,
@@ -48,6 +76,37 @@ impl<H: AxMmHal> PhysFrame<H> {
         }
     }
 
+    /// Takes the ownership of a frame allocated from `H` with the same size.
+    ///
+    /// This is the inverse of [`PhysFrameSized::into_raw`].
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `start_paddr` is the start of a frame of
+    /// `SIZE` bytes allocated from `H`, and that nobody else owns it.
+    pub const unsafe fn from_raw(start_paddr: HostPhysAddr) -> Self {
+        Self {
+            start_paddr: Some(start_paddr),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Consumes the frame without deallocating it, and returns its starting
+    /// physical address.
+    ///
+    /// The ownership is transferred to the caller, e.g., to a mapping which
+    /// deallocates the frame when it is unmapped.
+    pub fn into_raw(self) -> HostPhysAddr {
+        let start_paddr = self.start_paddr();
+        core::mem::forget(self);
+        start_paddr
+    }
+
+    /// Returns the size of the frame in bytes.
+    pub const fn size(&self) -> usize {
+        SIZE
+    }
+
     /// Get the starting physical address of the frame.
     pub fn start_paddr(&self) -> HostPhysAddr {
         self.start_paddr.expect("uninitialized PhysFrame")
@@ -58,17 +117,23 @@ impl<H: AxMmHal> PhysFrame<H> {
         H::phys_to_virt(self.start_paddr()).as_mut_ptr()
     }
 
-    /// Fill the frame with a byte. Works only when the frame is 4 KiB in size.
+    /// Fill the whole frame with a byte.
     pub fn fill(&mut self, byte: u8) {
-        unsafe { core::ptr::write_bytes(self.as_mut_ptr(), byte, PAGE_SIZE) }
+        unsafe { core::ptr::write_bytes(self.as_mut_ptr(), byte, SIZE) }
     }
 }
 
-impl<H: AxMmHal> Drop for PhysFrame<H> {
+impl<H: AxMmHal, const SIZE: usize> Drop for PhysFrameSized<H, SIZE> {
     fn drop(&mut self) {
         if let Some(start_paddr) = self.start_paddr {
-            H::dealloc_frame(start_paddr);
-            debug!("[AxVM] deallocated PhysFrame({start_paddr:#x})");
+            if Self::NUM_4K_FRAMES == 1 {
+                H::dealloc_frame(start_paddr);
+            } else {
+                H::dealloc_frames(start_paddr, Self::NUM_4K_FRAMES);
+            }
+            if log_enabled!(log::Level::Debug) {
+                debug!("[AxVM] deallocated PhysFrame({start_paddr:#x}, size {SIZE:#x})");
+            }
         }
     }
 }
@@ -77,6 +142,7 @@ impl<H: AxMmHal> Drop for PhysFrame<H> {
 mod test {
     use super::*;
     use crate::test_utils::{BASE_PADDR, MockHal, mock_hal_test, test_dealloc_count};
+    #[cfg(feature = "alloc")]
     use alloc::vec::Vec;
     use assert_matches::assert_matches;
     use axin::axin;
@@ -114,6 +180,7 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "alloc")]
     #[axin(decorator(mock_hal_test), on_exit(test_dealloc_count(5)))]
     fn test_fill_multiple_frames() {
         const NUM_FRAMES: usize = 5;
@@ -160,4 +227,30 @@ mod test {
         assert_matches!(result, Err(axerrno::AxError::NoMemory));
         MockHal::set_alloc_fail(false); // Reset for other tests
     }
+
+    #[test]
+    #[axin(decorator(mock_hal_test), on_exit(test_dealloc_count(4)))]
+    fn test_sized_frame() {
+        const SIZE: usize = 4 * PAGE_SIZE;
+        let mut frame = PhysFrameSized::<MockHal, SIZE>::alloc_zero().unwrap();
+        assert_eq!(frame.size(), SIZE);
+        assert_eq!(frame.start_paddr().as_usize() % SIZE, 0);
+        frame.fill(0x5A);
+        let bytes = unsafe { &*(frame.as_mut_ptr() as *const [u8; SIZE]) };
+        assert!(bytes.iter().all(|&x| x == 0x5A));
+        // All 4 frames are returned when the frame is dropped.
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test), on_exit(test_dealloc_count(1)))]
+    fn test_into_from_raw() {
+        let frame = PhysFrame::<MockHal>::alloc().unwrap();
+        let paddr = frame.into_raw();
+        assert_eq!(
+            crate::test_utils::DEALLOC_COUNT.load(core::sync::atomic::Ordering::SeqCst),
+            0
+        );
+        let frame = unsafe { PhysFrame::<MockHal>::from_raw(paddr) };
+        assert_eq!(frame.start_paddr(), paddr);
+    }
 }
@@ -0,0 +1,226 @@
+//! Access to guest memory through guest virtual addresses, for hypercall
+//! handlers receiving pointers from the guest.
+//!
+//! A [`GuestAddressSpaceView`] translates guest virtual addresses through the
+//! guest page tables (stage 1), and a [`GuestMemoryAccessor`] accesses the
+//! resulting guest physical addresses (stage 2). The accessors of
+//! [`GuestVirtAccessorExt`] combine both, checking the permissions of both
+//! stages and translating again at every guest page boundary, since
+//! contiguous guest virtual pages are usually not physically contiguous.
+
+use core::mem::MaybeUninit;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+
+use crate::{GuestMemoryAccessor, GuestPhysAddr, GuestVirtAddr, MappingFlags, PAGE_SIZE};
+
+/// The guest page tables of a vCPU, as seen by the hypervisor.
+pub trait GuestAddressSpaceView {
+    /// Translates `gva` through the guest page tables, checking that they
+    /// allow `access`.
+    ///
+    /// Returns the guest physical address and the number of bytes from `gva`
+    /// to the end of its guest page. Fails with `BadAddress` if `gva` is not
+    /// mapped, or with `PermissionDenied` if `access` is not allowed.
+    fn translate_gva(
+        &self,
+        gva: GuestVirtAddr,
+        access: MappingFlags,
+    ) -> AxResult<(GuestPhysAddr, usize)>;
+}
+
+/// Accessors to guest memory by guest virtual address, implemented for all
+/// [`GuestMemoryAccessor`]s.
+///
+/// Besides the guest page table permissions, the stage-2 permissions are
+/// checked with [`GuestMemoryAccessor::allows_access`], failing with
+/// `PermissionDenied`. The accesses stop at the first failing guest page,
+/// so a failed write may leave the previous pages written, as a faulting
+/// guest instruction would.
+pub trait GuestVirtAccessorExt: GuestMemoryAccessor {
+    /// Reads a value of type `V` at `gva`, which may straddle guest pages.
+    fn read_obj_gva<V: Copy>(
+        &self,
+        view: &dyn GuestAddressSpaceView,
+        gva: GuestVirtAddr,
+    ) -> AxResult<V> {
+        let size = core::mem::size_of::<V>();
+        let (gpa, limit) = view.translate_gva(gva, MappingFlags::READ)?;
+        if limit >= size {
+            check_stage2(self, gpa, size, MappingFlags::READ)?;
+            return self.read_obj(gpa);
+        }
+        let mut val = MaybeUninit::<V>::uninit();
+        // SAFETY: the bytes of `val` are all written before it is read.
+        let bytes = unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size) };
+        self.read_buffer_gva(view, gva, bytes)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Writes `val` at `gva`, which may straddle guest pages.
+    fn write_obj_gva<V: Copy>(
+        &self,
+        view: &dyn GuestAddressSpaceView,
+        gva: GuestVirtAddr,
+        val: V,
+    ) -> AxResult {
+        let size = core::mem::size_of::<V>();
+        let (gpa, limit) = view.translate_gva(gva, MappingFlags::WRITE)?;
+        if limit >= size {
+            check_stage2(self, gpa, size, MappingFlags::WRITE)?;
+            return self.write_obj(gpa, val);
+        }
+        // SAFETY: `V` is `Copy`, so it can be viewed as bytes.
+        let bytes = unsafe { core::slice::from_raw_parts(&val as *const V as *const u8, size) };
+        self.write_buffer_gva(view, gva, bytes)
+    }
+
+    /// Fills `buffer` from guest memory at `gva`.
+    fn read_buffer_gva(
+        &self,
+        view: &dyn GuestAddressSpaceView,
+        gva: GuestVirtAddr,
+        buffer: &mut [u8],
+    ) -> AxResult {
+        let mut done = 0;
+        while done < buffer.len() {
+            let (gpa, limit) = view.translate_gva(gva + done, MappingFlags::READ)?;
+            let len = limit.min(buffer.len() - done);
+            check_stage2(self, gpa, len, MappingFlags::READ)?;
+            self.read_buffer(gpa, &mut buffer[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `buffer` to guest memory at `gva`.
+    fn write_buffer_gva(
+        &self,
+        view: &dyn GuestAddressSpaceView,
+        gva: GuestVirtAddr,
+        buffer: &[u8],
+    ) -> AxResult {
+        let mut done = 0;
+        while done < buffer.len() {
+            let (gpa, limit) = view.translate_gva(gva + done, MappingFlags::WRITE)?;
+            let len = limit.min(buffer.len() - done);
+            check_stage2(self, gpa, len, MappingFlags::WRITE)?;
+            self.write_buffer(gpa, &buffer[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl<A: GuestMemoryAccessor + ?Sized> GuestVirtAccessorExt for A {}
+
+/// Checks that the stage-2 mappings of every page of `[gpa, gpa + len)`
+/// allow `access`.
+fn check_stage2<A: GuestMemoryAccessor + ?Sized>(
+    accessor: &A,
+    gpa: GuestPhysAddr,
+    len: usize,
+    access: MappingFlags,
+) -> AxResult {
+    let end = gpa + len;
+    let mut page = gpa;
+    while page < end {
+        if !accessor.allows_access(page, access) {
+            warn!("stage-2 mapping of {page:?} does not allow {access:?}");
+            return ax_err!(PermissionDenied, "access denied by the nested page table");
+        }
+        page = page.align_down(PAGE_SIZE) + PAGE_SIZE;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axerrno::AxError;
+    use core::cell::UnsafeCell;
+    use memory_addr::PhysAddr;
+
+    /// Maps guest addresses `[0, 0x100)` to its buffer, with `[0xc0, 0x100)`
+    /// read-only in the nested page table.
+    struct BufTranslator(UnsafeCell<[u8; 0x100]>);
+
+    impl GuestMemoryAccessor for BufTranslator {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            let gpa = guest_addr.as_usize();
+            (gpa < 0x100).then(|| (PhysAddr::from(self.0.get() as usize + gpa), 0x100 - gpa))
+        }
+
+        fn allows_access(&self, guest_addr: GuestPhysAddr, access: MappingFlags) -> bool {
+            guest_addr.as_usize() < 0xc0 || !access.contains(MappingFlags::WRITE)
+        }
+    }
+
+    /// Guest pages of 0x40 bytes, each mapped to a guest physical page with
+    /// some flags, or unmapped.
+    struct PageView([Option<(usize, MappingFlags)>; 4]);
+
+    impl GuestAddressSpaceView for PageView {
+        fn translate_gva(
+            &self,
+            gva: GuestVirtAddr,
+            access: MappingFlags,
+        ) -> AxResult<(GuestPhysAddr, usize)> {
+            let Some(&Some((gpa, flags))) = self.0.get(gva.as_usize() / 0x40) else {
+                return ax_err!(BadAddress);
+            };
+            if !flags.contains(access) {
+                return ax_err!(PermissionDenied);
+            }
+            let offset = gva.as_usize() % 0x40;
+            Ok((GuestPhysAddr::from(gpa + offset), 0x40 - offset))
+        }
+    }
+
+    #[test]
+    fn test_gva_accessors() {
+        let mem = BufTranslator(UnsafeCell::new([0; 0x100]));
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let view = PageView([
+            Some((0x80, rw)),
+            Some((0x00, rw)),
+            Some((0x40, MappingFlags::READ)),
+            Some((0xc0, rw)),
+        ]);
+        let gva = GuestVirtAddr::from;
+
+        // Contiguous guest virtual pages, but not guest physical ones.
+        mem.write_buffer_gva(&view, gva(0x3c), &[1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        assert_eq!(
+            mem.read_obj::<u32>(GuestPhysAddr::from(0xbc)),
+            Ok(0x0403_0201)
+        );
+        assert_eq!(mem.read_obj::<u32>(GuestPhysAddr::from(0)), Ok(0x0807_0605));
+        assert_eq!(
+            mem.read_obj_gva::<u64>(&view, gva(0x3c)),
+            Ok(0x0807_0605_0403_0201)
+        );
+        mem.write_obj_gva(&view, gva(0x3e), 0xaabb_ccddu32).unwrap();
+        let mut buf = [0; 4];
+        mem.read_buffer_gva(&view, gva(0x3e), &mut buf).unwrap();
+        assert_eq!(buf, [0xdd, 0xcc, 0xbb, 0xaa]);
+
+        // Denied by the guest page table, by the nested page table, and
+        // unmapped.
+        assert_eq!(
+            mem.write_obj_gva(&view, gva(0x80), 0u8),
+            Err(AxError::PermissionDenied)
+        );
+        assert_eq!(mem.read_obj_gva::<u8>(&view, gva(0xc0)), Ok(0));
+        assert_eq!(
+            mem.write_obj_gva(&view, gva(0xc0), 0u8),
+            Err(AxError::PermissionDenied)
+        );
+        assert_eq!(
+            mem.read_buffer_gva(&view, gva(0xf0), &mut [0; 0x20]),
+            Err(AxError::BadAddress)
+        );
+    }
+}
@@ -16,6 +16,42 @@ pub trait AxMmHal {
     /// * `paddr` - The physical address of the frame to deallocate.
     fn dealloc_frame(paddr: HostPhysAddr);
 
+    /// Allocates `num_frames` physically contiguous frames whose start address
+    /// is aligned to `align` bytes, and returns the host physical address of
+    /// the first frame.
+    ///
+    /// The default implementation only supports a single 4K frame, so
+    /// platforms that want huge frames (e.g., [`PhysFrame2M`]) must override
+    /// it.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<HostPhysAddr>` - Some containing the physical address of the first frame, or None if allocation fails.
+    ///
+    /// [`PhysFrame2M`]: crate::PhysFrame2M
+    fn alloc_frames(num_frames: usize, align: usize) -> Option<HostPhysAddr> {
+        if num_frames == 1 && align <= crate::PAGE_SIZE {
+            Self::alloc_frame()
+        } else {
+            None
+        }
+    }
+
+    /// Deallocates `num_frames` contiguous frames allocated by
+    /// [`AxMmHal::alloc_frames`].
+    ///
+    /// The default implementation deallocates the frames one by one.
+    ///
+    /// # Parameters
+    ///
+    /// * `paddr` - The physical address of the first frame to deallocate.
+    /// * `num_frames` - The number of frames to deallocate.
+    fn dealloc_frames(paddr: HostPhysAddr, num_frames: usize) {
+        for i in 0..num_frames {
+            Self::dealloc_frame(paddr + i * crate::PAGE_SIZE);
+        }
+    }
+
     /// Converts a host physical address to a host virtual address.
     ///
     /// # Parameters
@@ -0,0 +1,92 @@
+//! Well-known guest physical locations of interrupt controller regions.
+//!
+//! These helpers only compute addresses; reserve the returned ranges with
+//! [`AddrSpace::reserve_mmio`](crate::AddrSpace::reserve_mmio) so that guest
+//! RAM can never cover them.
+
+use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+/// Default guest physical address of the x86 local APIC page.
+pub const X86_LAPIC_BASE: GuestPhysAddr = GuestPhysAddr::from_usize(0xFEE0_0000);
+/// Size of the x86 local APIC page.
+pub const X86_LAPIC_SIZE: usize = 0x1000;
+/// Default guest physical address of the first x86 I/O APIC.
+pub const X86_IOAPIC_BASE: GuestPhysAddr = GuestPhysAddr::from_usize(0xFEC0_0000);
+/// Size of the register window of an x86 I/O APIC.
+pub const X86_IOAPIC_SIZE: usize = 0x1000;
+
+/// Size of a GICv3 distributor region.
+pub const GICV3_DIST_SIZE: usize = 0x1_0000;
+/// Per-CPU stride of GICv3 redistributors (`RD_base` and `SGI_base` frames).
+pub const GICV3_REDIST_STRIDE: usize = 0x2_0000;
+/// Per-CPU stride of GICv4 redistributors (additional `VLPI_base` frames).
+pub const GICV4_REDIST_STRIDE: usize = 0x4_0000;
+
+/// Returns the guest physical range of the x86 local APIC page.
+pub const fn x86_lapic_range() -> GuestPhysAddrRange {
+    GuestPhysAddrRange {
+        start: X86_LAPIC_BASE,
+        end: GuestPhysAddr::from_usize(X86_LAPIC_BASE.as_usize() + X86_LAPIC_SIZE),
+    }
+}
+
+/// Returns the guest physical range of the first x86 I/O APIC.
+pub const fn x86_ioapic_range() -> GuestPhysAddrRange {
+    GuestPhysAddrRange {
+        start: X86_IOAPIC_BASE,
+        end: GuestPhysAddr::from_usize(X86_IOAPIC_BASE.as_usize() + X86_IOAPIC_SIZE),
+    }
+}
+
+/// Returns the guest physical range of the GICv3 distributor at `base`.
+pub const fn gicv3_dist_range(base: GuestPhysAddr) -> GuestPhysAddrRange {
+    GuestPhysAddrRange {
+        start: base,
+        end: GuestPhysAddr::from_usize(base.as_usize() + GICV3_DIST_SIZE),
+    }
+}
+
+/// Returns the guest physical range of the redistributor of `vcpu_id`, in a
+/// redistributor region starting at `base` with the given per-CPU `stride`.
+pub const fn gic_redist_range(
+    base: GuestPhysAddr,
+    stride: usize,
+    vcpu_id: usize,
+) -> GuestPhysAddrRange {
+    let start = base.as_usize() + vcpu_id * stride;
+    GuestPhysAddrRange {
+        start: GuestPhysAddr::from_usize(start),
+        end: GuestPhysAddr::from_usize(start + stride),
+    }
+}
+
+/// Returns the guest physical range of a whole redistributor region at
+/// `base`, covering `num_vcpus` redistributors with the given `stride`.
+pub const fn gic_redist_region(
+    base: GuestPhysAddr,
+    stride: usize,
+    num_vcpus: usize,
+) -> GuestPhysAddrRange {
+    GuestPhysAddrRange {
+        start: base,
+        end: GuestPhysAddr::from_usize(base.as_usize() + num_vcpus * stride),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gic_redist_layout() {
+        let base = GuestPhysAddr::from_usize(0x080A_0000);
+        let region = gic_redist_region(base, GICV3_REDIST_STRIDE, 4);
+        assert_eq!(region.size(), 4 * GICV3_REDIST_STRIDE);
+        for vcpu_id in 0..4 {
+            let r = gic_redist_range(base, GICV3_REDIST_STRIDE, vcpu_id);
+            assert!(region.contains_range(r));
+            assert_eq!(r.start.as_usize(), 0x080A_0000 + vcpu_id * 0x2_0000);
+        }
+        assert!(!region.contains_range(gic_redist_range(base, GICV3_REDIST_STRIDE, 4)));
+    }
+}
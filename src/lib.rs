@@ -5,36 +5,148 @@
 
 #[macro_use]
 extern crate log;
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
+mod accessor_bridge;
 mod addr;
+#[cfg(feature = "alloc")]
 mod address_space;
+mod area_table;
+pub mod barrier;
+#[cfg(any(all(test, feature = "alloc"), feature = "bench"))]
+pub mod bench;
+mod bounded_accessor;
+pub mod decompress;
 pub mod device;
+mod dyn_handler;
 mod frame;
+mod gva_accessor;
 mod hal;
+pub mod irqchip;
+#[cfg(feature = "alloc")]
+pub mod loader;
+#[cfg(feature = "alloc")]
+mod mem_cursor;
+mod mem_type;
 mod memory_accessor;
-mod npt;
+#[cfg(feature = "mmio-decode")]
+pub mod mmio;
+pub mod npt;
+mod page_walker;
+pub mod prelude;
+mod static_space;
+mod throttled_accessor;
 
+pub use accessor_bridge::EptAccessor;
 pub use addr::*;
+#[cfg(feature = "alloc")]
 pub use address_space::*;
+pub use area_table::{AreaTable, StaticArea};
+pub use bounded_accessor::BoundedAccessor;
 
-pub use frame::PhysFrame;
+#[cfg(feature = "alloc")]
+pub use dyn_handler::DynAddrSpace;
+pub use dyn_handler::{DynPagingHandler, PagingHandlerDyn, StaticHandler};
+pub use frame::{PhysFrame, PhysFrame1G, PhysFrame2M, PhysFrameSized};
+pub use gva_accessor::{GuestAddressSpaceView, GuestVirtAccessorExt};
 pub use hal::AxMmHal;
+pub use mem_type::MemType;
+pub use npt::NestedPageTable;
+pub use page_walker::{GuestPageWalker, GuestPagingMode, GuestTranslation};
+pub use static_space::StaticAddrSpace;
+pub use throttled_accessor::{AccessLimits, AccessMonitor, ThrottledAccessor};
 
-pub use memory_accessor::GuestMemoryAccessor;
+#[cfg(feature = "alloc")]
+pub use mem_cursor::GuestMemCursor;
+#[cfg(feature = "alloc")]
+pub use memory_accessor::ChainedTranslator;
+pub use memory_accessor::{
+    GuestMemoryAccessor, GuestTranslator, MisalignedPolicy, PartialTransfer, RejectTranslator,
+};
+pub use page_table_entry::MappingFlags;
+pub use page_table_multiarch::PageSize;
 
 use axerrno::AxError;
+#[cfg(feature = "alloc")]
 use memory_set::MappingError;
+use page_table_multiarch::PagingError;
 
 /// Information about nested page faults.
+///
+/// Decoded from the raw exit data by the constructor of the target
+/// architecture: `from_vmx_exit`, `from_arm_hpfar_esr` or
+/// `from_riscv_htval_cause`.
 #[derive(Debug)]
 pub struct NestedPageFaultInfo {
     /// Access type that caused the nested page fault.
     pub access_flags: MappingFlags,
     /// Guest physical address that caused the nested page fault.
     pub fault_guest_paddr: GuestPhysAddr,
+    /// Width of the faulting data access, if the exit information tells it.
+    pub access_width: Option<device::AccessWidth>,
 }
 
+/// Where a nested page fault comes from, for statistics, dirty page
+/// attribution and logging.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultContext {
+    /// The vCPU that faulted, `None` for accesses not made by a vCPU.
+    pub vcpu_id: Option<usize>,
+    /// The guest program counter (RIP, ELR or SEPC) of the faulting
+    /// instruction, if known.
+    pub guest_pc: Option<usize>,
+    /// Whether the guest was running in user mode.
+    pub is_guest_user_mode: bool,
+}
+
+impl FaultContext {
+    /// The context of a fault not attributed to any vCPU.
+    pub const NONE: Self = Self {
+        vcpu_id: None,
+        guest_pc: None,
+        is_guest_user_mode: false,
+    };
+
+    /// The context of a fault of the vCPU `vcpu_id`.
+    pub const fn vcpu(vcpu_id: usize) -> Self {
+        Self {
+            vcpu_id: Some(vcpu_id),
+            ..Self::NONE
+        }
+    }
+}
+
+impl core::fmt::Display for FaultContext {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.vcpu_id {
+            Some(id) => write!(f, "vCPU {id}")?,
+            None => write!(f, "host")?,
+        }
+        if let Some(pc) = self.guest_pc {
+            write!(f, " at pc {pc:#x}")?;
+        }
+        if self.is_guest_user_mode {
+            write!(f, " (user)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Translates the guest physical addresses of a VM to host physical
+/// addresses, e.g., for device emulation setting up DMA.
+///
+/// Translation takes `&self`, so device code can hold the translator of its
+/// own VM (e.g., a `&dyn EPTTranslator`) instead of a global one. It is
+/// implemented by [`AddrSpace`], [`StaticAddrSpace`] and every
+/// [`GuestMemoryAccessor`], and turned into an accessor by [`EptAccessor`].
+pub trait EPTTranslator {
+    /// Returns the host physical address `gpa` is mapped to, or `None` if it
+    /// is out of range or not mapped.
+    fn guest_phys_to_host_phys(&self, gpa: GuestPhysAddr) -> Option<HostPhysAddr>;
+}
+
+#[cfg(feature = "alloc")]
 fn mapping_err_to_ax_err(err: MappingError) -> AxError {
     warn!("Mapping error: {err:?}");
     match err {
@@ -44,5 +156,13 @@ fn mapping_err_to_ax_err(err: MappingError) -> AxError {
     }
 }
 
+fn paging_err_to_ax_err(err: PagingError) -> AxError {
+    match err {
+        PagingError::NoMemory => AxError::NoMemory,
+        PagingError::AlreadyMapped | PagingError::MappedToHugePage => AxError::AlreadyExists,
+        PagingError::NotAligned | PagingError::NotMapped => AxError::InvalidInput,
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod test_utils;
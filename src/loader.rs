@@ -0,0 +1,288 @@
+//! Writing boot data (images, firmware tables) into guest memory.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use crate::decompress::{DecompressSink, Decompressor};
+use crate::{
+    AddrSpace, GuestPageIter, GuestPhysAddr, GuestPhysAddrRange, MemoryMapStyle, PAGE_SIZE,
+};
+
+/// Copies `data` into guest memory at `gpa`.
+///
+/// The destination must be covered by mapped areas and must not touch a
+/// reserved MMIO range. Lazily allocated pages are faulted in first.
+pub fn load_bytes<H: PagingHandler>(
+    aspace: &mut AddrSpace<H>,
+    gpa: GuestPhysAddr,
+    data: &[u8],
+) -> AxResult {
+    if data.is_empty() {
+        return Ok(());
+    }
+    check_destination(aspace, gpa, data.len())?;
+    populate(aspace, gpa, data.len())?;
+    copy_in(aspace, gpa, data)
+}
+
+/// Decompresses `compressed` with `decompressor` into guest memory at the
+/// start of `range`, returning the decompressed size.
+///
+/// The destination pages are populated one by one as the output reaches
+/// them, and back-references of the format are resolved against the guest
+/// memory already written, so no decompressed copy of the image is ever
+/// staged in host memory. The rest of `range` is left untouched.
+///
+/// `range` must be covered by mapped areas and must not touch a reserved
+/// MMIO range. Fails with `InvalidInput` if the image does not fit in
+/// `range`, and with `InvalidData` if it is malformed.
+pub fn populate_from_compressed<H: PagingHandler>(
+    aspace: &mut AddrSpace<H>,
+    range: GuestPhysAddrRange,
+    compressed: &[u8],
+    decompressor: &mut dyn Decompressor,
+) -> AxResult<usize> {
+    check_destination(aspace, range.start, range.size())?;
+    let mut sink = GuestSink {
+        aspace,
+        range,
+        pos: range.start,
+        populated: range.start,
+    };
+    decompressor.decompress(compressed, &mut sink)?;
+    Ok(sink.pos - range.start)
+}
+
+/// Checks that `[gpa, gpa + len)` can be written by the loader.
+fn check_destination<H: PagingHandler>(
+    aspace: &AddrSpace<H>,
+    gpa: GuestPhysAddr,
+    len: usize,
+) -> AxResult {
+    if !aspace.contains_range(gpa, len) {
+        return ax_err!(InvalidInput, "address out of range");
+    }
+    if !aspace.holes(gpa, len).is_empty() {
+        return ax_err!(InvalidInput, "destination not mapped");
+    }
+    Ok(())
+}
+
+/// Faults in the pages of `[gpa, gpa + len)` that are not present yet, and
+/// copies those still shared with the template of a copy-on-write area.
+fn populate<H: PagingHandler>(
+    aspace: &mut AddrSpace<H>,
+    gpa: GuestPhysAddr,
+    len: usize,
+) -> AxResult {
+    let end = (gpa + len).align_up(PAGE_SIZE);
+    for page in GuestPageIter::new(gpa.align_down(PAGE_SIZE), end).unwrap() {
+        if aspace.is_mmio(page) {
+            return ax_err!(InvalidInput, "destination is MMIO");
+        }
+        if (aspace.translate(page).is_none() || aspace.is_cow_shared(page))
+            && !aspace.handle_page_fault(page, MappingFlags::empty())
+        {
+            return ax_err!(NoMemory, "failed to populate destination");
+        }
+    }
+    Ok(())
+}
+
+/// Copies `data` to the populated guest memory at `gpa`.
+fn copy_in<H: PagingHandler>(aspace: &AddrSpace<H>, gpa: GuestPhysAddr, data: &[u8]) -> AxResult {
+    let mut offset = 0;
+    aspace.for_each_host_segment(gpa, data.len(), |_, paddr, len| {
+        let Some(paddr) = paddr else {
+            return ax_err!(BadState, "destination not present");
+        };
+        let dst = H::phys_to_virt(paddr).as_mut_ptr();
+        unsafe { core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), dst, len) };
+        offset += len;
+        Ok(())
+    })
+}
+
+/// Copies the populated guest memory at `gpa` to `buf`.
+fn copy_out<H: PagingHandler>(
+    aspace: &AddrSpace<H>,
+    gpa: GuestPhysAddr,
+    buf: &mut [u8],
+) -> AxResult {
+    let mut offset = 0;
+    aspace.for_each_host_segment(gpa, buf.len(), |_, paddr, len| {
+        let Some(paddr) = paddr else {
+            return ax_err!(BadState, "source not present");
+        };
+        let src = H::phys_to_virt(paddr).as_ptr();
+        unsafe { core::ptr::copy_nonoverlapping(src, buf[offset..].as_mut_ptr(), len) };
+        offset += len;
+        Ok(())
+    })
+}
+
+/// The output of [`populate_from_compressed`], written to guest memory.
+struct GuestSink<'a, H: PagingHandler> {
+    aspace: &'a mut AddrSpace<H>,
+    range: GuestPhysAddrRange,
+    /// The end of the output.
+    pos: GuestPhysAddr,
+    /// The end of the pages populated so far.
+    populated: GuestPhysAddr,
+}
+
+impl<H: PagingHandler> GuestSink<'_, H> {
+    /// Makes room for `len` more bytes of output.
+    fn reserve(&mut self, len: usize) -> AxResult {
+        if len > self.range.end - self.pos {
+            return ax_err!(InvalidInput, "decompressed image exceeds the range");
+        }
+        let end = self.pos + len;
+        if end > self.populated {
+            populate(self.aspace, self.populated, end - self.populated)?;
+            self.populated = end.align_up(PAGE_SIZE);
+        }
+        Ok(())
+    }
+}
+
+impl<H: PagingHandler> DecompressSink for GuestSink<'_, H> {
+    fn write(&mut self, data: &[u8]) -> AxResult {
+        self.reserve(data.len())?;
+        copy_in(self.aspace, self.pos, data)?;
+        self.pos += data.len();
+        Ok(())
+    }
+
+    fn copy_back(&mut self, distance: usize, len: usize) -> AxResult {
+        if distance == 0 || distance > self.pos - self.range.start {
+            return ax_err!(InvalidData, "back-reference before the output");
+        }
+        self.reserve(len)?;
+        // The match repeats with a period of `distance`, so any multiple of
+        // it reaching no further back than the match start minus `distance`
+        // works as well: grow it to copy short periods in bigger chunks.
+        let mut buf = [0; 256];
+        let mut step = distance;
+        let mut copied = 0;
+        while copied < len {
+            let chunk = (len - copied).min(step).min(buf.len());
+            copy_out(self.aspace, self.pos - step, &mut buf[..chunk])?;
+            copy_in(self.aspace, self.pos, &buf[..chunk])?;
+            self.pos += chunk;
+            copied += chunk;
+            while step * 2 <= copied + distance {
+                step *= 2;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Generates the memory map of `aspace` in the given style (see
+/// [`AddrSpace::generate_memory_map`]) and writes it into guest memory at
+/// `gpa`.
+///
+/// Returns the size of the table in bytes.
+pub fn load_memory_map<H: PagingHandler>(
+    aspace: &mut AddrSpace<H>,
+    style: MemoryMapStyle,
+    gpa: GuestPhysAddr,
+) -> AxResult<usize> {
+    let table = aspace.generate_memory_map(style);
+    load_bytes(aspace, gpa, &table)?;
+    Ok(table.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::E820_ENTRY_SIZE;
+    use crate::decompress::Lz4Frame;
+    use crate::test_utils::{MockHal, mock_hal_test};
+    use axerrno::AxError;
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_load_memory_map() {
+        let mut aspace = AddrSpace::<MockHal>::new_empty(GuestPhysAddr::from(0), 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(0x0.into(), 0x4000, rw, false).unwrap();
+        aspace
+            .reserve_mmio(GuestPhysAddrRange::new(0x8000.into(), 0x9000.into()))
+            .unwrap();
+
+        // Straddles a page boundary, faulting in both pages.
+        let gpa = GuestPhysAddr::from(PAGE_SIZE - 8);
+        let len = load_memory_map(&mut aspace, MemoryMapStyle::E820, gpa).unwrap();
+        assert_eq!(len, 2 * E820_ENTRY_SIZE);
+        let mut loaded = [0u8; 2 * E820_ENTRY_SIZE];
+        let mut offset = 0;
+        aspace
+            .for_each_host_segment(gpa, len, |_, paddr, n| {
+                let src = MockHal::phys_to_virt(paddr.unwrap()).as_ptr();
+                unsafe { core::ptr::copy_nonoverlapping(src, loaded[offset..].as_mut_ptr(), n) };
+                offset += n;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            loaded[..],
+            aspace.generate_memory_map(MemoryMapStyle::E820)[..]
+        );
+
+        assert!(load_bytes(&mut aspace, 0x3ff0.into(), &[0; 0x20]).is_err());
+        assert!(load_bytes(&mut aspace, PAGE_SIZE.into(), &[]).is_ok());
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_populate_from_compressed() {
+        let base = GuestPhysAddr::from(0x10000);
+        let mut aspace = AddrSpace::<MockHal>::new_empty(base, 0x10000).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace.map_alloc(base, 0x4000, rw, false).unwrap();
+
+        // "abcd", repeated by a match to 0x2000 bytes, then "!" and an
+        // uncompressed block "xyz".
+        let mut frame = alloc::vec![0x04, 0x22, 0x4d, 0x18, 0x60, 0x40, 0x00];
+        frame.extend_from_slice(&42u32.to_le_bytes());
+        frame.extend_from_slice(&[0x4f, b'a', b'b', b'c', b'd', 0x04, 0x00]);
+        frame.extend_from_slice(&[0xff; 32]);
+        frame.extend_from_slice(&[0x09, 0x10, b'!']);
+        frame.extend_from_slice(&0x8000_0003u32.to_le_bytes());
+        frame.extend_from_slice(b"xyz");
+        frame.extend_from_slice(&[0; 4]);
+
+        let range = GuestPhysAddrRange::from_start_size(base + 0x800, 0x3000);
+        assert_eq!(
+            populate_from_compressed(&mut aspace, range, &frame, &mut Lz4Frame),
+            Ok(0x2004)
+        );
+        let mut loaded = alloc::vec![0; 0x2004];
+        copy_out(&aspace, range.start, &mut loaded).unwrap();
+        assert!(loaded[..0x2000].chunks(4).all(|chunk| chunk == b"abcd"));
+        assert_eq!(&loaded[0x2000..], b"!xyz");
+        // Pages are populated as far as the image goes.
+        assert!(aspace.translate(base + 0x2000).is_some());
+        assert!(aspace.translate(base + 0x3000).is_none());
+
+        let small = GuestPhysAddrRange::from_start_size(base, 0x2000);
+        assert_eq!(
+            populate_from_compressed(&mut aspace, small, &frame, &mut Lz4Frame),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            populate_from_compressed(&mut aspace, range, &frame[..20], &mut Lz4Frame),
+            Err(AxError::InvalidData)
+        );
+        // A match reaching before the start of the image.
+        frame[16] = 0x05;
+        assert_eq!(
+            populate_from_compressed(&mut aspace, range, &frame, &mut Lz4Frame),
+            Err(AxError::InvalidData)
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Sequential access to a pre-translated guest range.
+
+use alloc::vec::Vec;
+use core::mem::{MaybeUninit, size_of};
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::PhysAddr;
+
+use crate::{GuestMemoryAccessor, GuestPhysAddr};
+
+/// A cursor over `[start, start + len)` in guest memory, translated once on
+/// creation.
+///
+/// Meant for parsing guest-provided tables (ACPI, SMBIOS, boot parameters)
+/// field by field without translating every few bytes. The translations are
+/// cached, so the range must not be remapped while the cursor is alive.
+pub struct GuestMemCursor<'a, A: GuestMemoryAccessor + ?Sized> {
+    accessor: &'a A,
+    start: GuestPhysAddr,
+    /// `(offset in the range, host address, length)` of each contiguous
+    /// segment, in ascending order.
+    segments: Vec<(usize, PhysAddr, usize)>,
+    len: usize,
+    pos: usize,
+}
+
+impl<'a, A: GuestMemoryAccessor + ?Sized> GuestMemCursor<'a, A> {
+    /// Translates `[start, start + len)` through `accessor`, and creates a
+    /// cursor at its start.
+    ///
+    /// Fails with `InvalidInput` if any part of the range is not accessible.
+    pub fn new(accessor: &'a A, start: GuestPhysAddr, len: usize) -> AxResult<Self> {
+        if start.as_usize().checked_add(len).is_none() {
+            return ax_err!(InvalidInput, "range overflows");
+        }
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            let Some((host_addr, limit)) = accessor.translate_and_get_limit(start + offset) else {
+                return ax_err!(InvalidInput, "range not accessible");
+            };
+            if limit == 0 {
+                return ax_err!(InvalidInput, "range not accessible");
+            }
+            let seg_len = limit.min(len - offset);
+            match segments.last_mut() {
+                // Merge segments contiguous in host memory.
+                Some((seg_off, seg_addr, last_len))
+                    if *seg_addr + *last_len == host_addr && *seg_off + *last_len == offset =>
+                {
+                    *last_len += seg_len
+                }
+                _ => segments.push((offset, host_addr, seg_len)),
+            }
+            offset += seg_len;
+        }
+        Ok(Self {
+            accessor,
+            start,
+            segments,
+            len,
+            pos: 0,
+        })
+    }
+
+    /// Returns the length of the range.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the range is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the offset of the cursor in the range.
+    pub const fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the number of bytes after the cursor.
+    pub const fn remaining(&self) -> usize {
+        self.len - self.pos
+    }
+
+    /// Returns the guest address of the cursor.
+    pub fn guest_addr(&self) -> GuestPhysAddr {
+        self.start + self.pos
+    }
+
+    /// Moves the cursor to offset `pos` in the range.
+    pub fn seek(&mut self, pos: usize) -> AxResult {
+        if pos > self.len {
+            return ax_err!(InvalidInput, "seek past the end of the range");
+        }
+        self.pos = pos;
+        Ok(())
+    }
+
+    /// Advances the cursor by `n` bytes.
+    pub fn skip(&mut self, n: usize) -> AxResult {
+        if n > self.remaining() {
+            return ax_err!(InvalidInput, "skip past the end of the range");
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    /// Reads a `T` at the cursor, and advances past it.
+    ///
+    /// Fails with `InvalidInput`, without moving, if fewer than
+    /// `size_of::<T>()` bytes remain.
+    pub fn read_next<T: Copy>(&mut self) -> AxResult<T> {
+        let mut val = MaybeUninit::<T>::uninit();
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        self.read_bytes(bytes)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Writes `val` at the cursor, and advances past it.
+    ///
+    /// Fails with `InvalidInput`, without moving, if fewer than
+    /// `size_of::<T>()` bytes remain.
+    pub fn write_next<T: Copy>(&mut self, val: T) -> AxResult {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(&val as *const T as *const u8, size_of::<T>()) };
+        self.write_bytes(bytes)
+    }
+
+    /// Fills `buf` from the cursor, and advances past it.
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> AxResult {
+        if buf.len() > self.remaining() {
+            return ax_err!(InvalidInput, "read past the end of the range");
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let (host_addr, len) = self.chunk(self.pos + done, buf.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    host_addr.as_usize() as *const u8,
+                    buf[done..].as_mut_ptr(),
+                    len,
+                )
+            };
+            done += len;
+        }
+        self.accessor.read_barrier();
+        self.pos += buf.len();
+        Ok(())
+    }
+
+    /// Writes `buf` at the cursor, and advances past it.
+    pub fn write_bytes(&mut self, buf: &[u8]) -> AxResult {
+        if buf.len() > self.remaining() {
+            return ax_err!(InvalidInput, "write past the end of the range");
+        }
+        self.accessor.write_barrier();
+        let mut done = 0;
+        while done < buf.len() {
+            let (host_addr, len) = self.chunk(self.pos + done, buf.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buf[done..].as_ptr(),
+                    host_addr.as_usize() as *mut u8,
+                    len,
+                )
+            };
+            self.accessor.mark_dirty(self.start + self.pos + done, len);
+            done += len;
+        }
+        self.pos += buf.len();
+        Ok(())
+    }
+
+    /// Returns the host address of `offset`, which must be in the range, and
+    /// the number of bytes up to `max` contiguous from it.
+    fn chunk(&self, offset: usize, max: usize) -> (PhysAddr, usize) {
+        let index = self.segments.partition_point(|&(off, _, _)| off <= offset) - 1;
+        let (seg_off, host_addr, seg_len) = self.segments[index];
+        let skip = offset - seg_off;
+        (host_addr + skip, (seg_len - skip).min(max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axerrno::AxError;
+    use core::cell::{Cell, UnsafeCell};
+
+    /// Maps guest addresses `[0, 0x40)` to `mem` in reversed 0x10-byte
+    /// windows, so that accesses cross discontiguous segments.
+    struct SplitTranslator<'a> {
+        mem: &'a UnsafeCell<[u8; 0x40]>,
+        translations: Cell<usize>,
+    }
+
+    impl GuestMemoryAccessor for SplitTranslator<'_> {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            self.translations.set(self.translations.get() + 1);
+            let gpa = guest_addr.as_usize();
+            if gpa >= 0x40 {
+                return None;
+            }
+            let host = self.mem.get() as usize + (0x30 - (gpa & !0xf)) + (gpa & 0xf);
+            Some((PhysAddr::from(host), 0x10 - (gpa & 0xf)))
+        }
+    }
+
+    #[test]
+    fn test_guest_mem_cursor() {
+        let mem = UnsafeCell::new([0u8; 0x40]);
+        let accessor = SplitTranslator {
+            mem: &mem,
+            translations: Cell::new(0),
+        };
+        let start = GuestPhysAddr::from(0x8);
+        assert!(GuestMemCursor::new(&accessor, start, 0x39).is_err());
+        let mut cursor = GuestMemCursor::new(&accessor, start, 0x30).unwrap();
+        accessor.translations.set(0);
+
+        cursor.write_next(0x1122_3344_5566_7788u64).unwrap();
+        cursor.write_next(0xaabb_ccddu32).unwrap();
+        cursor.skip(0x1d).unwrap();
+        assert_eq!(
+            cursor.write_next(0u64).map_err(|e| (e, cursor.position())),
+            Err((AxError::InvalidInput, 0x29))
+        );
+        cursor.write_next(0x99u8).unwrap();
+
+        cursor.seek(4).unwrap();
+        // Crosses from the first window to the second one.
+        assert_eq!(cursor.read_next::<u64>(), Ok(0xaabb_ccdd_1122_3344));
+        assert_eq!(cursor.guest_addr(), start + 0xc);
+        let mem = unsafe { &*mem.get() };
+        assert_eq!(mem[0x38..], 0x1122_3344_5566_7788u64.to_le_bytes());
+        cursor.seek(0x29).unwrap();
+        assert_eq!(cursor.read_next::<u8>(), Ok(0x99));
+        assert_eq!(cursor.remaining(), 6);
+        assert_eq!(mem[0x01], 0x99);
+        assert!(cursor.skip(8).is_err());
+        assert_eq!(accessor.translations.get(), 0);
+    }
+}
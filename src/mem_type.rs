@@ -0,0 +1,94 @@
+//! Memory types of guest mappings.
+
+use page_table_entry::MappingFlags;
+
+/// The memory type (cacheability attributes) of a guest mapping.
+///
+/// Memory types are encoded into [`MappingFlags`] with the `DEVICE` and
+/// `UNCACHED` bits, plus two bits above those defined by [`MappingFlags`]
+/// for the types that have no generic flag. The architecture-specific nested
+/// page tables turn them into their own attribute encodings, see
+/// [`npt::effective_mem_type`](crate::npt::effective_mem_type) for the types
+/// each of them supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemType {
+    /// Normal, cacheable memory (guest RAM).
+    Normal,
+    /// Device memory (MMIO), strongly ordered and uncached.
+    Device,
+    /// Normal but uncached memory.
+    Uncached,
+    /// Write-combining memory, e.g., framebuffers. Encoded with `UNCACHED`
+    /// set, so that it is at least uncached where unsupported.
+    WriteCombining,
+    /// Write-through memory.
+    WriteThrough,
+    /// Write-protected memory, e.g., persistent memory mapped read-mostly.
+    WriteProtected,
+}
+
+impl MemType {
+    /// The bits encoding the types without a generic flag.
+    const EXT_SHIFT: usize = 8;
+    const EXT_MASK: MappingFlags = MappingFlags::from_bits_retain(0b11 << Self::EXT_SHIFT);
+    const MASK: MappingFlags = MappingFlags::DEVICE
+        .union(MappingFlags::UNCACHED)
+        .union(Self::EXT_MASK);
+
+    const fn ext(value: usize) -> MappingFlags {
+        MappingFlags::from_bits_retain(value << Self::EXT_SHIFT)
+    }
+
+    /// Returns the memory type encoded in `flags`.
+    pub fn from_flags(flags: MappingFlags) -> Self {
+        match (flags & Self::EXT_MASK).bits() >> Self::EXT_SHIFT {
+            1 => return Self::WriteCombining,
+            2 => return Self::WriteThrough,
+            3 => return Self::WriteProtected,
+            _ => {}
+        }
+        match (
+            flags.contains(MappingFlags::DEVICE),
+            flags.contains(MappingFlags::UNCACHED),
+        ) {
+            (false, false) => Self::Normal,
+            (true, false) => Self::Device,
+            (_, true) => Self::Uncached,
+        }
+    }
+
+    /// Returns `flags` with its memory type replaced by `self`.
+    pub fn apply(self, flags: MappingFlags) -> MappingFlags {
+        let flags = flags - Self::MASK;
+        match self {
+            Self::Normal => flags,
+            Self::Device => flags | MappingFlags::DEVICE,
+            Self::Uncached => flags | MappingFlags::DEVICE | MappingFlags::UNCACHED,
+            Self::WriteCombining => flags | MappingFlags::UNCACHED | Self::ext(1),
+            Self::WriteThrough => flags | Self::ext(2),
+            Self::WriteProtected => flags | Self::ext(3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mem_type_round_trip() {
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        for mem_type in [
+            MemType::Normal,
+            MemType::Device,
+            MemType::Uncached,
+            MemType::WriteCombining,
+            MemType::WriteThrough,
+            MemType::WriteProtected,
+        ] {
+            let flags = mem_type.apply(rw | MappingFlags::DEVICE);
+            assert_eq!(MemType::from_flags(flags), mem_type);
+            assert!(flags.contains(rw));
+        }
+    }
+}
@@ -4,8 +4,61 @@
 //! from VirtIO device implementations, handling address translation and
 //! memory safety concerns.
 use crate::GuestPhysAddr;
+#[cfg(feature = "alloc")]
+use alloc::{ffi::CString, vec::Vec};
 use axerrno::{AxError, AxResult};
+use core::mem::MaybeUninit;
 use memory_addr::PhysAddr;
+use page_table_entry::MappingFlags;
+
+/// How [`GuestMemoryAccessor::read_obj`] and
+/// [`GuestMemoryAccessor::write_obj`] handle an object that is not naturally
+/// aligned, e.g., a guest issuing a misaligned MMIO access.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MisalignedPolicy {
+    /// Perform an unaligned access, whose width and atomicity are up to the
+    /// host CPU and the compiler.
+    #[default]
+    Allow,
+    /// Perform one volatile access per byte, in ascending address order,
+    /// for device models emulating misaligned accesses byte-wise.
+    SplitIntoBytes,
+    /// Fail with [`AxError::BadAddress`], for device models that must
+    /// fault the access.
+    Reject,
+}
+
+/// The progress of [`GuestMemoryAccessor::read_exact_at`] or
+/// [`GuestMemoryAccessor::write_all_at`] when it stopped before the end of
+/// the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialTransfer {
+    /// The number of bytes transferred, from the start of the buffer.
+    pub transferred: usize,
+    /// The guest address the transfer stopped at, i.e., the start address
+    /// plus `transferred`.
+    pub fault_gpa: GuestPhysAddr,
+    /// Why the transfer stopped, [`AxError::InvalidInput`] for an address
+    /// that cannot be translated.
+    pub error: AxError,
+}
+
+impl PartialTransfer {
+    /// A transfer that failed with `error` before its first byte.
+    pub(crate) const fn none(guest_addr: GuestPhysAddr, error: AxError) -> Self {
+        Self {
+            transferred: 0,
+            fault_gpa: guest_addr,
+            error,
+        }
+    }
+}
+
+impl From<PartialTransfer> for AxError {
+    fn from(partial: PartialTransfer) -> Self {
+        partial.error
+    }
+}
 
 /// A stateful accessor to the memory space of a guest
 pub trait GuestMemoryAccessor {
@@ -18,6 +71,9 @@ pub trait GuestMemoryAccessor {
 
     /// Read a value of type V from guest memory
     ///
+    /// A misaligned value is read according to
+    /// [`GuestMemoryAccessor::misaligned_policy`].
+    ///
     /// # Returns
     ///
     /// Returns `Err(AxError::InvalidInput)` in the following cases:
@@ -40,14 +96,35 @@ pub trait GuestMemoryAccessor {
             return Err(AxError::InvalidInput);
         }
 
-        unsafe {
-            let ptr = host_addr.as_usize() as *const V;
-            Ok(core::ptr::read_volatile(ptr))
-        }
+        let ptr = host_addr.as_usize() as *const V;
+        let val = if ptr.is_aligned() {
+            unsafe { core::ptr::read_volatile(ptr) }
+        } else {
+            match self.misaligned_policy() {
+                MisalignedPolicy::Allow => unsafe { ptr.read_unaligned() },
+                MisalignedPolicy::SplitIntoBytes => {
+                    let mut val = MaybeUninit::<V>::uninit();
+                    let dst = val.as_mut_ptr() as *mut u8;
+                    for i in 0..core::mem::size_of::<V>() {
+                        unsafe {
+                            dst.add(i)
+                                .write(core::ptr::read_volatile((ptr as *const u8).add(i)));
+                        }
+                    }
+                    unsafe { val.assume_init() }
+                }
+                MisalignedPolicy::Reject => return Err(AxError::BadAddress),
+            }
+        };
+        self.read_barrier();
+        Ok(val)
     }
 
     /// Write a value of type V to guest memory
     ///
+    /// A misaligned value is written according to
+    /// [`GuestMemoryAccessor::misaligned_policy`].
+    ///
     /// # Returns
     ///
     /// Returns `Err(AxError::InvalidInput)` in the following cases:
@@ -70,10 +147,23 @@ pub trait GuestMemoryAccessor {
             return Err(AxError::InvalidInput);
         }
 
-        unsafe {
-            let ptr = host_addr.as_usize() as *mut V;
-            core::ptr::write_volatile(ptr, val);
+        let ptr = host_addr.as_usize() as *mut V;
+        let policy = (!ptr.is_aligned()).then(|| self.misaligned_policy());
+        if policy == Some(MisalignedPolicy::Reject) {
+            return Err(AxError::BadAddress);
+        }
+        self.write_barrier();
+        match policy {
+            None => unsafe { core::ptr::write_volatile(ptr, val) },
+            Some(MisalignedPolicy::SplitIntoBytes) => {
+                let src = &val as *const V as *const u8;
+                for i in 0..core::mem::size_of::<V>() {
+                    unsafe { core::ptr::write_volatile((ptr as *mut u8).add(i), *src.add(i)) };
+                }
+            }
+            Some(_) => unsafe { ptr.write_unaligned(val) },
         }
+        self.mark_dirty(guest_addr, core::mem::size_of::<V>());
         Ok(())
     }
 
@@ -94,6 +184,7 @@ pub trait GuestMemoryAccessor {
                 let src_ptr = host_addr.as_usize() as *const u8;
                 core::ptr::copy_nonoverlapping(src_ptr, buffer.as_mut_ptr(), buffer.len());
             }
+            self.read_barrier();
             return Ok(());
         }
 
@@ -124,6 +215,7 @@ pub trait GuestMemoryAccessor {
             remaining_buffer = &mut remaining_buffer[bytes_to_read..];
         }
 
+        self.read_barrier();
         Ok(())
     }
 
@@ -137,6 +229,7 @@ pub trait GuestMemoryAccessor {
             .translate_and_get_limit(guest_addr)
             .ok_or(AxError::InvalidInput)?;
 
+        self.write_barrier();
         // Check if we can write the entire buffer to this accessible region
         if accessible_size >= buffer.len() {
             // Simple case: entire buffer fits within accessible region
@@ -144,6 +237,7 @@ pub trait GuestMemoryAccessor {
                 let dst_ptr = host_addr.as_usize() as *mut u8;
                 core::ptr::copy_nonoverlapping(buffer.as_ptr(), dst_ptr, buffer.len());
             }
+            self.mark_dirty(guest_addr, buffer.len());
             return Ok(());
         }
 
@@ -163,6 +257,7 @@ pub trait GuestMemoryAccessor {
                 let dst_ptr = current_host_addr.as_usize() as *mut u8;
                 core::ptr::copy_nonoverlapping(remaining_buffer.as_ptr(), dst_ptr, bytes_to_write);
             }
+            self.mark_dirty(current_guest_addr, bytes_to_write);
 
             // Move to next region
             current_guest_addr =
@@ -173,6 +268,225 @@ pub trait GuestMemoryAccessor {
         Ok(())
     }
 
+    /// Reads `buffer.len()` bytes from guest memory at `guest_addr`, up to
+    /// the first address that cannot be translated.
+    ///
+    /// Unlike [`GuestMemoryAccessor::read_buffer`], the bytes before a hole
+    /// are read, and the error tells how many they are and where the hole
+    /// is, e.g., for a virtio device to complete a descriptor partially and
+    /// report the faulting address.
+    fn read_exact_at(
+        &self,
+        guest_addr: GuestPhysAddr,
+        buffer: &mut [u8],
+    ) -> Result<(), PartialTransfer> {
+        let mut transferred = 0;
+        while transferred < buffer.len() {
+            let gpa = GuestPhysAddr::from_usize(guest_addr.as_usize() + transferred);
+            let Some((host_addr, limit)) = self
+                .translate_and_get_limit(gpa)
+                .filter(|&(_, limit)| limit > 0)
+            else {
+                self.read_barrier();
+                return Err(PartialTransfer {
+                    transferred,
+                    fault_gpa: gpa,
+                    error: AxError::InvalidInput,
+                });
+            };
+            let len = (buffer.len() - transferred).min(limit);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    host_addr.as_usize() as *const u8,
+                    buffer[transferred..].as_mut_ptr(),
+                    len,
+                );
+            }
+            transferred += len;
+        }
+        self.read_barrier();
+        Ok(())
+    }
+
+    /// Writes `buffer` to guest memory at `guest_addr`, up to the first
+    /// address that cannot be translated.
+    ///
+    /// The counterpart of [`GuestMemoryAccessor::read_exact_at`]: the bytes
+    /// before a hole are written, and marked dirty.
+    fn write_all_at(
+        &self,
+        guest_addr: GuestPhysAddr,
+        buffer: &[u8],
+    ) -> Result<(), PartialTransfer> {
+        self.write_barrier();
+        let mut transferred = 0;
+        while transferred < buffer.len() {
+            let gpa = GuestPhysAddr::from_usize(guest_addr.as_usize() + transferred);
+            let Some((host_addr, limit)) = self
+                .translate_and_get_limit(gpa)
+                .filter(|&(_, limit)| limit > 0)
+            else {
+                return Err(PartialTransfer {
+                    transferred,
+                    fault_gpa: gpa,
+                    error: AxError::InvalidInput,
+                });
+            };
+            let len = (buffer.len() - transferred).min(limit);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buffer[transferred..].as_ptr(),
+                    host_addr.as_usize() as *mut u8,
+                    len,
+                );
+            }
+            self.mark_dirty(gpa, len);
+            transferred += len;
+        }
+        Ok(())
+    }
+
+    /// Reads the NUL-terminated string at `guest_addr`, of at most `max_len`
+    /// bytes including the terminator, e.g., a path passed by a hypercall.
+    ///
+    /// The string is read through [`GuestMemoryAccessor::read_buffer`] one
+    /// accessible region at a time, so it may span areas, and nothing is
+    /// read past the region holding the terminator.
+    ///
+    /// Fails with `InvalidInput` if an address before the terminator cannot
+    /// be translated, and with `InvalidData` if there is no terminator in
+    /// the first `max_len` bytes.
+    #[cfg(feature = "alloc")]
+    fn read_cstr(&self, guest_addr: GuestPhysAddr, max_len: usize) -> AxResult<CString> {
+        let mut bytes = Vec::new();
+        while bytes.len() < max_len {
+            let gpa = guest_addr
+                .as_usize()
+                .checked_add(bytes.len())
+                .map(GuestPhysAddr::from_usize)
+                .ok_or(AxError::InvalidInput)?;
+            let (_, limit) = self
+                .translate_and_get_limit(gpa)
+                .filter(|&(_, limit)| limit > 0)
+                .ok_or(AxError::InvalidInput)?;
+            let start = bytes.len();
+            bytes.resize(start + limit.min(max_len - start), 0);
+            self.read_buffer(gpa, &mut bytes[start..])?;
+            if let Some(nul) = bytes[start..].iter().position(|&b| b == 0) {
+                bytes.truncate(start + nul);
+                // SAFETY: `bytes` stops at the first NUL.
+                return Ok(unsafe { CString::from_vec_unchecked(bytes) });
+            }
+        }
+        Err(AxError::InvalidData)
+    }
+
+    /// Reads `count` consecutive values of type V from guest memory, e.g., a
+    /// table of descriptors.
+    ///
+    /// The array is read with a single [`GuestMemoryAccessor::read_buffer`],
+    /// so it may span areas. Fails with `InvalidInput` if its size overflows
+    /// or any of it cannot be translated, in which case nothing is returned.
+    #[cfg(feature = "alloc")]
+    fn read_obj_array<V: Copy>(&self, guest_addr: GuestPhysAddr, count: usize) -> AxResult<Vec<V>> {
+        let len = count
+            .checked_mul(core::mem::size_of::<V>())
+            .filter(|&len| guest_addr.as_usize().checked_add(len).is_some())
+            .ok_or(AxError::InvalidInput)?;
+        let mut bytes = alloc::vec![0u8; len];
+        self.read_buffer(guest_addr, &mut bytes)?;
+        let ptr = bytes.as_ptr() as *const V;
+        Ok((0..count)
+            .map(|i| unsafe { ptr.add(i).read_unaligned() })
+            .collect())
+    }
+
+    /// Writes the values of `vals` consecutively to guest memory, the
+    /// counterpart of [`GuestMemoryAccessor::read_obj_array`].
+    ///
+    /// Every region the array spans is translated first: fails with
+    /// `InvalidInput` if its size overflows or any of it cannot be
+    /// translated, in which case nothing is written.
+    fn write_obj_array<V: Copy>(&self, guest_addr: GuestPhysAddr, vals: &[V]) -> AxResult<()> {
+        let len = core::mem::size_of_val(vals);
+        let end = guest_addr
+            .as_usize()
+            .checked_add(len)
+            .ok_or(AxError::InvalidInput)?;
+        let mut addr = guest_addr.as_usize();
+        while addr < end {
+            let (_, limit) = self
+                .translate_and_get_limit(GuestPhysAddr::from_usize(addr))
+                .filter(|&(_, limit)| limit > 0)
+                .ok_or(AxError::InvalidInput)?;
+            addr = addr.saturating_add(limit);
+        }
+        // SAFETY: `vals` is `len` bytes long, and `V: Copy` is plain data.
+        let bytes = unsafe { core::slice::from_raw_parts(vals.as_ptr() as *const u8, len) };
+        self.write_buffer(guest_addr, bytes)
+    }
+
+    /// Records that `[guest_addr, guest_addr + len)` was written through this
+    /// accessor.
+    ///
+    /// Such writes bypass the write protection used for dirty logging, so
+    /// accessors backed by an [`AddrSpace`](crate::AddrSpace) should forward
+    /// this to [`AddrSpace::mark_dirty`](crate::AddrSpace::mark_dirty).
+    /// Called by the provided write methods; does nothing by default.
+    fn mark_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+        let _ = (guest_addr, len);
+    }
+
+    /// Called by the provided write methods before storing to guest memory.
+    ///
+    /// Volatile stores are not reordered by the compiler, but may become
+    /// visible to the vCPUs of the guest in a different order, e.g., a
+    /// virtqueue "available index" update before the descriptors it
+    /// publishes. Accessors shared with running vCPUs should issue
+    /// [`barrier::smp_wmb`](crate::barrier::smp_wmb), or
+    /// [`barrier::dma_wmb`](crate::barrier::dma_wmb) if a device may also
+    /// observe the writes. Does nothing by default.
+    fn write_barrier(&self) {}
+
+    /// Called by the provided read methods after loading from guest memory,
+    /// so that a later read, e.g., of the descriptors published by an
+    /// "available index", cannot observe older data. See
+    /// [`barrier::smp_rmb`](crate::barrier::smp_rmb). Does nothing by
+    /// default.
+    fn read_barrier(&self) {}
+
+    /// Makes earlier writes to the write-combining range at `guest_addr`,
+    /// e.g., a frame written into a guest framebuffer, visible to the guest
+    /// and to devices. Device backends call this at frame boundaries.
+    ///
+    /// Issues [`barrier::wc_flush`](crate::barrier::wc_flush) by default.
+    /// Accessors backed by an [`AddrSpace`](crate::AddrSpace) may forward
+    /// this to [`AddrSpace::flush_wc`](crate::AddrSpace::flush_wc).
+    fn flush_wc(&self, guest_addr: GuestPhysAddr, len: usize) {
+        let _ = (guest_addr, len);
+        crate::barrier::wc_flush();
+    }
+
+    /// Returns how misaligned objects are accessed, [`MisalignedPolicy::Allow`]
+    /// by default.
+    fn misaligned_policy(&self) -> MisalignedPolicy {
+        MisalignedPolicy::Allow
+    }
+
+    /// Returns whether the stage-2 mapping of `guest_addr` allows `access`,
+    /// checked by the accessors of [`GuestVirtAccessorExt`] on top of the
+    /// permissions of the guest page tables.
+    ///
+    /// Accessors backed by an [`AddrSpace`](crate::AddrSpace) should forward
+    /// this to [`AddrSpace::allows_access`](crate::AddrSpace::allows_access).
+    /// Allows everything by default.
+    ///
+    /// [`GuestVirtAccessorExt`]: crate::GuestVirtAccessorExt
+    fn allows_access(&self, guest_addr: GuestPhysAddr, access: MappingFlags) -> bool {
+        let _ = (guest_addr, access);
+        true
+    }
+
     /// Read a volatile value from guest memory (for device registers)
     fn read_volatile<V: Copy>(&self, guest_addr: GuestPhysAddr) -> AxResult<V> {
         self.read_obj(guest_addr)
@@ -184,6 +498,96 @@ pub trait GuestMemoryAccessor {
     }
 }
 
+/// The translation part of [`GuestMemoryAccessor`], usable as a trait object.
+///
+/// It is implemented for every [`GuestMemoryAccessor`].
+pub trait GuestTranslator {
+    /// See [`GuestMemoryAccessor::translate_and_get_limit`].
+    fn translate_guest(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)>;
+
+    /// See [`GuestMemoryAccessor::mark_dirty`]. Does nothing by default.
+    fn mark_guest_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+        let _ = (guest_addr, len);
+    }
+}
+
+impl<T: GuestMemoryAccessor> GuestTranslator for T {
+    fn translate_guest(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        GuestMemoryAccessor::translate_and_get_limit(self, guest_addr)
+    }
+
+    fn mark_guest_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+        GuestMemoryAccessor::mark_dirty(self, guest_addr, len)
+    }
+}
+
+/// A [`GuestMemoryAccessor`] trying an ordered list of translators, e.g., the
+/// RAM address space first, then an MMIO shim, then a [`RejectTranslator`].
+///
+/// An address is translated by the first translator that accepts it, so
+/// device code gets a single accessor even when guest memory is split across
+/// multiple management objects. Buffer accesses crossing from one translator
+/// to another are split accordingly.
+#[cfg(feature = "alloc")]
+#[derive(Default)]
+pub struct ChainedTranslator<'a> {
+    translators: Vec<&'a dyn GuestTranslator>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> ChainedTranslator<'a> {
+    /// Creates an empty chain, which translates nothing.
+    pub const fn new() -> Self {
+        Self {
+            translators: Vec::new(),
+        }
+    }
+
+    /// Appends `translator` to the end of the chain.
+    pub fn with(mut self, translator: &'a dyn GuestTranslator) -> Self {
+        self.translators.push(translator);
+        self
+    }
+
+    /// Returns the number of translators in the chain.
+    pub fn len(&self) -> usize {
+        self.translators.len()
+    }
+
+    /// Whether the chain contains no translator.
+    pub fn is_empty(&self) -> bool {
+        self.translators.is_empty()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl GuestMemoryAccessor for ChainedTranslator<'_> {
+    fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        self.translators
+            .iter()
+            .find_map(|t| t.translate_guest(guest_addr))
+    }
+
+    /// Forwarded to every translator of the chain, which must ignore the
+    /// addresses they do not own.
+    fn mark_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+        for t in &self.translators {
+            t.mark_guest_dirty(guest_addr, len);
+        }
+    }
+}
+
+/// A translator rejecting every address with a warning, meant to terminate
+/// a [`ChainedTranslator`].
+pub struct RejectTranslator;
+
+impl GuestTranslator for RejectTranslator {
+    fn translate_guest(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        warn!("guest memory access to untranslatable address {guest_addr:?}");
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -401,11 +805,7 @@ mod tests {
             let read_byte: u8 = translator
                 .read_obj(byte_addr)
                 .expect("Failed to read individual byte");
-            assert_eq!(
-                read_byte, expected_byte,
-                "Byte at offset {} should match",
-                i
-            );
+            assert_eq!(read_byte, expected_byte, "Byte at offset {i} should match");
         }
     }
 
@@ -435,9 +835,9 @@ mod tests {
             .write_buffer(boundary_addr, empty_buffer)
             .expect("Empty buffer write should succeed");
 
-        let mut empty_read: &mut [u8] = &mut [];
+        let empty_read: &mut [u8] = &mut [];
         translator
-            .read_buffer(boundary_addr, &mut empty_read)
+            .read_buffer(boundary_addr, empty_read)
             .expect("Empty buffer read should succeed");
 
         // Test single byte at boundary (should work fine)
@@ -446,4 +846,257 @@ mod tests {
             .write_buffer(boundary_addr, &single_byte)
             .expect("Single byte write should succeed");
     }
+
+    /// Translates `[gpa_start, gpa_start + len)` to mock memory at `offset`.
+    #[cfg(feature = "alloc")]
+    struct WindowTranslator {
+        gpa_start: usize,
+        len: usize,
+        offset: usize,
+    }
+
+    #[cfg(feature = "alloc")]
+    impl GuestMemoryAccessor for WindowTranslator {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            let off = guest_addr.as_usize().checked_sub(self.gpa_start)?;
+            if off >= self.len {
+                return None;
+            }
+            let paddr = PhysAddr::from_usize(BASE_PADDR + self.offset + off);
+            let vaddr = crate::test_utils::MockHal::mock_phys_to_virt(paddr);
+            Some((PhysAddr::from_usize(vaddr.as_usize()), self.len - off))
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    #[axin(decorator(mock_hal_test))]
+    fn test_chained_translator() {
+        let ram = WindowTranslator {
+            gpa_start: 0x1000,
+            len: 0x1000,
+            offset: 0,
+        };
+        let shim = WindowTranslator {
+            gpa_start: 0x2000,
+            len: 0x1000,
+            offset: 0x8000,
+        };
+        let chain = ChainedTranslator::new()
+            .with(&ram)
+            .with(&shim)
+            .with(&RejectTranslator);
+        assert_eq!(chain.len(), 3);
+
+        // A buffer spanning both translators is split between them.
+        let data = [0xabu8; 0x10];
+        chain
+            .write_buffer(GuestPhysAddr::from_usize(0x1ff8), &data)
+            .unwrap();
+        assert_eq!(
+            shim.read_obj::<u8>(GuestPhysAddr::from_usize(0x2007)),
+            Ok(0xab)
+        );
+        assert_eq!(
+            ram.read_obj::<u8>(GuestPhysAddr::from_usize(0x1fff)),
+            Ok(0xab)
+        );
+        let mut buf = [0u8; 0x10];
+        chain
+            .read_buffer(GuestPhysAddr::from_usize(0x1ff8), &mut buf)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        assert_eq!(
+            chain.read_obj::<u32>(GuestPhysAddr::from_usize(0x3000)),
+            Err(AxError::InvalidInput)
+        );
+        assert!(ChainedTranslator::new().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    #[axin(decorator(mock_hal_test))]
+    fn test_partial_transfer() {
+        let low = WindowTranslator {
+            gpa_start: 0x1000,
+            len: 0x1000,
+            offset: 0,
+        };
+        let high = WindowTranslator {
+            gpa_start: 0x3000,
+            len: 0x1000,
+            offset: 0x8000,
+        };
+        let chain = ChainedTranslator::new().with(&low).with(&high);
+        let hole = PartialTransfer {
+            transferred: 8,
+            fault_gpa: GuestPhysAddr::from_usize(0x2000),
+            error: AxError::InvalidInput,
+        };
+
+        // The bytes before the hole are transferred.
+        let start = GuestPhysAddr::from_usize(0x1ff8);
+        assert_eq!(chain.write_all_at(start, &[0x5a; 0x10]), Err(hole));
+        assert_eq!(low.read_obj::<u64>(start), Ok(0x5a5a_5a5a_5a5a_5a5a));
+        let mut buf = [0u8; 0x10];
+        assert_eq!(chain.read_exact_at(start, &mut buf), Err(hole));
+        assert_eq!(buf[..8], [0x5a; 8]);
+        assert_eq!(AxError::from(hole), AxError::InvalidInput);
+
+        let inside = GuestPhysAddr::from_usize(0x3ff0);
+        assert_eq!(chain.write_all_at(inside, &[1; 0x10]), Ok(()));
+        assert_eq!(chain.read_exact_at(inside, &mut buf), Ok(()));
+        assert_eq!(buf, [1; 0x10]);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    #[axin(decorator(mock_hal_test))]
+    fn test_strings_and_arrays() {
+        let low = WindowTranslator {
+            gpa_start: 0x1000,
+            len: 0x1000,
+            offset: 0,
+        };
+        let high = WindowTranslator {
+            gpa_start: 0x2000,
+            len: 0x1000,
+            offset: 0x8000,
+        };
+        let chain = ChainedTranslator::new().with(&low).with(&high);
+
+        // Both span the boundary between the two windows.
+        let vals = [0x1111_2222u32, 0x3333_4444, 0x5555_6666, 0x7777_8888];
+        let array = GuestPhysAddr::from_usize(0x1ff8);
+        chain.write_obj_array(array, &vals).unwrap();
+        assert_eq!(chain.read_obj_array::<u32>(array, 4), Ok(vals.to_vec()));
+        assert_eq!(
+            high.read_obj::<u32>(GuestPhysAddr::from_usize(0x2000)),
+            Ok(0x5555_6666)
+        );
+
+        let name = GuestPhysAddr::from_usize(0x1ffc);
+        chain.write_buffer(name, b"virtio\0tail").unwrap();
+        assert_eq!(chain.read_cstr(name, 0x100).unwrap().as_bytes(), b"virtio");
+        assert_eq!(chain.read_cstr(name, 6), Err(AxError::InvalidData));
+        // Unterminated up to the end of guest memory.
+        chain
+            .write_buffer(GuestPhysAddr::from_usize(0x2ffc), b"abcd")
+            .unwrap();
+        assert_eq!(
+            chain.read_cstr(GuestPhysAddr::from_usize(0x2ffc), 0x100),
+            Err(AxError::InvalidInput)
+        );
+
+        // Arrays running into a hole are neither read nor written.
+        let tail = GuestPhysAddr::from_usize(0x2ff8);
+        assert_eq!(
+            chain.write_obj_array(tail, &vals),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(high.read_obj::<u32>(tail), Ok(0));
+        assert_eq!(
+            chain.read_obj_array::<u32>(tail, 4),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            chain.read_obj_array::<u64>(tail, usize::MAX),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    /// A [`MockTranslator`] with a given misaligned access policy.
+    struct PolicyTranslator(MockTranslator, MisalignedPolicy);
+
+    impl GuestMemoryAccessor for PolicyTranslator {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            self.0.translate_and_get_limit(guest_addr)
+        }
+
+        fn misaligned_policy(&self) -> MisalignedPolicy {
+            self.1
+        }
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_misaligned_policy() {
+        let translator = |policy| {
+            PolicyTranslator(
+                MockTranslator::new(PhysAddr::from_usize(0), crate::test_utils::MEMORY_LEN),
+                policy,
+            )
+        };
+        let addr = GuestPhysAddr::from_usize(0x101);
+        for policy in [MisalignedPolicy::Allow, MisalignedPolicy::SplitIntoBytes] {
+            let accessor = translator(policy);
+            accessor.write_obj(addr, 0x1122_3344u32).unwrap();
+            assert_eq!(accessor.read_obj::<u32>(addr), Ok(0x1122_3344));
+            assert_eq!(accessor.read_obj::<u8>(addr), Ok(0x44));
+            accessor.write_obj(addr, 0u32).unwrap();
+        }
+
+        let accessor = translator(MisalignedPolicy::Reject);
+        assert_eq!(accessor.read_obj::<u32>(addr), Err(AxError::BadAddress));
+        assert_eq!(accessor.write_obj(addr, 1u32), Err(AxError::BadAddress));
+        assert_eq!(accessor.read_obj::<u32>(addr - 1), Ok(0));
+        // Byte accesses are always aligned.
+        assert_eq!(accessor.read_obj::<u8>(addr), Ok(0));
+    }
+
+    /// Records the order of barriers and accesses.
+    #[cfg(feature = "alloc")]
+    struct OrderTranslator {
+        inner: MockTranslator,
+        log: core::cell::RefCell<Vec<&'static str>>,
+    }
+
+    #[cfg(feature = "alloc")]
+    impl GuestMemoryAccessor for OrderTranslator {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            self.inner.translate_and_get_limit(guest_addr)
+        }
+
+        fn mark_dirty(&self, _guest_addr: GuestPhysAddr, _len: usize) {
+            self.log.borrow_mut().push("write");
+        }
+
+        fn write_barrier(&self) {
+            self.log.borrow_mut().push("wmb");
+            crate::barrier::smp_wmb();
+        }
+
+        fn read_barrier(&self) {
+            self.log.borrow_mut().push("rmb");
+            crate::barrier::smp_rmb();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_barrier_hooks() {
+        let accessor = OrderTranslator {
+            inner: MockTranslator::new(PhysAddr::from_usize(0), crate::test_utils::MEMORY_LEN),
+            log: Default::default(),
+        };
+        let addr = GuestPhysAddr::from_usize(0x100);
+        accessor.write_buffer(addr, &[1, 2, 3, 4]).unwrap();
+        accessor.write_obj(addr + 4, 5u16).unwrap();
+        assert_eq!(*accessor.log.borrow(), ["wmb", "write", "wmb", "write"]);
+
+        accessor.log.borrow_mut().clear();
+        assert_eq!(accessor.read_obj::<u16>(addr + 4), Ok(5));
+        let mut buf = [0; 4];
+        accessor.read_buffer(addr, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(*accessor.log.borrow(), ["rmb", "rmb"]);
+
+        // Failed accesses do not issue barriers.
+        accessor.log.borrow_mut().clear();
+        let end = GuestPhysAddr::from_usize(crate::test_utils::MEMORY_LEN);
+        assert!(accessor.write_obj(end, 0u32).is_err());
+        assert!(accessor.read_obj::<u32>(end).is_err());
+        assert!(accessor.log.borrow().is_empty());
+    }
 }
@@ -0,0 +1,313 @@
+//! A minimal decoder of the load/store instructions that access MMIO regions,
+//! enough to emulate simple devices (e.g., a UART) without an external
+//! disassembler.
+//!
+//! Only plain moves between a general-purpose register (or an immediate)
+//! and memory are decoded. Instructions updating their base register,
+//! string instructions, and read-modify-write instructions are rejected, as
+//! are SIMD and floating-point accesses. The instruction bytes are fetched
+//! by the caller, e.g., by translating the faulting guest PC.
+
+use crate::device::AccessWidth;
+
+/// A load or store decoded by [`decode_access`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedAccess {
+    /// The general-purpose register loaded or stored: `0..=15` for RAX to
+    /// R15 on x86_64, `0..=30` for X0 to X30 on AArch64, where 31 is the
+    /// zero register (stores write 0, loads are discarded).
+    pub reg: u8,
+    /// The width of the memory access.
+    pub width: AccessWidth,
+    /// The width of the part of `reg` that a load writes. On x86_64, a
+    /// `Dword` write clears the upper half of the register, `Byte` and
+    /// `Word` writes leave the rest unchanged.
+    pub reg_width: AccessWidth,
+    /// Whether a load sign-extends the value to `reg_width`, instead of
+    /// zero-extending it.
+    pub sign_extend: bool,
+    /// Whether the access is a store.
+    pub is_write: bool,
+    /// The value stored instead of `reg`, for stores of an immediate.
+    pub imm: Option<u64>,
+    /// The length of the instruction in bytes, to skip it once emulated.
+    pub len: usize,
+}
+
+/// Decodes the load or store at the start of `bytes` for the target
+/// architecture.
+///
+/// Returns `None` if the instruction is not supported, or if `bytes` ends
+/// before it does.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn decode_access(bytes: &[u8]) -> Option<DecodedAccess> {
+    #[cfg(target_arch = "x86_64")]
+    return decode_x86_64(bytes);
+    #[cfg(target_arch = "aarch64")]
+    return decode_aarch64(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?));
+}
+
+/// Decodes an x86_64 `MOV`, `MOVZX` or `MOVSX` with a memory operand at the
+/// start of `bytes`.
+pub fn decode_x86_64(bytes: &[u8]) -> Option<DecodedAccess> {
+    let mut pos = 0;
+    let mut opsize = false;
+    loop {
+        match *bytes.get(pos)? {
+            0x66 => opsize = true,
+            // Address size and segment overrides do not change the access.
+            0x67 | 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => {}
+            _ => break,
+        }
+        pos += 1;
+    }
+    let rex = match bytes[pos] {
+        rex @ 0x40..=0x4f => {
+            pos += 1;
+            rex
+        }
+        _ => 0,
+    };
+    let rex_w = rex & 0x8 != 0;
+    let full = if rex_w {
+        AccessWidth::Qword
+    } else if opsize {
+        AccessWidth::Word
+    } else {
+        AccessWidth::Dword
+    };
+
+    let opcode = *bytes.get(pos)?;
+    pos += 1;
+    let (width, reg_width, sign_extend, is_write, imm_len) = match opcode {
+        0x88 => (AccessWidth::Byte, AccessWidth::Byte, false, true, 0),
+        0x89 => (full, full, false, true, 0),
+        0x8a => (AccessWidth::Byte, AccessWidth::Byte, false, false, 0),
+        0x8b => (full, full, false, false, 0),
+        0xc6 => (AccessWidth::Byte, AccessWidth::Byte, false, true, 1),
+        0xc7 => (
+            full,
+            full,
+            false,
+            true,
+            if opsize && !rex_w { 2 } else { 4 },
+        ),
+        0x0f => {
+            let (width, sign_extend) = match *bytes.get(pos)? {
+                0xb6 => (AccessWidth::Byte, false),
+                0xb7 => (AccessWidth::Word, false),
+                0xbe => (AccessWidth::Byte, true),
+                0xbf => (AccessWidth::Word, true),
+                _ => return None,
+            };
+            pos += 1;
+            (width, full, sign_extend, false, 0)
+        }
+        _ => return None,
+    };
+
+    let modrm = *bytes.get(pos)?;
+    pos += 1;
+    let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 7, modrm & 7);
+    if md == 3 {
+        // A register operand, no memory access.
+        return None;
+    }
+    if imm_len != 0 && reg != 0 {
+        return None;
+    }
+    if width == AccessWidth::Byte && reg_width == AccessWidth::Byte && rex == 0 && reg >= 4 {
+        // AH, CH, DH or BH.
+        return None;
+    }
+    let mut disp_len = match md {
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    if rm == 4 {
+        let sib = *bytes.get(pos)?;
+        pos += 1;
+        if md == 0 && sib & 7 == 5 {
+            disp_len = 4;
+        }
+    } else if md == 0 && rm == 5 {
+        // RIP-relative.
+        disp_len = 4;
+    }
+    pos += disp_len;
+
+    let imm = if imm_len == 0 {
+        None
+    } else {
+        let raw = bytes.get(pos..pos + imm_len)?;
+        pos += imm_len;
+        let mut buf = [0; 8];
+        buf[..imm_len].copy_from_slice(raw);
+        let shift = 64 - imm_len * 8;
+        // Sign-extended to 64 bits, then truncated to the access width.
+        let value = ((u64::from_le_bytes(buf) << shift) as i64 >> shift) as u64;
+        Some(value & (u64::MAX >> (64 - width.size() * 8)))
+    };
+    if pos > bytes.len() {
+        return None;
+    }
+    Some(DecodedAccess {
+        reg: reg | ((rex & 0x4) << 1),
+        width,
+        reg_width,
+        sign_extend,
+        is_write,
+        imm,
+        len: pos,
+    })
+}
+
+/// Decodes an AArch64 `LDR`/`STR` (including the byte, halfword and
+/// sign-extending variants, and `LDUR`/`STUR`) of a general-purpose register
+/// without base register writeback.
+pub fn decode_aarch64(insn: u32) -> Option<DecodedAccess> {
+    match insn & 0x3f00_0000 {
+        // Unsigned immediate offset.
+        0x3900_0000 => {}
+        // Unscaled immediate or register offset.
+        0x3800_0000 => match (insn >> 21 & 1, insn >> 10 & 3) {
+            (0, 0b00) | (1, 0b10) => {}
+            _ => return None,
+        },
+        _ => return None,
+    }
+    let size = insn >> 30;
+    let width = AccessWidth::try_from(1usize << size).ok()?;
+    let wide = if size == 3 {
+        AccessWidth::Qword
+    } else {
+        AccessWidth::Dword
+    };
+    let (reg_width, sign_extend, is_write) = match insn >> 22 & 3 {
+        0b00 => (wide, false, true),
+        0b01 => (wide, false, false),
+        // PRFM for size 3.
+        0b10 if size < 3 => (AccessWidth::Qword, true, false),
+        0b11 if size < 2 => (AccessWidth::Dword, true, false),
+        _ => return None,
+    };
+    Some(DecodedAccess {
+        reg: (insn & 0x1f) as u8,
+        width,
+        reg_width,
+        sign_extend,
+        is_write,
+        imm: None,
+        len: 4,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(
+        reg: u8,
+        width: AccessWidth,
+        reg_width: AccessWidth,
+        sign_extend: bool,
+        is_write: bool,
+        imm: Option<u64>,
+        len: usize,
+    ) -> Option<DecodedAccess> {
+        Some(DecodedAccess {
+            reg,
+            width,
+            reg_width,
+            sign_extend,
+            is_write,
+            imm,
+            len,
+        })
+    }
+
+    #[test]
+    fn test_decode_x86_64() {
+        use AccessWidth::*;
+        // mov [rdi], al
+        assert_eq!(
+            decode_x86_64(&[0x88, 0x07]),
+            access(0, Byte, Byte, false, true, None, 2)
+        );
+        // mov ecx, [rax + 0x10]
+        assert_eq!(
+            decode_x86_64(&[0x8b, 0x48, 0x10, 0xcc]),
+            access(1, Dword, Dword, false, false, None, 3)
+        );
+        // mov [r8 + rcx * 4 + 0x100], r9w
+        assert_eq!(
+            decode_x86_64(&[0x66, 0x45, 0x89, 0x8c, 0x88, 0x00, 0x01, 0x00, 0x00]),
+            access(9, Word, Word, false, true, None, 9)
+        );
+        // mov r12, [rip + 0x1000]
+        assert_eq!(
+            decode_x86_64(&[0x4c, 0x8b, 0x25, 0x00, 0x10, 0x00, 0x00]),
+            access(12, Qword, Qword, false, false, None, 7)
+        );
+        // mov dword ptr [rbx], 0x80000000 and mov qword ptr [rbx], -1
+        assert_eq!(
+            decode_x86_64(&[0xc7, 0x03, 0x00, 0x00, 0x00, 0x80]),
+            access(0, Dword, Dword, false, true, Some(0x8000_0000), 6)
+        );
+        assert_eq!(
+            decode_x86_64(&[0x48, 0xc7, 0x03, 0xff, 0xff, 0xff, 0xff]),
+            access(0, Qword, Qword, false, true, Some(u64::MAX), 7)
+        );
+        // movzx eax, byte ptr [rdx] and movsx rax, word ptr [rdx]
+        assert_eq!(
+            decode_x86_64(&[0x0f, 0xb6, 0x02]),
+            access(0, Byte, Dword, false, false, None, 3)
+        );
+        assert_eq!(
+            decode_x86_64(&[0x48, 0x0f, 0xbf, 0x02]),
+            access(0, Word, Qword, true, false, None, 4)
+        );
+
+        // mov eax, ecx; mov [rdi], ah; add [rdi], eax; truncated.
+        assert_eq!(decode_x86_64(&[0x89, 0xc8]), None);
+        assert_eq!(decode_x86_64(&[0x88, 0x27]), None);
+        assert_eq!(decode_x86_64(&[0x01, 0x07]), None);
+        assert_eq!(decode_x86_64(&[0x8b, 0x80, 0x00]), None);
+    }
+
+    #[test]
+    fn test_decode_aarch64() {
+        use AccessWidth::*;
+        // str w1, [x0, #4]
+        assert_eq!(
+            decode_aarch64(0xb900_0401),
+            access(1, Dword, Dword, false, true, None, 4)
+        );
+        // ldrb w2, [x3]
+        assert_eq!(
+            decode_aarch64(0x3940_0062),
+            access(2, Byte, Dword, false, false, None, 4)
+        );
+        // ldrsh x4, [x5, x6]
+        assert_eq!(
+            decode_aarch64(0x78a6_68a4),
+            access(4, Word, Qword, true, false, None, 4)
+        );
+        // ldursb w7, [x8, #-1]
+        assert_eq!(
+            decode_aarch64(0x38df_f107),
+            access(7, Byte, Dword, true, false, None, 4)
+        );
+        // stur xzr, [x9, #8]
+        assert_eq!(
+            decode_aarch64(0xf800_813f),
+            access(31, Qword, Qword, false, true, None, 4)
+        );
+
+        // ldr x0, [x1], #8 (writeback); prfm; ldr q0, [x1] (SIMD).
+        assert_eq!(decode_aarch64(0xf840_8420), None);
+        assert_eq!(decode_aarch64(0xf980_0020), None);
+        assert_eq!(decode_aarch64(0x3dc0_0020), None);
+    }
+}
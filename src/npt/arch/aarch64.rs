@@ -3,7 +3,9 @@ use core::fmt;
 use page_table_entry::{GenericPTE, MappingFlags};
 use page_table_multiarch::{PageTable64, PagingMetaData};
 // use memory_addr::HostPhysAddr;
-use crate::{GuestPhysAddr, HostPhysAddr};
+use crate::device::AccessWidth;
+use crate::npt::EntryInfo;
+use crate::{GuestPhysAddr, HostPhysAddr, NestedPageFaultInfo};
 
 bitflags::bitflags! {
     /// Memory attribute fields in the VMSAv8-64 translation table format descriptors.
@@ -86,30 +88,54 @@ impl DescriptorAttr {
     }
 
     fn mem_type(&self) -> MemType {
+        self.try_mem_type().expect("Invalid memory attribute index")
+    }
+
+    fn try_mem_type(&self) -> Option<MemType> {
         let idx = self.bits() & Self::ATTR_INDEX_MASK;
         match idx {
-            Self::NORMAL_BIT => MemType::Normal,
-            Self::PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_NOCACHEABLE => MemType::NormalNonCache,
-            0 => MemType::Device,
-            _ => panic!("Invalid memory attribute index"),
+            Self::NORMAL_BIT => Some(MemType::Normal),
+            Self::PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_NOCACHEABLE => {
+                Some(MemType::NormalNonCache)
+            }
+            0 => Some(MemType::Device),
+            _ => None,
         }
     }
 }
 
+/// The [`MappingFlags`] stage-2 descriptors cannot represent.
+///
+/// Stage-2 translation has no user/supervisor distinction.
+pub(crate) const UNSUPPORTED_FLAGS: MappingFlags = MappingFlags::USER;
+
+/// Only device, normal and normal non-cacheable memory are used: write
+/// combining becomes non-cacheable, write-through and write-protected become
+/// normal memory.
+pub(crate) const fn effective_mem_type(mem_type: crate::MemType) -> crate::MemType {
+    match mem_type {
+        crate::MemType::WriteCombining => crate::MemType::Uncached,
+        crate::MemType::WriteThrough | crate::MemType::WriteProtected => crate::MemType::Normal,
+        _ => mem_type,
+    }
+}
+
 impl From<DescriptorAttr> for MappingFlags {
     fn from(attr: DescriptorAttr) -> Self {
         let mut flags = Self::empty();
         if attr.contains(DescriptorAttr::VALID) {
             flags |= Self::READ;
         }
-        if !attr.contains(DescriptorAttr::S2AP_WO) {
+        if attr.contains(DescriptorAttr::S2AP_WO) {
             flags |= Self::WRITE;
         }
         if !attr.contains(DescriptorAttr::XN) {
             flags |= Self::EXECUTE;
         }
-        if attr.mem_type() == MemType::Device {
-            flags |= Self::DEVICE;
+        match attr.mem_type() {
+            MemType::Device => flags |= Self::DEVICE,
+            MemType::NormalNonCache => flags |= Self::DEVICE | Self::UNCACHED,
+            MemType::Normal => {}
         }
         flags
     }
@@ -117,21 +143,20 @@ impl From<DescriptorAttr> for MappingFlags {
 
 impl From<MappingFlags> for DescriptorAttr {
     fn from(flags: MappingFlags) -> Self {
-        let mut attr = if flags.contains(MappingFlags::DEVICE) {
-            if flags.contains(MappingFlags::UNCACHED) {
-                Self::from_mem_type(MemType::NormalNonCache)
-            } else {
-                Self::from_mem_type(MemType::Device)
-            }
-        } else {
-            Self::from_mem_type(MemType::Normal)
-        };
+        let mut attr = Self::from_mem_type(match crate::MemType::from_flags(flags) {
+            crate::MemType::Device => MemType::Device,
+            crate::MemType::Uncached | crate::MemType::WriteCombining => MemType::NormalNonCache,
+            _ => MemType::Normal,
+        });
         if flags.contains(MappingFlags::READ) {
             attr |= Self::VALID | Self::S2AP_RO;
         }
         if flags.contains(MappingFlags::WRITE) {
             attr |= Self::S2AP_WO;
         }
+        if !flags.contains(MappingFlags::EXECUTE) {
+            attr |= Self::XN;
+        }
         attr
     }
 }
@@ -145,6 +170,10 @@ impl From<MappingFlags> for DescriptorAttr {
 #[repr(transparent)]
 pub struct A64PTEHV(u64);
 
+/// The placeholder descriptor of lazily allocated pages: invalid, with the
+/// software-reserved bit 55 set to tell it from an unmapped descriptor.
+pub(crate) const LAZY_PLACEHOLDER: A64PTEHV = A64PTEHV(1 << 55);
+
 impl A64PTEHV {
     const PHYS_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000; // bits 12..48
 
@@ -152,6 +181,43 @@ impl A64PTEHV {
     pub const fn empty() -> Self {
         Self(0)
     }
+
+    /// Creates a descriptor from its raw value, e.g., read from a table dump.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the attributes of the descriptor.
+    pub const fn attr(&self) -> DescriptorAttr {
+        DescriptorAttr::from_bits_truncate(self.0)
+    }
+
+    /// Decodes the descriptor. Unlike [`GenericPTE::flags`], never panics on
+    /// an invalid memory attribute index.
+    pub fn describe(&self) -> EntryInfo {
+        let attr = self.attr();
+        let mem_type = attr.try_mem_type();
+        let mut flags = MappingFlags::from(attr - DescriptorAttr::ATTR) - MappingFlags::DEVICE;
+        match mem_type {
+            Some(MemType::Device) => flags |= MappingFlags::DEVICE,
+            Some(MemType::NormalNonCache) => flags |= MappingFlags::DEVICE | MappingFlags::UNCACHED,
+            _ => {}
+        }
+        EntryInfo {
+            raw: self.0,
+            paddr: self.paddr(),
+            flags,
+            present: self.is_present(),
+            huge: self.is_huge(),
+            lazy: self.0 == LAZY_PLACEHOLDER.0,
+            mem_type: match mem_type {
+                Some(MemType::Device) => "Device",
+                Some(MemType::Normal) => "Normal",
+                Some(MemType::NormalNonCache) => "NormalNC",
+                None => "?",
+            },
+        }
+    }
 }
 
 impl GenericPTE for A64PTEHV {
@@ -257,5 +323,126 @@ impl PagingMetaData for A64HVPagingMetaData {
         }
     }
 }
+/// The largest number of pages flushed one by one by
+/// [`flush_tlb_range`](crate::npt::flush_tlb_range).
+pub(crate) const MAX_FLUSH_PAGES: usize = 32;
+
+/// Flushes the TLB entries of the page at `gpa`.
+pub(crate) fn flush_tlb_page(gpa: GuestPhysAddr) {
+    A64HVPagingMetaData::flush_tlb(Some(gpa))
+}
+
+/// Returns the `T0SZ` (bits 5:0) and `SL0` (bits 7:6) fields of `VTCR_EL2`
+/// with the 4KB granule for IPAs `gpa_bits` wide, or `None` if the stage-2
+/// table cannot translate them.
+///
+/// The start level is fixed by the number of levels of the table, and the
+/// root is a single table, so IPA spaces narrower than the start level
+/// supports are widened to its minimum, e.g., 31 bits for a 3-level table.
+pub const fn vtcr_t0sz_sl0(gpa_bits: usize) -> Option<u64> {
+    let max = crate::npt::gpa_bits();
+    if gpa_bits > max {
+        return None;
+    }
+    // One bit more than the levels below the start level resolve.
+    let min = max - 8;
+    let bits = if gpa_bits < min { min } else { gpa_bits };
+    // With the 4KB granule, SL0 is 0 to start at level 2, 1 at level 1 and 2
+    // at level 0.
+    let sl0 = (A64HVPagingMetaData::LEVELS - 2) as u64;
+    Some((64 - bits) as u64 | sl0 << 6)
+}
+
+/// Returns words 2 and 3 of an SMMUv3 stream table entry (STE) translating
+/// the DMA of a stream through the stage-2 table at `root`, for IPAs
+/// `gpa_bits` wide as in [`vtcr_t0sz_sl0`], or `None` if the table cannot
+/// translate them.
+///
+/// SMMUv3 walks the VMSAv8-64 stage-2 format, so the SMMU shares the table
+/// of the CPU and a device passed through to the guest sees the same
+/// GPA-to-HPA translations. The words hold `S2VMID`, the table
+/// configuration (4KB granule, write-back walks, inner shareable), `S2AA64`,
+/// `S2R` and `S2TTB`; the caller sets `Config` to stage-2 translation and
+/// `V` in word 0. The SMMU caches translations apart from the CPU, so unless
+/// it takes part in broadcast TLB maintenance, changed mappings must also be
+/// invalidated with SMMU commands, e.g., `CMD_TLBI_S12_VMALL`.
+///
+/// Pages not present to the CPU, e.g., not faulted in yet or
+/// write-protected for dirty logging, fault for the device too, and DMA
+/// writes are not logged as dirty, so the memory of such devices should be
+/// populated and not logged.
+pub const fn smmu_ste_s2(root: HostPhysAddr, vmid: u16, gpa_bits: usize) -> Option<[u64; 2]> {
+    // Normal write-back read/write-allocate walks, inner shareable.
+    const WBWA: u64 = 0b01;
+    const INNER_SHAREABLE: u64 = 0b11;
+    const S2AA64: u64 = 1 << 51;
+    const S2R: u64 = 1 << 58;
+    const S2TTB_MASK: u64 = ((1 << 52) - 1) & !0xf;
+
+    let Some(t0sz_sl0) = vtcr_t0sz_sl0(gpa_bits) else {
+        return None;
+    };
+    // S2PS encodes the output size as VTCR_EL2.PS does.
+    let ps: u64 = match A64HVPagingMetaData::PA_MAX_BITS {
+        32 => 0b000,
+        36 => 0b001,
+        40 => 0b010,
+        42 => 0b011,
+        44 => 0b100,
+        48 => 0b101,
+        _ => 0b110,
+    };
+    // S2T0SZ and S2SL0 are laid out as in VTCR_EL2, from bit 32 of the word.
+    let word2 = vmid as u64
+        | t0sz_sl0 << 32
+        | WBWA << 40
+        | WBWA << 42
+        | INNER_SHAREABLE << 44
+        | ps << 48
+        | S2AA64
+        | S2R;
+    let word3 = root.as_usize() as u64 & S2TTB_MASK;
+    Some([word2, word3])
+}
+
 /// According to rust shyper, AArch64 translation table.
 pub type NestedPageTable<H> = PageTable64<A64HVPagingMetaData, A64PTEHV, H>;
+
+impl NestedPageFaultInfo {
+    /// Decodes a stage-2 abort taken to EL2 from `HPFAR_EL2`, `ESR_EL2` and
+    /// `FAR_EL2`, which provides the offset in the page.
+    ///
+    /// The access width is decoded from the syndrome of data aborts if it is
+    /// valid (`ISV`), i.e., for single loads and stores to general-purpose
+    /// registers.
+    ///
+    /// Returns `None` if `esr` is not an instruction or data abort from a
+    /// lower exception level.
+    pub fn from_arm_hpfar_esr(hpfar: u64, esr: u64, far: u64) -> Option<Self> {
+        const EC_IABT_LOW: u64 = 0x20;
+        const EC_DABT_LOW: u64 = 0x24;
+        const ESR_ISS_WNR: u64 = 1 << 6;
+        const ESR_ISS_CM: u64 = 1 << 8;
+        const ESR_ISS_ISV: u64 = 1 << 24;
+        // HPFAR_EL2.FIPA, bits [43:4], holds bits [51:12] of the IPA.
+        const HPFAR_FIPA_MASK: u64 = ((1 << 44) - 1) & !0xf;
+
+        let access_flags = match (esr >> 26) & 0x3f {
+            EC_IABT_LOW => MappingFlags::EXECUTE,
+            // Cache maintenance operations report writes, but only need read
+            // access.
+            EC_DABT_LOW if esr & ESR_ISS_WNR != 0 && esr & ESR_ISS_CM == 0 => MappingFlags::WRITE,
+            EC_DABT_LOW => MappingFlags::READ,
+            _ => return None,
+        };
+        let ipa = ((hpfar & HPFAR_FIPA_MASK) << 8) | (far & 0xfff);
+        // ESR_EL2.ISS.SAS, bits [23:22]: log2 of the access size.
+        let access_width = (access_flags != MappingFlags::EXECUTE && esr & ESR_ISS_ISV != 0)
+            .then(|| AccessWidth::try_from(1usize << ((esr >> 22) & 0x3)).unwrap());
+        Some(Self {
+            access_flags,
+            fault_guest_paddr: GuestPhysAddr::from(ipa as usize),
+            access_width,
+        })
+    }
+}
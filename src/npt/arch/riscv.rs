@@ -1,6 +1,66 @@
+use page_table_entry::MappingFlags;
 use page_table_entry::riscv::Rv64PTE;
 use page_table_multiarch::{PageTable64, riscv::Sv39MetaData};
 
-use crate::GuestPhysAddr;
+use crate::{GuestPhysAddr, MemType, NestedPageFaultInfo};
 
 pub type NestedPageTable<H> = PageTable64<Sv39MetaData<GuestPhysAddr>, Rv64PTE, H>;
+
+/// The placeholder entry of lazily allocated pages: invalid, with the RSW
+/// bit 8 set to tell it from an unmapped entry.
+// SAFETY: `Rv64PTE` is a transparent wrapper of its raw `u64` value.
+pub(crate) const LAZY_PLACEHOLDER: Rv64PTE = unsafe { core::mem::transmute(1u64 << 8) };
+
+/// The [`MappingFlags`] G-stage entries cannot represent.
+pub(crate) const UNSUPPORTED_FLAGS: MappingFlags = MappingFlags::empty();
+
+/// Memory types are not part of the entries: they come from PMAs and the
+/// Svpbmt extension, which is not used. Every page reads back as normal
+/// memory.
+pub(crate) const fn effective_mem_type(_mem_type: MemType) -> MemType {
+    MemType::Normal
+}
+
+/// The largest number of pages flushed one by one by
+/// [`flush_tlb_range`](crate::npt::flush_tlb_range).
+pub(crate) const MAX_FLUSH_PAGES: usize = 32;
+
+/// Flushes the G-stage TLB entries of the page at `gpa`, for all VMIDs.
+///
+/// Unlike the flush of the page table metadata, which always flushes
+/// everything, `hfence.gvma` is given the address (shifted right by 2).
+pub(crate) fn flush_tlb_page(gpa: GuestPhysAddr) {
+    unsafe {
+        core::arch::asm!(
+            "hfence.gvma {}, zero",
+            in(reg) gpa.as_usize() >> 2,
+            options(nostack, nomem, preserves_flags)
+        )
+    }
+}
+
+impl NestedPageFaultInfo {
+    /// Decodes a guest-page fault from `htval`, `scause` and `stval`, which
+    /// provides the two low bits of the address that `htval` shifts out.
+    /// The access width is left to the decoding of the faulting instruction.
+    ///
+    /// Returns `None` if `scause` is not a guest-page fault.
+    pub fn from_riscv_htval_cause(htval: usize, scause: usize, stval: usize) -> Option<Self> {
+        const INSTRUCTION_GUEST_PAGE_FAULT: usize = 20;
+        const LOAD_GUEST_PAGE_FAULT: usize = 21;
+        const STORE_GUEST_PAGE_FAULT: usize = 23;
+
+        // Exceptions have the interrupt bit (the MSB) clear.
+        let access_flags = match scause {
+            INSTRUCTION_GUEST_PAGE_FAULT => MappingFlags::EXECUTE,
+            LOAD_GUEST_PAGE_FAULT => MappingFlags::READ,
+            STORE_GUEST_PAGE_FAULT => MappingFlags::WRITE,
+            _ => return None,
+        };
+        Some(Self {
+            access_flags,
+            fault_guest_paddr: GuestPhysAddr::from((htval << 2) | (stval & 0x3)),
+            access_width: None,
+        })
+    }
+}
@@ -4,11 +4,15 @@ use bit_field::BitField;
 use page_table_entry::{GenericPTE, MappingFlags};
 use page_table_multiarch::{PageTable64, PagingMetaData};
 
-use crate::{GuestPhysAddr, HostPhysAddr};
+use crate::npt::EntryInfo;
+use crate::{GuestPhysAddr, HostPhysAddr, MemType, NestedPageFaultInfo};
 
 bitflags::bitflags! {
     /// EPT entry flags. (SDM Vol. 3C, Section 28.3.2)
-    struct EPTFlags: u64 {
+    ///
+    /// Converts from and to [`MappingFlags`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EPTFlags: u64 {
         /// Read access.
         const READ =                1 << 0;
         /// Write access.
@@ -33,29 +37,91 @@ bitflags::bitflags! {
 
 numeric_enum_macro::numeric_enum! {
     #[repr(u8)]
-    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     /// EPT memory typing. (SDM Vol. 3C, Section 28.3.7)
-    enum EPTMemType {
+    pub enum EPTMemType {
+        /// Uncacheable (UC).
         Uncached = 0,
+        /// Write combining (WC).
         WriteCombining = 1,
+        /// Write-through (WT).
         WriteThrough = 4,
+        /// Write-protected (WP).
         WriteProtected = 5,
+        /// Write-back (WB).
         WriteBack = 6,
     }
 }
 
+impl EPTMemType {
+    /// Returns the short name of the memory type, e.g., `"WB"`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Uncached => "UC",
+            Self::WriteCombining => "WC",
+            Self::WriteThrough => "WT",
+            Self::WriteProtected => "WP",
+            Self::WriteBack => "WB",
+        }
+    }
+}
+
 impl EPTFlags {
-    fn set_mem_type(&mut self, mem_type: EPTMemType) {
+    /// Sets the memory type field.
+    pub fn set_mem_type(&mut self, mem_type: EPTMemType) {
         let mut bits = self.bits();
         bits.set_bits(3..6, mem_type as u64);
         *self = Self::from_bits_truncate(bits)
     }
-    fn mem_type(&self) -> Result<EPTMemType, u8> {
+
+    /// Returns the memory type field, or its raw value if it is reserved.
+    pub fn mem_type(&self) -> Result<EPTMemType, u8> {
         EPTMemType::try_from(self.bits().get_bits(3..6) as u8)
     }
 }
 
+/// The [`MappingFlags`] EPT entries cannot represent: EPT has no
+/// user/supervisor distinction.
+pub(crate) const UNSUPPORTED_FLAGS: MappingFlags = MappingFlags::USER;
+
+/// Device and uncached memory are both UC, which reads back as device
+/// memory. Every other type is supported.
+pub(crate) const fn effective_mem_type(mem_type: MemType) -> MemType {
+    match mem_type {
+        MemType::Uncached => MemType::Device,
+        _ => mem_type,
+    }
+}
+
+impl From<MemType> for EPTMemType {
+    fn from(mem_type: MemType) -> Self {
+        match mem_type {
+            MemType::Normal => Self::WriteBack,
+            MemType::Device | MemType::Uncached => Self::Uncached,
+            MemType::WriteCombining => Self::WriteCombining,
+            MemType::WriteThrough => Self::WriteThrough,
+            MemType::WriteProtected => Self::WriteProtected,
+        }
+    }
+}
+
+impl From<EPTMemType> for MemType {
+    fn from(mem_type: EPTMemType) -> Self {
+        match mem_type {
+            EPTMemType::WriteBack => Self::Normal,
+            EPTMemType::Uncached => Self::Device,
+            EPTMemType::WriteCombining => Self::WriteCombining,
+            EPTMemType::WriteThrough => Self::WriteThrough,
+            EPTMemType::WriteProtected => Self::WriteProtected,
+        }
+    }
+}
+
 impl From<MappingFlags> for EPTFlags {
+    /// Normal memory is WB and subject to the guest PAT, like on bare metal.
+    /// The other types are forced with `IGNORE_PAT`, so that a guest cannot
+    /// make device memory or a framebuffer cacheable (UC is not affected by
+    /// the PAT anyway).
     fn from(f: MappingFlags) -> Self {
         if f.is_empty() {
             return Self::empty();
@@ -70,8 +136,13 @@ impl From<MappingFlags> for EPTFlags {
         if f.contains(MappingFlags::EXECUTE) {
             ret |= Self::EXECUTE;
         }
-        if !f.contains(MappingFlags::DEVICE) {
-            ret.set_mem_type(EPTMemType::WriteBack);
+        let mem_type = MemType::from_flags(f);
+        ret.set_mem_type(mem_type.into());
+        if !matches!(
+            mem_type,
+            MemType::Normal | MemType::Device | MemType::Uncached
+        ) {
+            ret |= Self::IGNORE_PAT;
         }
         ret
     }
@@ -89,10 +160,10 @@ impl From<EPTFlags> for MappingFlags {
         if f.contains(EPTFlags::EXECUTE) {
             ret |= Self::EXECUTE;
         }
-        if let Ok(EPTMemType::Uncached) = f.mem_type() {
-            ret |= Self::DEVICE;
+        match f.mem_type() {
+            Ok(mem_type) => MemType::from(mem_type).apply(ret),
+            Err(_) => ret,
         }
-        ret
     }
 }
 
@@ -102,8 +173,35 @@ impl From<EPTFlags> for MappingFlags {
 #[repr(transparent)]
 pub struct EPTEntry(u64);
 
+/// The placeholder entry of lazily allocated pages: not present, with the
+/// ignored bit 52 set to tell it from an unmapped entry.
+pub(crate) const LAZY_PLACEHOLDER: EPTEntry = EPTEntry(1 << 52);
+
 impl EPTEntry {
     const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // bits 12..52
+
+    /// Creates an entry from its raw value, e.g., read from a table dump.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the EPT flags of the entry.
+    pub const fn ept_flags(&self) -> EPTFlags {
+        EPTFlags::from_bits_truncate(self.0)
+    }
+
+    /// Decodes the entry.
+    pub fn describe(&self) -> EntryInfo {
+        EntryInfo {
+            raw: self.0,
+            paddr: self.paddr(),
+            flags: self.flags(),
+            present: self.is_present(),
+            huge: self.is_huge(),
+            lazy: self.0 == LAZY_PLACEHOLDER.0,
+            mem_type: self.ept_flags().mem_type().map_or("?", EPTMemType::name),
+        }
+    }
 }
 
 impl GenericPTE for EPTEntry {
@@ -176,6 +274,7 @@ impl PagingMetaData for ExtendedPageTableMetadata {
 
     // Under the x86 architecture, the flush_tlb operation will invoke the ring0 instruction,
     // causing the test to trigger a SIGSEGV exception.
+    #[cfg_attr(test, allow(unused_variables))]
     fn flush_tlb(vaddr: Option<GuestPhysAddr>) {
         #[cfg(not(test))]
         if let Some(vaddr) = vaddr {
@@ -186,5 +285,123 @@ impl PagingMetaData for ExtendedPageTableMetadata {
     }
 }
 
+/// Returns the page-walk length field (bits 5:3) of the EPTP for guest
+/// physical addresses `gpa_bits` wide, or `None` if the EPT cannot
+/// translate them.
+///
+/// The EPT always has 4 levels, so narrower guests get the same walk length
+/// and simply leave the upper part of the table unused.
+pub const fn eptp_walk_length(gpa_bits: usize) -> Option<u64> {
+    if gpa_bits > crate::npt::gpa_bits() {
+        return None;
+    }
+    Some(((ExtendedPageTableMetadata::LEVELS - 1) as u64) << 3)
+}
+
+/// The largest number of pages flushed one by one by
+/// [`flush_tlb_range`](crate::npt::flush_tlb_range).
+pub(crate) const MAX_FLUSH_PAGES: usize = 32;
+
+/// Flushes the TLB entries of the page at `gpa`.
+pub(crate) fn flush_tlb_page(gpa: GuestPhysAddr) {
+    ExtendedPageTableMetadata::flush_tlb(Some(gpa))
+}
+
 /// The VMX extended page table. (SDM Vol. 3C, Section 29.3)
 pub type ExtendedPageTable<H> = PageTable64<ExtendedPageTableMetadata, EPTEntry, H>;
+
+impl NestedPageFaultInfo {
+    /// Decodes an EPT violation from its exit qualification and the guest
+    /// physical address VMCS field. (SDM Vol. 3C, Table 28-7)
+    ///
+    /// The exit qualification does not tell the access width, which can only
+    /// be found by decoding the faulting instruction.
+    pub fn from_vmx_exit(qualification: u64, gpa: GuestPhysAddr) -> Self {
+        let mut access_flags = MappingFlags::empty();
+        // Bits 0 to 2: data read, data write, instruction fetch.
+        if qualification.get_bit(0) {
+            access_flags |= MappingFlags::READ;
+        }
+        if qualification.get_bit(1) {
+            access_flags |= MappingFlags::WRITE;
+        }
+        if qualification.get_bit(2) {
+            access_flags |= MappingFlags::EXECUTE;
+        }
+        Self {
+            access_flags,
+            fault_guest_paddr: gpa,
+            access_width: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_describe_entry() {
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let entry = EPTEntry::new_page(HostPhysAddr::from(0x20_0000), rw, true);
+        let raw = entry.bits() as u64;
+        let decoded = EPTEntry::from_bits(raw).describe();
+        assert_eq!(
+            decoded,
+            EntryInfo {
+                raw,
+                paddr: HostPhysAddr::from(0x20_0000),
+                flags: rw,
+                present: true,
+                huge: true,
+                lazy: false,
+                mem_type: "WB",
+            }
+        );
+        assert_eq!(
+            alloc::format!("{decoded}"),
+            "0x00000000002000b3 -> PA:0x200000 READ | WRITE WB huge"
+        );
+        assert_eq!(
+            alloc::format!("{}", EPTEntry::from_bits(0x38).describe()),
+            "0x0000000000000038 not present"
+        );
+        assert_eq!(
+            alloc::format!("{}", LAZY_PLACEHOLDER.describe()),
+            "0x0010000000000000 lazy"
+        );
+        assert_eq!(EPTEntry::from_bits(0x3b).ept_flags().mem_type(), Err(7));
+    }
+
+    #[test]
+    fn test_from_vmx_exit() {
+        let gpa = GuestPhysAddr::from(0x1234_5678);
+        // A write to a readable page, with a valid guest linear address.
+        let info = NestedPageFaultInfo::from_vmx_exit(0x18a, gpa);
+        assert_eq!(info.access_flags, MappingFlags::WRITE);
+        assert_eq!(info.fault_guest_paddr, gpa);
+        assert_eq!(info.access_width, None);
+        assert_eq!(
+            NestedPageFaultInfo::from_vmx_exit(0x4, gpa).access_flags,
+            MappingFlags::EXECUTE
+        );
+    }
+
+    #[test]
+    fn test_mem_type_ignore_pat() {
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        for (mem_type, ept_mem_type, ignore_pat) in [
+            (MemType::Normal, EPTMemType::WriteBack, false),
+            (MemType::Device, EPTMemType::Uncached, false),
+            (MemType::WriteCombining, EPTMemType::WriteCombining, true),
+            (MemType::WriteThrough, EPTMemType::WriteThrough, true),
+            (MemType::WriteProtected, EPTMemType::WriteProtected, true),
+        ] {
+            let flags = EPTFlags::from(mem_type.apply(rw));
+            assert_eq!(flags.mem_type(), Ok(ept_mem_type));
+            assert_eq!(flags.contains(EPTFlags::IGNORE_PAT), ignore_pat);
+            assert_eq!(MappingFlags::from(flags), mem_type.apply(rw));
+        }
+    }
+}
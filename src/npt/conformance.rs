@@ -0,0 +1,207 @@
+//! A conformance suite for nested page tables, run against any
+//! `PagingMetaData` and `GenericPTE` combination with `MockHal`.
+//!
+//! Every architecture backend runs [`run`] with its own entry type and
+//! metadata, so a new backend (e.g., AMD NPT or another stage-2 format) only
+//! needs to add an instantiation to the tests below.
+
+use memory_addr::PhysAddr;
+use page_table_entry::{GenericPTE, MappingFlags};
+use page_table_multiarch::{PageSize, PageTable64, PagingError, PagingMetaData};
+
+use crate::test_utils::{ALLOC_COUNT, DEALLOC_COUNT, MockHal};
+use core::sync::atomic::Ordering;
+
+type Table<M, PTE> = PageTable64<M, PTE, MockHal>;
+
+const RW: MappingFlags = MappingFlags::READ.union(MappingFlags::WRITE);
+
+fn va<M: PagingMetaData>(addr: usize) -> M::VirtAddr {
+    M::VirtAddr::from(addr)
+}
+
+/// Returns the flags a page mapped with `flags` is expected to read back
+/// with, as encoded by `PTE`.
+fn expected_flags<PTE: GenericPTE>(flags: MappingFlags, huge: bool) -> MappingFlags {
+    PTE::new_page(PhysAddr::from(0), flags, huge).flags()
+}
+
+/// Runs the whole suite. Must be called with the `MockHal` state reset.
+pub(crate) fn run<M: PagingMetaData, PTE: GenericPTE>() {
+    let live = || ALLOC_COUNT.load(Ordering::SeqCst) - DEALLOC_COUNT.load(Ordering::SeqCst);
+    let before = live();
+    map_query_unmap::<M, PTE>();
+    protect_and_remap::<M, PTE>();
+    huge_pages::<M, PTE>();
+    regions::<M, PTE>();
+    // Dropping a table frees every table it allocated.
+    assert_eq!(live(), before, "page table frames leaked");
+}
+
+fn map_query_unmap<M: PagingMetaData, PTE: GenericPTE>() {
+    let mut pt = Table::<M, PTE>::try_new().unwrap();
+    let gpa = va::<M>(0x4000_3000);
+    let paddr = PhysAddr::from(0x8000_5000);
+    assert_eq!(pt.query(gpa).err(), Some(PagingError::NotMapped));
+
+    pt.map(gpa, paddr, PageSize::Size4K, RW).unwrap().ignore();
+    let (queried, flags, size) = pt.query(va::<M>(0x4000_3abc)).unwrap();
+    assert_eq!(queried, paddr + 0xabc);
+    assert_eq!(flags, expected_flags::<PTE>(RW, false));
+    assert!(flags.contains(RW));
+    assert_eq!(size, PageSize::Size4K);
+    // Neighbouring pages stay unmapped.
+    assert_eq!(
+        pt.query(va::<M>(0x4000_4000)).err(),
+        Some(PagingError::NotMapped)
+    );
+    assert_eq!(
+        pt.map(gpa, paddr, PageSize::Size4K, RW).err(),
+        Some(PagingError::AlreadyMapped)
+    );
+
+    let (unmapped, size, tlb) = pt.unmap(gpa).unwrap();
+    tlb.ignore();
+    assert_eq!((unmapped, size), (paddr, PageSize::Size4K));
+    assert_eq!(pt.query(gpa).err(), Some(PagingError::NotMapped));
+    assert_eq!(pt.unmap(gpa).err(), Some(PagingError::NotMapped));
+    // The entry can be reused.
+    pt.map(gpa, paddr, PageSize::Size4K, MappingFlags::READ)
+        .unwrap()
+        .ignore();
+}
+
+fn protect_and_remap<M: PagingMetaData, PTE: GenericPTE>() {
+    let mut pt = Table::<M, PTE>::try_new().unwrap();
+    let gpa = va::<M>(0x1000);
+    let rx = MappingFlags::READ | MappingFlags::EXECUTE;
+    assert!(pt.protect(gpa, rx).is_err());
+
+    pt.map(gpa, PhysAddr::from(0x2000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    let (size, tlb) = pt.protect(gpa, rx).unwrap();
+    tlb.ignore();
+    assert_eq!(size, PageSize::Size4K);
+    let (paddr, flags, _) = pt.query(gpa).unwrap();
+    assert_eq!(paddr, PhysAddr::from(0x2000));
+    assert_eq!(flags, expected_flags::<PTE>(rx, false));
+
+    let (size, tlb) = pt.remap(gpa, PhysAddr::from(0x7000), RW).unwrap();
+    tlb.ignore();
+    assert_eq!(size, PageSize::Size4K);
+    let (paddr, flags, _) = pt.query(gpa).unwrap();
+    assert_eq!(paddr, PhysAddr::from(0x7000));
+    assert_eq!(flags, expected_flags::<PTE>(RW, false));
+}
+
+fn huge_pages<M: PagingMetaData, PTE: GenericPTE>() {
+    let mut pt = Table::<M, PTE>::try_new().unwrap();
+    for (gpa, paddr, size) in [
+        (0x20_0000, 0x60_0000, PageSize::Size2M),
+        (0x4000_0000, 0x8000_0000, PageSize::Size1G),
+    ] {
+        pt.map(va::<M>(gpa), PhysAddr::from(paddr), size, RW)
+            .unwrap()
+            .ignore();
+        // Any address inside the huge page resolves through it.
+        let offset = usize::from(size) - 0x1234;
+        let (queried, flags, queried_size) = pt.query(va::<M>(gpa + offset)).unwrap();
+        assert_eq!(queried, PhysAddr::from(paddr + offset));
+        assert_eq!(flags, expected_flags::<PTE>(RW, true));
+        assert_eq!(queried_size, size);
+        // A base page cannot be mapped inside it.
+        assert!(
+            pt.map(
+                va::<M>(gpa + 0x1000),
+                PhysAddr::from(0x1000),
+                PageSize::Size4K,
+                RW
+            )
+            .is_err()
+        );
+        let (size_after, tlb) = pt.protect(va::<M>(gpa), MappingFlags::READ).unwrap();
+        tlb.ignore();
+        assert_eq!(size_after, size);
+        assert_eq!(
+            pt.query(va::<M>(gpa)).unwrap().1,
+            expected_flags::<PTE>(MappingFlags::READ, true)
+        );
+        let (unmapped, unmapped_size, tlb) = pt.unmap(va::<M>(gpa + 0x1000)).unwrap();
+        tlb.ignore();
+        assert_eq!((unmapped, unmapped_size), (PhysAddr::from(paddr), size));
+        assert!(pt.query(va::<M>(gpa)).is_err());
+    }
+}
+
+fn regions<M: PagingMetaData, PTE: GenericPTE>() {
+    let mut pt = Table::<M, PTE>::try_new().unwrap();
+    // A 2M-aligned region with a 4K head and tail: only the middle can be
+    // mapped with a huge page.
+    let start = 0x1f_f000;
+    let size = 0x20_2000;
+    let offset = 0x4000_0000;
+    pt.map_region(
+        va::<M>(start),
+        |gpa| PhysAddr::from(gpa.into() + offset),
+        size,
+        RW,
+        true,
+        false,
+    )
+    .unwrap()
+    .ignore();
+    for (gpa, page_size) in [
+        (start, PageSize::Size4K),
+        (0x20_0000, PageSize::Size2M),
+        (0x3f_ffff, PageSize::Size2M),
+        (0x40_0000, PageSize::Size4K),
+    ] {
+        let (paddr, _, queried_size) = pt.query(va::<M>(gpa)).unwrap();
+        assert_eq!(paddr, PhysAddr::from(gpa + offset));
+        assert_eq!(queried_size, page_size, "page size at {gpa:#x}");
+    }
+    assert!(pt.query(va::<M>(start + size)).is_err());
+
+    pt.unmap_region(va::<M>(start), size, false)
+        .unwrap()
+        .ignore();
+    for gpa in [start, 0x20_0000, 0x40_0000] {
+        assert_eq!(pt.query(va::<M>(gpa)).err(), Some(PagingError::NotMapped));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GuestPhysAddr;
+    use crate::npt::{NestedPTE, NestedPagingMetaData};
+    use crate::test_utils::mock_hal_test;
+    use axin::axin;
+
+    /// The native entries in a 3-level table, as used by Sv39x4 G-stage and
+    /// 3-level stage-2 tables.
+    struct ThreeLevelMetaData;
+
+    impl PagingMetaData for ThreeLevelMetaData {
+        const LEVELS: usize = 3;
+        const PA_MAX_BITS: usize = 48;
+        const VA_MAX_BITS: usize = 39;
+
+        type VirtAddr = GuestPhysAddr;
+
+        fn flush_tlb(_vaddr: Option<GuestPhysAddr>) {}
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_native_conformance() {
+        run::<NestedPagingMetaData, NestedPTE>();
+    }
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_three_level_conformance() {
+        run::<ThreeLevelMetaData, NestedPTE>();
+    }
+}
@@ -1,14 +1,272 @@
+//! Nested page tables and their architecture-specific entries.
+//!
+//! The entry types can be built from raw values and decoded with their
+//! `describe()` method, e.g., to inspect table dumps taken from a crashed
+//! host.
+
+// Most helpers here only serve `AddrSpace`, which needs `alloc`.
+#![cfg_attr(not(feature = "alloc"), allow(dead_code))]
+
+use core::fmt;
+
+use page_table_entry::MappingFlags;
+use page_table_multiarch::{PagingError, PagingHandler, PagingMetaData, PagingResult};
+
+use crate::{GuestAddrRangeExt, GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr, MemType};
+
+pub use page_table_entry::GenericPTE;
+
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
         /// The architecture-specific nested page table for two-stage address translation.
         pub type NestedPageTable<H> = arch::ExtendedPageTable<H>;
+        pub(crate) type NestedPagingMetaData = arch::ExtendedPageTableMetadata;
+        /// The architecture-specific nested page table entry.
+        pub type NestedPTE = arch::EPTEntry;
+        pub use arch::{EPTEntry, EPTFlags, EPTMemType, eptp_walk_length};
     } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
         /// The architecture-specific page table.
         pub type NestedPageTable<H> = arch::NestedPageTable<H>;
+        pub(crate) type NestedPagingMetaData =
+            page_table_multiarch::riscv::Sv39MetaData<crate::GuestPhysAddr>;
+        /// The architecture-specific nested page table entry.
+        pub type NestedPTE = page_table_entry::riscv::Rv64PTE;
     } else if #[cfg(target_arch = "aarch64")]{
         /// The architecture-specific nested page table for two-stage address translation.
         pub type NestedPageTable<H> = arch::NestedPageTable<H>;
+        pub(crate) type NestedPagingMetaData = arch::A64HVPagingMetaData;
+        /// The architecture-specific nested page table entry.
+        pub type NestedPTE = arch::A64PTEHV;
+        pub use arch::{A64PTEHV, DescriptorAttr, smmu_ste_s2, vtcr_t0sz_sl0};
     }
 }
 
 mod arch;
+#[cfg(test)]
+mod conformance;
+pub(crate) mod tables;
+
+/// Returns the [`MappingFlags`] the nested page table entries of this
+/// architecture cannot represent, memory types aside.
+///
+/// They are dropped when mapping, so querying a page mapped with flags `f`
+/// returns `f - unsupported_flags()`, with the memory type of `f` replaced
+/// by its [`effective_mem_type`]. Flags without [`MappingFlags::READ`] are
+/// not considered, as they do not map a present page on every architecture.
+pub const fn unsupported_flags() -> MappingFlags {
+    arch::UNSUPPORTED_FLAGS
+}
+
+/// Returns the number of guest physical address bits the nested page table
+/// can translate.
+///
+/// This is the input width of the page table format, limited to the bits
+/// indexed from a single root table, e.g., 39 bits for the 3-level AArch64
+/// table, whose format would allow 40 bits with two concatenated roots.
+pub const fn gpa_bits() -> usize {
+    let indexed = 12 + 9 * NestedPagingMetaData::LEVELS;
+    if indexed < NestedPagingMetaData::VA_MAX_BITS {
+        indexed
+    } else {
+        NestedPagingMetaData::VA_MAX_BITS
+    }
+}
+
+/// Returns the highest guest physical address the nested page table can
+/// translate, which address spaces must not extend beyond.
+pub const fn max_supported_gpa() -> GuestPhysAddr {
+    let bits = gpa_bits();
+    if bits >= usize::BITS as usize {
+        GuestPhysAddr::from_usize(usize::MAX)
+    } else {
+        GuestPhysAddr::from_usize((1 << bits) - 1)
+    }
+}
+
+/// Returns the memory type pages of type `mem_type` are actually mapped
+/// with on this architecture, i.e., the closest supported one.
+pub const fn effective_mem_type(mem_type: MemType) -> MemType {
+    arch::effective_mem_type(mem_type)
+}
+
+/// A decoded nested page table entry, returned by the `describe()` method of
+/// the architecture's entry type.
+///
+/// Its [`Display`](fmt::Display) output is a single line, e.g.
+/// `0x00000000002000b3 -> PA:0x200000 READ | WRITE WB huge`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntryInfo {
+    /// The raw value of the entry.
+    pub raw: u64,
+    /// The physical address of the page or the next-level table.
+    pub paddr: HostPhysAddr,
+    /// The generic flags of the entry.
+    pub flags: MappingFlags,
+    /// Whether the entry is present.
+    pub present: bool,
+    /// Whether the entry maps a huge page (only meaningful above the last
+    /// level).
+    pub huge: bool,
+    /// Whether the entry is the placeholder of a lazily allocated page,
+    /// which is not present yet.
+    pub lazy: bool,
+    /// The name of the memory type or attributes of the entry, `"?"` if they
+    /// are invalid.
+    pub mem_type: &'static str,
+}
+
+impl fmt::Display for EntryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.lazy {
+            return write!(f, "{:#018x} lazy", self.raw);
+        }
+        if !self.present {
+            return write!(f, "{:#018x} not present", self.raw);
+        }
+        write!(
+            f,
+            "{:#018x} -> {:?} {:?} {}",
+            self.raw, self.paddr, self.flags, self.mem_type
+        )?;
+        if self.huge {
+            write!(f, " huge")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sets the entry of the base page at `gpa` to the placeholder of lazily
+/// allocated pages, creating the intermediate tables if needed.
+///
+/// Unlike mapping a page to address 0 with empty flags, the placeholder
+/// cannot be mistaken for an unmapped entry, nor for a page mapped to
+/// address 0. Fails with `AlreadyMapped` if the entry is already used.
+pub(crate) fn map_lazy_placeholder<H: PagingHandler>(
+    pt: &mut NestedPageTable<H>,
+    gpa: GuestPhysAddr,
+) -> PagingResult {
+    let entry = tables::leaf_entry_or_create::<H>(pt.root_paddr(), gpa.as_usize())?;
+    if !entry.is_unused() {
+        return Err(PagingError::AlreadyMapped);
+    }
+    *entry = arch::LAZY_PLACEHOLDER;
+    Ok(())
+}
+
+/// Returns whether the entry of the base page at `gpa` is the placeholder
+/// of a lazily allocated page.
+pub(crate) fn is_lazy_placeholder<H: PagingHandler>(
+    pt: &NestedPageTable<H>,
+    gpa: GuestPhysAddr,
+) -> bool {
+    tables::leaf_entry::<H>(pt.root_paddr(), gpa.as_usize())
+        .is_some_and(|entry| entry.bits() == arch::LAZY_PLACEHOLDER.bits())
+}
+
+/// Flushes the TLB entries of the nested page table for `gpa`, or all
+/// entries if `gpa` is `None`.
+pub(crate) fn flush_tlb(gpa: Option<crate::GuestPhysAddr>) {
+    use page_table_multiarch::PagingMetaData;
+    NestedPagingMetaData::flush_tlb(gpa)
+}
+
+/// Flushes the TLB entries of the nested page table for the pages of
+/// `range`, or all entries if `range` is `None`.
+///
+/// Ranges of up to `arch::MAX_FLUSH_PAGES` pages are flushed page by page,
+/// with the finest invalidation of the architecture, larger ones entirely.
+pub(crate) fn flush_tlb_range(range: Option<GuestPhysAddrRange>) {
+    match range {
+        Some(range)
+            if range.pages().take(arch::MAX_FLUSH_PAGES + 1).count() <= arch::MAX_FLUSH_PAGES =>
+        {
+            range.pages().for_each(arch::flush_tlb_page)
+        }
+        _ => flush_tlb(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEM_TYPES: [MemType; 6] = [
+        MemType::Normal,
+        MemType::Device,
+        MemType::Uncached,
+        MemType::WriteCombining,
+        MemType::WriteThrough,
+        MemType::WriteProtected,
+    ];
+
+    /// Every combination of [`MappingFlags`] readable pages can be mapped
+    /// with.
+    fn all_readable_flags() -> impl Iterator<Item = MappingFlags> {
+        let perms =
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE | MappingFlags::USER;
+        (0..=perms.bits())
+            .map(MappingFlags::from_bits_truncate)
+            .filter(|f| f.contains(MappingFlags::READ))
+            .flat_map(|f| MEM_TYPES.map(|mem_type| mem_type.apply(f)))
+    }
+
+    #[test]
+    fn test_flags_round_trip() {
+        let paddr = HostPhysAddr::from(0x4000_0000);
+        for flags in all_readable_flags() {
+            let mem_type = effective_mem_type(MemType::from_flags(flags));
+            let expected = mem_type.apply(flags) - unsupported_flags();
+            for huge in [false, true] {
+                let entry = NestedPTE::new_page(paddr, flags, huge);
+                assert!(entry.is_present());
+                assert_eq!(entry.is_huge(), huge);
+                assert_eq!(entry.paddr(), paddr);
+                assert_eq!(entry.flags(), expected, "flags {flags:?}, huge {huge}");
+
+                let mut updated = entry;
+                updated.set_flags(MappingFlags::READ, huge);
+                updated.set_flags(flags, huge);
+                assert_eq!(updated.bits(), entry.bits());
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_supported_gpa() {
+        let bits = gpa_bits();
+        assert!(bits <= NestedPagingMetaData::VA_MAX_BITS);
+        assert_eq!(max_supported_gpa().as_usize(), (1 << bits) - 1);
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(eptp_walk_length(48), Some(3 << 3));
+            assert_eq!(eptp_walk_length(32), Some(3 << 3));
+            assert_eq!(eptp_walk_length(49), None);
+        }
+        #[cfg(all(target_arch = "aarch64", not(feature = "4-level-ept")))]
+        {
+            // Starting at level 1, narrower IPA spaces are rounded up to 31
+            // bits.
+            assert_eq!(vtcr_t0sz_sl0(39), Some(25 | 1 << 6));
+            assert_eq!(vtcr_t0sz_sl0(32), Some(32 | 1 << 6));
+            assert_eq!(vtcr_t0sz_sl0(20), Some(33 | 1 << 6));
+            assert_eq!(vtcr_t0sz_sl0(40), None);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_flags() {
+        let unsupported = unsupported_flags();
+        // Access permissions are always represented.
+        assert!(
+            !unsupported
+                .intersects(MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE)
+        );
+        let paddr = HostPhysAddr::from(0x1000);
+        let entry = NestedPTE::new_page(paddr, MappingFlags::READ | unsupported, false);
+        assert!(!entry.flags().intersects(unsupported));
+        for mem_type in MEM_TYPES {
+            let effective = effective_mem_type(mem_type);
+            assert_eq!(effective_mem_type(effective), effective);
+        }
+    }
+}
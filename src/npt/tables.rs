@@ -0,0 +1,246 @@
+//! Direct inspection of the intermediate tables of a nested page table.
+//!
+//! `PageTable64` allocates intermediate tables on demand and only frees them
+//! when dropped. These helpers walk the raw tables to count and reclaim them.
+
+use memory_addr::PhysAddr;
+use page_table_entry::GenericPTE;
+use page_table_multiarch::{
+    MappingFlags, PagingError, PagingHandler, PagingMetaData, PagingResult,
+};
+
+use super::{NestedPTE, NestedPagingMetaData};
+
+const ENTRY_COUNT: usize = 512;
+const LEVELS: usize = NestedPagingMetaData::LEVELS;
+
+fn table_of<'a, H: PagingHandler>(paddr: PhysAddr) -> &'a mut [NestedPTE] {
+    let ptr = H::phys_to_virt(paddr).as_mut_ptr() as *mut NestedPTE;
+    unsafe { core::slice::from_raw_parts_mut(ptr, ENTRY_COUNT) }
+}
+
+/// Returns the physical address of the next-level table `entry` points to.
+fn next_table(entry: &NestedPTE, level: usize) -> Option<PhysAddr> {
+    (level < LEVELS - 1 && entry.paddr().as_usize() != 0 && !entry.is_huge()).then(|| entry.paddr())
+}
+
+/// Returns the size of the region mapped by an entry at `level`.
+const fn entry_span(level: usize) -> usize {
+    1 << (12 + 9 * (LEVELS - 1 - level))
+}
+
+/// Returns the index of the entry for `addr` in a table at `level`.
+const fn entry_index(addr: usize, level: usize) -> usize {
+    (addr / entry_span(level)) % ENTRY_COUNT
+}
+
+/// Returns the last-level entry for `addr` of the page table rooted at
+/// `root`, or `None` if a table on the way is missing or `addr` is mapped by
+/// a huge page.
+pub(crate) fn leaf_entry<'a, H: PagingHandler>(
+    root: PhysAddr,
+    addr: usize,
+) -> Option<&'a NestedPTE> {
+    let mut table = root;
+    for level in 0..LEVELS - 1 {
+        table = next_table(&table_of::<H>(table)[entry_index(addr, level)], level)?;
+    }
+    Some(&table_of::<H>(table)[entry_index(addr, LEVELS - 1)])
+}
+
+/// Like [`leaf_entry`], but creates the missing tables on the way.
+///
+/// Fails with `MappedToHugePage` if `addr` is mapped by a huge page, or with
+/// `NoMemory` if a table cannot be allocated.
+pub(crate) fn leaf_entry_or_create<'a, H: PagingHandler>(
+    root: PhysAddr,
+    addr: usize,
+) -> PagingResult<&'a mut NestedPTE> {
+    let mut table = root;
+    for level in 0..LEVELS - 1 {
+        let entry = &mut table_of::<H>(table)[entry_index(addr, level)];
+        table = match next_table(entry, level) {
+            Some(next) => next,
+            None if entry.is_unused() => {
+                let next = H::alloc_frame().ok_or(PagingError::NoMemory)?;
+                table_of::<H>(next).iter_mut().for_each(NestedPTE::clear);
+                *entry = NestedPTE::new_table(next);
+                next
+            }
+            None => return Err(PagingError::MappedToHugePage),
+        };
+    }
+    Ok(&mut table_of::<H>(table)[entry_index(addr, LEVELS - 1)])
+}
+
+/// Returns the number of frames used by the page table rooted at `root`,
+/// including the root itself.
+pub(crate) fn count_frames<H: PagingHandler>(root: PhysAddr) -> usize {
+    fn count<H: PagingHandler>(table: PhysAddr, level: usize) -> usize {
+        1 + table_of::<H>(table)
+            .iter()
+            .filter_map(|entry| next_table(entry, level))
+            .map(|next| count::<H>(next, level + 1))
+            .sum::<usize>()
+    }
+    count::<H>(root, 0)
+}
+
+/// Calls `f` with every frame of the page table rooted at `root`, the root
+/// first.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn for_each_table<H: PagingHandler>(root: PhysAddr, f: &mut impl FnMut(PhysAddr)) {
+    fn walk<H: PagingHandler>(table: PhysAddr, level: usize, f: &mut impl FnMut(PhysAddr)) {
+        f(table);
+        for next in table_of::<H>(table)
+            .iter()
+            .filter_map(|entry| next_table(entry, level))
+        {
+            walk::<H>(next, level + 1, f);
+        }
+    }
+    walk::<H>(root, 0, f)
+}
+
+/// Frees the intermediate tables of the page table rooted at `root` that map
+/// nothing, and returns how many were freed.
+///
+/// A table is kept, even if empty, if `in_use(start, size)` returns `true`
+/// for the region it maps (e.g., because a lazy mapping will be faulted in
+/// there later). The root is
+/// never freed. The caller must flush the TLB if any table is freed.
+pub(crate) fn shrink<H: PagingHandler>(
+    root: PhysAddr,
+    in_use: &impl Fn(usize, usize) -> bool,
+) -> usize {
+    /// Returns the number of freed tables below `table` and whether `table`
+    /// is empty afterwards.
+    fn shrink_table<H: PagingHandler>(
+        table: PhysAddr,
+        level: usize,
+        base: usize,
+        in_use: &impl Fn(usize, usize) -> bool,
+    ) -> (usize, bool) {
+        let span = entry_span(level);
+        let mut freed = 0;
+        let mut empty = true;
+        for (i, entry) in table_of::<H>(table).iter_mut().enumerate() {
+            let start = base + i * span;
+            if let Some(next) = next_table(entry, level) {
+                let (n, next_empty) = shrink_table::<H>(next, level + 1, start, in_use);
+                freed += n;
+                if next_empty && !in_use(start, span) {
+                    entry.clear();
+                    H::dealloc_frame(next);
+                    freed += 1;
+                    continue;
+                }
+            }
+            empty &= entry.is_unused();
+        }
+        (freed, empty)
+    }
+    shrink_table::<H>(root, 0, 0, in_use).0
+}
+
+/// Calls `f(start, size, entry)` for every present leaf entry of the page
+/// table rooted at `root`, in ascending order of `start`.
+pub(crate) fn for_each_leaf<H: PagingHandler>(
+    root: PhysAddr,
+    f: &mut impl FnMut(usize, usize, &NestedPTE),
+) {
+    for_each_leaf_in::<H>(root, 0, entry_span(0) * ENTRY_COUNT, f)
+}
+
+/// Like [`for_each_leaf`], but only for the leaf entries overlapping
+/// `[start, end)`, descending only into the tables overlapping it.
+pub(crate) fn for_each_leaf_in<H: PagingHandler>(
+    root: PhysAddr,
+    start: usize,
+    end: usize,
+    f: &mut impl FnMut(usize, usize, &NestedPTE),
+) {
+    fn walk<H: PagingHandler>(
+        table: PhysAddr,
+        level: usize,
+        base: usize,
+        (start, end): (usize, usize),
+        f: &mut impl FnMut(usize, usize, &NestedPTE),
+    ) {
+        let span = entry_span(level);
+        let first = (start.max(base) - base) / span;
+        let last = (end.min(base + span * ENTRY_COUNT) - 1 - base) / span;
+        for (i, entry) in table_of::<H>(table)[first..=last].iter().enumerate() {
+            let entry_start = base + (first + i) * span;
+            if let Some(next) = next_table(entry, level) {
+                walk::<H>(next, level + 1, entry_start, (start, end), f);
+            } else if entry.is_present() {
+                f(entry_start, span, entry);
+            }
+        }
+    }
+    if start < end {
+        walk::<H>(root, 0, 0, (start, end), f)
+    }
+}
+
+/// Maps every `(start, paddr, size, flags)` of `regions` with 4K pages,
+/// descending once into every table on the way rather than once per page.
+///
+/// The regions must be sorted, page-aligned and must not overlap. Fails with
+/// `AlreadyMapped` if a page is already mapped, or with `NoMemory` if a table
+/// cannot be allocated, leaving the pages mapped so far.
+pub(crate) fn map_batch<H: PagingHandler>(
+    root: PhysAddr,
+    regions: &[(usize, usize, usize, MappingFlags)],
+) -> PagingResult {
+    fn walk<H: PagingHandler>(
+        table: PhysAddr,
+        level: usize,
+        base: usize,
+        regions: &[(usize, usize, usize, MappingFlags)],
+    ) -> PagingResult {
+        let span = entry_span(level);
+        let table_end = base + span * ENTRY_COUNT;
+        let mut addr = base;
+        let mut i = 0;
+        while let Some(&(start, paddr, size, flags)) = regions.get(i) {
+            addr = addr.max(start);
+            if addr >= start + size {
+                i += 1;
+                continue;
+            }
+            if addr >= table_end {
+                break;
+            }
+            let entry_start = addr & !(span - 1);
+            let entry = &mut table_of::<H>(table)[(entry_start - base) / span];
+            if level == LEVELS - 1 {
+                if !entry.is_unused() {
+                    return Err(PagingError::AlreadyMapped);
+                }
+                *entry = NestedPTE::new_page(PhysAddr::from(paddr + (addr - start)), flags, false);
+            } else {
+                let next = match next_table(entry, level) {
+                    Some(next) => next,
+                    None if entry.is_unused() => {
+                        let next = H::alloc_frame().ok_or(PagingError::NoMemory)?;
+                        table_of::<H>(next).iter_mut().for_each(NestedPTE::clear);
+                        *entry = NestedPTE::new_table(next);
+                        next
+                    }
+                    None => return Err(PagingError::MappedToHugePage),
+                };
+                let entry_end = entry_start + span;
+                let inner = regions[i..]
+                    .iter()
+                    .take_while(|&&(start, ..)| start < entry_end)
+                    .count();
+                walk::<H>(next, level + 1, entry_start, &regions[i..i + inner])?;
+            }
+            addr = entry_start + span;
+        }
+        Ok(())
+    }
+    walk::<H>(root, 0, 0, regions)
+}
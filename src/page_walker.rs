@@ -0,0 +1,365 @@
+//! A software walker of guest page tables (stage 1), e.g., to fetch the
+//! instruction of an MMIO access, or to read guest memory for a debugger
+//! stub.
+//!
+//! The guest page tables are read through a [`GuestMemoryAccessor`], so they
+//! are subject to the nested page table like any other guest memory.
+//! Accessed and dirty bits are neither checked nor updated, and the walk
+//! does not model the permission controls of the guest (e.g., `CR0.WP`,
+//! `SMEP`, `SUM`, `PAN`), only the permissions of the entries.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::MemoryAddr;
+
+use crate::gva_accessor::GuestAddressSpaceView;
+use crate::{GuestMemoryAccessor, GuestPhysAddr, GuestVirtAddr, MappingFlags, PAGE_SIZE};
+
+/// The format of the guest page tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestPagingMode {
+    /// x86-64 4-level paging, with 48-bit virtual addresses.
+    X86_64,
+    /// AArch64 stage 1 with the 4KB granule, 4 levels and 48-bit virtual
+    /// addresses. The root is the table of `TTBR0_EL1` or `TTBR1_EL1`,
+    /// whichever covers the walked addresses.
+    Aarch64,
+    /// RISC-V Sv39.
+    Sv39,
+    /// RISC-V Sv48.
+    Sv48,
+}
+
+impl GuestPagingMode {
+    const fn levels(self) -> usize {
+        match self {
+            Self::Sv39 => 3,
+            _ => 4,
+        }
+    }
+
+    const fn va_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    /// Whether `gva` is a valid virtual address of this format.
+    const fn is_canonical(self, gva: usize) -> bool {
+        let bits = self.va_bits();
+        match self {
+            // Bits above the input size select TTBR0 or TTBR1.
+            Self::Aarch64 => gva >> bits == 0 || gva >> bits == usize::MAX >> bits,
+            // Bits above the input size copy its top bit.
+            _ => {
+                let unused = usize::BITS as usize - bits;
+                (((gva << unused) as isize) >> unused) as usize == gva
+            }
+        }
+    }
+}
+
+/// The guest page mapping a guest virtual address, returned by
+/// [`GuestPageWalker::walk`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GuestTranslation {
+    /// The guest physical address the walked address maps to.
+    pub gpa: GuestPhysAddr,
+    /// The size of the guest page, e.g., 2M for a huge page.
+    pub page_size: usize,
+    /// The access the entries allow, combined over all levels. `USER` tells
+    /// whether the guest user mode can access the page, and `EXECUTE`
+    /// whether the mode of the walker can execute it.
+    pub flags: MappingFlags,
+}
+
+/// A decoded page table entry.
+enum Entry {
+    Invalid,
+    /// The next-level table, and the access it allows at most.
+    Table(GuestPhysAddr, MappingFlags),
+    /// The page, and the access it allows.
+    Page(GuestPhysAddr, MappingFlags),
+}
+
+/// Walks guest page tables in memory accessed through `A`.
+///
+/// It is a [`GuestAddressSpaceView`], to access guest memory by guest
+/// virtual address with [`GuestVirtAccessorExt`](crate::GuestVirtAccessorExt).
+pub struct GuestPageWalker<'a, A: GuestMemoryAccessor + ?Sized> {
+    mem: &'a A,
+    mode: GuestPagingMode,
+    root: GuestPhysAddr,
+    user: bool,
+}
+
+impl<'a, A: GuestMemoryAccessor + ?Sized> GuestPageWalker<'a, A> {
+    /// Creates a walker of the guest page tables of format `mode` rooted at
+    /// `root`, e.g., the table of `CR3`, `TTBR0_EL1` or `satp`, checking
+    /// the permissions of the guest kernel mode.
+    pub const fn new(mem: &'a A, mode: GuestPagingMode, root: GuestPhysAddr) -> Self {
+        Self {
+            mem,
+            mode,
+            root,
+            user: false,
+        }
+    }
+
+    /// Checks the permissions of the guest user mode instead, e.g., for
+    /// accesses of a guest application.
+    pub const fn user(mut self, user: bool) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// Walks the guest page tables to the page mapping `gva`.
+    ///
+    /// Fails with `BadAddress` if `gva` is not a valid virtual address of
+    /// the format or not mapped, or if a table cannot be read.
+    pub fn walk(&self, gva: GuestVirtAddr) -> AxResult<GuestTranslation> {
+        let va = gva.as_usize();
+        if !self.mode.is_canonical(va) {
+            return ax_err!(BadAddress, "non-canonical guest virtual address");
+        }
+        let mut table = self.root;
+        let mut allowed =
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE | MappingFlags::USER;
+        for level in (0..self.mode.levels()).rev() {
+            let shift = 12 + 9 * level;
+            let slot = table + ((va >> shift) & 0x1ff) * 8;
+            let Ok(raw) = self.mem.read_obj::<u64>(slot) else {
+                warn!("cannot read the guest page table entry at {slot:?}");
+                return ax_err!(BadAddress, "guest page table not readable");
+            };
+            match self.decode(raw, level) {
+                Entry::Invalid => return ax_err!(BadAddress, "guest virtual address not mapped"),
+                Entry::Table(next, flags) => {
+                    table = next;
+                    allowed &= flags;
+                }
+                Entry::Page(page, flags) => {
+                    let page_size = 1 << shift;
+                    if !page.is_aligned(page_size) {
+                        return ax_err!(BadAddress, "misaligned guest huge page");
+                    }
+                    return Ok(GuestTranslation {
+                        gpa: page + (va & (page_size - 1)),
+                        page_size,
+                        flags: allowed & flags,
+                    });
+                }
+            }
+        }
+        unreachable!("the last level only holds pages")
+    }
+
+    /// Decodes the entry `raw` of a table at `level`, 0 being the last one.
+    fn decode(&self, raw: u64, level: usize) -> Entry {
+        use MappingFlags as F;
+        let mut flags = F::empty();
+        match self.mode {
+            GuestPagingMode::X86_64 => {
+                const PRESENT: u64 = 1 << 0;
+                const WRITABLE: u64 = 1 << 1;
+                const USER: u64 = 1 << 2;
+                const HUGE: u64 = 1 << 7;
+                const NO_EXECUTE: u64 = 1 << 63;
+                const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+                if raw & PRESENT == 0 {
+                    return Entry::Invalid;
+                }
+                flags |= F::READ;
+                flags.set(F::WRITE, raw & WRITABLE != 0);
+                flags.set(F::USER, raw & USER != 0);
+                flags.set(F::EXECUTE, raw & NO_EXECUTE == 0);
+                let addr = (raw & ADDR_MASK) as usize;
+                match level {
+                    0 => Entry::Page(GuestPhysAddr::from(addr), flags),
+                    // Bit 12 of huge pages selects their memory type.
+                    1 | 2 if raw & HUGE != 0 => {
+                        Entry::Page(GuestPhysAddr::from(addr & !PAGE_SIZE), flags)
+                    }
+                    _ if raw & HUGE != 0 => Entry::Invalid,
+                    _ => Entry::Table(GuestPhysAddr::from(addr), flags),
+                }
+            }
+            GuestPagingMode::Aarch64 => {
+                const VALID: u64 = 1 << 0;
+                const TABLE_OR_PAGE: u64 = 1 << 1;
+                const AP_EL0: u64 = 1 << 6;
+                const AP_RO: u64 = 1 << 7;
+                const PXN: u64 = 1 << 53;
+                const UXN: u64 = 1 << 54;
+                const PXN_TABLE: u64 = 1 << 59;
+                const UXN_TABLE: u64 = 1 << 60;
+                const AP_NO_EL0_TABLE: u64 = 1 << 61;
+                const AP_NO_WRITE_TABLE: u64 = 1 << 62;
+                const ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+                if raw & VALID == 0 {
+                    return Entry::Invalid;
+                }
+                let addr = GuestPhysAddr::from((raw & ADDR_MASK) as usize);
+                let (xn, xn_table) = if self.user {
+                    (UXN, UXN_TABLE)
+                } else {
+                    (PXN, PXN_TABLE)
+                };
+                match (level, raw & TABLE_OR_PAGE != 0) {
+                    (1.., true) => {
+                        flags |= F::READ;
+                        flags.set(F::WRITE, raw & AP_NO_WRITE_TABLE == 0);
+                        flags.set(F::USER, raw & AP_NO_EL0_TABLE == 0);
+                        flags.set(F::EXECUTE, raw & xn_table == 0);
+                        Entry::Table(addr, flags)
+                    }
+                    // Blocks of level 0 (512G) do not exist with 4 levels.
+                    (0, true) | (1 | 2, false) => {
+                        flags |= F::READ;
+                        flags.set(F::WRITE, raw & AP_RO == 0);
+                        flags.set(F::USER, raw & AP_EL0 != 0);
+                        flags.set(F::EXECUTE, raw & xn == 0);
+                        Entry::Page(addr, flags)
+                    }
+                    _ => Entry::Invalid,
+                }
+            }
+            GuestPagingMode::Sv39 | GuestPagingMode::Sv48 => {
+                const VALID: u64 = 1 << 0;
+                const READ: u64 = 1 << 1;
+                const WRITE: u64 = 1 << 2;
+                const EXECUTE: u64 = 1 << 3;
+                const USER: u64 = 1 << 4;
+                const PPN_MASK: u64 = (1 << 44) - 1;
+                // Writable pages must be readable.
+                if raw & VALID == 0 || raw & (READ | WRITE) == WRITE {
+                    return Entry::Invalid;
+                }
+                let addr = GuestPhysAddr::from((((raw >> 10) & PPN_MASK) << 12) as usize);
+                if raw & (READ | WRITE | EXECUTE) == 0 {
+                    if level == 0 {
+                        return Entry::Invalid;
+                    }
+                    return Entry::Table(addr, F::all());
+                }
+                flags.set(F::READ, raw & READ != 0);
+                flags.set(F::WRITE, raw & WRITE != 0);
+                flags.set(F::USER, raw & USER != 0);
+                // The supervisor never executes user pages.
+                flags.set(
+                    F::EXECUTE,
+                    raw & EXECUTE != 0 && (self.user || raw & USER == 0),
+                );
+                Entry::Page(addr, flags)
+            }
+        }
+    }
+}
+
+impl<A: GuestMemoryAccessor + ?Sized> GuestAddressSpaceView for GuestPageWalker<'_, A> {
+    fn translate_gva(
+        &self,
+        gva: GuestVirtAddr,
+        access: MappingFlags,
+    ) -> AxResult<(GuestPhysAddr, usize)> {
+        let translation = self.walk(gva)?;
+        if !translation.flags.contains(access)
+            || (self.user && !translation.flags.contains(MappingFlags::USER))
+        {
+            return ax_err!(PermissionDenied, "access denied by the guest page table");
+        }
+        let offset = gva.as_usize() & (translation.page_size - 1);
+        Ok((translation.gpa, translation.page_size - offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axerrno::AxError;
+    use core::cell::UnsafeCell;
+    use memory_addr::PhysAddr;
+
+    /// Six pages of guest memory at guest physical address 0.
+    struct Mem(UnsafeCell<[u64; 6 * 512]>);
+
+    impl GuestMemoryAccessor for Mem {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            let gpa = guest_addr.as_usize();
+            let len = 6 * PAGE_SIZE;
+            (gpa < len).then(|| (PhysAddr::from(self.0.get() as usize + gpa), len - gpa))
+        }
+    }
+
+    #[test]
+    fn test_guest_page_walker() {
+        use MappingFlags as F;
+        // Entries of the root at 0x1000, its next tables at 0x2000 and
+        // 0x3000, and the last-level table at 0x4000, which maps the user
+        // page 0x5000 read-only and executable at 0x1000. The second entry
+        // at 0x3000 maps a supervisor-only, non-executable 2M page at 2M.
+        let formats = [
+            (
+                GuestPagingMode::X86_64,
+                [0x2007, 0x3007, 0x4007, 0x8000_0000_0020_0083, 0x5005],
+            ),
+            (
+                GuestPagingMode::Aarch64,
+                [0x2003, 0x3003, 0x4003, 0x0060_0000_0020_0401, 0x54c3],
+            ),
+            (
+                GuestPagingMode::Sv48,
+                [0x801, 0xc01, 0x1001, 0x8_0007, 0x145b],
+            ),
+        ];
+        for (mode, [root, l3, l2, huge, page]) in formats {
+            // RISC-V supervisors do not execute user pages.
+            let kernel_exec = if mode == GuestPagingMode::Sv48 {
+                F::empty()
+            } else {
+                F::EXECUTE
+            };
+            let mem = Mem(UnsafeCell::new([0; 6 * 512]));
+            let table = unsafe { &mut *mem.0.get() };
+            table[0x1000 / 8] = root;
+            table[0x2000 / 8] = l3;
+            table[0x3000 / 8] = l2;
+            table[0x3000 / 8 + 1] = huge;
+            table[0x4000 / 8 + 1] = page;
+            let walker = GuestPageWalker::new(&mem, mode, GuestPhysAddr::from(0x1000));
+            let gva = GuestVirtAddr::from;
+
+            assert_eq!(
+                walker.walk(gva(0x1234)),
+                Ok(GuestTranslation {
+                    gpa: GuestPhysAddr::from(0x5234),
+                    page_size: PAGE_SIZE,
+                    flags: F::READ | F::USER | kernel_exec,
+                }),
+                "{mode:?}"
+            );
+            assert_eq!(
+                walker.translate_gva(gva(0x1234), F::WRITE),
+                Err(AxError::PermissionDenied)
+            );
+            assert_eq!(
+                walker.translate_gva(gva(0x20_1000), F::WRITE),
+                Ok((GuestPhysAddr::from(0x20_1000), 0x1f_f000)),
+                "{mode:?}"
+            );
+            assert_eq!(
+                walker.translate_gva(gva(0x20_1000), F::EXECUTE),
+                Err(AxError::PermissionDenied)
+            );
+
+            // The guest user mode only reaches user pages.
+            let user = GuestPageWalker::new(&mem, mode, GuestPhysAddr::from(0x1000)).user(true);
+            assert!(user.translate_gva(gva(0x1000), F::EXECUTE).is_ok());
+            assert_eq!(
+                user.translate_gva(gva(0x20_1000), F::READ),
+                Err(AxError::PermissionDenied)
+            );
+
+            // Unmapped and non-canonical addresses.
+            assert_eq!(walker.walk(gva(0x2000)), Err(AxError::BadAddress));
+            assert_eq!(walker.walk(gva(1 << 48)), Err(AxError::BadAddress));
+        }
+    }
+}
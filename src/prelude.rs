@@ -0,0 +1,12 @@
+//! The commonly used types of this crate.
+//!
+//! ```
+//! use axaddrspace::prelude::*;
+//! ```
+
+#[cfg(feature = "alloc")]
+pub use crate::AddrSpace;
+pub use crate::{
+    AxMmHal, FaultContext, GuestMemoryAccessor, GuestPhysAddr, GuestPhysAddrRange,
+    GuestVirtAccessorExt, HostPhysAddr, HostVirtAddr, MappingFlags, NestedPageFaultInfo, PhysFrame,
+};
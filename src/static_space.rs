@@ -0,0 +1,249 @@
+//! Guest address spaces with a layout fixed at compile-time capacity.
+//!
+//! [`StaticAddrSpace`] targets static partitioning hypervisors (Jailhouse-like
+//! cells), where every guest range is assigned up front and dynamic
+//! allocation after initialization is forbidden. Areas are kept in an
+//! [`AreaTable`] instead of on the heap, and only linear (RAM carve-outs) and
+//! device mappings are supported: there are no lazily allocated pages, so no
+//! nested page fault is ever resolved by the address space.
+
+use core::fmt;
+
+use axerrno::{AxError, AxResult, ax_err};
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use crate::addr::{check_max_gpa, checked_range};
+use crate::npt::{self, NestedPageTable as PageTable};
+use crate::{
+    AreaTable, EPTTranslator, GuestPhysAddr, GuestPhysAddrRange, MemType, StaticArea,
+    paging_err_to_ax_err,
+};
+
+/// A guest address space holding up to `MAX_AREAS` linear or device areas,
+/// without heap allocation.
+///
+/// Frames of the nested page table are still taken from `H`.
+pub struct StaticAddrSpace<H: PagingHandler, const MAX_AREAS: usize> {
+    va_range: GuestPhysAddrRange,
+    areas: AreaTable<MAX_AREAS>,
+    pt: PageTable<H>,
+}
+
+impl<H: PagingHandler, const MAX_AREAS: usize> StaticAddrSpace<H, MAX_AREAS> {
+    /// Creates a new empty address space.
+    ///
+    /// Fails with `InvalidInput` if `base + size` overflows, or exceeds
+    /// [`npt::max_supported_gpa`](crate::npt::max_supported_gpa).
+    pub fn new_empty(base: GuestPhysAddr, size: usize) -> AxResult<Self> {
+        let va_range = checked_range(base, size)?;
+        check_max_gpa(va_range)?;
+        Ok(Self {
+            va_range,
+            areas: AreaTable::new(),
+            pt: PageTable::try_new().map_err(|_| AxError::NoMemory)?,
+        })
+    }
+
+    /// Returns the address space base.
+    pub const fn base(&self) -> GuestPhysAddr {
+        self.va_range.start
+    }
+
+    /// Returns the address space end.
+    pub const fn end(&self) -> GuestPhysAddr {
+        self.va_range.end
+    }
+
+    /// Returns the address space size.
+    pub fn size(&self) -> usize {
+        self.va_range.size()
+    }
+
+    /// Returns the root physical address of the inner page table.
+    pub const fn page_table_root(&self) -> PhysAddr {
+        self.pt.root_paddr()
+    }
+
+    /// Returns the areas of the address space, in ascending address order.
+    pub fn areas(&self) -> impl Iterator<Item = &StaticArea> {
+        self.areas.iter()
+    }
+
+    /// Add a new linear mapping.
+    ///
+    /// The guest address `start_vaddr + off` is mapped to
+    /// `start_paddr + off`, with huge pages where the alignment allows it.
+    ///
+    /// Returns [`AxError::NoMemory`](axerrno::AxError::NoMemory) if the area
+    /// table is full, see [`AreaTable::insert`] for the other failures.
+    pub fn map_linear(
+        &mut self,
+        start_vaddr: GuestPhysAddr,
+        start_paddr: PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> AxResult {
+        if !checked_range(start_vaddr, size).is_ok_and(|r| self.va_range.contains_range(r)) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        let area = StaticArea::new(start_vaddr, start_paddr, size, flags);
+        self.areas.insert(area)?;
+
+        let offset = start_paddr.as_usize().wrapping_sub(start_vaddr.as_usize());
+        let get_paddr = |gpa: GuestPhysAddr| PhysAddr::from(gpa.as_usize().wrapping_add(offset));
+        match self
+            .pt
+            .map_region(start_vaddr, get_paddr, size, flags, true, false)
+        {
+            Ok(tlb) => {
+                tlb.ignore();
+                Ok(())
+            }
+            Err(err) => {
+                self.unmap_pages(start_vaddr);
+                self.areas.remove(start_vaddr);
+                Err(paging_err_to_ax_err(err))
+            }
+        }
+    }
+
+    /// Add a new device mapping of the host range at `start_paddr`, readable
+    /// and writable with the given memory type, which must not be
+    /// [`MemType::Normal`].
+    pub fn map_device(
+        &mut self,
+        start_vaddr: GuestPhysAddr,
+        start_paddr: PhysAddr,
+        size: usize,
+        mem_type: MemType,
+    ) -> AxResult {
+        if mem_type == MemType::Normal {
+            return ax_err!(InvalidInput, "device window must not be normal memory");
+        }
+        let flags = mem_type.apply(MappingFlags::READ | MappingFlags::WRITE);
+        self.map_linear(start_vaddr, start_paddr, size, flags)
+    }
+
+    /// Removes the area `[start, start + size)`, which must have been mapped
+    /// as a whole. Areas are never split.
+    pub fn unmap(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        match self.areas.find(start) {
+            Some(area) if area.range.start == start && area.range.size() == size => {}
+            _ => return ax_err!(InvalidInput, "not a mapped area"),
+        }
+        self.areas.remove(start);
+        self.unmap_pages(start);
+        npt::flush_tlb(None);
+        Ok(())
+    }
+
+    /// Removes all mappings in the address space.
+    pub fn clear(&mut self) {
+        loop {
+            let Some(area) = self.areas.iter().next() else {
+                break;
+            };
+            let start = area.range.start;
+            self.areas.remove(start);
+            self.unmap_pages(start);
+        }
+        npt::flush_tlb(None);
+    }
+
+    /// Unmaps the pages mapped consecutively from `start`.
+    fn unmap_pages(&mut self, start: GuestPhysAddr) {
+        let mut gpa = start;
+        while let Ok((_, page_size, tlb)) = self.pt.unmap(gpa) {
+            tlb.ignore();
+            gpa = gpa.align_down(page_size) + page_size as usize;
+        }
+    }
+
+    /// Translates the given guest physical address into a host physical
+    /// address.
+    ///
+    /// Returns `None` if the address is out of range or not mapped.
+    pub fn translate(&self, vaddr: GuestPhysAddr) -> Option<PhysAddr> {
+        if !self.va_range.contains(vaddr) {
+            return None;
+        }
+        self.pt.query(vaddr).map(|(paddr, _, _)| paddr).ok()
+    }
+
+    /// Translates the given guest physical address into a host physical
+    /// address, and returns the number of bytes up to the end of its area.
+    pub fn translate_and_get_limit(&self, vaddr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        let area = self.areas.find(vaddr)?;
+        Some((self.translate(vaddr)?, area.range.end - vaddr))
+    }
+}
+
+impl<H: PagingHandler, const MAX_AREAS: usize> EPTTranslator for StaticAddrSpace<H, MAX_AREAS> {
+    fn guest_phys_to_host_phys(&self, gpa: GuestPhysAddr) -> Option<PhysAddr> {
+        self.translate(gpa)
+    }
+}
+
+impl<H: PagingHandler, const MAX_AREAS: usize> fmt::Debug for StaticAddrSpace<H, MAX_AREAS> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StaticAddrSpace")
+            .field("va_range", &self.va_range)
+            .field("page_table_root", &self.pt.root_paddr())
+            .field("areas", &self.areas)
+            .finish()
+    }
+}
+
+impl<H: PagingHandler, const MAX_AREAS: usize> Drop for StaticAddrSpace<H, MAX_AREAS> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{BASE_PADDR, MockHal, mock_hal_test};
+    use axin::axin;
+
+    #[test]
+    #[axin(decorator(mock_hal_test))]
+    fn test_static_addr_space() {
+        const SIZE_2M: usize = 0x20_0000;
+        let base = GuestPhysAddr::from(0);
+        let mut aspace = StaticAddrSpace::<MockHal, 2>::new_empty(base, 4 * SIZE_2M).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let ram = PhysAddr::from(0x8000_0000);
+        aspace.map_linear(base, ram, SIZE_2M + 0x1000, rw).unwrap();
+        assert_eq!(aspace.translate(base + 0x1234), Some(ram + 0x1234));
+        assert_eq!(aspace.pt.query(base).unwrap().2 as usize, SIZE_2M);
+        assert_eq!(
+            aspace.translate_and_get_limit(base + SIZE_2M),
+            Some((ram + SIZE_2M, 0x1000))
+        );
+
+        let uart = PhysAddr::from(BASE_PADDR);
+        assert_eq!(
+            aspace.map_device(base + SIZE_2M, uart, 0x1000, MemType::Device),
+            Err(AxError::AlreadyExists)
+        );
+        assert_eq!(
+            aspace.map_device(base + 2 * SIZE_2M, uart, 0x1000, MemType::Normal),
+            Err(AxError::InvalidInput)
+        );
+        aspace
+            .map_device(base + 2 * SIZE_2M, uart, 0x1000, MemType::Device)
+            .unwrap();
+        assert_eq!(
+            aspace.map_linear(base + 3 * SIZE_2M, ram, 0x1000, rw),
+            Err(AxError::NoMemory)
+        );
+        assert_eq!(aspace.translate(base + 3 * SIZE_2M), None);
+
+        assert!(aspace.unmap(base, SIZE_2M).is_err());
+        aspace.unmap(base, SIZE_2M + 0x1000).unwrap();
+        assert_eq!(aspace.translate(base + SIZE_2M), None);
+        assert_eq!(aspace.areas().count(), 1);
+    }
+}
@@ -1,7 +1,15 @@
+#[cfg(feature = "alloc")]
+use crate::GuestPhysAddr;
 use crate::{AxMmHal, HostPhysAddr, HostVirtAddr};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
+#[cfg(feature = "alloc")]
+use memory_addr::MemoryAddr;
 use memory_addr::{PhysAddr, VirtAddr};
+#[cfg(feature = "alloc")]
+use page_table_multiarch::PagingError;
 use page_table_multiarch::PagingHandler;
 use spin::Mutex;
 
@@ -45,6 +53,70 @@ pub(crate) static DEALLOC_COUNT: AtomicUsize = AtomicUsize::new(0);
 /// Flag to simulate memory allocation failures for testing error handling.
 pub(crate) static ALLOC_SHOULD_FAIL: AtomicBool = AtomicBool::new(false);
 
+/// The start of the simulated memory huge frames are handed out from. It is
+/// never accessed, so it needs no backing storage.
+pub(crate) const HUGE_BASE_PADDR: usize = 0x4000_0000;
+
+/// The number of 4K frames left for huge allocations, see
+/// [`MockHal::set_huge_frames`].
+pub(crate) static HUGE_FRAMES_LEFT: AtomicUsize = AtomicUsize::new(0);
+
+/// The next free address of the huge frame memory.
+pub(crate) static NEXT_HUGE_PADDR: AtomicUsize = AtomicUsize::new(HUGE_BASE_PADDR);
+
+/// Counter to track the number of frames freed with
+/// [`AxMmHal::dealloc_frames`].
+pub(crate) static DEALLOC_FRAMES_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Nested page table operations [`FaultInjector`] can make fail.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PtOp {
+    /// `query`.
+    Query,
+    /// `map` and `remap`.
+    Map,
+}
+
+/// The failures injected with [`FaultInjector::fail`].
+#[cfg(feature = "alloc")]
+static INJECTED_FAULTS: Mutex<Vec<(PtOp, GuestPhysAddr, PagingError)>> = Mutex::new(Vec::new());
+
+/// Forces nested page table operations at given addresses to fail, to cover
+/// error branches that cannot be reached otherwise, e.g., a remap failing
+/// while handling a page fault.
+///
+/// Only the operations done through [`FaultInjector::check`] are affected.
+/// Injected failures last until [`MockHal::reset_state`].
+#[cfg(feature = "alloc")]
+pub(crate) struct FaultInjector;
+
+#[cfg(feature = "alloc")]
+impl FaultInjector {
+    /// Makes `op` fail with `err` at the page containing `gpa`.
+    pub(crate) fn fail(op: PtOp, gpa: GuestPhysAddr, err: PagingError) {
+        INJECTED_FAULTS.lock().push((op, gpa.align_down_4k(), err));
+    }
+
+    /// Removes all injected failures.
+    pub(crate) fn clear() {
+        INJECTED_FAULTS.lock().clear();
+    }
+
+    /// Returns the failure injected for `op` at `gpa`, if any.
+    pub(crate) fn check(op: PtOp, gpa: GuestPhysAddr) -> Result<(), PagingError> {
+        let page = gpa.align_down_4k();
+        match INJECTED_FAULTS
+            .lock()
+            .iter()
+            .find(|&&(o, g, _)| o == op && g == page)
+        {
+            Some(&(_, _, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 /// A mock implementation of AxMmHal for testing purposes.
 /// It simulates memory allocation and deallocation without actual hardware interaction.
@@ -62,6 +134,14 @@ impl AxMmHal for MockHal {
         Self::mock_dealloc_frame(_paddr)
     }
 
+    fn alloc_frames(num_frames: usize, align: usize) -> Option<HostPhysAddr> {
+        Self::mock_alloc_frames(num_frames, align)
+    }
+
+    fn dealloc_frames(paddr: HostPhysAddr, num_frames: usize) {
+        Self::mock_dealloc_frames(paddr, num_frames)
+    }
+
     fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
         Self::mock_phys_to_virt(paddr)
     }
@@ -120,20 +200,66 @@ impl MockHal {
         Some(PhysAddr::from_usize(paddr))
     }
 
+    /// Simulates the allocation of contiguous physical frames with the given
+    /// alignment. Frames skipped for alignment are simply leaked.
+    ///
+    /// Allocations aligned to 2M come from the huge frame memory, as long as
+    /// [`MockHal::set_huge_frames`] left enough frames.
+    pub(crate) fn mock_alloc_frames(num_frames: usize, align: usize) -> Option<PhysAddr> {
+        if ALLOC_SHOULD_FAIL.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        if align >= 0x20_0000 {
+            HUGE_FRAMES_LEFT
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(num_frames)
+                })
+                .ok()?;
+            let size = num_frames * PAGE_SIZE;
+            let paddr = NEXT_HUGE_PADDR
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+                    Some(next.next_multiple_of(align) + size)
+                })
+                .unwrap()
+                .next_multiple_of(align);
+            ALLOC_COUNT.fetch_add(num_frames, Ordering::SeqCst);
+            return Some(PhysAddr::from_usize(paddr));
+        }
+
+        let size = num_frames * PAGE_SIZE;
+        let paddr = NEXT_PADDR
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+                Some(next.next_multiple_of(align) + size)
+            })
+            .unwrap()
+            .next_multiple_of(align);
+        if paddr + size > MEMORY_LEN + BASE_PADDR {
+            return None;
+        }
+        ALLOC_COUNT.fetch_add(num_frames, Ordering::SeqCst);
+        Some(PhysAddr::from_usize(paddr))
+    }
+
     /// Simulates the deallocation of a single physical frame.
     pub(crate) fn mock_dealloc_frame(_paddr: PhysAddr) {
         DEALLOC_COUNT.fetch_add(1, Ordering::SeqCst);
     }
 
+    /// Simulates the deallocation of contiguous physical frames.
+    pub(crate) fn mock_dealloc_frames(_paddr: PhysAddr, num_frames: usize) {
+        DEALLOC_COUNT.fetch_add(num_frames, Ordering::SeqCst);
+        DEALLOC_FRAMES_COUNT.fetch_add(num_frames, Ordering::SeqCst);
+    }
+
     /// In this test mock, the "virtual address" is simply a direct pointer
     /// to the corresponding location within the `MEMORY` array.
     /// It simulates a physical-to-virtual memory mapping for test purposes.
     pub(crate) fn mock_phys_to_virt(paddr: PhysAddr) -> VirtAddr {
         let paddr_usize = paddr.as_usize();
         assert!(
-            paddr_usize >= BASE_PADDR && paddr_usize < BASE_PADDR + MEMORY_LEN,
-            "Physical address {:#x} out of bounds",
-            paddr_usize
+            (BASE_PADDR..BASE_PADDR + MEMORY_LEN).contains(&paddr_usize),
+            "Physical address {paddr_usize:#x} out of bounds"
         );
         let offset = paddr_usize - BASE_PADDR;
         VirtAddr::from_usize(MEMORY.lock().0.as_ptr() as usize + offset)
@@ -144,14 +270,18 @@ impl MockHal {
         let base_virt = MEMORY.lock().0.as_ptr() as usize;
         let vaddr_usize = vaddr.as_usize();
         assert!(
-            vaddr_usize >= base_virt && vaddr_usize < base_virt + MEMORY_LEN,
-            "Virtual address {:#x} out of bounds",
-            vaddr_usize
+            (base_virt..base_virt + MEMORY_LEN).contains(&vaddr_usize),
+            "Virtual address {vaddr_usize:#x} out of bounds"
         );
         let offset = vaddr_usize - base_virt;
         PhysAddr::from_usize(offset + BASE_PADDR)
     }
 
+    /// Makes `num_frames` 4K frames available for allocations aligned to 2M.
+    pub(crate) fn set_huge_frames(num_frames: usize) {
+        HUGE_FRAMES_LEFT.store(num_frames, Ordering::SeqCst);
+    }
+
     /// Helper function to control the simulated allocation failure.
     pub(crate) fn set_alloc_fail(fail: bool) {
         ALLOC_SHOULD_FAIL.store(fail, Ordering::SeqCst);
@@ -162,8 +292,16 @@ impl MockHal {
     pub(crate) fn reset_state() {
         NEXT_PADDR.store(BASE_PADDR, Ordering::SeqCst);
         ALLOC_SHOULD_FAIL.store(false, Ordering::SeqCst);
+        HUGE_FRAMES_LEFT.store(0, Ordering::SeqCst);
+        NEXT_HUGE_PADDR.store(HUGE_BASE_PADDR, Ordering::SeqCst);
+        #[cfg(feature = "alloc")]
+        FaultInjector::clear();
         ALLOC_COUNT.store(0, Ordering::SeqCst);
         DEALLOC_COUNT.store(0, Ordering::SeqCst);
+        DEALLOC_FRAMES_COUNT.store(0, Ordering::SeqCst);
+        // Frames reused by the allocator, and left over by leaked spaces.
+        #[cfg(feature = "frame-ownership")]
+        crate::address_space::reset_claims();
         // Lock and clear the simulated memory.
         MEMORY.lock().0.fill(0); // Fill with zeros to clear any previous test data.
     }
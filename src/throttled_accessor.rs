@@ -0,0 +1,287 @@
+//! Quotas on the guest memory accesses of less-trusted device backends.
+//!
+//! A device backend running in a less-trusted domain gets a
+//! [`ThrottledAccessor`] instead of the accessor of the guest memory itself,
+//! so that it can neither monopolize memory bandwidth nor scan all of the
+//! guest RAM unnoticed.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use axerrno::{AxError, AxResult};
+use memory_addr::PhysAddr;
+
+use crate::{GuestMemoryAccessor, GuestPhysAddr, MappingFlags, MisalignedPolicy, PartialTransfer};
+
+/// The limits enforced by a [`ThrottledAccessor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessLimits {
+    /// The number of bytes that may be accessed per second.
+    pub bytes_per_sec: u64,
+    /// The maximum number of bytes accessed by a single call.
+    pub max_bytes_per_call: usize,
+    /// The window the rate is measured over.
+    pub window: Duration,
+}
+
+impl AccessLimits {
+    /// Returns the number of bytes that may be accessed in a window.
+    fn budget(&self) -> u64 {
+        (self.bytes_per_sec as u128 * self.window.as_nanos() / 1_000_000_000).min(u64::MAX as u128)
+            as u64
+    }
+}
+
+/// Provides the time to a [`ThrottledAccessor`], and observes the accesses
+/// it rejects.
+pub trait AccessMonitor: Sync {
+    /// Returns the current time from any monotonic clock.
+    fn now(&self) -> Duration;
+
+    /// Called when an access of `len` bytes at `guest_addr` by the backend
+    /// tagged `tag` is rejected. Does nothing by default.
+    fn limit_exceeded(&self, tag: usize, guest_addr: GuestPhysAddr, len: usize) {
+        let _ = (tag, guest_addr, len);
+    }
+}
+
+/// A [`GuestMemoryAccessor`] forwarding to another one within the
+/// [`AccessLimits`] of a device backend identified by a tag.
+///
+/// Accesses larger than [`AccessLimits::max_bytes_per_call`] fail with
+/// [`AxError::InvalidInput`], and accesses beyond the rate limit fail with
+/// [`AxError::WouldBlock`] until the next window; both are reported to the
+/// [`AccessMonitor`].
+///
+/// Addresses translated directly with
+/// [`GuestMemoryAccessor::translate_and_get_limit`] are charged for the
+/// whole returned limit, which is capped to the per-call limit and to the
+/// remaining budget.
+pub struct ThrottledAccessor<'a, A: GuestMemoryAccessor> {
+    inner: &'a A,
+    tag: usize,
+    limits: AccessLimits,
+    monitor: &'a dyn AccessMonitor,
+    /// The start of the current window, in nanoseconds.
+    window_start: AtomicU64,
+    /// The number of bytes accessed in the current window.
+    used: AtomicU64,
+}
+
+impl<'a, A: GuestMemoryAccessor> ThrottledAccessor<'a, A> {
+    /// Creates an accessor to `inner` for the backend tagged `tag`.
+    pub fn new(
+        inner: &'a A,
+        tag: usize,
+        limits: AccessLimits,
+        monitor: &'a dyn AccessMonitor,
+    ) -> Self {
+        Self {
+            inner,
+            tag,
+            limits,
+            window_start: AtomicU64::new(monitor.now().as_nanos() as u64),
+            used: AtomicU64::new(0),
+            monitor,
+        }
+    }
+
+    /// Returns the tag of the backend.
+    pub const fn tag(&self) -> usize {
+        self.tag
+    }
+
+    /// Returns the number of bytes that can still be accessed in the current
+    /// window.
+    pub fn remaining(&self) -> u64 {
+        self.roll_window();
+        self.limits
+            .budget()
+            .saturating_sub(self.used.load(Ordering::Acquire))
+    }
+
+    /// Starts a new window if the current one is over.
+    fn roll_window(&self) {
+        let now = self.monitor.now().as_nanos() as u64;
+        let start = self.window_start.load(Ordering::Acquire);
+        if now.saturating_sub(start) >= self.limits.window.as_nanos() as u64
+            && self
+                .window_start
+                .compare_exchange(start, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            self.used.store(0, Ordering::Release);
+        }
+    }
+
+    /// Charges an access of `len` bytes at `guest_addr`.
+    fn charge(&self, guest_addr: GuestPhysAddr, len: usize) -> AxResult {
+        if len > self.limits.max_bytes_per_call {
+            self.monitor.limit_exceeded(self.tag, guest_addr, len);
+            return Err(AxError::InvalidInput);
+        }
+        self.roll_window();
+        let budget = self.limits.budget();
+        let charged = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(len as u64).filter(|&used| used <= budget)
+            });
+        if charged.is_err() {
+            self.monitor.limit_exceeded(self.tag, guest_addr, len);
+            return Err(AxError::WouldBlock);
+        }
+        Ok(())
+    }
+}
+
+impl<A: GuestMemoryAccessor> GuestMemoryAccessor for ThrottledAccessor<'_, A> {
+    fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+        let (host_addr, limit) = self.inner.translate_and_get_limit(guest_addr)?;
+        let limit = (limit as u64)
+            .min(self.limits.max_bytes_per_call as u64)
+            .min(self.remaining()) as usize;
+        if limit == 0 {
+            self.monitor.limit_exceeded(self.tag, guest_addr, 0);
+            return None;
+        }
+        self.charge(guest_addr, limit).ok()?;
+        Some((host_addr, limit))
+    }
+
+    fn read_obj<V: Copy>(&self, guest_addr: GuestPhysAddr) -> AxResult<V> {
+        self.charge(guest_addr, size_of::<V>())?;
+        self.inner.read_obj(guest_addr)
+    }
+
+    fn write_obj<V: Copy>(&self, guest_addr: GuestPhysAddr, val: V) -> AxResult<()> {
+        self.charge(guest_addr, size_of::<V>())?;
+        self.inner.write_obj(guest_addr, val)
+    }
+
+    fn read_buffer(&self, guest_addr: GuestPhysAddr, buffer: &mut [u8]) -> AxResult<()> {
+        self.charge(guest_addr, buffer.len())?;
+        self.inner.read_buffer(guest_addr, buffer)
+    }
+
+    fn write_buffer(&self, guest_addr: GuestPhysAddr, buffer: &[u8]) -> AxResult<()> {
+        self.charge(guest_addr, buffer.len())?;
+        self.inner.write_buffer(guest_addr, buffer)
+    }
+
+    fn read_exact_at(
+        &self,
+        guest_addr: GuestPhysAddr,
+        buffer: &mut [u8],
+    ) -> Result<(), PartialTransfer> {
+        self.charge(guest_addr, buffer.len())
+            .map_err(|error| PartialTransfer::none(guest_addr, error))?;
+        self.inner.read_exact_at(guest_addr, buffer)
+    }
+
+    fn write_all_at(
+        &self,
+        guest_addr: GuestPhysAddr,
+        buffer: &[u8],
+    ) -> Result<(), PartialTransfer> {
+        self.charge(guest_addr, buffer.len())
+            .map_err(|error| PartialTransfer::none(guest_addr, error))?;
+        self.inner.write_all_at(guest_addr, buffer)
+    }
+
+    fn mark_dirty(&self, guest_addr: GuestPhysAddr, len: usize) {
+        self.inner.mark_dirty(guest_addr, len)
+    }
+
+    fn write_barrier(&self) {
+        self.inner.write_barrier()
+    }
+
+    fn read_barrier(&self) {
+        self.inner.read_barrier()
+    }
+
+    fn flush_wc(&self, guest_addr: GuestPhysAddr, len: usize) {
+        self.inner.flush_wc(guest_addr, len)
+    }
+
+    fn misaligned_policy(&self) -> MisalignedPolicy {
+        self.inner.misaligned_policy()
+    }
+
+    fn allows_access(&self, guest_addr: GuestPhysAddr, access: MappingFlags) -> bool {
+        self.inner.allows_access(guest_addr, access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::AtomicUsize;
+
+    /// Maps guest addresses `[0, 0x100)` to its buffer.
+    struct BufTranslator(UnsafeCell<[u8; 0x100]>);
+
+    impl GuestMemoryAccessor for BufTranslator {
+        fn translate_and_get_limit(&self, guest_addr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
+            let gpa = guest_addr.as_usize();
+            (gpa < 0x100).then(|| (PhysAddr::from(self.0.get() as usize + gpa), 0x100 - gpa))
+        }
+    }
+
+    #[derive(Default)]
+    struct TestMonitor {
+        now_ms: AtomicU64,
+        rejected: AtomicUsize,
+    }
+
+    impl AccessMonitor for TestMonitor {
+        fn now(&self) -> Duration {
+            Duration::from_millis(self.now_ms.load(Ordering::Relaxed))
+        }
+
+        fn limit_exceeded(&self, tag: usize, _guest_addr: GuestPhysAddr, _len: usize) {
+            assert_eq!(tag, 7);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_throttled_accessor() {
+        let mem = BufTranslator(UnsafeCell::new([0; 0x100]));
+        let monitor = TestMonitor::default();
+        let limits = AccessLimits {
+            bytes_per_sec: 400,
+            max_bytes_per_call: 0x10,
+            window: Duration::from_millis(100),
+        };
+        let accessor = ThrottledAccessor::new(&mem, 7, limits, &monitor);
+        let gpa = GuestPhysAddr::from(0x20);
+
+        accessor.write_obj(gpa, 0x1122_3344_5566_7788u64).unwrap();
+        assert_eq!(accessor.read_obj::<u64>(gpa), Ok(0x1122_3344_5566_7788));
+        assert_eq!(
+            accessor.write_buffer(gpa, &[0; 0x11]),
+            Err(AxError::InvalidInput)
+        );
+        let mut buf = [0; 0x10];
+        accessor.read_buffer(gpa, &mut buf).unwrap();
+        assert_eq!(accessor.remaining(), 8);
+        // Direct translations are capped to the remaining budget.
+        assert_eq!(
+            accessor
+                .translate_and_get_limit(gpa)
+                .map(|(_, limit)| limit),
+            Some(8)
+        );
+        assert_eq!(accessor.read_obj::<u8>(gpa), Err(AxError::WouldBlock));
+        assert_eq!(accessor.translate_and_get_limit(gpa), None);
+        assert_eq!(monitor.rejected.load(Ordering::Relaxed), 3);
+
+        // A new window starts.
+        monitor.now_ms.store(100, Ordering::Relaxed);
+        assert_eq!(accessor.remaining(), 40);
+        assert_eq!(accessor.read_obj::<u32>(gpa), Ok(0x5566_7788));
+    }
+}